@@ -0,0 +1,51 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Batch verification of Ed25519 signatures compared to verifying
+//! each signature individually.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libp2p_core::identity::ed25519::{self, Keypair};
+
+fn verify(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ed25519");
+
+    for &n in &[1, 8, 64, 256] {
+        let keypairs = (0 .. n).map(|_| Keypair::generate()).collect::<Vec<_>>();
+        let msgs = (0 .. n).map(|i| i.to_be_bytes().repeat(8)).collect::<Vec<_>>();
+        let sigs = keypairs.iter().zip(&msgs).map(|(kp, msg)| kp.sign(msg)).collect::<Vec<_>>();
+        let items = keypairs.iter().zip(&msgs).zip(&sigs)
+            .map(|((kp, msg), sig)| (kp.public(), msg.as_slice(), sig.as_slice()))
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("individual", n), &items, |b, items| b.iter(|| {
+            assert!(items.iter().all(|(pk, msg, sig)| pk.verify(msg, sig)))
+        }));
+        group.bench_with_input(BenchmarkId::new("batch", n), &items, |b, items| b.iter(|| {
+            assert_eq!(ed25519::verify_batch(items), Ok(()))
+        }));
+    }
+
+    group.finish()
+}
+
+criterion_group!(benches, verify);
+criterion_main!(benches);
@@ -30,6 +30,8 @@ pub mod error;
 
 use self::error::*;
 use crate::{PeerId, keys_proto};
+use std::hash::{Hash, Hasher};
+use zeroize::Zeroize;
 
 /// Identity keypair of a node.
 ///
@@ -66,7 +68,19 @@ impl Keypair {
         Keypair::Ed25519(ed25519::Keypair::generate())
     }
 
+    /// Deterministically derive an Ed25519 keypair from a 32-byte seed.
+    ///
+    /// The same seed always yields the same keypair and thus the same
+    /// [`PeerId`], which is useful e.g. for reproducible test networks.
+    /// The seed is zeroed after use.
+    pub fn ed25519_from_seed(mut seed: [u8; 32]) -> Result<Keypair, DecodingError> {
+        ed25519::SecretKey::from_bytes(&mut seed)
+            .map(|sk| Keypair::Ed25519(ed25519::Keypair::from(sk)))
+    }
+
     /// Generate a new Secp256k1 keypair.
+    ///
+    /// Only available with the `secp256k1` feature (enabled by default).
     #[cfg(feature = "secp256k1")]
     pub fn generate_secp256k1() -> Keypair {
         Keypair::Secp256k1(secp256k1::Keypair::generate())
@@ -84,6 +98,8 @@ impl Keypair {
     /// Decode a keypair from a DER-encoded Secp256k1 secret key in an ECPrivateKey
     /// structure as defined in [RFC5915].
     ///
+    /// Only available with the `secp256k1` feature (enabled by default).
+    ///
     /// [RFC5915]: https://tools.ietf.org/html/rfc5915
     #[cfg(feature = "secp256k1")]
     pub fn secp256k1_from_der(der: &mut [u8]) -> Result<Keypair, DecodingError> {
@@ -94,16 +110,98 @@ impl Keypair {
     /// Sign a message using the private key of this keypair, producing
     /// a signature that can be verified using the corresponding public key.
     pub fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, SigningError> {
+        let mut signature = Vec::new();
+        self.sign_into(msg, &mut signature)?;
+        Ok(signature)
+    }
+
+    /// Sign a message like [`Keypair::sign`], but append the signature to
+    /// `out` instead of allocating a new buffer.
+    ///
+    /// This allows reusing a buffer when signing many messages.
+    pub fn sign_into(&self, msg: &[u8], out: &mut Vec<u8>) -> Result<(), SigningError> {
         use Keypair::*;
         match self {
-            Ed25519(ref pair) => Ok(pair.sign(msg)),
+            Ed25519(ref pair) => Ok(pair.sign_into(msg, out)),
             #[cfg(not(target_arch = "wasm32"))]
-            Rsa(ref pair) => pair.sign(msg),
+            Rsa(ref pair) => pair.sign_into(msg, out),
             #[cfg(feature = "secp256k1")]
-            Secp256k1(ref pair) => pair.secret().sign(msg)
+            Secp256k1(ref pair) => pair.secret().sign_into(msg, out)
         }
     }
 
+    /// Encode the keypair into a protobuf structure for storage, e.g. as a
+    /// node identity file.
+    ///
+    /// This is the `PrivateKey` message of the libp2p key specification,
+    /// which is understood by other libp2p implementations. The inverse is
+    /// [`Keypair::from_protobuf_encoding`].
+    pub fn to_protobuf_encoding(&self) -> Result<Vec<u8>, DecodingError> {
+        use prost::Message;
+
+        let pk = match self {
+            Self::Ed25519(data) => keys_proto::PrivateKey {
+                r#type: keys_proto::KeyType::Ed25519.into(),
+                data: data.encode().to_vec()
+            },
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Rsa(data) => keys_proto::PrivateKey {
+                r#type: keys_proto::KeyType::Rsa.into(),
+                data: data.encode_pkcs1()
+            },
+            #[cfg(feature = "secp256k1")]
+            Self::Secp256k1(data) => keys_proto::PrivateKey {
+                r#type: keys_proto::KeyType::Secp256k1.into(),
+                data: data.secret().to_bytes().to_vec()
+            }
+        };
+
+        let mut buf = Vec::with_capacity(pk.encoded_len());
+        pk.encode(&mut buf).expect("Vec<u8> provides capacity as needed");
+        Ok(buf)
+    }
+
+    /// Decode a keypair from a protobuf structure as produced by
+    /// [`Keypair::to_protobuf_encoding`], zeroing the key material of
+    /// the decoded structure.
+    pub fn from_protobuf_encoding(bytes: &[u8]) -> Result<Keypair, DecodingError> {
+        use prost::Message;
+
+        let mut private_key = keys_proto::PrivateKey::decode(bytes)?;
+
+        let key_type = keys_proto::KeyType::from_i32(private_key.r#type)
+            .ok_or(DecodingError::UnknownKeyType(private_key.r#type));
+
+        let keypair = match key_type {
+            Ok(keys_proto::KeyType::Ed25519) => {
+                ed25519::Keypair::decode(&mut private_key.data).map(Keypair::Ed25519)
+            },
+            #[cfg(not(target_arch = "wasm32"))]
+            Ok(keys_proto::KeyType::Rsa) => {
+                rsa::Keypair::from_pkcs1(&mut private_key.data).map(Keypair::Rsa)
+            },
+            #[cfg(target_arch = "wasm32")]
+            Ok(keys_proto::KeyType::Rsa) => {
+                log::debug!("support for RSA was disabled at compile-time");
+                Err(DecodingError::UnsupportedKeyType(KeyType::Rsa))
+            },
+            #[cfg(feature = "secp256k1")]
+            Ok(keys_proto::KeyType::Secp256k1) => {
+                secp256k1::SecretKey::from_bytes(&mut private_key.data)
+                    .map(|sk| Keypair::Secp256k1(sk.into()))
+            },
+            #[cfg(not(feature = "secp256k1"))]
+            Ok(keys_proto::KeyType::Secp256k1) => {
+                log::debug!("support for secp256k1 was disabled at compile-time");
+                Err(DecodingError::UnsupportedKeyType(KeyType::Secp256k1))
+            },
+            Err(e) => Err(e)
+        };
+
+        private_key.data.zeroize();
+        keypair
+    }
+
     /// Get the public key of this keypair.
     pub fn public(&self) -> PublicKey {
         use Keypair::*;
@@ -115,10 +213,89 @@ impl Keypair {
             Secp256k1(pair) => PublicKey::Secp256k1(pair.public().clone()),
         }
     }
+
+    /// Get the `PeerId` of this keypair.
+    ///
+    /// The `PeerId` is derived from the public key on first use and cached
+    /// afterwards, so this is cheaper than `keypair.public().into_peer_id()`
+    /// when called repeatedly.
+    pub fn peer_id(&self) -> PeerId {
+        use Keypair::*;
+        let cache = match self {
+            Ed25519(pair) => pair.peer_id_cache(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Rsa(pair) => pair.peer_id_cache(),
+            #[cfg(feature = "secp256k1")]
+            Secp256k1(pair) => pair.peer_id_cache(),
+        };
+        cache.get_or_init(|| {
+            #[cfg(test)]
+            tests::PEER_ID_DERIVATIONS.with(|n| n.set(n.get() + 1));
+            self.public().into_peer_id()
+        }).clone()
+    }
+}
+
+/// Overwrite the secret key material of this keypair and forget the
+/// cached `PeerId`.
+///
+/// What is overwritten depends on the key type, see the `Zeroize`
+/// implementations of `ed25519::Keypair`, `rsa::Keypair` and
+/// `secp256k1::Keypair`. In particular, the key held by the RSA backend
+/// and copies made by the Secp256k1 backend while signing are not
+/// overwritten.
+#[cfg(feature = "zeroize")]
+impl Zeroize for Keypair {
+    fn zeroize(&mut self) {
+        use Keypair::*;
+        match self {
+            Ed25519(pair) => pair.zeroize(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Rsa(pair) => pair.zeroize(),
+            #[cfg(feature = "secp256k1")]
+            Secp256k1(pair) => pair.zeroize()
+        }
+    }
+}
+
+/// Overwrite the secret key material of this keypair when it is dropped.
+#[cfg(feature = "zeroize")]
+impl Drop for Keypair {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+/// The type of a node's identity key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyType {
+    /// An Ed25519 key.
+    Ed25519,
+    /// An RSA key.
+    Rsa,
+    /// A Secp256k1 key.
+    Secp256k1
+}
+
+impl KeyType {
+    /// Get the key type of a protobuf key type tag, if known.
+    pub(crate) fn from_protobuf(tag: i32) -> Option<KeyType> {
+        match keys_proto::KeyType::from_i32(tag)? {
+            keys_proto::KeyType::Ed25519 => Some(KeyType::Ed25519),
+            keys_proto::KeyType::Rsa => Some(KeyType::Rsa),
+            keys_proto::KeyType::Secp256k1 => Some(KeyType::Secp256k1)
+        }
+    }
 }
 
 /// The public key of a node's identity keypair.
-#[derive(Clone, Debug, PartialEq, Eq)]
+///
+/// Equality and hashing are defined over the canonical protobuf encoding
+/// (see [`PublicKey::into_protobuf_encoding`]), so two keys are equal if and
+/// only if they yield the same [`PeerId`]. In particular, a key decoded from
+/// a different encoding of the same key, e.g. an uncompressed secp256k1
+/// point, compares equal to the key decoded from its canonical encoding.
+#[derive(Clone, Debug)]
 pub enum PublicKey {
     /// A public Ed25519 key.
     Ed25519(ed25519::PublicKey),
@@ -135,20 +312,52 @@ impl PublicKey {
     /// that the signature has been produced by the corresponding
     /// private key (authenticity), and that the message has not been
     /// tampered with (integrity).
+    ///
+    /// See [`PublicKey::verify_detailed`] for the reason of a failure.
     pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        self.verify_detailed(msg, sig).is_ok()
+    }
+
+    /// Verify a signature for a message using this public key, like
+    /// [`PublicKey::verify`], but report why verification failed.
+    ///
+    /// Returns `VerifyError::Unsupported` if the signature can not be
+    /// checked with this key, e.g. because it was produced by a key of a
+    /// different type, and `VerifyError::BadSignature` if the signature
+    /// is invalid for the message.
+    pub fn verify_detailed(&self, msg: &[u8], sig: &[u8]) -> Result<(), VerifyError> {
         use PublicKey::*;
         match self {
-            Ed25519(pk) => pk.verify(msg, sig),
+            Ed25519(pk) => pk.verify_detailed(msg, sig),
+            #[cfg(not(target_arch = "wasm32"))]
+            Rsa(pk) => pk.verify_detailed(msg, sig),
+            #[cfg(feature = "secp256k1")]
+            Secp256k1(pk) => pk.verify_detailed(msg, sig)
+        }
+    }
+
+    /// Get the type of this public key.
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            PublicKey::Ed25519(_) => KeyType::Ed25519,
             #[cfg(not(target_arch = "wasm32"))]
-            Rsa(pk) => pk.verify(msg, sig),
+            PublicKey::Rsa(_) => KeyType::Rsa,
             #[cfg(feature = "secp256k1")]
-            Secp256k1(pk) => pk.verify(msg, sig)
+            PublicKey::Secp256k1(_) => KeyType::Secp256k1
         }
     }
 
     /// Encode the public key into a protobuf structure for storage or
     /// exchange with other nodes.
+    ///
+    /// This is the `PublicKey` message of the libp2p key specification, as
+    /// used e.g. in identify payloads and for deriving a [`PeerId`]. The
+    /// inverse is [`PublicKey::from_protobuf_encoding`].
     pub fn into_protobuf_encoding(self) -> Vec<u8> {
+        self.encode_protobuf()
+    }
+
+    fn encode_protobuf(&self) -> Vec<u8> {
         use prost::Message;
 
         let public_key = match self {
@@ -178,15 +387,24 @@ impl PublicKey {
 
     /// Decode a public key from a protobuf structure, e.g. read from storage
     /// or received from another node.
+    ///
+    /// This is the inverse of [`PublicKey::into_protobuf_encoding`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `DecodingError::MalformedProtobuf` if the bytes are not a
+    /// valid protobuf `PublicKey` message, `DecodingError::UnknownKeyType`
+    /// or `DecodingError::UnsupportedKeyType` if the key type is unknown or
+    /// not supported by this build, and `DecodingError::InvalidKey` if the
+    /// key data is invalid for its key type.
     pub fn from_protobuf_encoding(bytes: &[u8]) -> Result<PublicKey, DecodingError> {
         use prost::Message;
 
         #[allow(unused_mut)] // Due to conditional compilation.
-        let mut pubkey = keys_proto::PublicKey::decode(bytes)
-            .map_err(|e| DecodingError::new("Protobuf").source(e))?;
+        let mut pubkey = keys_proto::PublicKey::decode(bytes)?;
 
         let key_type = keys_proto::KeyType::from_i32(pubkey.r#type)
-            .ok_or_else(|| DecodingError::new(format!("unknown key type: {}", pubkey.r#type)))?;
+            .ok_or(DecodingError::UnknownKeyType(pubkey.r#type))?;
 
         match key_type {
             keys_proto::KeyType::Ed25519 => {
@@ -199,7 +417,7 @@ impl PublicKey {
             #[cfg(target_arch = "wasm32")]
             keys_proto::KeyType::Rsa => {
                 log::debug!("support for RSA was disabled at compile-time");
-                Err(DecodingError::new("Unsupported"))
+                Err(DecodingError::UnsupportedKeyType(KeyType::Rsa))
             },
             #[cfg(feature = "secp256k1")]
             keys_proto::KeyType::Secp256k1 => {
@@ -208,14 +426,446 @@ impl PublicKey {
             #[cfg(not(feature = "secp256k1"))]
             keys_proto::KeyType::Secp256k1 => {
                 log::debug!("support for secp256k1 was disabled at compile-time");
-                Err("Unsupported".to_string().into())
+                Err(DecodingError::UnsupportedKeyType(KeyType::Secp256k1))
             }
         }
     }
 
+    /// Encode the public key as standard base64 (with padding) of its
+    /// protobuf encoding, e.g. for embedding in configuration files.
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.encode_protobuf())
+    }
+
+    /// Decode a public key from the base64 format produced by
+    /// [`PublicKey::to_base64`].
+    pub fn from_base64(s: &str) -> Result<PublicKey, DecodingError> {
+        let bytes = base64::decode(s)?;
+        PublicKey::from_protobuf_encoding(&bytes)
+    }
+
     /// Convert the `PublicKey` into the corresponding `PeerId`.
     pub fn into_peer_id(self) -> PeerId {
         self.into()
     }
+
+    /// Derive the `PeerId` corresponding to this `PublicKey` without
+    /// consuming it.
+    pub fn to_peer_id(&self) -> PeerId {
+        self.clone().into_peer_id()
+    }
+}
+
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &PublicKey) -> bool {
+        self.encode_protobuf() == other.encode_protobuf()
+    }
 }
 
+impl Eq for PublicKey {}
+
+impl Hash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.encode_protobuf().hash(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// The number of times `Keypair::peer_id` derived a `PeerId` on this thread.
+        pub(super) static PEER_ID_DERIVATIONS: Cell<usize> = Cell::new(0);
+    }
+
+    #[test]
+    fn keypair_peer_id_is_cached() {
+        let keypairs = vec![
+            Keypair::generate_ed25519(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Keypair::rsa_from_pkcs8(&mut include_bytes!("identity/test/rsa-2048.pk8").to_vec()).unwrap(),
+            #[cfg(feature = "secp256k1")]
+            Keypair::generate_secp256k1(),
+        ];
+        for keypair in keypairs {
+            let before = PEER_ID_DERIVATIONS.with(Cell::get);
+            let peer_id = keypair.peer_id();
+            assert_eq!(keypair.peer_id(), peer_id);
+            assert_eq!(keypair.clone().peer_id(), peer_id);
+            assert_eq!(peer_id, keypair.public().into_peer_id());
+            assert_eq!(PEER_ID_DERIVATIONS.with(Cell::get), before + 1);
+        }
+    }
+
+    #[test]
+    fn ed25519_from_seed_is_deterministic() {
+        let seed = [7; 32];
+        let kp1 = Keypair::ed25519_from_seed(seed).unwrap();
+        let kp2 = Keypair::ed25519_from_seed(seed).unwrap();
+        assert_eq!(kp1.public().into_peer_id(), kp2.public().into_peer_id());
+        assert_eq!(kp1.public().into_peer_id().to_base58(), "12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7");
+        assert_ne!(
+            kp1.public(),
+            Keypair::ed25519_from_seed([8; 32]).unwrap().public()
+        );
+    }
+
+    fn assert_keypair_protobuf_round_trip(keypair: Keypair) {
+        let encoded = keypair.to_protobuf_encoding().unwrap();
+        let decoded = Keypair::from_protobuf_encoding(&encoded).unwrap();
+        assert_eq!(decoded.public(), keypair.public());
+        assert_eq!(decoded.to_protobuf_encoding().unwrap(), encoded);
+        let msg = b"hello world";
+        assert!(keypair.public().verify(msg, &decoded.sign(msg).unwrap()));
+    }
+
+    #[test]
+    fn ed25519_keypair_protobuf_round_trip() {
+        assert_keypair_protobuf_round_trip(Keypair::generate_ed25519());
+    }
+
+    #[test]
+    #[cfg(feature = "secp256k1")]
+    fn secp256k1_keypair_protobuf_round_trip() {
+        assert_keypair_protobuf_round_trip(Keypair::generate_secp256k1());
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rsa_keypair_protobuf_round_trip() {
+        let mut pkcs8 = include_bytes!("identity/test/rsa-2048.pk8").to_vec();
+        assert_keypair_protobuf_round_trip(Keypair::rsa_from_pkcs8(&mut pkcs8).unwrap());
+    }
+
+    // Key vectors exported from go-libp2p, as published with the libp2p
+    // peer ID specification.
+    const GO_ED25519_PRIVATE: &[u8] = include_bytes!("identity/test/go-ed25519.pb");
+    const GO_ED25519_PUBLIC: &[u8] = include_bytes!("identity/test/go-ed25519.pub.pb");
+    #[cfg(feature = "secp256k1")]
+    const GO_SECP256K1_PRIVATE: &[u8] = include_bytes!("identity/test/go-secp256k1.pb");
+    #[cfg(feature = "secp256k1")]
+    const GO_SECP256K1_PUBLIC: &[u8] = include_bytes!("identity/test/go-secp256k1.pub.pb");
+    // An RSA key in the encoding of go-libp2p, i.e. the DER of a PKCS#1
+    // RSAPrivateKey and of a PKIX SubjectPublicKeyInfo. It has been
+    // generated with OpenSSL, whose DER output is the same as Go's.
+    #[cfg(not(target_arch = "wasm32"))]
+    const GO_RSA_PRIVATE: &[u8] = include_bytes!("identity/test/go-rsa.pb");
+    #[cfg(not(target_arch = "wasm32"))]
+    const GO_RSA_PUBLIC: &[u8] = include_bytes!("identity/test/go-rsa.pub.pb");
+
+    fn assert_go_interop(private: &[u8], public: &[u8], peer_id: &str) {
+        let keypair = Keypair::from_protobuf_encoding(private).unwrap();
+        assert_eq!(keypair.to_protobuf_encoding().unwrap(), private);
+        assert_eq!(keypair.public().into_protobuf_encoding(), public);
+
+        let public = PublicKey::from_protobuf_encoding(public).unwrap();
+        assert_eq!(public, keypair.public());
+        assert_eq!(public.to_peer_id().to_base58(), peer_id);
+    }
+
+    #[test]
+    fn go_ed25519_interop() {
+        assert_go_interop(GO_ED25519_PRIVATE, GO_ED25519_PUBLIC, "12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq");
+    }
+
+    #[test]
+    #[cfg(feature = "secp256k1")]
+    fn go_secp256k1_interop() {
+        assert_go_interop(
+            GO_SECP256K1_PRIVATE,
+            GO_SECP256K1_PUBLIC,
+            "16Uiu2HAmLhLvBoYaoZfaMUKuibM6ac163GwKY74c5kiSLg5KvLpY"
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn go_rsa_interop() {
+        assert_go_interop(GO_RSA_PRIVATE, GO_RSA_PUBLIC, "Qmd5Nh8WgvhSEha6GE3LaHFP5WdAvmPdro5cc81uRsSjbg");
+
+        // Read the DER length at the start of `der`, returning the length
+        // and the number of bytes it is encoded with.
+        fn der_len(der: &[u8]) -> (usize, usize) {
+            match der[0] {
+                n if n < 0x80 => (n as usize, 1),
+                n => {
+                    let k = (n & 0x7f) as usize;
+                    (der[1 ..= k].iter().fold(0, |l, b| l << 8 | *b as usize), k + 1)
+                }
+            }
+        }
+
+        // The INTEGERs of the PKCS#1 RSAPrivateKey after the protobuf
+        // header, i.e. version, n, e, d, p, q, dp, dq and qinv.
+        let mut der = &GO_RSA_PRIVATE[5 ..];
+        assert_eq!(der[0], 0x30);
+        der = &der[1 + der_len(&der[1 ..]).1 ..];
+        let mut integers = Vec::new();
+        while !der.is_empty() {
+            assert_eq!(der[0], 0x02);
+            let (len, n) = der_len(&der[1 ..]);
+            integers.push(&der[1 + n .. 1 + n + len]);
+            der = &der[1 + n + len ..];
+        }
+        assert_eq!(integers.len(), 9);
+
+        // The primes carry a leading zero byte, which must be preserved.
+        assert!(integers[4][0] == 0 && integers[5][0] == 0);
+    }
+
+    #[test]
+    #[cfg(feature = "secp256k1")]
+    fn go_secp256k1_uncompressed_public_key() {
+        use prost::Message;
+
+        // go-libp2p accepts uncompressed points but always encodes compressed ones.
+        let public = match PublicKey::from_protobuf_encoding(GO_SECP256K1_PUBLIC).unwrap() {
+            PublicKey::Secp256k1(pk) => pk,
+            _ => panic!("unexpected key type")
+        };
+        let uncompressed = keys_proto::PublicKey {
+            r#type: keys_proto::KeyType::Secp256k1 as i32,
+            data: public.encode_uncompressed().to_vec()
+        };
+        let mut encoded = Vec::new();
+        uncompressed.encode(&mut encoded).unwrap();
+
+        let decoded = PublicKey::from_protobuf_encoding(&encoded).unwrap();
+        assert_eq!(decoded, PublicKey::Secp256k1(public));
+        assert_eq!(decoded.into_protobuf_encoding(), GO_SECP256K1_PUBLIC);
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rsa_pkcs1_protobuf_is_byte_exact() {
+        // PKCS#1 integers with the high bit set carry a leading zero byte,
+        // which must be preserved when re-encoding.
+        for pkcs8 in [
+            &include_bytes!("identity/test/rsa-2048.pk8")[..],
+            &include_bytes!("identity/test/rsa-3072.pk8")[..],
+            &include_bytes!("identity/test/rsa-4096.pk8")[..],
+        ].iter() {
+            let keypair = Keypair::rsa_from_pkcs8(&mut pkcs8.to_vec()).unwrap();
+            let encoded = keypair.to_protobuf_encoding().unwrap();
+            let decoded = Keypair::from_protobuf_encoding(&encoded).unwrap();
+            assert_eq!(decoded.to_protobuf_encoding().unwrap(), encoded);
+            assert_eq!(decoded.public().to_peer_id(), keypair.public().to_peer_id());
+        }
+    }
+
+    #[test]
+    fn sign_into_appends_signature() {
+        let keypairs = vec![
+            Keypair::generate_ed25519(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Keypair::rsa_from_pkcs8(&mut include_bytes!("identity/test/rsa-2048.pk8").to_vec()).unwrap(),
+            #[cfg(feature = "secp256k1")]
+            Keypair::generate_secp256k1(),
+        ];
+        for keypair in &keypairs {
+            let mut out = b"prefix".to_vec();
+            for msg in &[&b"hello"[..], &b"world"[..]] {
+                out.truncate(6);
+                keypair.sign_into(msg, &mut out).unwrap();
+                assert_eq!(&out[.. 6], b"prefix");
+                assert!(keypair.public().verify(msg, &out[6 ..]));
+            }
+        }
+
+        // Ed25519 and RSA PKCS#1 v1.5 signatures are deterministic.
+        for keypair in keypairs.iter().filter(|k| k.public().key_type() != KeyType::Secp256k1) {
+            let mut out = Vec::new();
+            keypair.sign_into(b"hello", &mut out).unwrap();
+            assert_eq!(out, keypair.sign(b"hello").unwrap());
+        }
+    }
+
+    #[test]
+    fn public_key_hash_set_lookup() {
+        use std::collections::HashSet;
+
+        let key = Keypair::generate_ed25519().public();
+        let other = Keypair::generate_ed25519().public();
+
+        let mut keys = HashSet::new();
+        assert!(keys.insert(key.clone()));
+        assert!(!keys.insert(key.clone()));
+        assert!(keys.contains(&key.clone()));
+        assert!(!keys.contains(&other));
+
+        let decoded = PublicKey::from_protobuf_encoding(&key.clone().into_protobuf_encoding()).unwrap();
+        assert!(keys.contains(&decoded));
+    }
+
+    #[test]
+    fn unknown_key_type_keypair_protobuf_fails() {
+        use prost::Message;
+
+        let private_key = keys_proto::PrivateKey { r#type: 42, data: vec![0; 64] };
+        let mut encoded = Vec::new();
+        private_key.encode(&mut encoded).unwrap();
+        match Keypair::from_protobuf_encoding(&encoded) {
+            Err(e) => assert!(e.to_string().contains("unknown key type: 42")),
+            Ok(_) => panic!("unexpected success decoding unknown key type"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "secp256k1")]
+    fn secp256k1_keypair_peer_id_and_signature() {
+        let keypair = Keypair::generate_secp256k1();
+        let public = keypair.public();
+        let peer_id = public.to_peer_id();
+        assert_eq!(peer_id.is_public_key(&public), Some(true));
+
+        let msg = b"hello world";
+        let sig = keypair.sign(msg).unwrap();
+        assert!(public.verify(msg, &sig));
+        assert!(!public.verify(b"hello world!", &sig));
+    }
+
+    #[test]
+    fn verify_detailed_valid_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let sig = keypair.sign(b"hello").unwrap();
+        assert_eq!(keypair.public().verify_detailed(b"hello", &sig), Ok(()));
+    }
+
+    #[test]
+    fn verify_detailed_tampered_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let mut sig = keypair.sign(b"hello").unwrap();
+        sig[0] ^= 1;
+        assert_eq!(keypair.public().verify_detailed(b"hello", &sig), Err(VerifyError::BadSignature));
+        assert!(!keypair.public().verify(b"hello", &sig));
+    }
+
+    #[test]
+    #[cfg(feature = "secp256k1")]
+    fn verify_detailed_mismatched_key_type() {
+        let sig = Keypair::generate_ed25519().sign(b"hello").unwrap();
+        let public = Keypair::generate_secp256k1().public();
+        assert_eq!(public.verify_detailed(b"hello", &sig), Err(VerifyError::Unsupported));
+    }
+
+    #[test]
+    fn ed25519_key_type() {
+        let key = Keypair::generate_ed25519().public();
+        assert_eq!(key.key_type(), KeyType::Ed25519);
+        assert_eq!(key.to_peer_id().key_type(), Some(KeyType::Ed25519));
+    }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn keypair_zeroize_clears_secret() {
+        let mut keypair = Keypair::generate_ed25519();
+        keypair.peer_id();
+        keypair.zeroize();
+        match &keypair {
+            Keypair::Ed25519(kp) => {
+                assert!(kp.secret().as_ref().iter().all(|b| *b == 0));
+                assert!(kp.peer_id_cache().get().is_none())
+            }
+            _ => panic!("unexpected key type")
+        }
+    }
+
+    #[test]
+    fn public_key_to_peer_id_equals_into_peer_id() {
+        let key = Keypair::generate_ed25519().public();
+        assert_eq!(key.to_peer_id(), key.clone().into_peer_id());
+    }
+
+    #[test]
+    fn ed25519_public_key_protobuf_round_trip() {
+        let key = Keypair::generate_ed25519().public();
+        let encoded = key.clone().into_protobuf_encoding();
+        assert_eq!(PublicKey::from_protobuf_encoding(&encoded).unwrap(), key);
+    }
+
+    #[test]
+    fn ed25519_public_key_base64_round_trip() {
+        let key = Keypair::generate_ed25519().public();
+        assert_eq!(PublicKey::from_base64(&key.to_base64()).unwrap(), key);
+        assert!(PublicKey::from_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn truncated_public_key_protobuf_fails() {
+        let encoded = Keypair::generate_ed25519().public().into_protobuf_encoding();
+        for len in 0 .. encoded.len() {
+            assert!(PublicKey::from_protobuf_encoding(&encoded[.. len]).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_key_type_protobuf_fails() {
+        use prost::Message;
+
+        let pubkey = keys_proto::PublicKey { r#type: 42, data: vec![0; 32] };
+        let mut encoded = Vec::new();
+        pubkey.encode(&mut encoded).unwrap();
+        let err = PublicKey::from_protobuf_encoding(&encoded).unwrap_err();
+        assert!(err.to_string().contains("unknown key type: 42"));
+    }
+
+    #[test]
+    fn decoding_error_variants() {
+        use prost::Message;
+
+        fn encode(r#type: keys_proto::KeyType, data: Vec<u8>) -> Vec<u8> {
+            let pubkey = keys_proto::PublicKey { r#type: r#type as i32, data };
+            let mut encoded = Vec::new();
+            pubkey.encode(&mut encoded).unwrap();
+            encoded
+        }
+
+        match PublicKey::from_protobuf_encoding(&[0xff]) {
+            Err(DecodingError::MalformedProtobuf(_)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        match Keypair::from_protobuf_encoding(&[0xff]) {
+            Err(DecodingError::MalformedProtobuf(_)) => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        match PublicKey::from_base64("not base64!") {
+            Err(DecodingError::MalformedBase64(_)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        let mut encoded = encode(keys_proto::KeyType::Ed25519, vec![0; 32]);
+        encoded[1] = 42;
+        match PublicKey::from_protobuf_encoding(&encoded) {
+            Err(DecodingError::UnknownKeyType(42)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        let encoded = encode(keys_proto::KeyType::Ed25519, vec![0; 31]);
+        match PublicKey::from_protobuf_encoding(&encoded) {
+            Err(DecodingError::InvalidKey { .. }) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        #[cfg(feature = "secp256k1")]
+        {
+            // Not a valid point encoding prefix.
+            let encoded = encode(keys_proto::KeyType::Secp256k1, vec![5; 33]);
+            match PublicKey::from_protobuf_encoding(&encoded) {
+                Err(DecodingError::InvalidKey { .. }) => {}
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+
+        #[cfg(not(feature = "secp256k1"))]
+        {
+            let encoded = encode(keys_proto::KeyType::Secp256k1, vec![2; 33]);
+            match PublicKey::from_protobuf_encoding(&encoded) {
+                Err(DecodingError::UnsupportedKeyType(KeyType::Secp256k1)) => {}
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+}
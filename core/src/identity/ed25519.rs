@@ -23,12 +23,14 @@
 use ed25519_dalek::{self as ed25519, Signer as _, Verifier as _};
 use rand::RngCore;
 use std::convert::TryFrom;
-use super::error::DecodingError;
+use super::error::{DecodingError, VerifyError};
+use crate::PeerId;
+use once_cell::sync::OnceCell;
 use zeroize::Zeroize;
 use core::fmt;
 
 /// An Ed25519 keypair.
-pub struct Keypair(ed25519::Keypair);
+pub struct Keypair(ed25519::Keypair, OnceCell<PeerId>);
 
 impl Keypair {
     /// Generate a new Ed25519 keypair.
@@ -47,13 +49,21 @@ impl Keypair {
     /// zeroing the input on success.
     pub fn decode(kp: &mut [u8]) -> Result<Keypair, DecodingError> {
         ed25519::Keypair::from_bytes(kp)
-            .map(|k| { kp.zeroize(); Keypair(k) })
+            .map(|k| { kp.zeroize(); Keypair(k, OnceCell::new()) })
             .map_err(|e| DecodingError::new("Ed25519 keypair").source(e))
     }
 
     /// Sign a message using the private key of this keypair.
     pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
-        self.0.sign(msg).to_bytes().to_vec()
+        let mut signature = Vec::with_capacity(ed25519::SIGNATURE_LENGTH);
+        self.sign_into(msg, &mut signature);
+        signature
+    }
+
+    /// Sign a message using the private key of this keypair, appending
+    /// the signature to `out`.
+    pub fn sign_into(&self, msg: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.sign(msg).to_bytes())
     }
 
     /// Get the public key of this keypair.
@@ -66,6 +76,21 @@ impl Keypair {
         SecretKey::from_bytes(&mut self.0.secret.to_bytes())
             .expect("ed25519::SecretKey::from_bytes(to_bytes(k)) != k")
     }
+
+    /// The cached `PeerId` of this keypair, see `identity::Keypair::peer_id`.
+    pub(crate) fn peer_id_cache(&self) -> &OnceCell<PeerId> {
+        &self.1
+    }
+}
+
+/// Overwrite the secret key material of this keypair with zeros and
+/// forget the cached `PeerId`.
+#[cfg(feature = "zeroize")]
+impl Zeroize for Keypair {
+    fn zeroize(&mut self) {
+        self.0.secret.zeroize();
+        self.1.take();
+    }
 }
 
 impl fmt::Debug for Keypair {
@@ -81,7 +106,7 @@ impl Clone for Keypair {
             .expect("ed25519::SecretKey::from_bytes(to_bytes(k)) != k").0;
         let public = ed25519::PublicKey::from_bytes(&self.0.public.to_bytes())
             .expect("ed25519::PublicKey::from_bytes(to_bytes(k)) != k");
-        Keypair(ed25519::Keypair { secret, public })
+        Keypair(ed25519::Keypair { secret, public }, self.1.clone())
     }
 }
 
@@ -97,7 +122,7 @@ impl From<SecretKey> for Keypair {
     fn from(sk: SecretKey) -> Keypair {
         let secret: ed25519::ExpandedSecretKey = (&sk.0).into();
         let public = ed25519::PublicKey::from(&secret);
-        Keypair(ed25519::Keypair { secret: sk.0, public })
+        Keypair(ed25519::Keypair { secret: sk.0, public }, OnceCell::new())
     }
 }
 
@@ -108,7 +133,14 @@ pub struct PublicKey(ed25519::PublicKey);
 impl PublicKey {
     /// Verify the Ed25519 signature on a message using the public key.
     pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
-        ed25519::Signature::try_from(sig).and_then(|s| self.0.verify(msg, &s)).is_ok()
+        self.verify_detailed(msg, sig).is_ok()
+    }
+
+    /// Verify the Ed25519 signature on a message using the public key,
+    /// reporting why verification failed.
+    pub fn verify_detailed(&self, msg: &[u8], sig: &[u8]) -> Result<(), VerifyError> {
+        let sig = ed25519::Signature::try_from(sig).map_err(|_| VerifyError::Unsupported)?;
+        self.0.verify(msg, &sig).map_err(|_| VerifyError::BadSignature)
     }
 
     /// Encode the public key into a byte array in compressed form, i.e.
@@ -125,6 +157,32 @@ impl PublicKey {
     }
 }
 
+/// Verify a batch of Ed25519 signatures, given as tuples of public key,
+/// message and signature.
+///
+/// Batch verification is considerably faster than verifying each signature
+/// individually. Returns `Ok(())` if all signatures are valid, otherwise
+/// the index of the first invalid signature.
+pub fn verify_batch(items: &[(PublicKey, &[u8], &[u8])]) -> Result<(), usize> {
+    let mut messages = Vec::with_capacity(items.len());
+    let mut signatures = Vec::with_capacity(items.len());
+    let mut public_keys = Vec::with_capacity(items.len());
+    for (i, (pk, msg, sig)) in items.iter().enumerate() {
+        signatures.push(ed25519::Signature::try_from(*sig).map_err(|_| i)?);
+        messages.push(*msg);
+        public_keys.push(pk.0);
+    }
+    if ed25519::verify_batch(&messages, &signatures, &public_keys).is_ok() {
+        return Ok(())
+    }
+    // The batch contains at least one invalid signature, so fall back to
+    // individual verification to find the first one.
+    match items.iter().position(|(pk, msg, sig)| !pk.verify(msg, sig)) {
+        Some(i) => Err(i),
+        None => Ok(())
+    }
+}
+
 /// An Ed25519 secret key.
 pub struct SecretKey(ed25519::SecretKey);
 
@@ -194,6 +252,36 @@ mod tests {
         QuickCheck::new().tests(10).quickcheck(prop as fn() -> _);
     }
 
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn ed25519_keypair_zeroize() {
+        let mut kp = Keypair::generate();
+        kp.zeroize();
+        assert!(kp.secret().as_ref().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn ed25519_verify_batch() {
+        let keypairs = (0 .. 10).map(|_| Keypair::generate()).collect::<Vec<_>>();
+        let msgs = (0 .. 10u8).map(|i| vec![i; 16]).collect::<Vec<_>>();
+        let mut sigs = keypairs.iter().zip(&msgs).map(|(kp, msg)| kp.sign(msg)).collect::<Vec<_>>();
+
+        fn items<'a>(kps: &[Keypair], msgs: &'a [Vec<u8>], sigs: &'a [Vec<u8>])
+            -> Vec<(PublicKey, &'a [u8], &'a [u8])>
+        {
+            kps.iter().zip(msgs).zip(sigs)
+                .map(|((kp, msg), sig)| (kp.public(), msg.as_slice(), sig.as_slice()))
+                .collect()
+        }
+        assert_eq!(verify_batch(&items(&keypairs, &msgs, &sigs)), Ok(()));
+
+        sigs[6][0] ^= 1;
+        assert_eq!(verify_batch(&items(&keypairs, &msgs, &sigs)), Err(6));
+
+        sigs[3].truncate(10);
+        assert_eq!(verify_batch(&items(&keypairs, &msgs, &sigs)), Err(3));
+    }
+
     #[test]
     fn ed25519_keypair_from_secret() {
         fn prop() -> bool {
@@ -20,35 +20,84 @@
 
 //! Errors during identity key operations.
 
+use super::KeyType;
 use std::error::Error;
 use std::fmt;
 
 /// An error during decoding of key material.
 #[derive(Debug)]
-pub struct DecodingError {
-    msg: String,
-    source: Option<Box<dyn Error + Send + Sync>>
+pub enum DecodingError {
+    /// The input is not a valid protobuf `PublicKey` or `PrivateKey` message.
+    MalformedProtobuf(prost::DecodeError),
+    /// The input is not valid base64.
+    MalformedBase64(base64::DecodeError),
+    /// The key type tag of a protobuf encoded key is not known.
+    UnknownKeyType(i32),
+    /// The key type is known, but support for it is disabled in this build.
+    UnsupportedKeyType(KeyType),
+    /// The key material is invalid for its key type, e.g. it has the wrong
+    /// length or does not encode a valid curve point.
+    InvalidKey {
+        /// A description of the invalid key material.
+        msg: String,
+        /// The error reported by the underlying key implementation, if any.
+        source: Option<Box<dyn Error + Send + Sync>>
+    }
 }
 
 impl DecodingError {
     pub(crate) fn new<S: ToString>(msg: S) -> Self {
-        Self { msg: msg.to_string(), source: None }
+        DecodingError::InvalidKey { msg: msg.to_string(), source: None }
     }
 
     pub(crate) fn source(self, source: impl Error + Send + Sync + 'static) -> Self {
-        Self { source: Some(Box::new(source)), .. self }
+        match self {
+            DecodingError::InvalidKey { msg, .. } =>
+                DecodingError::InvalidKey { msg, source: Some(Box::new(source)) },
+            other => other
+        }
     }
 }
 
 impl fmt::Display for DecodingError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Key decoding error: {}", self.msg)
+        match self {
+            DecodingError::MalformedProtobuf(_) =>
+                write!(f, "Key decoding error: malformed protobuf encoding"),
+            DecodingError::MalformedBase64(_) =>
+                write!(f, "Key decoding error: malformed base64 encoding"),
+            DecodingError::UnknownKeyType(t) =>
+                write!(f, "Key decoding error: unknown key type: {}", t),
+            DecodingError::UnsupportedKeyType(t) =>
+                write!(f, "Key decoding error: unsupported key type: {:?}", t),
+            DecodingError::InvalidKey { msg, .. } =>
+                write!(f, "Key decoding error: {}", msg)
+        }
     }
 }
 
 impl Error for DecodingError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.source.as_ref().map(|s| &**s as &dyn Error)
+        match self {
+            DecodingError::MalformedProtobuf(e) => Some(e),
+            DecodingError::MalformedBase64(e) => Some(e),
+            DecodingError::UnknownKeyType(_) => None,
+            DecodingError::UnsupportedKeyType(_) => None,
+            DecodingError::InvalidKey { source, .. } =>
+                source.as_ref().map(|s| &**s as &dyn Error)
+        }
+    }
+}
+
+impl From<prost::DecodeError> for DecodingError {
+    fn from(e: prost::DecodeError) -> Self {
+        DecodingError::MalformedProtobuf(e)
+    }
+}
+
+impl From<base64::DecodeError> for DecodingError {
+    fn from(e: base64::DecodeError) -> Self {
+        DecodingError::MalformedBase64(e)
     }
 }
 
@@ -82,3 +131,23 @@ impl Error for SigningError {
     }
 }
 
+/// An error during verification of a signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The signature is well-formed but not valid for the message and key.
+    BadSignature,
+    /// The signature can not be checked with this key, e.g. because it is
+    /// not in the signature format of the key type.
+    Unsupported
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::BadSignature => f.write_str("Signature verification failed: bad signature"),
+            VerifyError::Unsupported => f.write_str("Signature verification failed: unsupported signature")
+        }
+    }
+}
+
+impl Error for VerifyError {}
@@ -20,18 +20,32 @@
 
 //! RSA keys.
 
+use crate::PeerId;
 use asn1_der::{Asn1Der, FromDerObject, IntoDerObject, DerObject, DerTag, DerValue, Asn1DerError};
 use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
 use super::error::*;
 use ring::rand::SystemRandom;
 use ring::signature::{self, RsaKeyPair, RSA_PKCS1_SHA256, RSA_PKCS1_2048_8192_SHA256};
 use ring::signature::KeyPair;
 use std::{fmt::{self, Write}, sync::Arc};
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
 
 /// An RSA keypair.
+///
+/// Besides the key of the signing backend, the keypair holds the DER
+/// encoding of the private key for `encode_pkcs1`, which is overwritten
+/// when the keypair is dropped. The key of the signing backend is shared
+/// between clones and can not be overwritten, see the `Zeroize`
+/// implementation.
 #[derive(Clone)]
-pub struct Keypair(Arc<RsaKeyPair>);
+pub struct Keypair {
+    inner: Arc<RsaKeyPair>,
+    /// The DER-encoded PKCS#1 RSAPrivateKey of this keypair.
+    pkcs1: Zeroizing<Vec<u8>>,
+    /// The `PeerId` derived from the public key, computed on first use.
+    peer_id: OnceCell<PeerId>
+}
 
 impl Keypair {
     /// Decode an RSA keypair from a DER-encoded private key in PKCS#8 PrivateKeyInfo
@@ -41,26 +55,91 @@ impl Keypair {
     pub fn from_pkcs8(der: &mut [u8]) -> Result<Keypair, DecodingError> {
         let kp = RsaKeyPair::from_pkcs8(&der)
             .map_err(|e| DecodingError::new("RSA PKCS#8 PrivateKeyInfo").source(e))?;
+        let pkcs1 = pkcs8_private_key(der)?;
+        der.zeroize();
+        Ok(Keypair { inner: Arc::new(kp), pkcs1, peer_id: OnceCell::new() })
+    }
+
+    /// Decode an RSA keypair from a DER-encoded private key in PKCS#1
+    /// RSAPrivateKey format as defined in [RFC3447].
+    ///
+    /// [RFC3447]: https://tools.ietf.org/html/rfc3447#appendix-A.1.2
+    pub fn from_pkcs1(der: &mut [u8]) -> Result<Keypair, DecodingError> {
+        let kp = RsaKeyPair::from_der(&der)
+            .map_err(|e| DecodingError::new("RSA PKCS#1 RSAPrivateKey").source(e))?;
+        let pkcs1 = Zeroizing::new(der.to_vec());
         der.zeroize();
-        Ok(Keypair(Arc::new(kp)))
+        Ok(Keypair { inner: Arc::new(kp), pkcs1, peer_id: OnceCell::new() })
+    }
+
+    /// Encode the private key in DER as a PKCS#1 RSAPrivateKey structure,
+    /// as defined in [RFC3447].
+    ///
+    /// [RFC3447]: https://tools.ietf.org/html/rfc3447#appendix-A.1.2
+    pub fn encode_pkcs1(&self) -> Vec<u8> {
+        self.pkcs1.to_vec()
     }
 
     /// Get the public key from the keypair.
     pub fn public(&self) -> PublicKey {
-        PublicKey(self.0.public_key().as_ref().to_vec())
+        PublicKey(self.inner.public_key().as_ref().to_vec())
+    }
+
+    /// The cached `PeerId` of this keypair, see `identity::Keypair::peer_id`.
+    pub(crate) fn peer_id_cache(&self) -> &OnceCell<PeerId> {
+        &self.peer_id
     }
 
     /// Sign a message with this keypair.
     pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, SigningError> {
-        let mut signature = vec![0; self.0.public_modulus_len()];
+        let mut signature = Vec::with_capacity(self.inner.public_modulus_len());
+        self.sign_into(data, &mut signature)?;
+        Ok(signature)
+    }
+
+    /// Sign a message with this keypair, appending the signature to `out`.
+    ///
+    /// On error, `out` is left unchanged.
+    pub fn sign_into(&self, data: &[u8], out: &mut Vec<u8>) -> Result<(), SigningError> {
+        let start = out.len();
+        out.resize(start + self.inner.public_modulus_len(), 0);
         let rng = SystemRandom::new();
-        match self.0.sign(&RSA_PKCS1_SHA256, &rng, &data, &mut signature) {
-            Ok(()) => Ok(signature),
-            Err(e) => Err(SigningError::new("RSA").source(e))
+        match self.inner.sign(&RSA_PKCS1_SHA256, &rng, &data, &mut out[start ..]) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                out.truncate(start);
+                Err(SigningError::new("RSA").source(e))
+            }
         }
     }
 }
 
+/// Overwrite the encoded private key of this keypair with zeros and
+/// forget the cached `PeerId`.
+///
+/// Clones of the keypair are not affected. The key of the signing backend
+/// is not overwritten, so the keypair can still be used for signing.
+#[cfg(feature = "zeroize")]
+impl Zeroize for Keypair {
+    fn zeroize(&mut self) {
+        self.pkcs1.zeroize();
+        self.peer_id.take();
+    }
+}
+
+/// Extract the PKCS#1 RSAPrivateKey from a PKCS#8 PrivateKeyInfo, i.e.
+/// the contents of its `privateKey` OCTET STRING.
+fn pkcs8_private_key(pkcs8: &[u8]) -> Result<Zeroizing<Vec<u8>>, DecodingError> {
+    let obj: Vec<DerObject> = FromDerObject::deserialize(pkcs8.iter())
+        .map_err(|e| DecodingError::new("RSA PKCS#8 PrivateKeyInfo").source(e))?;
+    let mut obj = obj.into_iter().map(|o| Zeroizing::new(o.value.data)).collect::<Vec<_>>();
+    // PrivateKeyInfo ::= SEQUENCE { version, privateKeyAlgorithm, privateKey, .. }
+    if obj.len() < 3 {
+        return Err(DecodingError::new("Not enough elements in RSA PKCS#8 PrivateKeyInfo"))
+    }
+    Ok(obj.swap_remove(2))
+}
+
 /// An RSA public key.
 #[derive(Clone, PartialEq, Eq)]
 pub struct PublicKey(Vec<u8>);
@@ -68,8 +147,17 @@ pub struct PublicKey(Vec<u8>);
 impl PublicKey {
     /// Verify an RSA signature on a message using the public key.
     pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        self.verify_detailed(msg, sig).is_ok()
+    }
+
+    /// Verify an RSA signature on a message using the public key,
+    /// reporting why verification failed.
+    ///
+    /// Since the RSA backend does not distinguish malformed from invalid
+    /// signatures, failures are always reported as `BadSignature`.
+    pub fn verify_detailed(&self, msg: &[u8], sig: &[u8]) -> Result<(), VerifyError> {
         let key = signature::UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, &self.0);
-        key.verify(msg, sig).is_ok()
+        key.verify(msg, sig).map_err(|_| VerifyError::BadSignature)
     }
 
     /// Encode the RSA public key in DER as a PKCS#1 RSAPublicKey structure,
@@ -249,6 +337,27 @@ mod tests {
         assert!(Keypair::from_pkcs8(&mut KEY3.to_vec()).is_ok());
     }
 
+    #[test]
+    fn rsa_pkcs1_encode_decode() {
+        fn prop(SomeKeypair(kp): SomeKeypair) -> Result<bool, String> {
+            Keypair::from_pkcs1(&mut kp.encode_pkcs1())
+                .map_err(|e| e.to_string())
+                .map(|kp2| kp2.public() == kp.public() && kp2.encode_pkcs1() == kp.encode_pkcs1())
+        }
+        QuickCheck::new().tests(10).quickcheck(prop as fn(_) -> _);
+    }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn rsa_keypair_zeroize() {
+        let mut kp = Keypair::from_pkcs8(&mut KEY1.to_vec()).unwrap();
+        let other = kp.clone();
+        kp.zeroize();
+        assert!(kp.encode_pkcs1().iter().all(|b| *b == 0));
+        // Clones hold their own copy of the encoded private key.
+        assert_eq!(other.encode_pkcs1(), Keypair::from_pkcs8(&mut KEY1.to_vec()).unwrap().encode_pkcs1());
+    }
+
     #[test]
     fn rsa_x509_encode_decode() {
         fn prop(SomeKeypair(kp): SomeKeypair) -> Result<bool, String> {
@@ -20,11 +20,13 @@
 
 //! Secp256k1 keys.
 
+use crate::PeerId;
 use asn1_der::{FromDerObject, DerObject};
+use once_cell::sync::OnceCell;
 use rand::RngCore;
 use sha2::{Digest as ShaDigestTrait, Sha256};
 use secp256k1::{Message, Signature};
-use super::error::{DecodingError, SigningError};
+use super::error::{DecodingError, SigningError, VerifyError};
 use zeroize::Zeroize;
 use core::fmt;
 
@@ -32,7 +34,9 @@ use core::fmt;
 #[derive(Clone)]
 pub struct Keypair {
     secret: SecretKey,
-    public: PublicKey
+    public: PublicKey,
+    /// The `PeerId` derived from the public key, computed on first use.
+    peer_id: OnceCell<PeerId>
 }
 
 impl Keypair {
@@ -50,6 +54,23 @@ impl Keypair {
     pub fn secret(&self) -> &SecretKey {
         &self.secret
     }
+
+    /// The cached `PeerId` of this keypair, see `identity::Keypair::peer_id`.
+    pub(crate) fn peer_id_cache(&self) -> &OnceCell<PeerId> {
+        &self.peer_id
+    }
+}
+
+/// Overwrite the secret key material of this keypair and forget
+/// the cached `PeerId`.
+///
+/// See the `Zeroize` implementation of `SecretKey`.
+#[cfg(feature = "zeroize")]
+impl Zeroize for Keypair {
+    fn zeroize(&mut self) {
+        self.secret.zeroize();
+        self.peer_id.take();
+    }
 }
 
 impl fmt::Debug for Keypair {
@@ -62,7 +83,7 @@ impl fmt::Debug for Keypair {
 impl From<SecretKey> for Keypair {
     fn from(secret: SecretKey) -> Keypair {
         let public = PublicKey(secp256k1::PublicKey::from_secret_key(&secret.0));
-        Keypair { secret, public }
+        Keypair { secret, public, peer_id: OnceCell::new() }
     }
 }
 
@@ -83,6 +104,20 @@ impl fmt::Debug for SecretKey {
     }
 }
 
+/// Overwrite the secret key material.
+///
+/// Since a Secp256k1 secret key can not be zero, the secret is overwritten
+/// in place by the fixed key `1`. Copies made by the secp256k1 backend,
+/// e.g. while signing, are not overwritten.
+#[cfg(feature = "zeroize")]
+impl Zeroize for SecretKey {
+    fn zeroize(&mut self) {
+        let mut one = [0; secp256k1::util::SECRET_KEY_SIZE];
+        one[secp256k1::util::SECRET_KEY_SIZE - 1] = 1;
+        self.0 = secp256k1::SecretKey::parse(&one).expect("1 is a valid secret key; qed");
+    }
+}
+
 impl SecretKey {
     /// Generate a new Secp256k1 secret key.
     pub fn generate() -> SecretKey {
@@ -136,6 +171,12 @@ impl SecretKey {
         self.sign_hash(Sha256::digest(msg).as_ref())
     }
 
+    /// Sign a message with this secret key, like [`SecretKey::sign`], but
+    /// append the DER-encoded signature to `out`.
+    pub fn sign_into(&self, msg: &[u8], out: &mut Vec<u8>) -> Result<(), SigningError> {
+        self.sign_hash_into(Sha256::digest(msg).as_ref(), out)
+    }
+
     /// Returns the raw bytes of the secret key.
     pub fn to_bytes(&self) -> [u8; 32] {
         self.0.serialize()
@@ -144,9 +185,18 @@ impl SecretKey {
     /// Sign a raw message of length 256 bits with this secret key, produces a DER-encoded
     /// ECDSA signature.
     pub fn sign_hash(&self, msg: &[u8]) -> Result<Vec<u8>, SigningError> {
+        let mut signature = Vec::new();
+        self.sign_hash_into(msg, &mut signature)?;
+        Ok(signature)
+    }
+
+    /// Sign a raw message of length 256 bits with this secret key, appending
+    /// the DER-encoded ECDSA signature to `out`.
+    pub fn sign_hash_into(&self, msg: &[u8], out: &mut Vec<u8>) -> Result<(), SigningError> {
         let m = Message::parse_slice(msg)
             .map_err(|_| SigningError::new("failed to parse secp256k1 digest"))?;
-        Ok(secp256k1::sign(&m, &self.0).0.serialize_der().as_ref().into())
+        out.extend_from_slice(secp256k1::sign(&m, &self.0).0.serialize_der().as_ref());
+        Ok(())
     }
 }
 
@@ -157,7 +207,20 @@ pub struct PublicKey(secp256k1::PublicKey);
 impl PublicKey {
     /// Verify the Secp256k1 signature on a message using the public key.
     pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
-        self.verify_hash(Sha256::digest(msg).as_ref(), sig)
+        self.verify_detailed(msg, sig).is_ok()
+    }
+
+    /// Verify the Secp256k1 signature on a message using the public key,
+    /// reporting why verification failed.
+    pub fn verify_detailed(&self, msg: &[u8], sig: &[u8]) -> Result<(), VerifyError> {
+        let m = Message::parse_slice(Sha256::digest(msg).as_ref())
+            .expect("a SHA-256 digest is a valid secp256k1 message; qed");
+        let s = Signature::parse_der(sig).map_err(|_| VerifyError::Unsupported)?;
+        if secp256k1::verify(&m, &s, &self.0) {
+            Ok(())
+        } else {
+            Err(VerifyError::BadSignature)
+        }
     }
 
     /// Verify the Secp256k1 DER-encoded signature on a raw 256-bit message using the public key.
@@ -179,9 +242,9 @@ impl PublicKey {
     }
 
     /// Decode a public key from a byte slice in the the format produced
-    /// by `encode`.
+    /// by `encode` or `encode_uncompressed`.
     pub fn decode(k: &[u8]) -> Result<PublicKey, DecodingError> {
-        secp256k1::PublicKey::parse_slice(k, Some(secp256k1::PublicKeyFormat::Compressed))
+        secp256k1::PublicKey::parse_slice(k, None)
             .map_err(|_| DecodingError::new("failed to parse secp256k1 public key"))
             .map(PublicKey)
     }
@@ -200,4 +263,14 @@ mod tests {
         assert_eq!(sk1.0.serialize(), sk2.0.serialize());
         assert_eq!(sk_bytes, [0; 32]);
     }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn secp256k1_keypair_zeroize() {
+        let mut kp = Keypair::generate();
+        kp.zeroize();
+        let mut one = [0; 32];
+        one[31] = 1;
+        assert_eq!(kp.secret().to_bytes(), one);
+    }
 }
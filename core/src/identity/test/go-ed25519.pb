@@ -0,0 +1 @@
+@~0a|J}9%߲iEV)6wH읦}ġDԴ{ӳK<Bt~
\ No newline at end of file
@@ -0,0 +1 @@
+ ġDԴ{ӳK<Bt~
\ No newline at end of file
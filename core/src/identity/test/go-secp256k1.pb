@@ -0,0 +1 @@
+ SZMkJͱ^$L[4aB@MV
\ No newline at end of file
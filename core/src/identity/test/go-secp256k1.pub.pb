@@ -0,0 +1 @@
+!wwRA_T2݌ѧ0ʙ
\ No newline at end of file
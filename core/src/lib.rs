@@ -43,7 +43,6 @@ mod keys_proto {
 pub use multiaddr;
 pub type Negotiated<T> = multistream_select::Negotiated<T>;
 
-mod peer_id;
 mod translation;
 
 pub mod connection;
@@ -51,6 +50,7 @@ pub mod either;
 pub mod identity;
 pub mod muxing;
 pub mod network;
+pub mod peer_id;
 pub mod transport;
 pub mod upgrade;
 
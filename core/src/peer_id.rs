@@ -18,17 +18,25 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use crate::PublicKey;
+use crate::{PublicKey, identity::KeyType, keys_proto};
+use crate::multiaddr::{Multiaddr, Protocol};
 use bs58;
 use thiserror::Error;
 use multihash::{self, Code, Multihash};
 use rand::Rng;
-use std::{convert::TryFrom, borrow::Borrow, fmt, hash, str::FromStr, cmp};
+use std::{convert::TryFrom, borrow::Borrow, fmt, hash, io, str::FromStr, cmp, sync::Arc};
+use unsigned_varint::encode;
 
 /// Public keys with byte-lengths smaller than `MAX_INLINE_KEY_LENGTH` will be
 /// automatically used as the peer id using an identity multihash.
 const MAX_INLINE_KEY_LENGTH: usize = 42;
 
+/// The multihash codes accepted for peer IDs.
+///
+/// `Code::Identity` is only accepted for digests of at most
+/// `MAX_INLINE_KEY_LENGTH` bytes, i.e. inlined public keys.
+pub const ACCEPTED_CODES: &[Code] = &[Code::Identity, Code::Sha2_256];
+
 /// Identifier of a peer of the network.
 ///
 /// The data is a multihash of the public key of the peer.
@@ -69,6 +77,10 @@ impl cmp::Ord for PeerId {
 
 impl PeerId {
     /// Builds a `PeerId` from a public key.
+    ///
+    /// Public keys whose protobuf encoding is at most `MAX_INLINE_KEY_LENGTH`
+    /// bytes long are inlined using the identity multihash, larger keys
+    /// (e.g. all RSA keys) are hashed with SHA-256.
     pub fn from_public_key(key: PublicKey) -> PeerId {
         let key_enc = key.into_protobuf_encoding();
 
@@ -99,13 +111,70 @@ impl PeerId {
     /// peer ID, it is returned as an `Err`.
     pub fn from_multihash(multihash: Multihash) -> Result<PeerId, Multihash> {
         match multihash.algorithm() {
-            Code::Sha2_256 => Ok(PeerId { multihash }),
-            Code::Identity if multihash.digest().len() <= MAX_INLINE_KEY_LENGTH
-                => Ok(PeerId { multihash }),
+            Code::Identity if multihash.digest().len() > MAX_INLINE_KEY_LENGTH
+                => Err(multihash),
+            code if PeerId::is_accepted_code(code) => Ok(PeerId { multihash }),
             _ => Err(multihash)
         }
     }
 
+    /// Extracts the `PeerId` of the last `/p2p` component of a `Multiaddr`.
+    ///
+    /// Returns `None` if the address has no `/p2p` component or if its
+    /// multihash is not a valid `PeerId`.
+    pub fn try_from_multiaddr(address: &Multiaddr) -> Option<PeerId> {
+        address.iter()
+            .filter_map(|p| match p {
+                Protocol::P2p(multihash) => Some(multihash),
+                _ => None
+            })
+            .last()
+            .and_then(|multihash| PeerId::from_multihash(multihash).ok())
+    }
+
+    /// Extracts the relay and destination `PeerId`s of a circuit relay
+    /// address of the form `/<relay-addr>/p2p/<relay>/p2p-circuit/p2p/<dst>`.
+    ///
+    /// The relay is the last `/p2p` component before `/p2p-circuit` and the
+    /// destination the last `/p2p` component after it. Either is `None` if
+    /// the respective part of the address has no `/p2p` component.
+    ///
+    /// Returns `None` if the address does not contain exactly one
+    /// `/p2p-circuit` component or if any `/p2p` component is not a valid
+    /// `PeerId`.
+    pub fn try_from_circuit_multiaddr(address: &Multiaddr)
+        -> Option<(Option<PeerId>, Option<PeerId>)>
+    {
+        let mut relay = None;
+        let mut dst = None;
+        let mut circuit = false;
+        for protocol in address.iter() {
+            match protocol {
+                Protocol::P2pCircuit if circuit => return None,
+                Protocol::P2pCircuit => circuit = true,
+                Protocol::P2p(multihash) => {
+                    let peer_id = PeerId::from_multihash(multihash).ok()?;
+                    if circuit {
+                        dst = Some(peer_id)
+                    } else {
+                        relay = Some(peer_id)
+                    }
+                }
+                _ => {}
+            }
+        }
+        if circuit {
+            Some((relay, dst))
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether `code` is one of the [`ACCEPTED_CODES`] for peer IDs.
+    pub fn is_accepted_code(code: Code) -> bool {
+        ACCEPTED_CODES.contains(&code)
+    }
+
     /// Generates a random peer ID from a cryptographically secure PRNG.
     ///
     /// This is useful for randomly walking on a DHT, or for testing purposes.
@@ -116,6 +185,30 @@ impl PeerId {
         }
     }
 
+    /// Generates a random peer ID using the given hash algorithm.
+    ///
+    /// With `Code::Identity` this is equivalent to [`PeerId::random`]. With
+    /// `Code::Sha2_256` random bytes are hashed, which yields a peer ID that
+    /// does not inline a public key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `code` is not a valid hash algorithm for peer IDs.
+    pub fn random_with_hash(code: Code) -> PeerId {
+        let bytes = rand::thread_rng().gen::<[u8; 32]>();
+        let multihash = match code {
+            Code::Identity => multihash::wrap(Code::Identity, &bytes),
+            code if PeerId::is_accepted_code(code) => code.digest(&bytes),
+            other => panic!("{:?} is not a valid hash algorithm for peer IDs", other)
+        };
+        PeerId { multihash }
+    }
+
+    /// Returns the hash algorithm of the multihash underlying this `PeerId`.
+    pub fn hash_code(&self) -> Code {
+        self.multihash.algorithm()
+    }
+
     /// Returns a raw bytes representation of this `PeerId`.
     ///
     /// **NOTE:** This byte representation is not necessarily consistent with
@@ -125,6 +218,33 @@ impl PeerId {
         self.multihash.into_bytes()
     }
 
+    /// Returns a raw bytes representation of this `PeerId`.
+    ///
+    /// The bytes are written with [`PeerId::write_bytes`] into a buffer
+    /// of exactly [`PeerId::encoded_len`] bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_bytes(&mut bytes).expect("writing to a `Vec` never fails");
+        bytes
+    }
+
+    /// Writes the multihash code, digest length and digest of this
+    /// `PeerId` into `w`.
+    pub fn write_bytes<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let digest = self.multihash.digest();
+        w.write_all(encode::u64(self.hash_code().into(), &mut encode::u64_buffer()))?;
+        w.write_all(encode::usize(digest.len(), &mut encode::usize_buffer()))?;
+        w.write_all(digest)
+    }
+
+    /// Returns the number of bytes written by [`PeerId::write_bytes`].
+    pub fn encoded_len(&self) -> usize {
+        let digest = self.multihash.digest();
+        encode::u64(self.hash_code().into(), &mut encode::u64_buffer()).len()
+            + encode::usize(digest.len(), &mut encode::usize_buffer()).len()
+            + digest.len()
+    }
+
     /// Returns a raw bytes representation of this `PeerId`.
     ///
     /// **NOTE:** This byte representation is not necessarily consistent with
@@ -139,6 +259,49 @@ impl PeerId {
         bs58::encode(self.borrow() as &[u8]).into_string()
     }
 
+    /// Returns the public key inlined in this `PeerId`.
+    ///
+    /// Returns `None` if the public key is not inlined, which is always
+    /// the case for keys whose encoding exceeds `MAX_INLINE_KEY_LENGTH`
+    /// bytes, such as RSA keys, or if the inlined key can not be decoded.
+    pub fn to_public_key(&self) -> Option<PublicKey> {
+        if self.multihash.algorithm() != Code::Identity {
+            return None
+        }
+        PublicKey::from_protobuf_encoding(self.multihash.digest()).ok()
+    }
+
+    /// Returns the type of the public key inlined in this `PeerId`.
+    ///
+    /// Returns `None` if the public key is not inlined, i.e. if it has been
+    /// hashed, or if the inlined key is not a valid key encoding.
+    pub fn key_type(&self) -> Option<KeyType> {
+        use prost::Message;
+
+        if self.multihash.algorithm() != Code::Identity {
+            return None
+        }
+        let key = keys_proto::PublicKey::decode(self.multihash.digest()).ok()?;
+        KeyType::from_protobuf(key.r#type)
+    }
+
+    /// Returns the `/p2p` multiaddr protocol component for this `PeerId`.
+    ///
+    /// The component is built directly from the multihash, without a
+    /// base-58 round-trip.
+    pub fn to_multiaddr_protocol(&self) -> Protocol<'static> {
+        Protocol::P2p(self.multihash.clone())
+    }
+
+    /// Returns a key that orders consistently with this `PeerId`.
+    ///
+    /// The key can be computed once and reused across repeated sorts of
+    /// the same set of peer IDs, avoiding repeated byte comparisons through
+    /// the multihash. Cloning a `SortKey` is cheap.
+    pub fn sort_key(&self) -> SortKey {
+        SortKey(Arc::from(self.borrow() as &[u8]))
+    }
+
     /// Checks whether the public key passed as parameter matches the public key of this `PeerId`.
     ///
     /// Returns `None` if this `PeerId`s hash algorithm is not supported when encoding the
@@ -211,12 +374,171 @@ impl From<PeerId> for Multihash {
     }
 }
 
+/// A cheaply clonable comparison key for a `PeerId`, see [`PeerId::sort_key`].
+///
+/// The ordering of `SortKey`s is the same as the ordering of the `PeerId`s
+/// they were created from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SortKey(Arc<[u8]>);
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PeerId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer
+    {
+        if serializer.is_human_readable() {
+            Base58Serde::serialize(self, serializer)
+        } else {
+            BytesSerde::serialize(self, serializer)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PeerId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        if deserializer.is_human_readable() {
+            Base58Serde::deserialize(deserializer)
+        } else {
+            BytesSerde::deserialize(deserializer)
+        }
+    }
+}
+
+/// Serializes a `PeerId` as a base-58 string, regardless of whether the
+/// format is human readable.
+///
+/// Intended for use with `#[serde(with = "peer_id::Base58Serde")]`.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum Base58Serde {}
+
+#[cfg(feature = "serde")]
+impl Base58Serde {
+    pub fn serialize<S>(peer_id: &PeerId, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer
+    {
+        serializer.serialize_str(&peer_id.to_base58())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<PeerId, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        deserializer.deserialize_str(PeerIdVisitor)
+    }
+}
+
+/// Serializes a `PeerId` as raw multihash bytes, regardless of whether the
+/// format is human readable.
+///
+/// Intended for use with `#[serde(with = "peer_id::BytesSerde")]`.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum BytesSerde {}
+
+#[cfg(feature = "serde")]
+impl BytesSerde {
+    pub fn serialize<S>(peer_id: &PeerId, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer
+    {
+        serializer.serialize_bytes(peer_id.as_bytes())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<PeerId, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        deserializer.deserialize_bytes(PeerIdVisitor)
+    }
+}
+
+/// Visitor accepting a `PeerId` either as base-58 string or as raw bytes.
+#[cfg(feature = "serde")]
+struct PeerIdVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> serde::de::Visitor<'de> for PeerIdVisitor {
+    type Value = PeerId;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a peer ID as base-58 string or multihash bytes")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<PeerId, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<PeerId, E> {
+        self.visit_byte_buf(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<PeerId, E> {
+        PeerId::from_bytes(v).map_err(|_| E::custom("invalid peer ID multihash"))
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<PeerId, A::Error> {
+        let mut buf = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element()? {
+            buf.push(b)
+        }
+        self.visit_byte_buf(buf)
+    }
+}
+
+/// Inputs longer than this are truncated when included in a `ParseError`.
+const MAX_ERROR_INPUT_LENGTH: usize = 64;
+
+/// Error when parsing a `PeerId` from a string.
+///
+/// Every variant carries the (possibly truncated) input that failed to parse.
 #[derive(Debug, Error)]
 pub enum ParseError {
-    #[error("base-58 decode error: {0}")]
-    B58(#[from] bs58::decode::Error),
-    #[error("decoding multihash failed")]
-    MultiHash,
+    #[error("base-58 decode error in '{input}': {source}")]
+    B58 {
+        input: String,
+        #[source]
+        source: bs58::decode::Error
+    },
+    #[error("decoding multihash failed for '{input}'")]
+    MultiHash {
+        input: String
+    },
+}
+
+impl ParseError {
+    /// Creates a `ParseError::B58` for the given input string.
+    pub fn b58(input: &str, source: bs58::decode::Error) -> Self {
+        ParseError::B58 { input: truncate_input(input), source }
+    }
+
+    /// Creates a `ParseError::MultiHash` for the given input string.
+    pub fn multihash(input: &str) -> Self {
+        ParseError::MultiHash { input: truncate_input(input) }
+    }
+
+    /// Returns the (possibly truncated) input that failed to parse.
+    pub fn input(&self) -> &str {
+        match self {
+            ParseError::B58 { input, .. } => input,
+            ParseError::MultiHash { input } => input,
+        }
+    }
+}
+
+/// Truncates `input` to at most `MAX_ERROR_INPUT_LENGTH` characters,
+/// appending an ellipsis if anything was cut off.
+fn truncate_input(input: &str) -> String {
+    match input.char_indices().nth(MAX_ERROR_INPUT_LENGTH) {
+        Some((i, _)) => format!("{}...", &input[.. i]),
+        None => input.to_string(),
+    }
 }
 
 impl FromStr for PeerId {
@@ -224,14 +546,15 @@ impl FromStr for PeerId {
 
     #[inline]
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = bs58::decode(s).into_vec()?;
-        PeerId::from_bytes(bytes).map_err(|_| ParseError::MultiHash)
+        let bytes = bs58::decode(s).into_vec().map_err(|e| ParseError::b58(s, e))?;
+        PeerId::from_bytes(bytes).map_err(|_| ParseError::multihash(s))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{PeerId, identity};
+    use crate::{PeerId, identity, multiaddr::{Multiaddr, Protocol}};
+    use multihash::Code;
 
     #[test]
     fn peer_id_is_public_key() {
@@ -240,6 +563,33 @@ mod tests {
         assert_eq!(peer_id.is_public_key(&key), Some(true));
     }
 
+    #[test]
+    fn ed25519_peer_id_inlines_public_key() {
+        let key = identity::Keypair::generate_ed25519().public();
+        let peer_id = key.to_peer_id();
+        assert_eq!(peer_id.hash_code(), Code::Identity);
+        assert_eq!(peer_id.to_public_key(), Some(key));
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rsa_peer_id_is_hashed() {
+        let keys: [&[u8]; 3] = [
+            include_bytes!("identity/test/rsa-2048.pk8"),
+            include_bytes!("identity/test/rsa-3072.pk8"),
+            include_bytes!("identity/test/rsa-4096.pk8"),
+        ];
+        for key in keys.iter() {
+            let key = identity::Keypair::rsa_from_pkcs8(&mut key.to_vec()).unwrap().public();
+            assert!(key.clone().into_protobuf_encoding().len() > super::MAX_INLINE_KEY_LENGTH);
+            let peer_id = key.to_peer_id();
+            assert_eq!(peer_id.hash_code(), Code::Sha2_256);
+            assert_eq!(peer_id.to_public_key(), None);
+            assert_eq!(peer_id.key_type(), None);
+            assert_eq!(peer_id.is_public_key(&key), Some(true));
+        }
+    }
+
     #[test]
     fn peer_id_into_bytes_then_from_bytes() {
         let peer_id = identity::Keypair::generate_ed25519().public().into_peer_id();
@@ -247,6 +597,45 @@ mod tests {
         assert_eq!(peer_id, second);
     }
 
+    #[test]
+    fn accepted_codes_parse() {
+        for code in super::ACCEPTED_CODES {
+            assert!(PeerId::is_accepted_code(*code));
+            let multihash = code.digest(&[0; 32]);
+            assert!(PeerId::from_bytes(multihash.into_bytes()).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejected_code_fails() {
+        assert!(!PeerId::is_accepted_code(Code::Sha3_256));
+        let multihash = Code::Sha3_256.digest(&[0; 32]);
+        assert!(PeerId::from_bytes(multihash.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn oversized_identity_fails() {
+        let multihash = multihash::wrap(Code::Identity, &[0; 43]);
+        assert!(PeerId::from_multihash(multihash).is_err());
+    }
+
+    #[test]
+    fn hashed_peer_id_has_no_key_type() {
+        assert_eq!(PeerId::random_with_hash(Code::Sha2_256).key_type(), None);
+    }
+
+    #[test]
+    fn peer_id_write_bytes_equals_to_bytes() {
+        let keypair = identity::Keypair::generate_ed25519().public().into_peer_id();
+        for peer_id in &[keypair, PeerId::random_with_hash(Code::Sha2_256)] {
+            let mut buf = Vec::new();
+            peer_id.write_bytes(&mut buf).unwrap();
+            assert_eq!(buf, peer_id.to_bytes());
+            assert_eq!(buf, peer_id.as_bytes());
+            assert_eq!(buf.len(), peer_id.encoded_len());
+        }
+    }
+
     #[test]
     fn peer_id_to_base58_then_back() {
         let peer_id = identity::Keypair::generate_ed25519().public().into_peer_id();
@@ -254,6 +643,138 @@ mod tests {
         assert_eq!(peer_id, second);
     }
 
+    #[test]
+    fn parse_error_contains_input() {
+        let err = "12D3xyz0".parse::<PeerId>().unwrap_err();
+        assert!(err.to_string().contains("'12D3xyz0'"));
+
+        let err = "12D3K".parse::<PeerId>().unwrap_err();
+        assert!(err.to_string().contains("'12D3K'"));
+    }
+
+    #[test]
+    fn parse_error_truncates_long_input() {
+        let input = "0".repeat(200);
+        let err = input.parse::<PeerId>().unwrap_err();
+        assert_eq!(err.input().len(), 64 + "...".len());
+    }
+
+    #[test]
+    fn peer_id_to_multiaddr_protocol_then_back() {
+        let peer_id = identity::Keypair::generate_ed25519().public().into_peer_id();
+        let addr = "/ip4/1.2.3.4/tcp/4001".parse::<Multiaddr>().unwrap()
+            .with(peer_id.to_multiaddr_protocol());
+        assert_eq!(addr.to_string(), format!("/ip4/1.2.3.4/tcp/4001/p2p/{}", peer_id));
+        match addr.iter().last() {
+            Some(Protocol::P2p(mh)) => assert_eq!(PeerId::from_multihash(mh).unwrap(), peer_id),
+            other => panic!("unexpected protocol: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn peer_id_from_multiaddr() {
+        let peer_id = PeerId::random();
+        let addr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{}", peer_id).parse::<Multiaddr>().unwrap();
+        assert_eq!(PeerId::try_from_multiaddr(&addr), Some(peer_id));
+
+        let addr = "/ip4/1.2.3.4/tcp/4001".parse::<Multiaddr>().unwrap();
+        assert_eq!(PeerId::try_from_multiaddr(&addr), None);
+
+        let multihash = Code::Sha3_256.digest(b"not a peer id");
+        let addr = addr.with(Protocol::P2p(multihash));
+        assert_eq!(PeerId::try_from_multiaddr(&addr), None);
+    }
+
+    #[test]
+    fn peer_ids_from_circuit_multiaddr() {
+        let relay = PeerId::random();
+        let dst = PeerId::random();
+
+        let addr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{}/p2p-circuit/p2p/{}", relay, dst)
+            .parse::<Multiaddr>().unwrap();
+        assert_eq!(
+            PeerId::try_from_circuit_multiaddr(&addr),
+            Some((Some(relay.clone()), Some(dst.clone())))
+        );
+
+        let addr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{}/p2p-circuit", relay)
+            .parse::<Multiaddr>().unwrap();
+        assert_eq!(PeerId::try_from_circuit_multiaddr(&addr), Some((Some(relay.clone()), None)));
+
+        let addr = format!("/p2p-circuit/p2p/{}", dst).parse::<Multiaddr>().unwrap();
+        assert_eq!(PeerId::try_from_circuit_multiaddr(&addr), Some((None, Some(dst.clone()))));
+
+        // Not a circuit address.
+        let addr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{}", relay).parse::<Multiaddr>().unwrap();
+        assert_eq!(PeerId::try_from_circuit_multiaddr(&addr), None);
+
+        // Nested circuits.
+        let addr = format!("/p2p/{}/p2p-circuit/p2p-circuit/p2p/{}", relay, dst)
+            .parse::<Multiaddr>().unwrap();
+        assert_eq!(PeerId::try_from_circuit_multiaddr(&addr), None);
+
+        // Invalid destination peer ID.
+        let addr = format!("/p2p/{}/p2p-circuit", relay).parse::<Multiaddr>().unwrap()
+            .with(Protocol::P2p(Code::Sha3_256.digest(b"not a peer id")));
+        assert_eq!(PeerId::try_from_circuit_multiaddr(&addr), None);
+    }
+
+    #[test]
+    fn sort_key_order_matches_peer_id_order() {
+        let mut by_peer_id = (0 .. 100).map(|_| PeerId::random()).collect::<Vec<_>>();
+        by_peer_id.extend((0 .. 10).map(|_| {
+            identity::Keypair::generate_ed25519().public().into_peer_id()
+        }));
+        let mut by_sort_key = by_peer_id.clone();
+        by_peer_id.sort();
+        by_sort_key.sort_by_cached_key(PeerId::sort_key);
+        assert_eq!(by_peer_id, by_sort_key);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn peer_id_serde_wrappers_json() {
+        use crate::peer_id::{Base58Serde, BytesSerde};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Base58 {
+            #[serde(with = "Base58Serde")]
+            peer: PeerId,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bytes {
+            #[serde(with = "BytesSerde")]
+            peer: PeerId,
+        }
+
+        let peer = identity::Keypair::generate_ed25519().public().into_peer_id();
+
+        let json = serde_json::to_string(&Base58 { peer: peer.clone() }).unwrap();
+        assert_eq!(json, format!("{{\"peer\":\"{}\"}}", peer.to_base58()));
+        assert_eq!(serde_json::from_str::<Base58>(&json).unwrap().peer, peer);
+
+        let json = serde_json::to_string(&Bytes { peer: peer.clone() }).unwrap();
+        assert_eq!(json, format!("{{\"peer\":{}}}", serde_json::to_string(peer.as_bytes()).unwrap()));
+        assert_eq!(serde_json::from_str::<Bytes>(&json).unwrap().peer, peer);
+    }
+
+    #[test]
+    fn random_peer_id_with_hash_reports_hash_code() {
+        for code in &[Code::Identity, Code::Sha2_256] {
+            let peer_id = PeerId::random_with_hash(*code);
+            assert_eq!(peer_id.hash_code(), *code);
+            assert_eq!(peer_id, PeerId::from_bytes(peer_id.clone().into_bytes()).unwrap());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn random_peer_id_with_unaccepted_hash() {
+        PeerId::random_with_hash(Code::Sha3_256);
+    }
+
     #[test]
     fn random_peer_id_is_valid() {
         for _ in 0 .. 5000 {
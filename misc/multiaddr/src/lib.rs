@@ -7,6 +7,8 @@ mod onion_addr;
 mod errors;
 mod from_url;
 
+use multihash::Multihash;
+
 use serde::{
     Deserialize,
     Deserializer,
@@ -113,6 +115,29 @@ impl Multiaddr {
         self
     }
 
+    /// Appends a `/p2p` component with the given peer ID to this multiaddr.
+    ///
+    /// If the multiaddr already ends with a `/p2p` component, that component
+    /// is replaced, i.e. the result always ends with exactly the given peer ID.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parity_multiaddr::{Multiaddr, Protocol, multihash::Code};
+    ///
+    /// let peer_id = Code::Sha2_256.digest(b"some public key");
+    /// let address: Multiaddr = "/ip4/127.0.0.1/tcp/10000".parse().unwrap();
+    /// let address = address.with_peer_id(peer_id.clone());
+    /// assert_eq!(address.iter().last(), Some(Protocol::P2p(peer_id)));
+    /// ```
+    ///
+    pub fn with_peer_id<P: Into<Multihash>>(mut self, peer_id: P) -> Self {
+        if let Some(Protocol::P2p(_)) = self.iter().last() {
+            self.pop();
+        }
+        self.with(Protocol::P2p(peer_id.into()))
+    }
+
     /// Returns the components of this multiaddress.
     ///
     /// # Example
@@ -143,6 +143,28 @@ fn multihash(s: &str) -> Multihash {
     Multihash::from_bytes(bs58::decode(s).into_vec().unwrap()).unwrap()
 }
 
+#[test]
+fn with_peer_id_appends() {
+    let peer_id = multihash("QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN");
+    let addr = "/ip4/127.0.0.1/tcp/1234".parse::<Multiaddr>().unwrap();
+    assert_eq!(
+        addr.with_peer_id(peer_id),
+        "/ip4/127.0.0.1/tcp/1234/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN".parse().unwrap()
+    );
+}
+
+#[test]
+fn with_peer_id_replaces_trailing_p2p() {
+    let peer_id = multihash("QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN");
+    let addr = "/ip4/127.0.0.1/tcp/1234/p2p/QmcgpsyWgH8Y8ajJz1Cu72KnS5uo2Aa2LpzU7kinSupNKC"
+        .parse::<Multiaddr>()
+        .unwrap();
+    assert_eq!(
+        addr.with_peer_id(peer_id),
+        "/ip4/127.0.0.1/tcp/1234/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN".parse().unwrap()
+    );
+}
+
 #[test]
 fn multiaddr_eq() {
     let m1 = "/ip4/127.0.0.1/udp/1234".parse::<Multiaddr>().unwrap();
@@ -0,0 +1,154 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Encoding of the headers `Throttled` prepends to every message.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use libp2p_request_response::throttled::{Header, HeaderEncoding, HeaderFormat, Message};
+use std::{alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}};
+
+/// The system allocator, counting the number of allocations.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn request_header() -> Header {
+    Message::request(()).into_parts().0
+}
+
+/// The headers compared across encodings.
+fn headers() -> [(&'static str, Header); 3] {
+    [
+        ("request", request_header()),
+        ("credit", Message::<()>::credit(u32::MAX, u64::MAX).into_parts().0),
+        ("ack", Message::<()>::ack(7).into_parts().0)
+    ]
+}
+
+const ENCODINGS: [(&str, HeaderEncoding); 2] = [("cbor", HeaderEncoding::Cbor), ("compact", HeaderEncoding::Compact)];
+
+/// Encode a header into a new buffer.
+fn encode_fresh(hdr: &Header) -> Vec<u8> {
+    let mut buf = Vec::new();
+    HeaderEncoding::Cbor.encode(hdr, &mut buf).unwrap();
+    buf
+}
+
+/// Encode a header into a buffer reused across messages, as the codec does.
+fn encode_reused(hdr: &Header, buf: &mut Vec<u8>) -> usize {
+    buf.clear();
+    HeaderEncoding::Cbor.encode(hdr, buf).unwrap();
+    buf.len()
+}
+
+/// The average number of allocations of the given function.
+fn allocations_per_call(mut f: impl FnMut()) -> f64 {
+    const CALLS: usize = 100_000;
+    let start = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0 .. CALLS {
+        f()
+    }
+    (ALLOCATIONS.load(Ordering::SeqCst) - start) as f64 / CALLS as f64
+}
+
+/// Allocations per encoded header when encoding into a buffer which is
+/// reused across messages, compared to allocating a new buffer for every
+/// message.
+///
+/// Criterion measures time only, so the counts are printed.
+fn allocations() {
+    let hdr = request_header();
+    let fresh = allocations_per_call(|| { black_box(encode_fresh(&hdr)); });
+    let mut buf = Vec::new();
+    let reused = allocations_per_call(|| { black_box(encode_reused(&hdr, &mut buf)); });
+    println!("header-allocations/fresh      {:.2} allocs/message", fresh);
+    println!("header-allocations/reused     {:.2} allocs/message", reused)
+}
+
+/// Encoded length of every header with CBOR compared to the compact
+/// encoding.
+///
+/// Criterion measures time only, so the lengths are printed.
+fn sizes() {
+    for (name, hdr) in &headers() {
+        for (encoding_name, encoding) in &ENCODINGS {
+            let mut buf = Vec::new();
+            encoding.encode(hdr, &mut buf).unwrap();
+            println!("header-size/{}/{:<16} {} bytes", encoding_name, name, buf.len())
+        }
+    }
+}
+
+/// The time taken by the allocations avoided.
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("header-encode");
+    let hdr = request_header();
+
+    group.bench_function("fresh", |b| b.iter(|| encode_fresh(&hdr)));
+    let mut buf = Vec::new();
+    group.bench_function("reused", |b| b.iter(|| encode_reused(&hdr, &mut buf)));
+
+    group.finish()
+}
+
+/// Encoding and decoding of headers with CBOR compared to the compact
+/// encoding.
+fn encodings(c: &mut Criterion) {
+    let mut group = c.benchmark_group("header-encoding");
+
+    for (name, hdr) in &headers() {
+        for (encoding_name, encoding) in &ENCODINGS {
+            let mut buf = Vec::new();
+            group.bench_with_input(BenchmarkId::new(format!("encode/{}", encoding_name), name), hdr, |b, hdr| b.iter(|| {
+                buf.clear();
+                encoding.encode(hdr, &mut buf).unwrap()
+            }));
+            let mut bytes = Vec::new();
+            encoding.encode(hdr, &mut bytes).unwrap();
+            group.bench_with_input(BenchmarkId::new(format!("decode/{}", encoding_name), name), &bytes, |b, bytes| {
+                b.iter(|| encoding.decode(bytes).unwrap())
+            });
+        }
+    }
+
+    group.finish()
+}
+
+criterion_group!(benches, encode, encodings);
+criterion_main!(allocations, sizes, benches);
@@ -0,0 +1,163 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Lookups of per-peer state, which `Throttled` performs for every
+//! message sent or received.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use fnv::FnvHashMap;
+use futures::prelude::*;
+use libp2p_core::{ConnectedPoint, Multiaddr, PeerId, connection::ConnectionId};
+use libp2p_request_response::*;
+use libp2p_swarm::NetworkBehaviour;
+use std::{collections::HashMap, hash::BuildHasher, io, iter, num::NonZeroU16};
+
+/// The number of peers in the maps looked up.
+const PEERS: usize = 1000;
+
+/// The number of requests sent per iteration.
+const REQUESTS: u16 = 1000;
+
+/// Lookups in peer maps using FNV, as `Throttled` does, compared to
+/// the default SipHash.
+fn hasher(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hasher");
+    group.throughput(Throughput::Elements(PEERS as u64));
+
+    let peers = (0 .. PEERS).map(|_| PeerId::random()).collect::<Vec<_>>();
+
+    fn get_all<S: BuildHasher>(map: &HashMap<PeerId, u32, S>, peers: &[PeerId]) -> u32 {
+        peers.iter().map(|p| map[p]).sum()
+    }
+
+    let sip = peers.iter().map(|p| (p.clone(), 1u32)).collect::<HashMap<_, _>>();
+    group.bench_function("sip", |b| b.iter(|| get_all(&sip, &peers)));
+    let fnv = peers.iter().map(|p| (p.clone(), 1u32)).collect::<FnvHashMap<_, _>>();
+    group.bench_function("fnv", |b| b.iter(|| get_all(&fnv, &peers)));
+
+    group.finish()
+}
+
+/// A single lookup of a present peer, as `Throttled::send_request` does
+/// for a connected peer, compared to checking for the peer first.
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    group.throughput(Throughput::Elements(PEERS as u64));
+
+    let peers = (0 .. PEERS).map(|_| PeerId::random()).collect::<Vec<_>>();
+    let mut map = peers.iter().map(|p| (p.clone(), 0u32)).collect::<FnvHashMap<_, _>>();
+
+    group.bench_function("contains_key+get_mut", |b| b.iter(|| {
+        for p in &peers {
+            if !map.contains_key(p) {
+                map.insert(p.clone(), 0);
+            }
+            *map.get_mut(p).unwrap() += 1
+        }
+    }));
+    group.bench_function("get_mut", |b| b.iter(|| {
+        for p in &peers {
+            match map.get_mut(p) {
+                Some(n) => *n += 1,
+                None => { map.insert(p.clone(), 1); }
+            }
+        }
+    }));
+
+    group.finish()
+}
+
+/// `Throttled::send_request` to a connected peer.
+fn send_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_request");
+    group.throughput(Throughput::Elements(u64::from(REQUESTS)));
+
+    let setup = || {
+        let protocols = iter::once((NullProtocol(), ProtocolSupport::Full));
+        let mut t = RequestResponse::throttled(NullCodec(), protocols, RequestResponseConfig::default());
+        t.set_initial_send_budget(NonZeroU16::new(REQUESTS).unwrap());
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        (t, p)
+    };
+
+    group.bench_function("connected", |b| b.iter_batched(setup, |(mut t, p)| {
+        for _ in 0 .. REQUESTS {
+            t.send_request(&p, ()).unwrap();
+        }
+        t
+    }, BatchSize::SmallInput));
+
+    group.finish()
+}
+
+criterion_group!(benches, hasher, lookup, send_request);
+criterion_main!(benches);
+
+// A protocol which is never used for actual I/O.
+
+#[derive(Debug, Clone)]
+struct NullProtocol();
+#[derive(Clone)]
+struct NullCodec();
+
+impl ProtocolName for NullProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/null/1".as_bytes()
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for NullCodec {
+    type Protocol = NullProtocol;
+    type Request = ();
+    type Response = ();
+
+    async fn read_request<T>(&mut self, _: &NullProtocol, _: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        Ok(())
+    }
+
+    async fn read_response<T>(&mut self, _: &NullProtocol, _: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        Ok(())
+    }
+
+    async fn write_request<T>(&mut self, _: &NullProtocol, _: &mut T, _: ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send
+    {
+        Ok(())
+    }
+
+    async fn write_response<T>(&mut self, _: &NullProtocol, _: &mut T, _: ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send
+    {
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Request throughput of `Throttled` compared to a bare `RequestResponse`
+//! behaviour, measured between two nodes connected over a memory transport.
+//!
+//! With a receive limit below the number of requests of a round, the
+//! sender repeatedly uses up its send budget and has to wait for credit.
+//!
+//! With large payloads, any copying of the payload to add or strip the
+//! header of `Throttled` shows as a lower throughput than that of the
+//! bare behaviour.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::prelude::*;
+use libp2p_core::{
+    Multiaddr,
+    PeerId,
+    identity,
+    muxing::StreamMuxerBox,
+    transport::{self, MemoryTransport, Transport},
+    upgrade::{self, read_one, write_one}
+};
+use libp2p_noise::{NoiseConfig, X25519Spec, Keypair};
+use libp2p_request_response::*;
+use libp2p_swarm::Swarm;
+use std::{io, iter, num::NonZeroU16};
+
+/// The number of requests sent per iteration.
+const REQUESTS: usize = 256;
+
+/// The number of large requests sent per iteration.
+const LARGE_REQUESTS: usize = 32;
+
+/// The payload size of large requests.
+///
+/// Yamux resets streams whose receive buffer exceeds 1 MiB by default,
+/// which payloads close to that size can run into.
+const LARGE_PAYLOAD: usize = 512 * 1024;
+
+/// The max. payload size the codec reads.
+const MAX_PAYLOAD: usize = 1024 * 1024;
+
+fn bare(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request-response");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+
+    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
+    let cfg = RequestResponseConfig::default();
+
+    let (peer1_id, trans) = mk_transport();
+    let mut swarm1 = Swarm::new(trans, RequestResponse::new(PingCodec(), protocols.clone(), cfg.clone()), peer1_id.clone());
+    let (peer2_id, trans) = mk_transport();
+    let mut swarm2 = Swarm::new(trans, RequestResponse::new(PingCodec(), protocols, cfg), peer2_id);
+
+    let addr = mk_addr();
+    Swarm::listen_on(&mut swarm1, addr.clone()).unwrap();
+    swarm2.add_address(&peer1_id, addr);
+
+    async_std::task::spawn(async move {
+        loop {
+            if let RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { channel, .. }, ..
+            } = swarm1.next().await {
+                swarm1.send_response(channel, pong())
+            }
+        }
+    });
+
+    group.bench_function("bare", |b| b.iter(|| async_std::task::block_on(async {
+        for _ in 0 .. REQUESTS {
+            swarm2.send_request(&peer1_id, ping());
+        }
+        let mut received = 0;
+        while received < REQUESTS {
+            match swarm2.next().await {
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                } => received += 1,
+                e@RequestResponseEvent::OutboundFailure { .. } => panic!("Unexpected event: {:?}", e),
+                _ => {}
+            }
+        }
+    })));
+
+    group.finish()
+}
+
+fn throttled(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request-response");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+
+    for &limit in &[1, 16, REQUESTS as u16] {
+        let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
+        let cfg = RequestResponseConfig::default();
+
+        let (peer1_id, trans) = mk_transport();
+        let mut swarm1 = Swarm::new(trans, RequestResponse::throttled(PingCodec(), protocols.clone(), cfg.clone()), peer1_id.clone());
+        let (peer2_id, trans) = mk_transport();
+        let mut swarm2 = Swarm::new(trans, RequestResponse::throttled(PingCodec(), protocols, cfg), peer2_id);
+
+        swarm1.set_receive_limit(NonZeroU16::new(limit).unwrap());
+
+        let addr = mk_addr();
+        Swarm::listen_on(&mut swarm1, addr.clone()).unwrap();
+        swarm2.add_address(&peer1_id, addr);
+
+        async_std::task::spawn(async move {
+            loop {
+                if let throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                }) = swarm1.next().await {
+                    swarm1.send_response(channel, pong())
+                }
+            }
+        });
+
+        group.bench_with_input(BenchmarkId::new("throttled", limit), &limit, |b, _| b.iter(|| async_std::task::block_on(async {
+            let mut sent = 0;
+            let mut received = 0;
+            while received < REQUESTS {
+                while sent < REQUESTS && swarm2.send_request(&peer1_id, ping()).is_ok() {
+                    sent += 1
+                }
+                match swarm2.next().await {
+                    throttled::Event::Event(RequestResponseEvent::Message {
+                        message: RequestResponseMessage::Response { .. }, ..
+                    }) => received += 1,
+                    e@throttled::Event::Event(RequestResponseEvent::OutboundFailure { .. }) =>
+                        panic!("Unexpected event: {:?}", e),
+                    _ => {}
+                }
+            }
+        })));
+    }
+
+    group.finish()
+}
+
+fn large_payload(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large-payload");
+    group.throughput(Throughput::Bytes((LARGE_REQUESTS * LARGE_PAYLOAD) as u64));
+    group.sample_size(10);
+
+    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
+    let cfg = RequestResponseConfig::default();
+
+    let (peer1_id, trans) = mk_transport();
+    let mut swarm1 = Swarm::new(trans, RequestResponse::new(PingCodec(), protocols.clone(), cfg.clone()), peer1_id.clone());
+    let (peer2_id, trans) = mk_transport();
+    let mut swarm2 = Swarm::new(trans, RequestResponse::new(PingCodec(), protocols.clone(), cfg.clone()), peer2_id);
+
+    let addr = mk_addr();
+    Swarm::listen_on(&mut swarm1, addr.clone()).unwrap();
+    swarm2.add_address(&peer1_id, addr);
+
+    async_std::task::spawn(async move {
+        loop {
+            if let RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { channel, .. }, ..
+            } = swarm1.next().await {
+                swarm1.send_response(channel, pong())
+            }
+        }
+    });
+
+    group.bench_function("bare", |b| b.iter(|| async_std::task::block_on(async {
+        for _ in 0 .. LARGE_REQUESTS {
+            swarm2.send_request(&peer1_id, large_ping());
+        }
+        let mut received = 0;
+        while received < LARGE_REQUESTS {
+            match swarm2.next().await {
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                } => received += 1,
+                e@RequestResponseEvent::OutboundFailure { .. } => panic!("Unexpected event: {:?}", e),
+                _ => {}
+            }
+        }
+    })));
+
+    let (peer1_id, trans) = mk_transport();
+    let mut swarm1 = Swarm::new(trans, RequestResponse::throttled(PingCodec(), protocols.clone(), cfg.clone()), peer1_id.clone());
+    let (peer2_id, trans) = mk_transport();
+    let mut swarm2 = Swarm::new(trans, RequestResponse::throttled(PingCodec(), protocols, cfg), peer2_id);
+
+    // The budget does not limit the requests of a round.
+    swarm1.set_receive_limit(NonZeroU16::new(LARGE_REQUESTS as u16).unwrap());
+
+    let addr = mk_addr();
+    Swarm::listen_on(&mut swarm1, addr.clone()).unwrap();
+    swarm2.add_address(&peer1_id, addr);
+
+    async_std::task::spawn(async move {
+        loop {
+            if let throttled::Event::Event(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { channel, .. }, ..
+            }) = swarm1.next().await {
+                swarm1.send_response(channel, pong())
+            }
+        }
+    });
+
+    group.bench_function("throttled", |b| b.iter(|| async_std::task::block_on(async {
+        let mut sent = 0;
+        let mut received = 0;
+        while received < LARGE_REQUESTS {
+            while sent < LARGE_REQUESTS && swarm2.send_request(&peer1_id, large_ping()).is_ok() {
+                sent += 1
+            }
+            match swarm2.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => received += 1,
+                e@throttled::Event::Event(RequestResponseEvent::OutboundFailure { .. }) =>
+                    panic!("Unexpected event: {:?}", e),
+                _ => {}
+            }
+        }
+    })));
+
+    group.finish()
+}
+
+criterion_group!(benches, bare, throttled, large_payload);
+criterion_main!(benches);
+
+fn mk_addr() -> Multiaddr {
+    format!("/memory/{}", rand::random::<u64>()).parse().unwrap()
+}
+
+fn mk_transport() -> (PeerId, transport::Boxed<(PeerId, StreamMuxerBox)>) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let peer_id = id_keys.public().into_peer_id();
+    let noise_keys = Keypair::<X25519Spec>::new().into_authentic(&id_keys).unwrap();
+    (peer_id, MemoryTransport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(libp2p_yamux::Config::default())
+        .boxed())
+}
+
+fn ping() -> Ping {
+    Ping(b"ping".to_vec())
+}
+
+fn large_ping() -> Ping {
+    Ping(vec![0x55; LARGE_PAYLOAD])
+}
+
+fn pong() -> Pong {
+    Pong(b"pong".to_vec())
+}
+
+// Simple Ping-Pong Protocol
+
+#[derive(Debug, Clone)]
+struct PingProtocol();
+#[derive(Clone)]
+struct PingCodec();
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Ping(Vec<u8>);
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pong(Vec<u8>);
+
+impl ProtocolName for PingProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/ping/1".as_bytes()
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for PingCodec {
+    type Protocol = PingProtocol;
+    type Request = Ping;
+    type Response = Pong;
+
+    async fn read_request<T>(&mut self, _: &PingProtocol, io: &mut T)
+        -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        read_one(io, MAX_PAYLOAD).await
+            .map(Ping)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &PingProtocol, io: &mut T)
+        -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        read_one(io, MAX_PAYLOAD).await
+            .map(Pong)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(&mut self, _: &PingProtocol, io: &mut T, Ping(data): Ping)
+        -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send
+    {
+        write_one(io, data).await
+    }
+
+    async fn write_response<T>(&mut self, _: &PingProtocol, io: &mut T, Pong(data): Pong)
+        -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send
+    {
+        write_one(io, data).await
+    }
+}
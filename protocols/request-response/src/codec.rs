@@ -22,7 +22,7 @@ pub use libp2p_core::ProtocolName;
 
 use async_trait::async_trait;
 use futures::prelude::*;
-use std::io;
+use std::{error::Error, fmt, io};
 
 /// A `RequestResponseCodec` defines the request and response types
 /// for a [`RequestResponse`](crate::RequestResponse) protocol or
@@ -65,3 +65,38 @@ pub trait RequestResponseCodec {
         T: AsyncWrite + Unpin + Send;
 }
 
+
+/// The error returned by a codec when a message exceeds the max. size
+/// it is willing to read.
+///
+/// Codecs return it wrapped in an [`io::Error`] (see the `From` impl).
+/// Unlike other I/O errors while reading a message, it does not close the
+/// connection but is reported as [`InboundFailure::MessageTooLarge`] or
+/// [`OutboundFailure::MessageTooLarge`].
+///
+/// [`InboundFailure::MessageTooLarge`]: crate::InboundFailure::MessageTooLarge
+/// [`OutboundFailure::MessageTooLarge`]: crate::OutboundFailure::MessageTooLarge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    /// The max. message size in bytes.
+    pub limit: usize
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message exceeds max. size of {} bytes", self.limit)
+    }
+}
+
+impl Error for MessageTooLarge {}
+
+impl From<MessageTooLarge> for io::Error {
+    fn from(e: MessageTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Checks whether the given I/O error wraps a [`MessageTooLarge`] error.
+pub(crate) fn is_message_too_large(e: &io::Error) -> bool {
+    matches!(e.get_ref(), Some(e) if e.is::<MessageTooLarge>())
+}
@@ -21,7 +21,7 @@
 mod protocol;
 
 use crate::{EMPTY_QUEUE_SHRINK_THRESHOLD, RequestId};
-use crate::codec::RequestResponseCodec;
+use crate::codec::{RequestResponseCodec, is_message_too_large};
 
 pub use protocol::{RequestProtocol, ResponseProtocol, ProtocolSupport};
 
@@ -45,7 +45,8 @@ use libp2p_swarm::{
 };
 use smallvec::SmallVec;
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
     io,
     sync::{atomic::{AtomicU64, Ordering}, Arc},
     time::Duration,
@@ -64,11 +65,17 @@ where
     /// The request/response message codec.
     codec: TCodec,
     /// The keep-alive timeout of idle connections. A connection is considered
-    /// idle if there are no outbound substreams.
+    /// idle if there are no inbound or outbound requests in progress.
     keep_alive_timeout: Duration,
-    /// The timeout for inbound and outbound substreams (i.e. request
-    /// and response processing).
+    /// The timeout for inbound substreams (i.e. request and response
+    /// processing).
     substream_timeout: Duration,
+    /// The outbound requests whose substreams have been requested
+    /// but not yet finished. Dropping the sender cancels the request.
+    active_outbound: HashMap<RequestId, oneshot::Sender<()>>,
+    /// The inbound requests which have been received but whose
+    /// substreams have not yet finished.
+    active_inbound: HashSet<RequestId>,
     /// The current connection keep-alive.
     keep_alive: KeepAlive,
     /// A pending fatal error that results in the connection being closed.
@@ -80,7 +87,7 @@ where
     /// Inbound upgrades waiting for the incoming request.
     inbound: FuturesUnordered<BoxFuture<'static,
         Result<
-            ((RequestId, TCodec::Request), oneshot::Sender<TCodec::Response>),
+            ((RequestId, TCodec::Request), oneshot::Sender<TCodec::Response>, Instant),
             oneshot::Canceled
         >>>,
     inbound_request_id: Arc<AtomicU64>
@@ -103,6 +110,8 @@ where
             keep_alive: KeepAlive::Yes,
             keep_alive_timeout,
             substream_timeout,
+            active_outbound: HashMap::new(),
+            active_inbound: HashSet::new(),
             outbound: VecDeque::new(),
             inbound: FuturesUnordered::new(),
             pending_events: VecDeque::new(),
@@ -112,6 +121,45 @@ where
     }
 }
 
+/// The events received by the [`RequestResponseHandler`].
+#[doc(hidden)]
+pub enum RequestResponseHandlerInEvent<TCodec>
+where
+    TCodec: RequestResponseCodec
+{
+    /// An outbound request to send.
+    Request(RequestProtocol<TCodec>),
+    /// Cancel an outbound request, dropping its substream.
+    Cancel(RequestId),
+}
+
+impl<TCodec> Clone for RequestResponseHandlerInEvent<TCodec>
+where
+    TCodec: RequestResponseCodec,
+    RequestProtocol<TCodec>: Clone
+{
+    fn clone(&self) -> Self {
+        match self {
+            RequestResponseHandlerInEvent::Request(r) => RequestResponseHandlerInEvent::Request(r.clone()),
+            RequestResponseHandlerInEvent::Cancel(id) => RequestResponseHandlerInEvent::Cancel(*id)
+        }
+    }
+}
+
+impl<TCodec> fmt::Debug for RequestResponseHandlerInEvent<TCodec>
+where
+    TCodec: RequestResponseCodec
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestResponseHandlerInEvent::Request(r) =>
+                f.debug_tuple("Request").field(&r.request_id).finish(),
+            RequestResponseHandlerInEvent::Cancel(id) =>
+                f.debug_tuple("Cancel").field(id).finish()
+        }
+    }
+}
+
 /// The events emitted by the [`RequestResponseHandler`].
 #[doc(hidden)]
 #[derive(Debug)]
@@ -123,7 +171,8 @@ where
     Request {
         request_id: RequestId,
         request: TCodec::Request,
-        sender: oneshot::Sender<TCodec::Response>
+        sender: oneshot::Sender<TCodec::Response>,
+        deadline: Instant
     },
     /// An inbound response.
     Response {
@@ -136,15 +185,22 @@ where
     OutboundUnsupportedProtocols(RequestId),
     /// An inbound request timed out.
     InboundTimeout(RequestId),
+    /// No response was sent to an inbound request because the
+    /// response channel was dropped.
+    ResponseOmission(RequestId),
     /// An inbound request failed to negotiate a mutually supported protocol.
     InboundUnsupportedProtocols(RequestId),
+    /// The response to an outbound request exceeded the max. message size.
+    OutboundMessageTooLarge(RequestId),
+    /// An inbound request exceeded the max. message size.
+    InboundMessageTooLarge(RequestId),
 }
 
 impl<TCodec> ProtocolsHandler for RequestResponseHandler<TCodec>
 where
     TCodec: RequestResponseCodec + Send + Clone + 'static,
 {
-    type InEvent = RequestProtocol<TCodec>;
+    type InEvent = RequestResponseHandlerInEvent<TCodec>;
     type OutEvent = RequestResponseHandlerEvent<TCodec>;
     type Error = ProtocolsHandlerUpgrErr<io::Error>;
     type InboundProtocol = ResponseProtocol<TCodec>;
@@ -163,6 +219,10 @@ where
 
         let request_id = RequestId(self.inbound_request_id.fetch_add(1, Ordering::Relaxed));
 
+        // The substream timeout starts with the inbound upgrade, so this
+        // is the deadline for sending the response.
+        let deadline = Instant::now() + self.substream_timeout;
+
         // By keeping all I/O inside the `ResponseProtocol` and thus the
         // inbound substream upgrade via above channels, we ensure that it
         // is all subject to the configured timeout without extra bookkeeping
@@ -180,32 +240,53 @@ where
         // The handler waits for the request to come in. It then emits
         // `RequestResponseHandlerEvent::Request` together with a
         // `ResponseChannel`.
-        self.inbound.push(rq_recv.map_ok(move |rq| (rq, rs_send)).boxed());
+        self.inbound.push(rq_recv.map_ok(move |rq| (rq, rs_send, deadline)).boxed());
 
         SubstreamProtocol::new(proto, request_id).with_timeout(self.substream_timeout)
     }
 
     fn inject_fully_negotiated_inbound(
         &mut self,
-        (): (),
-        _: RequestId
+        sent: bool,
+        request_id: RequestId
     ) {
+        self.active_inbound.remove(&request_id);
+        if !sent {
+            self.pending_events.push_back(
+                RequestResponseHandlerEvent::ResponseOmission(request_id));
+        }
     }
 
     fn inject_fully_negotiated_outbound(
         &mut self,
-        response: TCodec::Response,
+        response: Option<TCodec::Response>,
         request_id: RequestId,
     ) {
-        self.pending_events.push_back(
-            RequestResponseHandlerEvent::Response {
-                request_id, response
-            });
+        if self.active_outbound.remove(&request_id).is_none() {
+            // The request has been cancelled.
+            return
+        }
+        if let Some(response) = response {
+            self.pending_events.push_back(
+                RequestResponseHandlerEvent::Response {
+                    request_id, response
+                });
+        }
     }
 
-    fn inject_event(&mut self, request: Self::InEvent) {
-        self.keep_alive = KeepAlive::Yes;
-        self.outbound.push_back(request);
+    fn inject_event(&mut self, event: Self::InEvent) {
+        match event {
+            RequestResponseHandlerInEvent::Request(request) => {
+                self.keep_alive = KeepAlive::Yes;
+                self.outbound.push_back(request)
+            }
+            RequestResponseHandlerInEvent::Cancel(request_id) => {
+                // Dropping the sender aborts the upgrade of a requested
+                // substream, whose error is then ignored.
+                self.outbound.retain(|r| r.request_id != request_id);
+                self.active_outbound.remove(&request_id);
+            }
+        }
     }
 
     fn inject_dial_upgrade_error(
@@ -213,6 +294,10 @@ where
         info: RequestId,
         error: ProtocolsHandlerUpgrErr<io::Error>,
     ) {
+        if self.active_outbound.remove(&info).is_none() {
+            // The request has been cancelled.
+            return
+        }
         match error {
             ProtocolsHandlerUpgrErr::Timeout => {
                 self.pending_events.push_back(
@@ -227,6 +312,11 @@ where
                 self.pending_events.push_back(
                     RequestResponseHandlerEvent::OutboundUnsupportedProtocols(info));
             }
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(ref e)) if is_message_too_large(e) => {
+                // Only the substream of the oversized response is affected.
+                self.pending_events.push_back(
+                    RequestResponseHandlerEvent::OutboundMessageTooLarge(info));
+            }
             _ => {
                 // Anything else is considered a fatal error or misbehaviour of
                 // the remote peer and results in closing the connection.
@@ -240,6 +330,7 @@ where
         info: RequestId,
         error: ProtocolsHandlerUpgrErr<io::Error>
     ) {
+        self.active_inbound.remove(&info);
         match error {
             ProtocolsHandlerUpgrErr::Timeout => {
                 self.pending_events.push_back(RequestResponseHandlerEvent::InboundTimeout(info))
@@ -253,6 +344,11 @@ where
                 self.pending_events.push_back(
                     RequestResponseHandlerEvent::InboundUnsupportedProtocols(info));
             }
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(ref e)) if is_message_too_large(e) => {
+                // Only the substream of the oversized request is affected.
+                self.pending_events.push_back(
+                    RequestResponseHandlerEvent::InboundMessageTooLarge(info));
+            }
             _ => {
                 // Anything else is considered a fatal error or misbehaviour of
                 // the remote peer and results in closing the connection.
@@ -287,12 +383,13 @@ where
         // Check for inbound requests.
         while let Poll::Ready(Some(result)) = self.inbound.poll_next_unpin(cx) {
             match result {
-                Ok(((id, rq), rs_sender)) => {
+                Ok(((id, rq), rs_sender, deadline)) => {
                     // We received an inbound request.
                     self.keep_alive = KeepAlive::Yes;
+                    self.active_inbound.insert(id);
                     return Poll::Ready(ProtocolsHandlerEvent::Custom(
                         RequestResponseHandlerEvent::Request {
-                            request_id: id, request: rq, sender: rs_sender
+                            request_id: id, request: rq, sender: rs_sender, deadline
                         }))
                 }
                 Err(oneshot::Canceled) => {
@@ -304,12 +401,16 @@ where
         }
 
         // Emit outbound requests.
-        if let Some(request) = self.outbound.pop_front() {
+        if let Some(mut request) = self.outbound.pop_front() {
             let info = request.request_id;
+            let timeout = request.timeout;
+            let (cancel, cancelled) = oneshot::channel();
+            request.cancelled = Some(cancelled);
+            self.active_outbound.insert(info, cancel);
             return Poll::Ready(
                 ProtocolsHandlerEvent::OutboundSubstreamRequest {
                     protocol: SubstreamProtocol::new(request, info)
-                        .with_timeout(self.substream_timeout)
+                        .with_timeout(timeout)
                 },
             )
         }
@@ -320,12 +421,16 @@ where
             self.outbound.shrink_to_fit();
         }
 
-        if self.inbound.is_empty() && self.keep_alive.is_yes() {
-            // No new inbound or outbound requests. However, we may just have
-            // started the latest inbound or outbound upgrade(s), so make sure
-            // the keep-alive timeout is preceded by the substream timeout.
-            let until = Instant::now() + self.substream_timeout + self.keep_alive_timeout;
-            self.keep_alive = KeepAlive::Until(until);
+        let idle = self.inbound.is_empty()
+            && self.active_inbound.is_empty()
+            && self.active_outbound.is_empty();
+
+        if idle && self.keep_alive.is_yes() {
+            // All requests have finished, so the connection is idle from
+            // now on. Each request is subject to its own timeout, hence the
+            // keep-alive timeout does not have to allow for the substream
+            // timeout.
+            self.keep_alive = KeepAlive::Until(Instant::now() + self.keep_alive_timeout);
         }
 
         Poll::Pending
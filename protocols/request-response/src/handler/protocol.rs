@@ -26,14 +26,14 @@
 use crate::RequestId;
 use crate::codec::RequestResponseCodec;
 
-use futures::{channel::oneshot, future::BoxFuture, prelude::*};
+use futures::{channel::oneshot, future::{self, BoxFuture}, prelude::*};
 use libp2p_core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
 use libp2p_swarm::NegotiatedSubstream;
 use smallvec::SmallVec;
-use std::io;
+use std::{io, time::Duration};
 
 /// The level of support for a particular protocol.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProtocolSupport {
     /// The protocol is only supported for inbound requests.
     Inbound,
@@ -93,7 +93,8 @@ impl<TCodec> InboundUpgrade<NegotiatedSubstream> for ResponseProtocol<TCodec>
 where
     TCodec: RequestResponseCodec + Send + 'static,
 {
-    type Output = ();
+    /// Whether a response has been sent.
+    type Output = bool;
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
@@ -101,22 +102,24 @@ where
         async move {
             let read = self.codec.read_request(&protocol, &mut io);
             let request = read.await?;
+            let mut sent = false;
             if let Ok(()) = self.request_sender.send((self.request_id, request)) {
                 if let Ok(response) = self.response_receiver.await {
                     let write = self.codec.write_response(&protocol, &mut io, response);
                     write.await?;
+                    sent = true
                 }
             }
             io.close().await?;
-            Ok(())
+            Ok(sent)
         }.boxed()
     }
 }
 
 /// Request substream upgrade protocol.
 ///
-/// Sends a request and receives a response.
-#[derive(Debug, Clone)]
+/// Sends a request and receives a response, unless no response is expected.
+#[derive(Debug)]
 pub struct RequestProtocol<TCodec>
 where
     TCodec: RequestResponseCodec
@@ -125,6 +128,30 @@ where
     pub(crate) protocols: SmallVec<[TCodec::Protocol; 2]>,
     pub(crate) request_id: RequestId,
     pub(crate) request: TCodec::Request,
+    pub(crate) timeout: Duration,
+    pub(crate) expect_response: bool,
+    /// Resolves when the request is cancelled, which aborts the upgrade
+    /// and drops the substream. Set by the handler.
+    pub(crate) cancelled: Option<oneshot::Receiver<()>>,
+}
+
+impl<TCodec> Clone for RequestProtocol<TCodec>
+where
+    TCodec: RequestResponseCodec + Clone,
+    TCodec::Request: Clone
+{
+    fn clone(&self) -> Self {
+        RequestProtocol {
+            codec: self.codec.clone(),
+            protocols: self.protocols.clone(),
+            request_id: self.request_id,
+            request: self.request.clone(),
+            timeout: self.timeout,
+            expect_response: self.expect_response,
+            // A copy of the request is not subject to the cancellation.
+            cancelled: None
+        }
+    }
 }
 
 impl<TCodec> UpgradeInfo for RequestProtocol<TCodec>
@@ -143,18 +170,33 @@ impl<TCodec> OutboundUpgrade<NegotiatedSubstream> for RequestProtocol<TCodec>
 where
     TCodec: RequestResponseCodec + Send + 'static,
 {
-    type Output = TCodec::Response;
+    /// The response, if one is expected.
+    type Output = Option<TCodec::Response>;
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
     fn upgrade_outbound(mut self, mut io: NegotiatedSubstream, protocol: Self::Info) -> Self::Future {
-        async move {
+        let cancelled = self.cancelled.take();
+        let exchange = async move {
             let write = self.codec.write_request(&protocol, &mut io, self.request);
             write.await?;
             io.close().await?;
+            if !self.expect_response {
+                return Ok(None)
+            }
             let read = self.codec.read_response(&protocol, &mut io);
             let response = read.await?;
-            Ok(response)
-        }.boxed()
+            Ok(Some(response))
+        }.boxed();
+        if let Some(cancelled) = cancelled {
+            // Dropping the exchange drops the substream.
+            future::select(exchange, cancelled).map(|result| match result {
+                future::Either::Left((result, _)) => result,
+                future::Either::Right(_) =>
+                    Err(io::Error::new(io::ErrorKind::Interrupted, "request cancelled"))
+            }).boxed()
+        } else {
+            exchange
+        }
     }
 }
@@ -72,7 +72,7 @@ pub mod codec;
 pub mod handler;
 pub mod throttled;
 
-pub use codec::{RequestResponseCodec, ProtocolName};
+pub use codec::{RequestResponseCodec, ProtocolName, MessageTooLarge};
 pub use handler::ProtocolSupport;
 pub use throttled::Throttled;
 
@@ -83,6 +83,7 @@ use handler::{
     RequestProtocol,
     RequestResponseHandler,
     RequestResponseHandlerEvent,
+    RequestResponseHandlerInEvent,
 };
 use libp2p_core::{
     ConnectedPoint,
@@ -105,6 +106,7 @@ use std::{
     sync::{atomic::AtomicU64, Arc},
     task::{Context, Poll}
 };
+use wasm_timer::Instant;
 
 /// An inbound request or response.
 #[derive(Debug)]
@@ -179,6 +181,8 @@ pub enum OutboundFailure {
     ConnectionClosed,
     /// The remote supports none of the requested protocols.
     UnsupportedProtocols,
+    /// The response exceeded the max. message size of the codec.
+    MessageTooLarge,
 }
 
 /// Possible failures occurring in the context of receiving an
@@ -194,6 +198,11 @@ pub enum InboundFailure {
     UnsupportedProtocols,
     /// The connection closed before a response was delivered.
     ConnectionClosed,
+    /// The local peer dropped the [`ResponseChannel`] of the inbound
+    /// request without sending a response.
+    ResponseOmission,
+    /// The inbound request exceeded the max. message size of the codec.
+    MessageTooLarge,
 }
 
 /// A channel for sending a response to an inbound request.
@@ -204,6 +213,7 @@ pub struct ResponseChannel<TResponse> {
     request_id: RequestId,
     peer: PeerId,
     sender: oneshot::Sender<TResponse>,
+    deadline: Instant,
 }
 
 impl<TResponse> ResponseChannel<TResponse> {
@@ -218,6 +228,16 @@ impl<TResponse> ResponseChannel<TResponse> {
         !self.sender.is_canceled()
     }
 
+    /// The point in time at which the inbound request times out.
+    ///
+    /// A response has to be sent via [`RequestResponse::send_response`]
+    /// before the deadline, otherwise [`InboundFailure::Timeout`] is emitted.
+    /// If the channel is dropped without sending a response,
+    /// [`InboundFailure::ResponseOmission`] is emitted.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
     /// Get the ID of the inbound request waiting for a response.
     pub(crate) fn request_id(&self) -> RequestId {
         self.request_id
@@ -228,6 +248,24 @@ impl<TResponse> ResponseChannel<TResponse> {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct RequestId(u64);
 
+impl RequestId {
+    /// Creates a request ID from its numeric value, e.g. to compare
+    /// it with the IDs of events in tests.
+    ///
+    /// IDs of requests are otherwise assigned by [`RequestResponse`].
+    pub fn new(n: u64) -> Self {
+        RequestId(n)
+    }
+
+    /// The numeric value of this request ID.
+    ///
+    /// The IDs of outbound requests increase by one with each
+    /// request sent. Inbound requests are numbered separately.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
 impl fmt::Display for RequestId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -251,13 +289,20 @@ impl Default for RequestResponseConfig {
 }
 
 impl RequestResponseConfig {
-    /// Sets the keep-alive timeout of idle connections.
+    /// Sets the keep-alive timeout of idle connections (default: 10s).
+    ///
+    /// A connection is idle once no inbound or outbound requests are in
+    /// progress on it and it is closed if it stays idle for this long.
+    /// Sending a request to a peer without a connection dials it again.
     pub fn set_connection_keep_alive(&mut self, v: Duration) -> &mut Self {
         self.connection_keep_alive = v;
         self
     }
 
     /// Sets the timeout for inbound and outbound requests.
+    ///
+    /// Outbound requests may use a different timeout, see
+    /// [`RequestResponse::send_request_with_timeout`].
     pub fn set_request_timeout(&mut self, v: Duration) -> &mut Self {
         self.request_timeout = v;
         self
@@ -284,7 +329,7 @@ where
     /// Pending events to return from `poll`.
     pending_events: VecDeque<
         NetworkBehaviourAction<
-            RequestProtocol<TCodec>,
+            RequestResponseHandlerInEvent<TCodec>,
             RequestResponseEvent<TCodec::Request, TCodec::Response>>>,
     /// The currently connected peers and their known, reachable addresses, if any.
     connected: HashMap<PeerId, SmallVec<[Connection; 2]>>,
@@ -294,7 +339,9 @@ where
     /// to be established.
     pending_requests: HashMap<PeerId, SmallVec<[RequestProtocol<TCodec>; 10]>>,
     /// Responses that have not yet been received.
-    pending_responses: HashMap<RequestId, (PeerId, ConnectionId)>
+    pending_responses: HashMap<RequestId, (PeerId, ConnectionId)>,
+    /// Inbound requests that have not yet been answered.
+    pending_inbound: HashMap<RequestId, (PeerId, ConnectionId)>
 }
 
 impl<TCodec> RequestResponse<TCodec>
@@ -328,6 +375,7 @@ where
             connected: HashMap::new(),
             pending_requests: HashMap::new(),
             pending_responses: HashMap::new(),
+            pending_inbound: HashMap::new(),
             addresses: HashMap::new(),
         }
     }
@@ -358,14 +406,52 @@ where
     /// > managed via [`RequestResponse::add_address`] and
     /// > [`RequestResponse::remove_address`].
     pub fn send_request(&mut self, peer: &PeerId, request: TCodec::Request) -> RequestId {
+        let timeout = self.config.request_timeout;
+        self.send_request_with_timeout(peer, request, timeout)
+    }
+
+    /// Initiates sending a request which times out after the given
+    /// duration instead of the configured request timeout.
+    ///
+    /// See [`RequestResponse::send_request`] for details.
+    pub fn send_request_with_timeout(&mut self, peer: &PeerId, request: TCodec::Request, timeout: Duration)
+        -> RequestId
+    {
         let request_id = self.next_request_id();
+        self.send_request_with_id(peer, request, request_id, timeout, true);
+        request_id
+    }
+
+    /// Initiates sending a request with an ID previously obtained from
+    /// [`RequestResponse::next_request_id`].
+    ///
+    /// If no response is expected, the substream is closed once the request
+    /// has been written, so that no [`RequestResponseEvent`] is emitted for
+    /// it unless a dialing attempt fails. The request itself must tell the
+    /// remote peer not to respond, as [`Throttled`](crate::throttled::Throttled)
+    /// does for notifications.
+    pub(crate) fn send_request_with_id(
+        &mut self,
+        peer: &PeerId,
+        request: TCodec::Request,
+        request_id: RequestId,
+        timeout: Duration,
+        expect_response: bool
+    ) {
         let request = RequestProtocol {
             request_id,
             codec: self.codec.clone(),
             protocols: self.outbound_protocols.clone(),
             request,
+            timeout,
+            expect_response,
+            cancelled: None
         };
+        self.send_request_protocol(peer, request)
+    }
 
+    /// Sends a request to the peer, dialing it if necessary.
+    fn send_request_protocol(&mut self, peer: &PeerId, request: RequestProtocol<TCodec>) {
         if let Some(request) = self.try_send_request(peer, request) {
             self.pending_events.push_back(NetworkBehaviourAction::DialPeer {
                 peer_id: peer.clone(),
@@ -373,8 +459,6 @@ where
             });
             self.pending_requests.entry(peer.clone()).or_default().push(request);
         }
-
-        request_id
     }
 
     /// Initiates sending a response to an inbound request.
@@ -387,8 +471,10 @@ where
     /// [`RequestResponseMessage::Request`].
     pub fn send_response(&mut self, ch: ResponseChannel<TCodec::Response>, rs: TCodec::Response) {
         // Fails only if the inbound upgrade timed out waiting for the response,
-        // in which case the handler emits `RequestResponseHandlerEvent::InboundTimeout`
-        // which in turn results in `RequestResponseEvent::InboundFailure`.
+        // i.e. the deadline of the channel has passed, in which case the handler
+        // emits `RequestResponseHandlerEvent::InboundTimeout` which in turn
+        // results in `RequestResponseEvent::InboundFailure`.
+        self.pending_inbound.remove(&ch.request_id);
         let _ = ch.sender.send(rs);
     }
 
@@ -429,8 +515,78 @@ where
         self.pending_responses.contains_key(req_id)
     }
 
+    /// Checks whether an inbound request is still pending, i.e. waiting
+    /// for a response to be sent via [`RequestResponse::send_response`].
+    pub fn is_pending_inbound(&self, req_id: &RequestId) -> bool {
+        self.pending_inbound.contains_key(req_id)
+    }
+
+    /// Returns the number of inbound requests of a peer which are
+    /// waiting for a response.
+    pub fn pending_inbound_count(&self, peer: &PeerId) -> usize {
+        self.pending_inbound.values().filter(|(p, _)| p == peer).count()
+    }
+
+    /// Returns the connection an outbound request which is waiting for
+    /// a response has been sent over.
+    pub(crate) fn pending_connection(&self, req_id: &RequestId) -> Option<ConnectionId> {
+        self.pending_responses.get(req_id).map(|(_, conn)| *conn)
+    }
+
+    /// Checks whether any outbound request is still waiting for a
+    /// connection or a response.
+    pub(crate) fn has_pending_outbound(&self) -> bool {
+        !self.pending_requests.is_empty() || !self.pending_responses.is_empty()
+    }
+
+    /// Cancels an outbound request to the given peer initiated by
+    /// [`RequestResponse::send_request`].
+    ///
+    /// A request which is still waiting for a connection to be established
+    /// is discarded. Otherwise the connection handler drops the substream
+    /// of the request. The request may have reached the remote peer already,
+    /// but no [`RequestResponseEvent`] is emitted for it, i.e. a response
+    /// or a later failure, such as a timeout, is ignored. Returns `false`
+    /// if the request to the peer is no longer pending.
+    pub fn cancel_request(&mut self, peer: &PeerId, req_id: RequestId) -> bool {
+        if self.take_unsent_request(peer, &req_id).is_some() {
+            return true
+        }
+        // Inbound requests are numbered separately, so `pending_inbound`
+        // is not affected by cancelling an outbound request.
+        match self.pending_responses.get(&req_id) {
+            Some((p, conn)) if p == peer => {
+                let conn = *conn;
+                // Requests are only handed to the handler of the connection
+                // recorded here, whose pending responses are failed when it
+                // closes, so no other handler can know about the request.
+                debug_assert!(self.connected.get(peer).map_or(false, |cs| cs.iter().any(|c| c.id == conn)));
+                self.pending_responses.remove(&req_id);
+                self.pending_events.push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer.clone(),
+                    handler: NotifyHandler::One(conn),
+                    event: RequestResponseHandlerInEvent::Cancel(req_id)
+                });
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Removes an outbound request to the given peer which is waiting
+    /// for a connection to be established.
+    pub(crate) fn take_unsent_request(&mut self, peer: &PeerId, req_id: &RequestId) -> Option<TCodec::Request> {
+        let requests = self.pending_requests.get_mut(peer)?;
+        let pos = requests.iter().position(|r| &r.request_id == req_id)?;
+        let request = requests.remove(pos).request;
+        if requests.is_empty() {
+            self.pending_requests.remove(peer);
+        }
+        Some(request)
+    }
+
     /// Returns the next request ID.
-    fn next_request_id(&mut self) -> RequestId {
+    pub(crate) fn next_request_id(&mut self) -> RequestId {
         let request_id = self.next_request_id;
         self.next_request_id.0 += 1;
         request_id
@@ -448,11 +604,13 @@ where
             }
             let ix = (request.request_id.0 as usize) % connections.len();
             let conn = connections[ix].id;
-            self.pending_responses.insert(request.request_id, (peer.clone(), conn));
+            if request.expect_response {
+                self.pending_responses.insert(request.request_id, (peer.clone(), conn));
+            }
             self.pending_events.push_back(NetworkBehaviourAction::NotifyHandler {
                 peer_id: peer.clone(),
                 handler: NotifyHandler::One(conn),
-                event: request
+                event: RequestResponseHandlerInEvent::Request(request)
             });
             None
         } else {
@@ -530,6 +688,9 @@ where
             ));
             false
         });
+
+        // Inbound requests received over this connection can no longer be answered.
+        self.pending_inbound.retain(|_, (_, cid)| conn != cid);
     }
 
     fn inject_disconnected(&mut self, peer: &PeerId) {
@@ -559,19 +720,22 @@ where
     fn inject_event(
         &mut self,
         peer: PeerId,
-        _: ConnectionId,
+        conn: ConnectionId,
         event: RequestResponseHandlerEvent<TCodec>,
     ) {
         match event {
             RequestResponseHandlerEvent::Response { request_id, response } => {
-                self.pending_responses.remove(&request_id);
-                let message = RequestResponseMessage::Response { request_id, response };
-                self.pending_events.push_back(
-                    NetworkBehaviourAction::GenerateEvent(
-                        RequestResponseEvent::Message { peer, message }));
+                // The response to a cancelled request is discarded.
+                if self.pending_responses.remove(&request_id).is_some() {
+                    let message = RequestResponseMessage::Response { request_id, response };
+                    self.pending_events.push_back(
+                        NetworkBehaviourAction::GenerateEvent(
+                            RequestResponseEvent::Message { peer, message }));
+                }
             }
-            RequestResponseHandlerEvent::Request { request_id, request, sender } => {
-                let channel = ResponseChannel { request_id, peer: peer.clone(), sender };
+            RequestResponseHandlerEvent::Request { request_id, request, sender, deadline } => {
+                self.pending_inbound.insert(request_id, (peer.clone(), conn));
+                let channel = ResponseChannel { request_id, peer: peer.clone(), sender, deadline };
                 let message = RequestResponseMessage::Request { request_id, request, channel };
                 self.pending_events.push_back(NetworkBehaviourAction::GenerateEvent(
                     RequestResponseEvent::Message { peer, message }
@@ -589,6 +753,7 @@ where
                 }
             }
             RequestResponseHandlerEvent::InboundTimeout(request_id) => {
+                self.pending_inbound.remove(&request_id);
                 self.pending_events.push_back(
                     NetworkBehaviourAction::GenerateEvent(
                         RequestResponseEvent::InboundFailure {
@@ -597,13 +762,45 @@ where
                             error: InboundFailure::Timeout,
                         }));
             }
+            RequestResponseHandlerEvent::ResponseOmission(request_id) => {
+                self.pending_inbound.remove(&request_id);
+                self.pending_events.push_back(
+                    NetworkBehaviourAction::GenerateEvent(
+                        RequestResponseEvent::InboundFailure {
+                            peer,
+                            request_id,
+                            error: InboundFailure::ResponseOmission,
+                        }));
+            }
             RequestResponseHandlerEvent::OutboundUnsupportedProtocols(request_id) => {
+                if self.pending_responses.remove(&request_id).is_some() {
+                    self.pending_events.push_back(
+                        NetworkBehaviourAction::GenerateEvent(
+                            RequestResponseEvent::OutboundFailure {
+                                peer,
+                                request_id,
+                                error: OutboundFailure::UnsupportedProtocols,
+                            }));
+                }
+            }
+            RequestResponseHandlerEvent::OutboundMessageTooLarge(request_id) => {
+                if self.pending_responses.remove(&request_id).is_some() {
+                    self.pending_events.push_back(
+                        NetworkBehaviourAction::GenerateEvent(
+                            RequestResponseEvent::OutboundFailure {
+                                peer,
+                                request_id,
+                                error: OutboundFailure::MessageTooLarge,
+                            }));
+                }
+            }
+            RequestResponseHandlerEvent::InboundMessageTooLarge(request_id) => {
                 self.pending_events.push_back(
                     NetworkBehaviourAction::GenerateEvent(
-                        RequestResponseEvent::OutboundFailure {
+                        RequestResponseEvent::InboundFailure {
                             peer,
                             request_id,
-                            error: OutboundFailure::UnsupportedProtocols,
+                            error: InboundFailure::MessageTooLarge,
                         }));
             }
             RequestResponseHandlerEvent::InboundUnsupportedProtocols(request_id) => {
@@ -620,7 +817,7 @@ where
 
     fn poll(&mut self, _: &mut Context<'_>, _: &mut impl PollParameters)
         -> Poll<NetworkBehaviourAction<
-            RequestProtocol<TCodec>,
+            RequestResponseHandlerInEvent<TCodec>,
             RequestResponseEvent<TCodec::Request, TCodec::Response>
         >>
     {
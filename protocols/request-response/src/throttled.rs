@@ -31,20 +31,71 @@
 //! The `Throttled` behaviour wraps an existing `RequestResponse` behaviour
 //! and uses a codec implementation that sends ordinary requests and responses
 //! as well as a special credit message to which an ack message is expected
-//! as a response. It does so by putting a small CBOR encoded header in front
-//! of each message the inner codec produces.
+//! as a response. It does so by putting a small header in front of each
+//! message the inner codec produces. The header is CBOR encoded unless a
+//! more compact encoding is selected with [`Throttled::set_header_encoding`]
+//! or another [`HeaderFormat`] with [`Throttled::set_header_format`].
+//!
+//! Only the header is framed by the `Throttled` codec. The data of a message
+//! is read and written by the inner codec directly from and to the stream,
+//! so an inner codec may transfer a large response incrementally, e.g. in
+//! chunks, without the payload being buffered. Budgets count requests, so
+//! such a response does not use up more budget than any other. This does
+//! not hold for compressed messages (see `Throttled::set_compression` with
+//! the `compression` feature): their data is buffered in full on both ends,
+//! so compression effectively turns off streaming.
+//!
+//! By default budgets count requests. Alternatively, with
+//! [`Throttled::use_byte_budget`], budgets and credit grants are measured
+//! in bytes of encoded request data, so that a peer can not exceed its
+//! limit by sending few but large requests.
+//!
+//! Optionally, the rate at which credit is granted can be limited with
+//! [`Throttled::set_rate_limit`], so that a peer which is answered quickly
+//! can not send an unbounded number of requests over time.
+//!
+//! Budgets are kept per peer and shared by all protocols of a `Throttled`
+//! behaviour. The protocol an outbound request is sent with is only
+//! determined during protocol negotiation, so the sender can not account
+//! for requests per protocol. The receiver can, though: with
+//! [`Throttled::set_receive_limit_for_protocol`], requests of a protocol
+//! beyond its limit are rejected while they still use up the peer's budget.
+//!
+//! Both directions are throttled independently. The credit grants for
+//! the requests of one peer travel alongside the requests of the other,
+//! so that peers exhausting each other's budget at the same time each grant
+//! credit without waiting for the other. A credit grant is considered to
+//! have reached the peer once it is acknowledged or once the peer sends
+//! more requests than it could have without the grant. Requests within the
+//! budget the peer had before may have crossed the grant on the wire.
+//!
+//! Likewise, budgets are shared by all connections to a peer. Requests
+//! and credit grants may be sent over any of them and a peer is only
+//! considered disconnected once its last connection has been closed.
 
 mod codec;
+#[cfg(feature = "metrics")]
+mod metrics;
 
-use codec::{Codec, Message, ProtocolWrapper, Type};
-use crate::handler::{RequestProtocol, RequestResponseHandler, RequestResponseHandlerEvent};
-use futures::ready;
+pub use codec::{Compression, Header, HeaderEncoding, HeaderFormat, Message, Type};
+#[cfg(feature = "bincode")]
+pub use codec::BincodeFormat;
+#[cfg(feature = "serde_json")]
+pub use codec::JsonFormat;
+
+use bytes::Bytes;
+use codec::{Codec, ProtocolWrapper, VERSION};
+use crate::handler::{RequestResponseHandler, RequestResponseHandlerEvent, RequestResponseHandlerInEvent};
+use fnv::FnvHashMap;
+use futures::{channel::oneshot, prelude::*};
 use libp2p_core::{ConnectedPoint, connection::ConnectionId, Multiaddr, PeerId};
 use libp2p_swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
 use lru::LruCache;
-use std::{collections::{HashMap, VecDeque}, task::{Context, Poll}};
-use std::{cmp::max, num::NonZeroU16};
+use std::{collections::{HashSet, VecDeque}, fmt, sync::Arc, task::{Context, Poll}};
+use std::{cmp::{max, min}, convert::TryFrom, num::{NonZeroU16, NonZeroU32, NonZeroUsize}, time::Duration};
 use super::{
+    InboundFailure,
+    ProtocolName,
     ProtocolSupport,
     RequestId,
     RequestResponse,
@@ -54,6 +105,47 @@ use super::{
     RequestResponseMessage,
     ResponseChannel
 };
+use wasm_timer::{Delay, Instant, SystemTime, UNIX_EPOCH};
+
+/// Determines the encoded size of an outbound request, see
+/// [`Throttled::use_byte_budget`].
+type RequestSize<R> = Box<dyn Fn(&R) -> u32 + Send>;
+
+/// Determines the next receive limit of a peer, see
+/// [`Throttled::set_limit_policy`].
+type LimitPolicy = Box<dyn FnMut(&PeerId, &PeerStats) -> NonZeroU16 + Send>;
+
+/// Determines the key by which duplicate inbound requests are recognised,
+/// see [`Throttled::set_request_dedup`].
+type DedupKey<R> = Box<dyn Fn(&R) -> u64 + Send>;
+
+/// The result of [`Throttled::send_requests`]: the IDs of the requests sent
+/// and, if the budget did not allow to send all, the remaining requests.
+type SendRequests<R> = Result<Vec<RequestId>, (Vec<RequestId>, Vec<R>)>;
+
+/// Enter a `tracing` span with the given name, the id of the behaviour and
+/// the given fields until the end of the enclosing block.
+///
+/// Without the `tracing` feature, this expands to nothing.
+macro_rules! enter_span {
+    ($span:ident = $name:literal, $id:expr $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let $span = tracing::trace_span!($name, id = %format_args!("{:08x}", $id) $(, $($fields)*)?);
+        #[cfg(feature = "tracing")]
+        let _guard = $span.enter();
+    }
+}
+
+/// Record the value of a field of a span entered with `enter_span!`.
+macro_rules! record_field {
+    ($span:ident, $field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        $span.record($field, &tracing::field::display($value));
+    }
+}
+
+/// The prefix of the protocol names used by [`Throttled::new`].
+pub const DEFAULT_PREFIX: &[u8] = b"/t/1";
 
 /// A wrapper around [`RequestResponse`] which adds request limits per peer.
 pub struct Throttled<C>
@@ -61,57 +153,640 @@ where
     C: RequestResponseCodec + Send,
     C::Protocol: Sync
 {
-    /// A random id used for logging.
+    /// An id used for logging, random unless set by the user.
     id: u32,
     /// The wrapped behaviour.
     behaviour: RequestResponse<Codec<C>>,
     /// Information per peer.
-    peer_info: HashMap<PeerId, PeerInfo>,
+    peer_info: FnvHashMap<PeerId, PeerInfo>,
     /// Information about previously connected peers.
     offline_peer_info: LruCache<PeerId, PeerInfo>,
     /// The default limit applies to all peers unless overriden.
     default_limit: Limit,
     /// Permanent limit overrides per peer.
-    limit_overrides: HashMap<PeerId, Limit>,
+    limit_overrides: FnvHashMap<PeerId, Limit>,
+    /// The default send budget of peers we have not received credit from.
+    initial_send_budget: u32,
+    /// Initial send budget overrides per peer.
+    initial_send_budget_overrides: FnvHashMap<PeerId, u32>,
     /// Pending events to report in `Throttled::poll`.
     events: VecDeque<Event<C::Request, C::Response, Message<C::Response>>>,
+    /// The capacity above which the empty event queue is shrunk.
+    events_shrink_threshold: usize,
     /// Current outbound credit grants in flight.
-    credit_messages: HashMap<PeerId, Credit>,
-    /// The current credit ID.
+    credit_messages: FnvHashMap<PeerId, Credit>,
+    /// The current credit ID, see [`initial_credit_id`].
+    credit_id: u64,
+    /// The ID of the most recent credit grant.
+    last_credit_id: Option<u64>,
+    /// If set, budgets are measured in bytes and this function determines
+    /// the encoded size of an outbound request.
+    request_size: Option<RequestSize<C::Request>>,
+    /// If set, determines the key by which duplicate inbound requests
+    /// are recognised.
+    dedup_key: Option<DedupKey<C::Request>>,
+    /// Keys of recently received inbound requests.
+    seen_requests: Option<SeenRequests>,
+    /// Inbound requests which have not been answered yet.
+    inbound_requests: FnvHashMap<RequestId, PeerId>,
+    /// Are inbound requests rejected until all in-flight requests are done?
+    draining: bool,
+    /// Has `Event::Drained` been emitted?
+    drained: bool,
+    /// Emit `Event::UnknownMessage`?
+    report_unknown_messages: bool,
+    /// Max. credit that can be granted to a peer per time interval.
+    rate_limit: Option<RateLimit>,
+    /// The source of the current time.
+    clock: Box<dyn Clock>,
+    /// If set, determines the next receive limit of a peer.
+    limit_policy: Option<LimitPolicy>,
+    /// Timer to grant credit which has been deferred due to the rate limit.
+    rate_timer: Option<Delay>,
+    /// If set, failed credit grants are retransmitted with exponential backoff.
+    credit_backoff: Option<Backoff>,
+    /// If set, unacknowledged credit grants are abandoned after this duration.
+    credit_timeout: Option<Duration>,
+    /// If set, credit grants are abandoned after this many failed retransmissions.
+    max_credit_retries: Option<u32>,
+    /// If set, inbound requests of peers whose receive budget remains
+    /// above this value skip the credit bookkeeping.
+    unthrottled_threshold: Option<u32>,
+    /// Timer to retransmit credit grants after a backoff delay
+    /// or to abandon them after the credit timeout.
+    credit_timer: Option<Delay>,
+    /// Counters for statistics.
+    stats: ThrottledStats,
+    /// Prometheus metrics, updated from `stats` and the peer budgets.
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
+    /// Collect the sizes of inbound messages?
+    collect_sizes: bool,
+    /// Outbound requests waiting for send budget, ordered by priority.
+    pending_requests: FnvHashMap<PeerId, VecDeque<QueuedRequest<C::Request>>>,
+    /// The send budget used up by outbound requests which wait for
+    /// a connection to the peer to be established.
+    unsent_costs: FnvHashMap<PeerId, FnvHashMap<RequestId, u32>>,
+    /// Is sending of outbound requests paused?
+    paused: bool,
+    /// Respond to inbound requests exceeding the receive budget?
+    reject_over_budget: bool,
+    /// What to do when a peer exceeds its receive budget.
+    over_budget_action: OverBudgetAction,
+    /// Peers whose inbound requests are dropped until the given instant.
+    banned: FnvHashMap<PeerId, Instant>,
+    /// Receive limits of individual protocols, keyed by protocol name.
+    protocol_limits: FnvHashMap<Bytes, u32>,
+    /// Peers for which inbound or outbound requests are disabled.
+    protocol_support: FnvHashMap<PeerId, ProtocolSupport>,
+    /// If set, peers which repeatedly exceed their budget are banned.
+    auto_ban: Option<AutoBan>,
+    /// Recent instants at which peers exceeded their budget.
+    violations: FnvHashMap<PeerId, VecDeque<Instant>>,
+    /// If set, the max. number of unacknowledged credit grants and
+    /// what to do once it is reached.
+    max_pending_credits: Option<(NonZeroUsize, CreditOverflow)>,
+    /// Have credit grants been deferred due to `max_pending_credits`?
+    credit_cap_deferred: bool
+}
+
+/// What to do with a new credit grant if the max. number of
+/// unacknowledged credit grants has been reached.
+///
+/// See [`Throttled::set_max_pending_credits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CreditOverflow {
+    /// Forget the oldest unacknowledged credit grant, which is then no
+    /// longer retransmitted. If it has not reached the peer, the peer
+    /// stalls until it reconnects.
+    DropOldest,
+    /// Defer the new credit grant until another one is acknowledged.
+    Defer
+}
+
+/// The action taken when a peer exceeds its receive budget.
+///
+/// See [`Throttled::set_over_budget_action`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverBudgetAction {
+    /// Drop the request.
+    Ignore,
+    /// Drop the request and emit [`Event::Disconnect`].
+    Disconnect,
+    /// Drop the request as well as all further inbound requests of
+    /// the peer for the given duration.
+    Ban {
+        /// How long the peer is banned.
+        duration: Duration
+    }
+}
+
+/// A source of the current time.
+///
+/// See [`Throttled::set_clock`]. Closures returning an `Instant` are clocks.
+pub trait Clock: Send {
+    /// Get the current time.
+    fn now(&self) -> Instant;
+}
+
+impl<F: Fn() -> Instant + Send> Clock for F {
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
+/// The clock of the system, i.e. `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The configuration of a [`Throttled`] behaviour.
+///
+/// Used with [`Throttled::with_config`]. Each setting corresponds to the
+/// `Throttled` method of the same name.
+#[derive(Clone, Debug)]
+pub struct ThrottledConfig {
+    prefix: &'static [u8],
+    request_response: RequestResponseConfig,
+    receive_limit: NonZeroU16,
+    initial_send_budget: NonZeroU16,
+    offline_cache_capacity: usize,
+    max_header_size: usize,
+    max_message_size: Option<usize>,
+    header_format: Arc<dyn HeaderFormat>,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+    rate_limit: Option<RateLimit>,
+    credit_backoff: Option<Backoff>,
+    credit_timeout: Option<Duration>,
+    max_credit_retries: Option<u32>,
+    reject_over_budget: bool,
+    over_budget_action: OverBudgetAction
+}
+
+impl Default for ThrottledConfig {
+    fn default() -> Self {
+        ThrottledConfig {
+            prefix: DEFAULT_PREFIX,
+            request_response: RequestResponseConfig::default(),
+            receive_limit: NonZeroU16::new(1).expect("1 > 0"),
+            initial_send_budget: NonZeroU16::new(1).expect("1 > 0"),
+            offline_cache_capacity: 8192,
+            max_header_size: 8192,
+            max_message_size: None,
+            header_format: Arc::new(HeaderEncoding::Cbor),
+            #[cfg(feature = "compression")]
+            compression: None,
+            rate_limit: None,
+            credit_backoff: None,
+            credit_timeout: None,
+            max_credit_retries: None,
+            reject_over_budget: false,
+            over_budget_action: OverBudgetAction::Ignore
+        }
+    }
+}
+
+impl ThrottledConfig {
+    /// Sets the prefix of the protocol names (default: [`DEFAULT_PREFIX`]).
+    pub fn set_prefix(&mut self, prefix: &'static [u8]) -> &mut Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Sets the configuration of the wrapped `RequestResponse` behaviour.
+    pub fn set_request_response_config(&mut self, cfg: RequestResponseConfig) -> &mut Self {
+        self.request_response = cfg;
+        self
+    }
+
+    /// Sets the global default receive limit per peer.
+    pub fn set_receive_limit(&mut self, limit: NonZeroU16) -> &mut Self {
+        self.receive_limit = limit;
+        self
+    }
+
+    /// Sets the send budget of peers before receiving credit from them.
+    pub fn set_initial_send_budget(&mut self, budget: NonZeroU16) -> &mut Self {
+        self.initial_send_budget = budget;
+        self
+    }
+
+    /// Sets the max. number of previously connected peers whose budget
+    /// information is remembered. A capacity of 0 disables the cache.
+    pub fn set_offline_cache_capacity(&mut self, cap: usize) -> &mut Self {
+        self.offline_cache_capacity = cap;
+        self
+    }
+
+    /// Sets the max. size in bytes of the header in front of each message.
+    pub fn set_max_header_size(&mut self, size: usize) -> &mut Self {
+        self.max_header_size = size;
+        self
+    }
+
+    /// Sets the max. size in bytes of the payload of each message read.
+    pub fn set_max_message_size(&mut self, size: usize) -> &mut Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Sets the encoding of the header in front of each message.
+    pub fn set_header_encoding(&mut self, e: HeaderEncoding) -> &mut Self {
+        self.header_format = Arc::new(e);
+        self
+    }
+
+    /// Sets the format of the header in front of each message.
+    ///
+    /// Formats which [require their own prefix](HeaderFormat::requires_own_prefix)
+    /// need a prefix other than [`DEFAULT_PREFIX`], so that only peers using
+    /// the same format negotiate the protocol.
+    pub fn set_header_format(&mut self, f: Arc<dyn HeaderFormat>) -> &mut Self {
+        self.header_format = f;
+        self
+    }
+
+    /// Sets the compression of the data of each message sent.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, c: Compression) -> &mut Self {
+        self.compression = Some(c);
+        self
+    }
+
+    /// Limits the credit granted to each peer to `max` requests per `interval`.
+    pub fn set_rate_limit(&mut self, max: NonZeroU32, interval: Duration) -> &mut Self {
+        self.rate_limit = Some(RateLimit { max, interval });
+        self
+    }
+
+    /// Delays the retransmission of credit grants which failed to be sent.
+    pub fn set_credit_backoff(&mut self, base: Duration, max: Duration, jitter: f64) -> &mut Self {
+        self.credit_backoff = Some(Backoff { base, max, jitter });
+        self
+    }
+
+    /// Abandons credit grants which have not been acknowledged in time.
+    pub fn set_credit_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.credit_timeout = Some(timeout);
+        self
+    }
+
+    /// Abandons credit grants which repeatedly failed to be sent.
+    pub fn set_max_credit_retries(&mut self, n: u32) -> &mut Self {
+        self.max_credit_retries = Some(n);
+        self
+    }
+
+    /// Responds to inbound requests which exceed the receive budget
+    /// (default: `false`).
+    pub fn set_reject_over_budget(&mut self, reject: bool) -> &mut Self {
+        self.reject_over_budget = reject;
+        self
+    }
+
+    /// Sets the action taken when a peer exceeds its receive budget.
+    pub fn set_over_budget_action(&mut self, action: OverBudgetAction) -> &mut Self {
+        self.over_budget_action = action;
+        self
+    }
+
+    /// Checks that the settings are consistent.
+    fn validate(&self) -> Result<(), ConfigError> {
+        // Remote peers are assumed to use the same receive limit.
+        if self.initial_send_budget > self.receive_limit {
+            return Err(ConfigError::InitialSendBudgetExceedsLimit)
+        }
+        if let Some(rate) = self.rate_limit {
+            if rate.interval == Duration::from_secs(0) {
+                return Err(ConfigError::InvalidRateLimit)
+            }
+        }
+        if let Some(backoff) = self.credit_backoff {
+            if backoff.base > backoff.max || !(0.0 ..= 1.0).contains(&backoff.jitter) {
+                return Err(ConfigError::InvalidCreditBackoff)
+            }
+        }
+        if let Some(timeout) = self.credit_timeout {
+            let min = self.credit_backoff.map(|b| b.base).unwrap_or_default();
+            if timeout <= min {
+                return Err(ConfigError::CreditTimeoutTooShort)
+            }
+        }
+        if self.header_format.requires_own_prefix() && self.prefix == DEFAULT_PREFIX {
+            return Err(ConfigError::HeaderEncodingRequiresPrefix)
+        }
+        Ok(())
+    }
+}
+
+/// Possible errors of an inconsistent [`ThrottledConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The initial send budget exceeds the receive limit, so that remote
+    /// peers with the same configuration would reject requests.
+    InitialSendBudgetExceedsLimit,
+    /// The interval of the rate limit is zero.
+    InvalidRateLimit,
+    /// The base delay of the credit backoff exceeds its max. delay or
+    /// the jitter is not between 0 and 1.
+    InvalidCreditBackoff,
+    /// The credit timeout would expire credit grants before their first
+    /// retransmission.
+    CreditTimeoutTooShort,
+    /// The header format can not be told apart from the default encodings
+    /// and requires a protocol prefix other than [`DEFAULT_PREFIX`].
+    HeaderEncodingRequiresPrefix
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InitialSendBudgetExceedsLimit =>
+                f.write_str("initial send budget exceeds receive limit"),
+            ConfigError::InvalidRateLimit =>
+                f.write_str("rate limit interval is zero"),
+            ConfigError::InvalidCreditBackoff =>
+                f.write_str("invalid credit backoff"),
+            ConfigError::CreditTimeoutTooShort =>
+                f.write_str("credit timeout is shorter than the credit backoff"),
+            ConfigError::HeaderEncodingRequiresPrefix =>
+                f.write_str("header format requires a protocol prefix other than the default")
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The budgets and limit overrides of a [`Throttled`] behaviour.
+///
+/// Created by [`Throttled::export_state`] and restored with
+/// [`Throttled::import_state`], e.g. to retain budgets across restarts.
+/// With the `serde` feature enabled, the state can be serialized.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
+pub struct ThrottledState {
+    /// Budget information per peer.
+    peer_info: FnvHashMap<PeerId, PeerInfo>,
+    /// Permanent limit overrides per peer.
+    limit_overrides: FnvHashMap<PeerId, Limit>,
+    /// Credit grants which have not been acknowledged yet.
+    credit_messages: FnvHashMap<PeerId, Credit>,
+    /// The next credit ID.
     credit_id: u64
 }
 
+/// A view of the flow control state of a [`Throttled`] behaviour for
+/// diagnostics.
+///
+/// Created by [`Throttled::debug_snapshot`]. Unlike [`ThrottledState`],
+/// connected peers are recorded as they are and a snapshot can not be
+/// imported. With the `serde` feature enabled, the snapshot can be
+/// serialized, e.g. to dump it as JSON.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
+// The fields are only read by the `Debug` and `Serialize` impls.
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+pub struct ThrottledSnapshot {
+    /// Budget information per connected peer.
+    connected: FnvHashMap<PeerId, PeerInfo>,
+    /// Budget information per previously connected peer.
+    offline: FnvHashMap<PeerId, PeerInfo>,
+    /// The default receive limit.
+    default_limit: Limit,
+    /// Permanent limit overrides per peer.
+    limit_overrides: FnvHashMap<PeerId, Limit>,
+    /// Credit grants which have not been acknowledged yet.
+    credit_messages: FnvHashMap<PeerId, Credit>
+}
+
+/// The result of [`Throttled::try_send_request`].
+#[derive(Debug)]
+pub enum TrySend<T> {
+    /// The request has been sent.
+    Sent(RequestId),
+    /// The send budget of the peer is used up.
+    NoBudget(T),
+    /// The peer is not connected.
+    NotConnected(T),
+    /// Sending is paused, see [`Throttled::pause_sending`], or outbound
+    /// requests to the peer are disabled, see [`Throttled::set_protocol_support`].
+    Paused(T)
+}
+
+/// The priority of an outbound request.
+///
+/// See [`Throttled::send_request_with_priority`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sent after all queued requests of high priority.
+    Low,
+    /// Sent before all queued requests of low priority.
+    High
+}
+
+/// A snapshot of the counters of a [`Throttled`] behaviour.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThrottledStats {
+    /// Number of requests sent.
+    pub requests_sent: u64,
+    /// Number of requests which were not sent due to the send budget.
+    pub requests_throttled: u64,
+    /// Number of credit grants sent, excluding retransmissions.
+    pub credits_granted: u64,
+    /// Number of credit grants acknowledged by peers.
+    pub credits_acknowledged: u64,
+    /// Number of retransmitted credit grants, due to failed attempts,
+    /// closed connections or reconnecting peers.
+    ///
+    /// A rising rate of retransmissions indicates network trouble.
+    pub credit_retransmissions: u64,
+    /// Number of [`Event::TooManyInboundRequests`] events.
+    pub too_many_inbound_requests: u64,
+    /// Number of inbound messages of an unknown or unexpected type.
+    pub unknown_messages: u64,
+    /// Sizes of the data of inbound requests, if collected.
+    ///
+    /// See [`Throttled::set_collect_sizes`].
+    pub request_sizes: SizeHistogram,
+    /// Sizes of the data of inbound responses, if collected.
+    ///
+    /// See [`Throttled::set_collect_sizes`].
+    pub response_sizes: SizeHistogram
+}
+
+/// The number of buckets of a [`SizeHistogram`].
+const SIZE_BUCKETS: usize = 10;
+
+/// A histogram of message sizes in bytes.
+///
+/// The upper bounds of the buckets grow by a factor of 4, starting with
+/// 64 bytes. The last bucket counts all sizes above 4 MiB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: [u64; SIZE_BUCKETS]
+}
+
+impl SizeHistogram {
+    /// The upper bound of the given bucket, if any.
+    fn upper_bound(bucket: usize) -> Option<usize> {
+        if bucket + 1 < SIZE_BUCKETS {
+            Some(64 << (2 * bucket))
+        } else {
+            None
+        }
+    }
+
+    /// Count a message of the given size.
+    fn record(&mut self, size: usize) {
+        let bucket = (0 .. SIZE_BUCKETS)
+            .find(|b| matches!(Self::upper_bound(*b), Some(n) if size <= n))
+            .unwrap_or(SIZE_BUCKETS - 1);
+        self.counts[bucket] += 1
+    }
+
+    /// Get the upper bound in bytes, if any, and the number of messages
+    /// of each bucket, in ascending order.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<usize>, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(b, n)| (Self::upper_bound(b), *n))
+    }
+
+    /// Get the total number of messages counted.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// The budgets and receive limit of a peer.
+///
+/// See [`Throttled::peers`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerBudget {
+    /// Remaining number of requests (or bytes) that can be sent.
+    pub send_budget: u32,
+    /// Remaining number of requests (or bytes) that can be received.
+    pub recv_budget: u32,
+    /// The receive limit currently in effect.
+    pub limit: NonZeroU32
+}
+
+/// A credit grant which has not been acknowledged yet.
+///
+/// See [`Throttled::pending_credits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingCredit {
+    /// The ID of the credit grant.
+    pub credit_id: u64,
+    /// The number of requests (or bytes) granted.
+    pub amount: u32,
+    /// The ID of the request which carries the credit grant, unless it
+    /// has not been sent yet.
+    pub request_id: Option<RequestId>
+}
+
+/// Counters of the inbound requests of a single peer.
+///
+/// See [`Throttled::set_limit_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
+pub struct PeerStats {
+    /// Number of inbound requests which have been answered.
+    pub requests_served: u64,
+    /// Number of inbound requests which exceeded the receive budget.
+    pub over_budget: u64
+}
+
 /// Credit information that is sent to remote peers.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
 struct Credit {
     /// A credit ID. Used to deduplicate retransmitted credit messages.
     id: u64,
-    /// The ID of the outbound credit grant message.
-    request: RequestId,
-    /// The number of requests the remote is allowed to send.
-    amount: u16
+    /// The ID of the outbound credit grant message. Credit imported with
+    /// [`Throttled::import_state`] has not been sent yet.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    request: Option<RequestId>,
+    /// The number of requests (or bytes) the remote is allowed to send.
+    amount: u32,
+    /// The remaining receive budget of the remote before this grant.
+    /// Requests within this budget may have been sent before the grant
+    /// arrived and are not taken as proof of its receipt.
+    #[cfg_attr(feature = "serde", serde(default))]
+    prior: u32,
+    /// Credit granted while this grant is pending, which is sent as a
+    /// single grant once this one has reached the peer.
+    #[cfg_attr(feature = "serde", serde(default))]
+    queued: u32,
+    /// The number of failed attempts to send this credit grant.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    attempts: u32,
+    /// When to retransmit this credit grant after a failed attempt.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    retry_at: Option<Instant>,
+    /// When this credit grant has been sent first.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sent_at: Option<Instant>,
+    /// When this credit grant has been sent most recently.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_sent_at: Option<Instant>
+}
+
+impl Credit {
+    /// Record a retransmission of this credit grant.
+    fn resent(&mut self, request: RequestId, now: Instant) {
+        self.request = Some(request);
+        self.retry_at = None;
+        self.last_sent_at = Some(now)
+    }
 }
 
-/// Max. number of inbound requests that can be received.
+/// Max. number of inbound requests (or bytes) that can be received.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
 struct Limit {
     /// The current receive limit.
-    max_recv: NonZeroU16,
+    max_recv: NonZeroU32,
     /// The next receive limit which becomes active after
     /// the current limit has been reached.
-    next_max: NonZeroU16
+    next_max: NonZeroU32,
+    /// Does the limit start with a single request?
+    warm_up: bool
 }
 
 impl Limit {
     /// Create a new limit.
-    fn new(max: NonZeroU16) -> Self {
+    fn new(max: NonZeroU32) -> Self {
         // The max. limit provided will be effective after the initial request
         // from a peer which is always allowed has been answered. Values greater
         // than 1 would prevent sending the credit grant, leading to a stalling
         // sender so we must not use `max` right away.
         Limit {
-            max_recv: NonZeroU16::new(1).expect("1 > 0"),
-            next_max: max
+            max_recv: NonZeroU32::new(1).expect("1 > 0"),
+            next_max: max,
+            warm_up: true
+        }
+    }
+
+    /// Create a new limit which is effective right away.
+    ///
+    /// Unless the remote peer's initial send budget equals `max`, it will
+    /// not use up the budget and stall waiting for a credit grant, as
+    /// described in [`Limit::new`].
+    fn without_warm_up(max: NonZeroU32) -> Self {
+        Limit {
+            max_recv: max,
+            next_max: max,
+            warm_up: false
         }
     }
 
@@ -119,39 +794,169 @@ impl Limit {
     ///
     /// The new limit becomes effective when all current inbound
     /// requests have been processed and replied to.
-    fn set(&mut self, next: NonZeroU16) {
+    fn set(&mut self, next: NonZeroU32) {
         self.next_max = next
     }
 
     /// Activate the new limit.
-    fn switch(&mut self) -> u16 {
+    fn switch(&mut self) -> u32 {
         self.max_recv = self.next_max;
         self.max_recv.get()
     }
 }
 
+/// An outbound request waiting for send budget.
+#[derive(Debug)]
+struct QueuedRequest<T> {
+    priority: Priority,
+    /// The ID reserved for the request when it has been queued.
+    request_id: RequestId,
+    request: T,
+    /// Receives the request ID once the request has been sent.
+    notify: Option<oneshot::Sender<RequestId>>
+}
+
+/// Keys of inbound requests received within a sliding window.
+#[derive(Debug)]
+struct SeenRequests {
+    window: Duration,
+    keys: HashSet<(PeerId, u64)>,
+    /// The keys in the order they have been received.
+    order: VecDeque<(Instant, PeerId, u64)>
+}
+
+impl SeenRequests {
+    fn new(window: Duration) -> Self {
+        SeenRequests { window, keys: HashSet::new(), order: VecDeque::new() }
+    }
+
+    /// Record the key of a request received from a peer.
+    ///
+    /// Returns `false` if the key has been received from the
+    /// peer before within the window.
+    fn insert(&mut self, p: &PeerId, key: u64, now: Instant) -> bool {
+        while let Some((t, _, _)) = self.order.front() {
+            if now.saturating_duration_since(*t) < self.window {
+                break
+            }
+            if let Some((_, q, k)) = self.order.pop_front() {
+                self.keys.remove(&(q, k));
+            }
+        }
+        if !self.keys.insert((p.clone(), key)) {
+            return false
+        }
+        self.order.push_back((now, p.clone(), key));
+        true
+    }
+}
+
+/// Exponential backoff of credit grant retransmissions.
+#[derive(Clone, Copy, Debug)]
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: f64
+}
+
+impl Backoff {
+    /// The delay before the retransmission after the given number of
+    /// failed attempts.
+    fn delay(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        let delay = self.base.checked_mul(factor).map_or(self.max, |d| min(d, self.max));
+        let jitter = delay.mul_f64(self.jitter * rand::random::<f64>());
+        min(delay + jitter, self.max)
+    }
+}
+
+/// Max. number of requests (or bytes) that can be granted per interval.
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    max: NonZeroU32,
+    interval: Duration
+}
+
+/// The remaining credit that can be granted in the current interval.
+#[derive(Clone, Copy, Debug)]
+struct Allowance {
+    /// Remaining number of requests (or bytes).
+    remaining: u32,
+    /// Start of the current interval.
+    start: Instant
+}
+
+/// Ban peers which exceed their budget too often.
+#[derive(Clone, Copy, Debug)]
+struct AutoBan {
+    /// Number of violations within `window` which result in a ban.
+    violations: NonZeroU32,
+    window: Duration,
+    /// How long the peer is banned.
+    duration: Duration
+}
+
 /// Budget information about a peer.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
 struct PeerInfo {
     /// Limit that applies to this peer.
     limit: Limit,
-    /// Remaining number of outbound requests that can be sent.
-    send_budget: u16,
-    /// Remaining number of inbound requests that can be received.
-    recv_budget: u16,
+    /// Remaining number of outbound requests (or bytes) that can be sent.
+    send_budget: u32,
+    /// Remaining number of inbound requests (or bytes) that can be received.
+    recv_budget: u32,
     /// The ID of the credit message that granted the current `send_budget`.
-    send_budget_id: Option<u64>
+    send_budget_id: Option<u64>,
+    /// Rate limit allowance, if rate limiting is enabled.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    allowance: Option<Allowance>,
+    /// Is a credit grant waiting for the rate limit allowance to refill?
+    credit_deferred: bool,
+    /// Has the peer been reported for exceeding its budget since its
+    /// last request within the budget?
+    #[cfg_attr(feature = "serde", serde(skip))]
+    over_budget_reported: bool,
+    /// When a request has last been sent to or received from the peer.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_activity: Option<Instant>,
+    /// Counters of the peer's inbound requests.
+    stats: PeerStats,
+    /// Remaining receive budgets of protocols with their own limit, see
+    /// [`Throttled::set_receive_limit_for_protocol`]. Protocols without
+    /// an entry have their full limit left.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    protocol_budgets: FnvHashMap<Bytes, u32>
 }
 
 impl PeerInfo {
-    fn new(limit: Limit) -> Self {
+    fn new(limit: Limit, send_budget: u32) -> Self {
         PeerInfo {
             limit,
-            send_budget: 1,
-            recv_budget: 1,
-            send_budget_id: None
+            send_budget,
+            recv_budget: limit.max_recv.get(),
+            send_budget_id: None,
+            allowance: None,
+            credit_deferred: false,
+            over_budget_reported: false,
+            last_activity: None,
+            stats: PeerStats::default(),
+            protocol_budgets: FnvHashMap::default()
         }
     }
+
+    /// Reset the budgets after the peer has disconnected.
+    fn disconnected(&mut self, send_budget: u32) {
+        self.send_budget = send_budget;
+        self.protocol_budgets.clear();
+        self.recv_budget =
+            if self.limit.warm_up {
+                max(1, self.recv_budget)
+            } else {
+                self.limit.switch()
+            }
+    }
 }
 
 impl<C> Throttled<C>
@@ -160,404 +965,4768 @@ where
     C::Protocol: Sync
 {
     /// Create a new throttled request-response behaviour.
+    ///
+    /// The protocol names are prefixed with [`DEFAULT_PREFIX`]. Each protocol
+    /// is negotiated under its own prefixed name, but all of them share the
+    /// per-peer budgets of this behaviour.
     pub fn new<I>(c: C, protos: I, cfg: RequestResponseConfig) -> Self
     where
         I: IntoIterator<Item = (C::Protocol, ProtocolSupport)>,
         C: Send,
         C::Protocol: Sync
     {
-        let protos = protos.into_iter().map(|(p, ps)| (ProtocolWrapper::new(b"/t/1", p), ps));
+        Throttled::with_prefix(DEFAULT_PREFIX, c, protos, cfg)
+    }
+
+    /// Create a new throttled request-response behaviour whose protocol
+    /// names are prefixed with the given prefix instead of [`DEFAULT_PREFIX`].
+    ///
+    /// Both ends must use the same prefix to be able to communicate.
+    pub fn with_prefix<I>(prefix: &'static [u8], c: C, protos: I, cfg: RequestResponseConfig) -> Self
+    where
+        I: IntoIterator<Item = (C::Protocol, ProtocolSupport)>,
+        C: Send,
+        C::Protocol: Sync
+    {
+        let protos = protos.into_iter().map(|(p, ps)| (ProtocolWrapper::new(prefix, p), ps));
         Throttled::from(RequestResponse::new(Codec::new(c, 8192), protos, cfg))
     }
 
+    /// Create a new throttled request-response behaviour with all settings
+    /// taken from the given configuration.
+    ///
+    /// Returns an error if the configuration is inconsistent.
+    pub fn with_config<I>(c: C, protos: I, config: ThrottledConfig) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = (C::Protocol, ProtocolSupport)>,
+        C: Send,
+        C::Protocol: Sync
+    {
+        config.validate()?;
+        let mut t = Throttled::with_prefix(config.prefix, c, protos, config.request_response);
+        t.set_receive_limit(config.receive_limit);
+        t.set_initial_send_budget(config.initial_send_budget);
+        t.set_offline_cache_capacity(config.offline_cache_capacity);
+        t.set_max_header_size(config.max_header_size);
+        if let Some(size) = config.max_message_size {
+            t.set_max_message_size(size)
+        }
+        t.set_header_format(config.header_format)?;
+        #[cfg(feature = "compression")]
+        t.set_compression(config.compression);
+        if let Some(rate) = config.rate_limit {
+            t.set_rate_limit(rate.max, rate.interval)
+        }
+        if let Some(backoff) = config.credit_backoff {
+            t.set_credit_backoff(backoff.base, backoff.max, backoff.jitter)
+        }
+        if let Some(timeout) = config.credit_timeout {
+            t.set_credit_timeout(timeout)
+        }
+        if let Some(n) = config.max_credit_retries {
+            t.set_max_credit_retries(n)
+        }
+        t.set_reject_over_budget(config.reject_over_budget);
+        t.set_over_budget_action(config.over_budget_action);
+        Ok(t)
+    }
+
     /// Wrap an existing `RequestResponse` behaviour and apply send/recv limits.
     pub fn from(behaviour: RequestResponse<Codec<C>>) -> Self {
         Throttled {
             id: rand::random(),
             behaviour,
-            peer_info: HashMap::new(),
+            peer_info: FnvHashMap::default(),
             offline_peer_info: LruCache::new(8192),
-            default_limit: Limit::new(NonZeroU16::new(1).expect("1 > 0")),
-            limit_overrides: HashMap::new(),
+            default_limit: Limit::new(NonZeroU32::new(1).expect("1 > 0")),
+            limit_overrides: FnvHashMap::default(),
+            initial_send_budget: 1,
+            initial_send_budget_overrides: FnvHashMap::default(),
             events: VecDeque::new(),
-            credit_messages: HashMap::new(),
-            credit_id: 0
+            events_shrink_threshold: super::EMPTY_QUEUE_SHRINK_THRESHOLD,
+            credit_messages: FnvHashMap::default(),
+            credit_id: initial_credit_id(),
+            last_credit_id: None,
+            request_size: None,
+            rate_limit: None,
+            clock: Box::new(SystemClock),
+            limit_policy: None,
+            rate_timer: None,
+            credit_backoff: None,
+            credit_timeout: None,
+            max_credit_retries: None,
+            unthrottled_threshold: None,
+            credit_timer: None,
+            stats: ThrottledStats::default(),
+            #[cfg(feature = "metrics")]
+            metrics: metrics::Metrics::new(),
+            collect_sizes: false,
+            pending_requests: FnvHashMap::default(),
+            unsent_costs: FnvHashMap::default(),
+            paused: false,
+            reject_over_budget: false,
+            over_budget_action: OverBudgetAction::Ignore,
+            banned: FnvHashMap::default(),
+            protocol_support: FnvHashMap::default(),
+            protocol_limits: FnvHashMap::default(),
+            auto_ban: None,
+            violations: FnvHashMap::default(),
+            max_pending_credits: None,
+            credit_cap_deferred: false,
+            dedup_key: None,
+            seen_requests: None,
+            inbound_requests: FnvHashMap::default(),
+            draining: false,
+            drained: false,
+            report_unknown_messages: false
         }
     }
 
-    /// Set the global default receive limit per peer.
-    pub fn set_receive_limit(&mut self, limit: NonZeroU16) {
-        log::trace!("{:08x}: new default limit: {:?}", self.id, limit);
-        self.default_limit = Limit::new(limit)
+    /// Set the max. number of previously connected peers whose budget
+    /// information is remembered (default: 8192).
+    ///
+    /// If the new capacity is smaller than the current number of entries,
+    /// the least recently used entries are discarded. A capacity of 0
+    /// disables the cache, so that peers start afresh whenever they
+    /// reconnect.
+    pub fn set_offline_cache_capacity(&mut self, cap: usize) {
+        log::trace!("{:08x}: new offline peer cache capacity: {}", self.id, cap);
+        self.offline_peer_info.resize(cap)
     }
 
-    /// Override the receive limit of a single peer.
-    pub fn override_receive_limit(&mut self, p: &PeerId, limit: NonZeroU16) {
-        log::debug!("{:08x}: override limit for {}: {:?}", self.id, p, limit);
-        if let Some(info) = self.peer_info.get_mut(p) {
-            info.limit.set(limit)
-        } else if let Some(info) = self.offline_peer_info.get_mut(p) {
-            info.limit.set(limit)
-        }
-        self.limit_overrides.insert(p.clone(), Limit::new(limit));
+    /// Remove the budget information of all previously connected peers.
+    ///
+    /// Imported credit grants to these peers are forgotten as well.
+    pub fn clear_offline_cache(&mut self) {
+        log::debug!("{:08x}: clearing {} offline peers", self.id, self.offline_peer_info.len());
+        self.offline_peer_info.clear();
+        let connected = &self.peer_info;
+        self.credit_messages.retain(|p, _| connected.contains_key(p))
     }
 
-    /// Remove any limit overrides for the given peer.
-    pub fn remove_override(&mut self, p: &PeerId) {
-        log::trace!("{:08x}: removing limit override for {}", self.id, p);
-        self.limit_overrides.remove(p);
+    /// Get the number of previously connected peers whose budget
+    /// information is remembered.
+    pub fn offline_cache_len(&self) -> usize {
+        self.offline_peer_info.len()
     }
 
-    /// Has the limit of outbound requests been reached for the given peer?
-    pub fn can_send(&mut self, p: &PeerId) -> bool {
-        self.peer_info.get(p).map(|i| i.send_budget > 0).unwrap_or(true)
+    /// Set the id which prefixes the log output of this instance
+    /// (default: random).
+    ///
+    /// Pinning the id makes log output reproducible across runs.
+    pub fn set_log_id(&mut self, id: u32) {
+        self.id = id
     }
 
-    /// Send a request to a peer.
+    /// Set the capacity above which the queue of events is shrunk once it
+    /// has been emptied (default: 100).
     ///
-    /// If the limit of outbound requests has been reached, the request is
-    /// returned. Sending more outbound requests should only be attempted
-    /// once [`Event::ResumeSending`] has been received from [`NetworkBehaviour::poll`].
-    pub fn send_request(&mut self, p: &PeerId, req: C::Request) -> Result<RequestId, C::Request> {
-        let info =
-            if let Some(info) = self.peer_info.get_mut(p) {
-                info
-            } else if let Some(info) = self.offline_peer_info.pop(p) {
-                if info.recv_budget > 1 {
-                    self.send_credit(p, info.recv_budget - 1)
-                }
-                self.peer_info.entry(p.clone()).or_insert(info)
-            } else {
-                let limit = self.limit_overrides.get(p).copied().unwrap_or(self.default_limit);
-                self.peer_info.entry(p.clone()).or_insert(PeerInfo::new(limit))
-            };
-
-        if info.send_budget == 0 {
-            log::trace!("{:08x}: no more budget to send another request to {}", self.id, p);
-            return Err(req)
-        }
-
-        info.send_budget -= 1;
+    /// A larger threshold avoids repeated reallocations if events occur
+    /// in bursts, at the cost of retaining more memory in between.
+    pub fn set_events_shrink_threshold(&mut self, n: usize) {
+        log::trace!("{:08x}: new events shrink threshold: {}", self.id, n);
+        self.events_shrink_threshold = n
+    }
 
-        let rid = self.behaviour.send_request(p, Message::request(req));
+    /// Set the max. size in bytes of the header in front of each message
+    /// (default: 8192).
+    ///
+    /// Messages whose header exceeds this size are rejected with an error.
+    /// The new size applies to connections established afterwards.
+    pub fn set_max_header_size(&mut self, size: usize) {
+        log::trace!("{:08x}: new max. header size: {}", self.id, size);
+        let size = u32::try_from(size).unwrap_or(u32::MAX);
+        self.behaviour.codec.set_max_header_len(size)
+    }
 
-        log::trace! { "{:08x}: sending request {} to {} (send budget = {})",
-            self.id,
-            rid,
-            p,
-            info.send_budget + 1
-        };
+    /// Set the max. size in bytes of the payload of each message read
+    /// (default: unlimited).
+    ///
+    /// Larger requests are rejected with [`InboundFailure::MessageTooLarge`]
+    /// and larger responses with
+    /// [`OutboundFailure::MessageTooLarge`](crate::OutboundFailure::MessageTooLarge). The
+    /// connection remains open. An oversized request still uses up the
+    /// budget of the remote peer. The new size applies to connections
+    /// established afterwards.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        log::trace!("{:08x}: new max. message size: {}", self.id, size);
+        self.behaviour.codec.set_max_message_len(size)
+    }
 
-        Ok(rid)
+    /// Set the encoding of the header in front of each message sent
+    /// (default: [`HeaderEncoding::Cbor`]).
+    ///
+    /// Headers are read in either encoding, but peers of versions without
+    /// support for [`HeaderEncoding::Compact`] can not read compact headers.
+    /// The new encoding applies to connections established afterwards.
+    pub fn set_header_encoding(&mut self, e: HeaderEncoding) {
+        log::trace!("{:08x}: new header encoding: {:?}", self.id, e);
+        self.behaviour.codec.set_header_encoding(e)
     }
 
-    /// Answer an inbound request with a response.
+    /// Set the format of the header in front of each message, which is
+    /// used to read headers as well.
     ///
-    /// See [`RequestResponse::send_response`] for details.
-    pub fn send_response(&mut self, ch: ResponseChannel<Message<C::Response>>, res: C::Response) {
-        log::trace!("{:08x}: sending response {} to peer {}", self.id, ch.request_id(), &ch.peer);
-        if let Some(info) = self.peer_info.get_mut(&ch.peer) {
-            if info.recv_budget == 0 { // need to send more credit to the remote peer
-                let crd = info.limit.switch();
-                info.recv_budget = info.limit.max_recv.get();
-                self.send_credit(&ch.peer, crd)
+    /// Returns [`ConfigError::HeaderEncodingRequiresPrefix`] if the format
+    /// [requires its own prefix](HeaderFormat::requires_own_prefix) and any
+    /// protocol of this behaviour uses [`DEFAULT_PREFIX`]. The new format
+    /// applies to connections established afterwards.
+    pub fn set_header_format(&mut self, f: Arc<dyn HeaderFormat>) -> Result<(), ConfigError> {
+        if f.requires_own_prefix() {
+            let b = &self.behaviour;
+            if b.inbound_protocols.iter().chain(b.outbound_protocols.iter()).any(|p| p.prefix() == DEFAULT_PREFIX) {
+                return Err(ConfigError::HeaderEncodingRequiresPrefix)
             }
         }
-        self.behaviour.send_response(ch, Message::response(res))
+        log::trace!("{:08x}: new header format: {:?}", self.id, f);
+        self.behaviour.codec.set_header_format(f);
+        Ok(())
     }
 
-    /// Add a known peer address.
+    /// Set the compression of the data of each message sent (default: none).
     ///
-    /// See [`RequestResponse::add_address`] for details.
-    pub fn add_address(&mut self, p: &PeerId, a: Multiaddr) {
-        self.behaviour.add_address(p, a)
+    /// Data is only sent compressed if that makes it smaller. Compressed
+    /// messages are read regardless of this setting, but peers without the
+    /// `compression` feature reject them. Budgets and the sizes recorded in
+    /// [`ThrottledStats`] are based on the uncompressed data. The new
+    /// compression applies to connections established afterwards.
+    ///
+    /// The data of compressed messages is buffered in full when writing and
+    /// reading, so inner codecs which stream large messages should not be
+    /// combined with compression. Set a max. message size with
+    /// [`Throttled::set_max_message_size`] to bound these buffers.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, c: Option<Compression>) {
+        log::trace!("{:08x}: new compression: {:?}", self.id, c);
+        self.behaviour.codec.set_compression(c)
     }
 
-    /// Remove a previously added peer address.
-    ///
-    /// See [`RequestResponse::remove_address`] for details.
-    pub fn remove_address(&mut self, p: &PeerId, a: &Multiaddr) {
-        self.behaviour.remove_address(p, a)
+    /// Set the global default receive limit per peer.
+    pub fn set_receive_limit(&mut self, limit: NonZeroU16) {
+        log::trace!("{:08x}: new default limit: {:?}", self.id, limit);
+        self.default_limit = Limit::new(limit.into())
     }
 
-    /// Are we connected to the given peer?
+    /// Set the receive limit per peer of a single protocol.
     ///
-    /// See [`RequestResponse::is_connected`] for details.
-    pub fn is_connected(&self, p: &PeerId) -> bool {
-        self.behaviour.is_connected(p)
+    /// Within the budget granted to a peer, requests received with the
+    /// given protocol use up at most `limit` (bytes with byte budgets).
+    /// Further requests of the protocol are rejected until the peer is
+    /// granted more credit, as the sender can not know which protocol is
+    /// negotiated for a request. Rejected requests still use up the peer's
+    /// receive budget. The limit applies in addition to the limit per peer.
+    pub fn set_receive_limit_for_protocol(&mut self, proto: &C::Protocol, limit: NonZeroU16) {
+        log::trace!("{:08x}: new limit of {}: {:?}", self.id, String::from_utf8_lossy(proto.protocol_name()), limit);
+        let name = Bytes::copy_from_slice(proto.protocol_name());
+        self.protocol_limits.insert(name, u32::from(limit.get()));
     }
 
-    /// Are we waiting for a response to the given request?
+    /// Get the global default receive limit per peer.
     ///
-    /// See [`RequestResponse::is_pending_outbound`] for details.
-    pub fn is_pending_outbound(&self, p: &RequestId) -> bool {
-        self.behaviour.is_pending_outbound(p)
+    /// With byte budgets, see [`Throttled::use_byte_budget`], this is the
+    /// limit in bytes.
+    pub fn receive_limit(&self) -> NonZeroU32 {
+        self.default_limit.next_max
     }
 
-    /// Send a credit grant to the given peer.
-    fn send_credit(&mut self, p: &PeerId, amount: u16) {
-        let cid = self.next_credit_id();
-        let rid = self.behaviour.send_request(p, Message::credit(amount, cid));
-        log::trace!("{:08x}: sending {} as credit {} to {}", self.id, amount, cid, p);
-        let credit = Credit { id: cid, request: rid, amount };
-        self.credit_messages.insert(p.clone(), credit);
+    /// Measure budgets in bytes instead of number of requests.
+    ///
+    /// The given limit becomes the default receive limit in bytes of
+    /// encoded request data and credit grants are sent in bytes. The size
+    /// of an outbound request is determined with `request_size`, which
+    /// should match the number of bytes the codec writes for the request.
+    ///
+    /// A request may be sent (and is accepted) as long as the remaining
+    /// budget is not zero, i.e. the last request within a budget may
+    /// exceed it. Limits set with [`Throttled::set_receive_limit`] and
+    /// [`Throttled::override_receive_limit`] are interpreted as bytes.
+    /// Both peers must use the same mode.
+    pub fn use_byte_budget<F>(&mut self, limit: NonZeroU32, request_size: F)
+    where
+        F: Fn(&C::Request) -> u32 + Send + 'static
+    {
+        log::trace!("{:08x}: new default byte limit: {:?}", self.id, limit);
+        self.default_limit = Limit::new(limit);
+        self.request_size = Some(Box::new(request_size))
     }
 
-    /// Create a new credit message ID.
-    fn next_credit_id(&mut self) -> u64 {
-        let n = self.credit_id;
-        self.credit_id += 1;
-        n
+    /// Drop inbound requests which have already been received from the
+    /// same peer within `window`.
+    ///
+    /// Requests are considered equal if `key` returns the same value for
+    /// them. Dropped duplicates use up receive budget like any other
+    /// request, so that credit keeps flowing to the peer.
+    pub fn set_request_dedup<F>(&mut self, window: Duration, key: F)
+    where
+        F: Fn(&C::Request) -> u64 + Send + 'static
+    {
+        log::trace!("{:08x}: dropping duplicate requests within {:?}", self.id, window);
+        self.dedup_key = Some(Box::new(key));
+        self.seen_requests = Some(SeenRequests::new(window))
     }
-}
 
-/// A Wrapper around [`RequestResponseEvent`].
-#[derive(Debug)]
-pub enum Event<Req, Res, CRes = Res> {
-    /// A regular request-response event.
-    Event(RequestResponseEvent<Req, Res, CRes>),
-    /// We received more inbound requests than allowed.
-    TooManyInboundRequests(PeerId),
-    /// When previously reaching the send limit of a peer,
-    /// this event is eventually emitted when sending is
-    /// allowed to resume.
-    ResumeSending(PeerId)
-}
+    /// Limit the credit granted to each peer to `max` requests (or bytes)
+    /// per `interval`.
+    ///
+    /// Once a peer's allowance for the current interval is used up, credit
+    /// grants are deferred until the next interval starts, which in turn
+    /// defers further requests of the peer.
+    pub fn set_rate_limit(&mut self, max: NonZeroU32, interval: Duration) {
+        log::trace!("{:08x}: new rate limit: {:?} per {:?}", self.id, max, interval);
+        self.rate_limit = Some(RateLimit { max, interval })
+    }
 
-impl<C> NetworkBehaviour for Throttled<C>
-where
-    C: RequestResponseCodec + Send + Clone + 'static,
-    C::Protocol: Sync
-{
-    type ProtocolsHandler = RequestResponseHandler<Codec<C>>;
-    type OutEvent = Event<C::Request, C::Response, Message<C::Response>>;
+    /// Delay the retransmission of a credit grant which failed to be sent.
+    ///
+    /// After the n-th failed attempt, the retransmission is delayed by
+    /// `base * 2^(n - 1)`, plus a random fraction of up to `jitter` (between
+    /// 0 and 1) of this delay, but at most by `max`. By default, credit
+    /// grants are retransmitted immediately.
+    ///
+    /// Panics if `jitter` is not between 0 and 1.
+    pub fn set_credit_backoff(&mut self, base: Duration, max: Duration, jitter: f64) {
+        log::trace!("{:08x}: new credit backoff: {:?} .. {:?}", self.id, base, max);
+        assert!((0.0 ..= 1.0).contains(&jitter), "jitter must be between 0 and 1");
+        self.credit_backoff = Some(Backoff { base, max, jitter })
+    }
 
-    fn new_handler(&mut self) -> Self::ProtocolsHandler {
-        self.behaviour.new_handler()
+    /// Abandon credit grants which have not been acknowledged within the
+    /// given duration and emit [`Event::CreditTimeout`] for each of them.
+    ///
+    /// By default, credit grants are retransmitted until acknowledged.
+    pub fn set_credit_timeout(&mut self, timeout: Duration) {
+        log::trace!("{:08x}: new credit timeout: {:?}", self.id, timeout);
+        self.credit_timeout = Some(timeout)
     }
 
-    fn addresses_of_peer(&mut self, p: &PeerId) -> Vec<Multiaddr> {
-        self.behaviour.addresses_of_peer(p)
+    /// Abandon credit grants which failed to be sent more than `n` times
+    /// in a row and emit [`Event::CreditFailed`] for each of them.
+    ///
+    /// By default, credit grants are retransmitted until acknowledged.
+    pub fn set_max_credit_retries(&mut self, n: u32) {
+        log::trace!("{:08x}: new max. credit retries: {}", self.id, n);
+        self.max_credit_retries = Some(n)
     }
 
-    fn inject_connection_established(&mut self, p: &PeerId, id: &ConnectionId, end: &ConnectedPoint) {
-        self.behaviour.inject_connection_established(p, id, end)
+    /// Skip the credit bookkeeping for inbound requests of peers whose
+    /// receive budget remains above `n`, e.g. trusted peers with a large
+    /// receive limit.
+    ///
+    /// Budgets are still counted, so that accounting resumes once a peer's
+    /// budget drops to `n`. While above it, a pending credit grant to the
+    /// peer is only considered delivered once it has been acknowledged.
+    pub fn set_unthrottled_threshold(&mut self, n: u32) {
+        log::trace!("{:08x}: new unthrottled threshold: {}", self.id, n);
+        self.unthrottled_threshold = Some(n)
     }
 
-    fn inject_connection_closed(&mut self, peer: &PeerId, id: &ConnectionId, end: &ConnectedPoint) {
-        self.behaviour.inject_connection_closed(peer, id, end);
-        if self.is_connected(peer) {
-            if let Some(credit) = self.credit_messages.get_mut(peer) {
-                log::debug! { "{:08x}: resending credit grant {} to {} after connection closed",
-                    self.id,
-                    credit.id,
-                    peer
-                };
-                let msg = Message::credit(credit.amount, credit.id);
-                credit.request = self.behaviour.send_request(peer, msg)
+    /// Limit the number of credit grants which have not been acknowledged
+    /// yet to `max` across all peers.
+    ///
+    /// Once the limit is reached, new credit grants are handled according
+    /// to `overflow`. Credit grants which are resent when a peer reconnects
+    /// can not be deferred and always replace the oldest one.
+    pub fn set_max_pending_credits(&mut self, max: NonZeroUsize, overflow: CreditOverflow) {
+        log::trace!("{:08x}: max. pending credits: {} ({:?})", self.id, max, overflow);
+        self.max_pending_credits = Some((max, overflow))
+    }
+
+    /// Respond to inbound requests which exceed the receive budget
+    /// instead of dropping them (default: `false`).
+    ///
+    /// The sender is informed right away with [`Event::RequestThrottled`].
+    /// Remote peers which do not support this fail to decode the response
+    /// and close the connection, so this should only be enabled if all
+    /// remote peers support it. A dropped request is not answered at all
+    /// and the sender fails to read a response, without learning why.
+    pub fn set_reject_over_budget(&mut self, reject: bool) {
+        self.reject_over_budget = reject
+    }
+
+    /// Set the action taken when a peer exceeds its receive budget
+    /// (default: [`OverBudgetAction::Ignore`]).
+    ///
+    /// [`Event::TooManyInboundRequests`] is emitted regardless of the action.
+    pub fn set_over_budget_action(&mut self, action: OverBudgetAction) {
+        log::trace!("{:08x}: new over budget action: {:?}", self.id, action);
+        self.over_budget_action = action
+    }
+
+    /// Ban peers which exceed their receive budget `violations` times
+    /// within `window` for the given duration.
+    ///
+    /// While banned, inbound requests of a peer are dropped without
+    /// emitting any events. This applies in addition to the
+    /// [`OverBudgetAction`].
+    pub fn set_auto_ban(&mut self, violations: NonZeroU32, window: Duration, duration: Duration) {
+        log::trace!("{:08x}: ban after {} violations within {:?} for {:?}",
+            self.id, violations, window, duration);
+        self.auto_ban = Some(AutoBan { violations, window, duration })
+    }
+
+    /// Are inbound requests of the given peer dropped due to a ban?
+    pub fn is_banned(&self, p: &PeerId) -> bool {
+        matches!(self.banned.get(p), Some(until) if self.clock.now() < *until)
+    }
+
+    /// Restrict the requests exchanged with a single peer, e.g. to stop
+    /// accepting requests of a misbehaving peer (default: [`ProtocolSupport::Full`]).
+    ///
+    /// This applies in addition to the support the protocols have been
+    /// configured with. Without inbound support, new requests of the peer are
+    /// rejected like requests exceeding its budget, i.e. with a response if
+    /// enabled by [`Throttled::set_reject_over_budget`], and notifications are
+    /// dropped. They still use up the peer's receive budget, so credit
+    /// continues to be granted. Without outbound support, requests to the
+    /// peer are returned as if sending was paused. Requests in flight
+    /// complete normally and queued requests are sent once outbound
+    /// support is restored.
+    pub fn set_protocol_support(&mut self, p: &PeerId, support: ProtocolSupport) {
+        log::debug!("{:08x}: protocol support for {}: {:?}", self.id, p, support);
+        let resume = !self.protocol_support(p).outbound() && support.outbound();
+        if support == ProtocolSupport::Full {
+            self.protocol_support.remove(p);
+        } else {
+            self.protocol_support.insert(p.clone(), support);
+        }
+        if resume && !self.paused {
+            self.send_pending_requests(p);
+            if self.peer_info.contains_key(p) && self.send_budget(p) > 0 {
+                self.events.push_back(Event::ResumeSending(p.clone()))
             }
         }
     }
 
-    fn inject_connected(&mut self, p: &PeerId) {
-        log::trace!("{:08x}: connected to {}", self.id, p);
-        self.behaviour.inject_connected(p);
-        // The limit may have been added by `Throttled::send_request` already.
-        if !self.peer_info.contains_key(p) {
-            let info =
-                if let Some(info) = self.offline_peer_info.pop(p) {
-                    if info.recv_budget > 1 {
-                        self.send_credit(p, info.recv_budget - 1)
-                    }
-                    info
-                } else {
-                    let limit = self.limit_overrides.get(p).copied().unwrap_or(self.default_limit);
-                    PeerInfo::new(limit)
-                };
-            self.peer_info.insert(p.clone(), info);
-        }
+    /// The support for requests exchanged with the given peer, see
+    /// [`Throttled::set_protocol_support`].
+    pub fn protocol_support(&self, p: &PeerId) -> ProtocolSupport {
+        self.protocol_support.get(p).copied().unwrap_or(ProtocolSupport::Full)
     }
 
-    fn inject_disconnected(&mut self, p: &PeerId) {
-        log::trace!("{:08x}: disconnected from {}", self.id, p);
-        if let Some(mut info) = self.peer_info.remove(p) {
-            info.send_budget = 1;
-            info.recv_budget = max(1, info.recv_budget);
-            self.offline_peer_info.put(p.clone(), info);
-        }
-        self.credit_messages.remove(p);
-        self.behaviour.inject_disconnected(p)
+    /// Is sending of outbound requests to the given peer paused, either
+    /// for all peers or by its protocol support?
+    fn is_sending_paused(&self, p: &PeerId) -> bool {
+        self.paused || !self.protocol_support(p).outbound()
     }
 
-    fn inject_dial_failure(&mut self, p: &PeerId) {
-        self.behaviour.inject_dial_failure(p)
+    /// Emit [`Event::UnknownMessage`] for inbound messages of an unknown
+    /// or unexpected type (default: `false`).
+    ///
+    /// Such messages indicate that the remote peer uses an incompatible
+    /// version of the protocol. They are counted in
+    /// [`ThrottledStats::unknown_messages`] regardless of this setting.
+    pub fn set_report_unknown_messages(&mut self, report: bool) {
+        self.report_unknown_messages = report
     }
 
-    fn inject_event(&mut self, p: PeerId, i: ConnectionId, e: RequestResponseHandlerEvent<Codec<C>>) {
-        self.behaviour.inject_event(p, i, e)
+    /// Set the source of the current time used for rate limiting, credit
+    /// retransmission backoff, credit timeouts, bans and activity timestamps
+    /// (default: [`SystemClock`]).
+    ///
+    /// The timers which trigger deferred work still run in real time, but
+    /// when they fire, all deadlines are checked against this clock.
+    pub fn set_clock<K>(&mut self, clock: K)
+    where
+        K: Clock + 'static
+    {
+        self.clock = Box::new(clock)
     }
 
-    fn poll(&mut self, cx: &mut Context<'_>, params: &mut impl PollParameters)
-        -> Poll<NetworkBehaviourAction<RequestProtocol<Codec<C>>, Self::OutEvent>>
+    /// Determine the receive limit of peers based on their behaviour.
+    ///
+    /// Whenever a peer has used up its receive budget and more credit is
+    /// about to be granted, `policy` is called with the peer's counters and
+    /// returns the peer's next receive limit. This takes precedence over
+    /// [`Throttled::set_receive_limit`] and [`Throttled::override_receive_limit`].
+    pub fn set_limit_policy<F>(&mut self, policy: F)
+    where
+        F: FnMut(&PeerId, &PeerStats) -> NonZeroU16 + Send + 'static
     {
-        loop {
-            if let Some(ev) = self.events.pop_front() {
-                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(ev))
-            } else if self.events.capacity() > super::EMPTY_QUEUE_SHRINK_THRESHOLD {
-                self.events.shrink_to_fit()
-            }
+        self.limit_policy = Some(Box::new(policy))
+    }
 
-            let event = match ready!(self.behaviour.poll(cx, params)) {
-                | NetworkBehaviourAction::GenerateEvent(RequestResponseEvent::Message { peer, message }) => {
-                    let message = match message {
-                        | RequestResponseMessage::Response { request_id, response } =>
-                            match &response.header().typ {
-                                | Some(Type::Ack) => {
-                                    if let Some(id) = self.credit_messages.get(&peer).map(|c| c.id) {
-                                        if Some(id) == response.header().ident {
-                                            log::trace!("{:08x}: received ack {} from {}", self.id, id, peer);
-                                            self.credit_messages.remove(&peer);
-                                        }
-                                    }
-                                    continue
-                                }
-                                | Some(Type::Response) => {
-                                    log::trace!("{:08x}: received response {} from {}", self.id, request_id, peer);
-                                    if let Some(rs) = response.into_parts().1 {
-                                        RequestResponseMessage::Response { request_id, response: rs }
-                                    } else {
-                                        log::error! { "{:08x}: missing data for response {} from peer {}",
-                                            self.id,
-                                            request_id,
-                                            peer
-                                        }
-                                        continue
-                                    }
-                                }
-                                | ty => {
-                                    log::trace! {
-                                        "{:08x}: unknown message type: {:?} from {}; expected response or credit",
-                                        self.id,
-                                        ty,
-                                        peer
-                                    };
-                                    continue
-                                }
-                            }
-                        | RequestResponseMessage::Request { request_id, request, channel } =>
-                            match &request.header().typ {
-                                | Some(Type::Credit) => {
-                                    if let Some(info) = self.peer_info.get_mut(&peer) {
-                                        let id = if let Some(n) = request.header().ident {
-                                            n
-                                        } else {
-                                            log::warn! { "{:08x}: missing credit id in message from {}",
-                                                self.id,
-                                                peer
-                                            }
-                                            continue
-                                        };
-                                        let credit = request.header().credit.unwrap_or(0);
-                                        log::trace! { "{:08x}: received {} additional credit {} from {}",
-                                            self.id,
-                                            credit,
-                                            id,
-                                            peer
-                                        };
-                                        if info.send_budget_id < Some(id) {
-                                            if info.send_budget == 0 && credit > 0 {
-                                                log::trace!("{:08x}: sending to peer {} can resume", self.id, peer);
-                                                self.events.push_back(Event::ResumeSending(peer.clone()))
-                                            }
-                                            info.send_budget += credit;
-                                            info.send_budget_id = Some(id)
-                                        }
-                                        self.behaviour.send_response(channel, Message::ack(id))
-                                    }
-                                    continue
-                                }
-                                | Some(Type::Request) => {
-                                    if let Some(info) = self.peer_info.get_mut(&peer) {
-                                        log::trace! { "{:08x}: received request {} (recv. budget = {})",
-                                            self.id,
-                                            request_id,
-                                            info.recv_budget
-                                        };
-                                        if info.recv_budget == 0 {
-                                            log::debug!("{:08x}: peer {} exceeds its budget", self.id, peer);
-                                            self.events.push_back(Event::TooManyInboundRequests(peer.clone()));
-                                            continue
-                                        }
-                                        info.recv_budget -= 1;
-                                        // We consider a request as proof that our credit grant has
-                                        // reached the peer. Usually, an ACK has already been
-                                        // received.
-                                        self.credit_messages.remove(&peer);
-                                    }
-                                    if let Some(rq) = request.into_parts().1 {
-                                        RequestResponseMessage::Request { request_id, request: rq, channel }
-                                    } else {
-                                        log::error! { "{:08x}: missing data for request {} from peer {}",
-                                            self.id,
-                                            request_id,
-                                            peer
-                                        }
-                                        continue
-                                    }
-                                }
-                                | ty => {
-                                    log::trace! {
-                                        "{:08x}: unknown message type: {:?} from {}; expected request or ack",
-                                        self.id,
-                                        ty,
-                                        peer
-                                    };
-                                    continue
-                                }
-                            }
-                    };
-                    let event = RequestResponseEvent::Message { peer, message };
-                    NetworkBehaviourAction::GenerateEvent(Event::Event(event))
-                }
-                | NetworkBehaviourAction::GenerateEvent(RequestResponseEvent::OutboundFailure {
-                    peer,
-                    request_id,
-                    error
-                }) => {
-                    if let Some(credit) = self.credit_messages.get_mut(&peer) {
-                        if credit.request == request_id {
-                            log::debug! { "{:08x}: failed to send {} as credit {} to {}; retrying...",
-                                self.id,
-                                credit.amount,
-                                credit.id,
-                                peer
-                            };
-                            let msg = Message::credit(credit.amount, credit.id);
-                            credit.request = self.behaviour.send_request(&peer, msg)
-                        }
-                    }
-                    let event = RequestResponseEvent::OutboundFailure { peer, request_id, error };
-                    NetworkBehaviourAction::GenerateEvent(Event::Event(event))
-                }
-                | NetworkBehaviourAction::GenerateEvent(RequestResponseEvent::InboundFailure {
-                    peer,
-                    request_id,
-                    error
-                }) => {
-                    let event = RequestResponseEvent::InboundFailure { peer, request_id, error };
-                    NetworkBehaviourAction::GenerateEvent(Event::Event(event))
+    /// Override the receive limit of a single peer.
+    pub fn override_receive_limit(&mut self, p: &PeerId, limit: NonZeroU16) {
+        log::debug!("{:08x}: override limit for {}: {:?}", self.id, p, limit);
+        let limit = NonZeroU32::from(limit);
+        if let Some(info) = self.peer_info.get_mut(p) {
+            info.limit.set(limit)
+        } else if let Some(info) = self.offline_peer_info.get_mut(p) {
+            info.limit.set(limit)
+        }
+        self.limit_overrides.insert(p.clone(), Limit::new(limit));
+    }
+
+    /// Get the receive limit override of a single peer, if any.
+    pub fn receive_limit_override(&self, p: &PeerId) -> Option<NonZeroU32> {
+        self.limit_overrides.get(p).map(|l| l.next_max)
+    }
+
+    /// Override the receive limit of a single peer and apply it to the
+    /// current budget right away.
+    ///
+    /// With [`Throttled::override_receive_limit`], the new limit only
+    /// applies once the peer has used up its current budget. Here, a lower
+    /// limit also caps the remaining receive budget of the peer. Credit
+    /// already granted can not be taken back, so the peer may still send
+    /// requests beyond the new limit, which are then treated as exceeding
+    /// its budget. A higher limit is granted to a connected peer as
+    /// additional credit, unless a previous credit grant is still
+    /// outstanding, in which case it applies with the next grant.
+    pub fn force_receive_limit(&mut self, p: &PeerId, limit: NonZeroU16) {
+        self.override_receive_limit(p, limit);
+        let info =
+            if let Some(info) = self.peer_info.get_mut(p) {
+                info
+            } else {
+                if let Some(info) = self.offline_peer_info.get_mut(p) {
+                    info.recv_budget = min(info.recv_budget, u32::from(limit.get()))
                 }
-                | NetworkBehaviourAction::DialAddress { address } =>
-                    NetworkBehaviourAction::DialAddress { address },
-                | NetworkBehaviourAction::DialPeer { peer_id, condition } =>
-                    NetworkBehaviourAction::DialPeer { peer_id, condition },
-                | NetworkBehaviourAction::NotifyHandler { peer_id, handler, event } =>
-                    NetworkBehaviourAction::NotifyHandler { peer_id, handler, event },
-                | NetworkBehaviourAction::ReportObservedAddr { address } =>
-                    NetworkBehaviourAction::ReportObservedAddr { address }
+                return
             };
+        let current = info.limit.max_recv.get();
+        info.limit.max_recv = limit.into();
+        info.recv_budget = min(info.recv_budget, u32::from(limit.get()));
+        if let Some(more) = u32::from(limit.get()).checked_sub(current).and_then(|n| NonZeroU16::new(n as u16)) {
+            self.grant_credit(p, more);
+        }
+    }
 
-            return Poll::Ready(event)
+    /// Set the number of requests (or bytes) that can be sent to a peer
+    /// before receiving credit from it (default: 1).
+    ///
+    /// The send budget is reset to this value whenever a peer disconnects.
+    /// Remote peers must be prepared to accept the initial budget,
+    /// otherwise excess requests are rejected.
+    pub fn set_initial_send_budget(&mut self, budget: NonZeroU16) {
+        log::trace!("{:08x}: new initial send budget: {}", self.id, budget);
+        self.initial_send_budget = u32::from(budget.get())
+    }
+
+    /// Override the initial send budget of a single peer.
+    ///
+    /// The new budget applies the next time the peer's budget is
+    /// initialised, i.e. for new or disconnected peers.
+    pub fn override_initial_send_budget(&mut self, p: &PeerId, budget: NonZeroU16) {
+        log::debug!("{:08x}: override initial send budget for {}: {}", self.id, p, budget);
+        let budget = u32::from(budget.get());
+        if let Some(info) = self.offline_peer_info.get_mut(p) {
+            info.send_budget = budget
         }
+        self.initial_send_budget_overrides.insert(p.clone(), budget);
+    }
+
+    /// Set the send and receive budgets of a peer, e.g. before connecting
+    /// to a peer which is expected to exchange many requests with us.
+    ///
+    /// If the peer is not connected, it starts with these budgets once it
+    /// connects, and credit is granted right away for a receive budget
+    /// greater than 1. For a connected peer, the budgets are replaced
+    /// without granting credit. The send budget only affects our own
+    /// accounting: the remote peer still enforces its own limit and
+    /// rejects requests exceeding it.
+    pub fn preset_peer(&mut self, p: &PeerId, send: u16, recv: NonZeroU16) {
+        log::debug!("{:08x}: preset budgets of {}: send = {}, recv = {}", self.id, p, send, recv);
+        let (send, recv) = (u32::from(send), u32::from(recv.get()));
+        if let Some(info) = self.peer_info.get_mut(p) {
+            info.send_budget = send;
+            info.recv_budget = recv;
+            return
+        }
+        let limit = self.limit_overrides.get(p).copied().unwrap_or(self.default_limit);
+        let mut info = self.offline_peer_info.pop(p).unwrap_or_else(|| PeerInfo::new(limit, send));
+        info.send_budget = send;
+        info.recv_budget = recv;
+        self.offline_peer_info.put(p.clone(), info);
+    }
+
+    /// Override the receive limit of a trusted peer, skipping the warm-up.
+    ///
+    /// Usually, a peer may only send a single request until it has been
+    /// granted credit for the full limit. A trusted peer may instead send
+    /// up to `limit` requests right away after connecting. The peer must
+    /// use an initial send budget of `limit` for us, see
+    /// [`Throttled::override_initial_send_budget`], otherwise it stalls.
+    ///
+    /// If the peer is connected, the limit applies like with
+    /// [`Throttled::override_receive_limit`] until it reconnects.
+    /// Afterwards, the budget is reset to `limit` whenever the peer
+    /// disconnects.
+    pub fn trust_peer(&mut self, p: &PeerId, limit: NonZeroU16) {
+        log::debug!("{:08x}: trusting {} with limit {:?}", self.id, p, limit);
+        let limit = NonZeroU32::from(limit);
+        if let Some(info) = self.peer_info.get_mut(p) {
+            info.limit.set(limit);
+            info.limit.warm_up = false
+        } else if let Some(info) = self.offline_peer_info.get_mut(p) {
+            info.limit = Limit::without_warm_up(limit);
+            info.recv_budget = limit.get()
+        }
+        self.limit_overrides.insert(p.clone(), Limit::without_warm_up(limit));
+    }
+
+    /// Remove any limit and initial send budget overrides for the given peer.
+    pub fn remove_override(&mut self, p: &PeerId) {
+        log::trace!("{:08x}: removing limit override for {}", self.id, p);
+        self.limit_overrides.remove(p);
+        self.initial_send_budget_overrides.remove(p);
+    }
+
+    /// The send budget of the given peer before receiving credit from it.
+    fn initial_send_budget(&self, p: &PeerId) -> u32 {
+        self.initial_send_budget_overrides.get(p).copied().unwrap_or(self.initial_send_budget)
+    }
+
+    /// Reset the send and receive budgets of the given peer to their
+    /// initial values, using the current limit override or default limit.
+    ///
+    /// Any credit grant in flight to the peer is forgotten. The remote peer
+    /// is not informed, so until the next credit exchange both sides may
+    /// disagree about the budgets, e.g. inbound requests may be rejected
+    /// with [`Event::TooManyInboundRequests`].
+    pub fn reset_peer(&mut self, p: &PeerId) {
+        log::debug!("{:08x}: resetting budgets of {}", self.id, p);
+        let limit = self.limit_overrides.get(p).copied().unwrap_or(self.default_limit);
+        let budget = self.initial_send_budget(p);
+        if let Some(info) = self.peer_info.get_mut(p) {
+            *info = PeerInfo::new(limit, budget)
+        } else if self.offline_peer_info.pop(p).is_some() {
+            self.offline_peer_info.put(p.clone(), PeerInfo::new(limit, budget));
+        }
+        self.credit_messages.remove(p);
+    }
+
+    /// Remove all information about the given peer, including its limit
+    /// override and any queued outbound requests.
+    ///
+    /// If the peer is still connected, it is treated like a new peer.
+    pub fn forget_peer(&mut self, p: &PeerId) {
+        log::debug!("{:08x}: forgetting {}", self.id, p);
+        self.peer_info.remove(p);
+        self.offline_peer_info.pop(p);
+        self.limit_overrides.remove(p);
+        self.initial_send_budget_overrides.remove(p);
+        self.credit_messages.remove(p);
+        self.pending_requests.remove(p);
+        self.banned.remove(p);
+        self.violations.remove(p);
+        self.protocol_support.remove(p);
+    }
+
+    /// Get the remaining number of requests that can be sent to the given peer.
+    ///
+    /// Peers we have no information about have the initial send budget,
+    /// see [`Throttled::set_initial_send_budget`].
+    pub fn send_budget(&self, p: &PeerId) -> u32 {
+        self.peer_info.get(p)
+            .or_else(|| self.offline_peer_info.peek(p))
+            .map(|i| i.send_budget)
+            .unwrap_or_else(|| self.initial_send_budget(p))
+    }
+
+    /// Get the remaining number of requests the given peer can send to us
+    /// before it needs to be granted more credit.
+    ///
+    /// Peers we have no information about have an initial budget of 1.
+    pub fn recv_budget(&self, p: &PeerId) -> u32 {
+        self.peer_info.get(p)
+            .or_else(|| self.offline_peer_info.peek(p))
+            .map(|i| i.recv_budget)
+            .unwrap_or(1)
+    }
+
+    /// Get the instant at which a request has last been sent to or
+    /// received from the given peer, as determined by the clock set with
+    /// [`Throttled::set_clock`].
+    pub fn last_activity(&self, p: &PeerId) -> Option<Instant> {
+        self.peer_info.get(p)
+            .or_else(|| self.offline_peer_info.peek(p))
+            .and_then(|i| i.last_activity)
+    }
+
+    /// Is sending to the given connected peer blocked because its send
+    /// budget is used up and we are waiting for credit?
+    pub fn is_send_blocked(&self, p: &PeerId) -> bool {
+        matches!(self.peer_info.get(p), Some(info) if info.send_budget == 0)
+    }
+
+    /// Has the given connected peer used up its receive budget, so that
+    /// further requests are rejected until it has been granted credit?
+    pub fn is_recv_throttled(&self, p: &PeerId) -> bool {
+        matches!(self.peer_info.get(p), Some(info) if info.recv_budget == 0)
+    }
+
+    /// Get the number of requests that can be sent to the given peer right
+    /// now without being rejected by [`Throttled::send_request`].
+    ///
+    /// Peers we have no information about have the initial send budget,
+    /// see [`Throttled::set_initial_send_budget`]. While sending to the
+    /// peer is paused, no requests can be sent.
+    pub fn available_send_budget(&self, p: &PeerId) -> u32 {
+        if self.is_sending_paused(p) {
+            return 0
+        }
+        self.send_budget(p)
+    }
+
+    /// Iterate over all peers with budget information, except for
+    /// disconnected peers which are only kept in the offline cache.
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, PeerBudget)> {
+        self.peer_info.iter().map(|(p, i)| {
+            let budget = PeerBudget {
+                send_budget: i.send_budget,
+                recv_budget: i.recv_budget,
+                limit: i.limit.max_recv
+            };
+            (p, budget)
+        })
+    }
+
+    /// Get the ID of the most recent credit grant, if any.
+    ///
+    /// Credit IDs increase by one with every grant and are reported by
+    /// [`Event::CreditGranted`] and [`Event::CreditRtt`], which allows
+    /// matching them with the logs of the remote peer.
+    pub fn last_credit_id(&self) -> Option<u64> {
+        self.last_credit_id
+    }
+
+    /// Iterate over all peers with a credit grant which has not been
+    /// acknowledged yet.
+    pub fn pending_credits(&self) -> impl Iterator<Item = (&PeerId, PendingCredit)> {
+        self.credit_messages.iter().map(|(p, c)| {
+            let credit = PendingCredit {
+                credit_id: c.id,
+                amount: c.amount,
+                request_id: c.request
+            };
+            (p, credit)
+        })
+    }
+
+    /// Get a snapshot of the counters of this behaviour.
+    pub fn stats(&self) -> ThrottledStats {
+        self.stats
+    }
+
+    /// Register the counters of [`ThrottledStats`] and gauges of the send
+    /// and receive budgets of all connected peers with a Prometheus registry.
+    ///
+    /// The metrics are updated while the behaviour is polled, at most once
+    /// per second. They are not prefixed, so the metrics of several
+    /// behaviours have to be registered with registries of different
+    /// prefixes, see `Registry::new_custom`.
+    #[cfg(feature = "metrics")]
+    pub fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        self.metrics.register(registry)
+    }
+
+    /// Update the Prometheus metrics, unless they have recently been updated.
+    #[cfg(feature = "metrics")]
+    fn update_metrics(&mut self) {
+        let now = self.clock.now();
+        if matches!(self.metrics.updated_at, Some(t) if now < t + metrics::UPDATE_INTERVAL) {
+            return
+        }
+        let budgets = self.peer_info.iter().map(|(p, i)| (p, i.send_budget, i.recv_budget));
+        self.metrics.update(now, &self.stats, budgets)
+    }
+
+    /// Collect the sizes of the data of inbound requests and responses in
+    /// [`ThrottledStats::request_sizes`] and [`ThrottledStats::response_sizes`]
+    /// (default: `false`).
+    pub fn set_collect_sizes(&mut self, collect: bool) {
+        self.collect_sizes = collect
+    }
+
+    /// Has the limit of outbound requests been reached for the given peer?
+    pub fn can_send(&mut self, p: &PeerId) -> bool {
+        self.available_send_budget(p) > 0
+    }
+
+    /// Pause sending of outbound requests to all peers.
+    ///
+    /// While paused, [`Throttled::send_request`] returns every request
+    /// back to the caller. Connections remain open and inbound requests
+    /// as well as credit grants continue to be processed.
+    pub fn pause_sending(&mut self) {
+        log::debug!("{:08x}: pausing outbound requests", self.id);
+        self.paused = true
+    }
+
+    /// Resume sending of outbound requests after [`Throttled::pause_sending`].
+    ///
+    /// Queued requests are sent as far as the budget allows and
+    /// [`Event::ResumeSending`] is emitted for every peer with
+    /// remaining send budget.
+    pub fn resume_sending(&mut self) {
+        if !self.paused {
+            return
+        }
+        log::debug!("{:08x}: resuming outbound requests", self.id);
+        self.paused = false;
+        let peers = self.peer_info.keys()
+            .filter(|p| self.protocol_support(p).outbound())
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in peers {
+            self.send_pending_requests(&p);
+            if self.send_budget(&p) > 0 {
+                self.events.push_back(Event::ResumeSending(p))
+            }
+        }
+    }
+
+    /// Stop accepting inbound requests, e.g. before shutting down.
+    ///
+    /// Afterwards, inbound requests are rejected, with a response if
+    /// enabled by [`Throttled::set_reject_over_budget`], and no more credit
+    /// is granted. Outbound requests and inbound requests received before
+    /// complete normally. Once none of them is left, [`Event::Drained`] is
+    /// emitted. An inbound request whose `ResponseChannel` is dropped
+    /// without a response counts as in flight until its peer disconnects.
+    pub fn begin_drain(&mut self) {
+        log::debug!("{:08x}: draining", self.id);
+        self.draining = true
+    }
+
+    /// Emit [`Event::Drained`] once no requests are in flight while draining.
+    ///
+    /// Returns `true` if the event has been emitted.
+    fn check_drained(&mut self) -> bool {
+        if !self.draining || self.drained {
+            return false
+        }
+        if self.inbound_requests.is_empty() && self.pending_requests.is_empty() && !self.behaviour.has_pending_outbound() {
+            log::debug!("{:08x}: drained", self.id);
+            self.drained = true;
+            self.events.push_back(Event::Drained);
+            return true
+        }
+        false
+    }
+
+    /// Send a request to a peer.
+    ///
+    /// If the limit of outbound requests has been reached or sending has
+    /// been paused, the request is returned. Sending more outbound requests
+    /// should only be attempted once [`Event::ResumeSending`] has been
+    /// received from [`NetworkBehaviour::poll`].
+    pub fn send_request(&mut self, p: &PeerId, req: C::Request) -> Result<RequestId, C::Request> {
+        self.send_request_inner(p, req, None, None, None, false)
+    }
+
+    /// Send a request to a peer which times out after the given duration
+    /// instead of the configured request timeout.
+    ///
+    /// Budgets apply as for [`Throttled::send_request`].
+    pub fn send_request_with_timeout(&mut self, p: &PeerId, req: C::Request, timeout: Duration)
+        -> Result<RequestId, C::Request>
+    {
+        self.send_request_inner(p, req, None, Some(timeout), None, false)
+    }
+
+    /// Send a notification to a peer, i.e. a request to which the peer
+    /// sends no response.
+    ///
+    /// Notifications use up send budget like requests, but no response or
+    /// failure is reported for them, except if dialing the peer fails.
+    /// The peer receives them as [`Event::Notification`].
+    pub fn send_notification(&mut self, p: &PeerId, req: C::Request) -> Result<RequestId, C::Request> {
+        self.send_request_inner(p, req, None, None, None, true)
+    }
+
+    /// Send a request with the given or a new ID if the budget allows.
+    ///
+    /// A request without a weight may be sent as long as the send budget is
+    /// not zero. A weighted request requires its full cost to be available.
+    /// A notification is sent instead of a request if `notification` is set.
+    fn send_request_inner(
+        &mut self,
+        p: &PeerId,
+        req: C::Request,
+        rid: Option<RequestId>,
+        timeout: Option<Duration>,
+        weight: Option<NonZeroU32>,
+        notification: bool
+    ) -> Result<RequestId, C::Request> {
+        enter_span!(span = "send_request", self.id, peer = %p, request_id = tracing::field::Empty);
+        if self.is_sending_paused(p) {
+            log::trace!("{:08x}: sending is paused; not sending request to {}", self.id, p);
+            return Err(req)
+        }
+
+        // With byte budgets, the remote peer charges at least the size
+        // of a request, whatever its weight.
+        let cost = match weight {
+            Some(w) if self.request_size.is_some() => max(w.get(), self.request_cost(&req)),
+            Some(w) => w.get(),
+            None => self.request_cost(&req)
+        };
+        let now = self.clock.now();
+        // Sending to a connected peer, the common case, takes a single lookup.
+        let info = match self.peer_info.get_mut(p) {
+            Some(info) => info,
+            None => self.peer_info_mut(p)
+        };
+
+        if info.send_budget == 0 || (weight.is_some() && info.send_budget < cost) {
+            log::trace!("{:08x}: not enough budget to send request of cost {} to {}", self.id, cost, p);
+            self.stats.requests_throttled += 1;
+            return Err(req)
+        }
+
+        let budget = info.send_budget;
+        info.send_budget = budget.saturating_sub(cost);
+        info.last_activity = Some(now);
+
+        let rid = rid.unwrap_or_else(|| self.behaviour.next_request_id());
+        record_field!(span, "request_id", rid);
+        let timeout = timeout.unwrap_or(self.behaviour.config.request_timeout);
+        let msg = match weight {
+            _ if notification => Message::notification(req),
+            Some(_) => Message::weighted_request(req, cost),
+            None => Message::request(req)
+        };
+        self.behaviour.send_request_with_id(p, msg, rid, timeout, !notification);
+        self.record_unsent(p, rid, budget - budget.saturating_sub(cost));
+        self.stats.requests_sent += 1;
+
+        log::trace! { "{:08x}: sending {} {} to {} (send budget = {})",
+            self.id,
+            if notification { "notification" } else { "request" },
+            rid,
+            p,
+            budget.saturating_sub(cost)
+        };
+
+        Ok(rid)
+    }
+
+    /// Send a request to a connected peer if the budget allows.
+    ///
+    /// Unlike [`Throttled::send_request`], no connection is established
+    /// and the result tells why a request could not be sent, so that
+    /// callers need not check [`Throttled::can_send`] beforehand.
+    pub fn try_send_request(&mut self, p: &PeerId, req: C::Request) -> TrySend<C::Request> {
+        if !self.is_connected(p) {
+            return TrySend::NotConnected(req)
+        }
+        if self.is_sending_paused(p) {
+            return TrySend::Paused(req)
+        }
+        match self.send_request(p, req) {
+            Ok(rid) => TrySend::Sent(rid),
+            Err(req) => TrySend::NoBudget(req)
+        }
+    }
+
+    /// Send a request to a peer which uses up `weight` units of the
+    /// send budget instead of the default cost of a request.
+    ///
+    /// If the remaining send budget is less than `weight`, the request is
+    /// returned. The remote peer accounts for the request with the same
+    /// weight. With byte budgets, the request uses up the larger of its
+    /// weight and its size, since the remote peer measures the size itself.
+    pub fn send_request_weighted(&mut self, p: &PeerId, req: C::Request, weight: NonZeroU32)
+        -> Result<RequestId, C::Request>
+    {
+        self.send_request_inner(p, req, None, None, Some(weight), false)
+    }
+
+    /// Send multiple requests to a peer.
+    ///
+    /// Requests are sent in order for as long as the send budget allows.
+    /// If the limit of outbound requests is reached, the IDs of the requests
+    /// sent so far are returned together with the remaining requests.
+    pub fn send_requests(&mut self, p: &PeerId, reqs: Vec<C::Request>) -> SendRequests<C::Request> {
+        enter_span!(span = "send_requests", self.id, peer = %p, count = reqs.len());
+        if self.is_sending_paused(p) {
+            log::trace!("{:08x}: sending is paused; not sending requests to {}", self.id, p);
+            return Err((Vec::new(), reqs))
+        }
+
+        let mut rids = Vec::with_capacity(reqs.len());
+        let mut reqs = reqs.into_iter();
+
+        while let Some(req) = reqs.next() {
+            match self.send_request_inner(p, req, None, None, None, false) {
+                Ok(rid) => rids.push(rid),
+                Err(req) => {
+                    let rest = std::iter::once(req).chain(reqs).collect::<Vec<_>>();
+                    log::trace!("{:08x}: no more budget to send {} requests to {}", self.id, rest.len(), p);
+                    // The first request has been counted already.
+                    self.stats.requests_throttled += rest.len() as u64 - 1;
+                    return Err((rids, rest))
+                }
+            }
+        }
+
+        Ok(rids)
+    }
+
+    /// Send a request to a peer with the given priority.
+    ///
+    /// If the limit of outbound requests has been reached, the request is
+    /// queued, reported with an [`Event::RequestQueued`] event, and `None`
+    /// is returned. Queued requests are sent in order of their priority once
+    /// more credit has been received and each of them is reported with an
+    /// [`Event::RequestSent`] event carrying the same ID. Requests of equal
+    /// priority are sent in the order they have been queued.
+    pub fn send_request_with_priority(&mut self, p: &PeerId, req: C::Request, prio: Priority)
+        -> Option<RequestId>
+    {
+        if !self.is_sending_paused(p) && !self.pending_requests.contains_key(p) && self.peer_info_mut(p).send_budget > 0 {
+            return self.send_request(p, req).ok()
+        }
+        self.enqueue_request(p, prio, req);
+        None
+    }
+
+    /// Send a request to a peer once the send budget allows.
+    ///
+    /// Unlike [`Throttled::send_request`], the request is queued if the
+    /// budget is used up, like a request of low priority passed to
+    /// [`Throttled::send_request_with_priority`]. The returned future
+    /// resolves to the ID of the request once it has been sent, which
+    /// requires the behaviour to be polled, or to an error if the request
+    /// is discarded, e.g. by [`Throttled::forget_peer`].
+    pub fn send_request_when_ready(&mut self, p: &PeerId, req: C::Request)
+        -> impl Future<Output = Result<RequestId, oneshot::Canceled>>
+    {
+        let (tx, rx) = oneshot::channel();
+        match self.send_request_with_priority(p, req, Priority::Low) {
+            Some(rid) => { let _ = tx.send(rid); }
+            None => {
+                if let Some(queued) = self.pending_requests.get_mut(p).and_then(|q| q.back_mut()) {
+                    queued.notify = Some(tx)
+                }
+            }
+        }
+        rx
+    }
+
+    /// Insert a request into the queue of the given peer, after all
+    /// requests of the same or higher priority, and emit [`Event::RequestQueued`].
+    fn enqueue_request(&mut self, p: &PeerId, priority: Priority, request: C::Request) {
+        let request_id = self.behaviour.next_request_id();
+        log::trace!("{:08x}: queueing request {} to {} with priority {:?}", self.id, request_id, p, priority);
+        let queue = self.pending_requests.entry(p.clone()).or_default();
+        let i = queue.iter().position(|q| q.priority < priority).unwrap_or(queue.len());
+        queue.insert(i, QueuedRequest { priority, request_id, request, notify: None });
+        self.events.push_back(Event::RequestQueued { peer: p.clone(), request_id })
+    }
+
+    /// Send queued requests to the given peer as far as the budget allows.
+    fn send_pending_requests(&mut self, p: &PeerId) {
+        if let Some(mut queue) = self.pending_requests.remove(p) {
+            while self.send_budget(p) > 0 {
+                let queued = if let Some(r) = queue.pop_front() { r } else { break };
+                match self.send_request_inner(p, queued.request, Some(queued.request_id), None, None, false) {
+                    Ok(request_id) => {
+                        if let Some(tx) = queued.notify {
+                            let _ = tx.send(request_id);
+                        }
+                        self.events.push_back(Event::RequestSent { peer: p.clone(), request_id })
+                    }
+                    Err(request) => {
+                        queue.push_front(QueuedRequest { request, .. queued });
+                        break
+                    }
+                }
+            }
+            if !queue.is_empty() {
+                self.pending_requests.insert(p.clone(), queue);
+            }
+        }
+    }
+
+    /// Add credit received from a peer to its send budget.
+    ///
+    /// If sending was blocked, queued requests are sent first and
+    /// [`Event::ResumeSending`] is emitted if budget remains afterwards.
+    fn receive_credit(&mut self, p: &PeerId, id: u64, credit: u32) {
+        enter_span!(span = "receive_credit", self.id, peer = %p, credit_id = id, amount = credit);
+        log::trace!("{:08x}: received {} additional credit {} from {}", self.id, credit, id, p);
+        let info = if let Some(info) = self.peer_info.get_mut(p) { info } else { return };
+        if info.send_budget_id >= Some(id) {
+            return
+        }
+        let resume = info.send_budget == 0 && credit > 0;
+        // Huge or repeated grants of a misbehaving peer must not wrap around.
+        info.send_budget = info.send_budget.saturating_add(credit);
+        info.send_budget_id = Some(id);
+        if resume && !self.is_sending_paused(p) {
+            self.send_pending_requests(p);
+            if self.send_budget(p) > 0 {
+                log::trace!("{:08x}: sending to peer {} can resume", self.id, p);
+                self.events.push_back(Event::ResumeSending(p.clone()))
+            }
+        }
+    }
+
+    /// Take a snapshot of the budgets, limits and pending credit grants
+    /// of all peers for diagnostics.
+    pub fn debug_snapshot(&self) -> ThrottledSnapshot {
+        ThrottledSnapshot {
+            connected: self.peer_info.clone(),
+            offline: self.offline_peer_info.iter().map(|(p, i)| (p.clone(), i.clone())).collect(),
+            default_limit: self.default_limit,
+            limit_overrides: self.limit_overrides.clone(),
+            credit_messages: self.credit_messages.clone()
+        }
+    }
+
+    /// Export the budgets and limit overrides of all peers.
+    ///
+    /// Connected peers are recorded as if they had been disconnected.
+    pub fn export_state(&self) -> ThrottledState {
+        let mut peer_info = self.offline_peer_info.iter()
+            .map(|(p, i)| (p.clone(), i.clone()))
+            .collect::<FnvHashMap<_, _>>();
+        for (p, i) in &self.peer_info {
+            let mut info = i.clone();
+            info.disconnected(self.initial_send_budget(p));
+            peer_info.insert(p.clone(), info);
+        }
+        ThrottledState {
+            peer_info,
+            limit_overrides: self.limit_overrides.clone(),
+            credit_messages: self.credit_messages.clone(),
+            credit_id: self.credit_id
+        }
+    }
+
+    /// Import state previously created by [`Throttled::export_state`].
+    ///
+    /// Information about currently connected peers is not replaced.
+    /// Credit grants which had not been acknowledged are sent again
+    /// once the peer is connected.
+    pub fn import_state(&mut self, state: ThrottledState) {
+        self.credit_id = max(self.credit_id, state.credit_id);
+        self.limit_overrides.extend(state.limit_overrides);
+        for (p, mut info) in state.peer_info {
+            if self.peer_info.contains_key(&p) {
+                continue
+            }
+            info.allowance = None;
+            self.offline_peer_info.put(p, info);
+        }
+        for (p, mut credit) in state.credit_messages {
+            if self.peer_info.contains_key(&p) || self.credit_messages.contains_key(&p) {
+                continue
+            }
+            credit.request = None;
+            credit.attempts = 0;
+            credit.retry_at = None;
+            credit.sent_at = None;
+            credit.last_sent_at = None;
+            self.credit_messages.insert(p, credit);
+        }
+    }
+
+    /// Get the information about the given peer, creating it if necessary.
+    ///
+    /// This is the only place where the information of a previously
+    /// connected peer is restored from the offline cache and credit is
+    /// granted for its remaining budget. Whichever of `send_request` and
+    /// `inject_connected` comes first restores the peer, the other finds
+    /// it in `peer_info` already, so credit is never granted twice.
+    fn peer_info_mut(&mut self, p: &PeerId) -> &mut PeerInfo {
+        if !self.peer_info.contains_key(p) {
+            let info =
+                if let Some(info) = self.offline_peer_info.pop(p) {
+                    if let Some(credit) = self.credit_messages.get_mut(p).filter(|c| c.request.is_none()) {
+                        // Imported credit may or may not have reached the peer
+                        // before, so we send it again with the same ID.
+                        log::debug!("{:08x}: resending imported credit grant {} to {}", self.id, credit.id, p);
+                        let msg = Message::credit(credit.amount, credit.id);
+                        let now = self.clock.now();
+                        credit.resent(self.behaviour.send_request(p, msg), now);
+                        credit.sent_at = Some(now);
+                        // The peer may use its initial send budget first.
+                        credit.prior = 1;
+                        self.stats.credit_retransmissions += 1;
+                        if self.credit_timeout.is_some() {
+                            self.schedule_credit_timer(self.clock.now())
+                        }
+                    } else if info.limit.warm_up && info.recv_budget > 1 {
+                        self.send_credit(p, info.recv_budget - 1, 1)
+                    }
+                    info
+                } else {
+                    let limit = self.limit_overrides.get(p).copied().unwrap_or(self.default_limit);
+                    PeerInfo::new(limit, self.initial_send_budget(p))
+                };
+            self.peer_info.insert(p.clone(), info);
+        }
+        self.peer_info.get_mut(p).expect("peer info is present")
+    }
+
+    /// Cancel an outbound request to the given peer.
+    ///
+    /// See [`RequestResponse::cancel_request`] for details. If the request
+    /// has not been sent yet because the peer is not connected, the send
+    /// budget it used up is reclaimed. Once sent, the remote peer accounts
+    /// for the request regardless of the cancellation, so the budget is not
+    /// reclaimed, whether or not a response is already on its way.
+    /// Returns `false` if no request with this ID to the peer is pending.
+    pub fn cancel_request(&mut self, p: &PeerId, id: RequestId) -> bool {
+        if self.behaviour.take_unsent_request(p, &id).is_some() {
+            let cost = self.unsent_costs.get_mut(p).and_then(|costs| costs.remove(&id)).unwrap_or(0);
+            if let Some(info) = self.peer_info.get_mut(p) {
+                info.send_budget = info.send_budget.saturating_add(cost);
+                log::trace! { "{:08x}: cancelled request {} to {} (send budget = {})",
+                    self.id,
+                    id,
+                    p,
+                    info.send_budget
+                };
+            }
+            return true
+        }
+        let cancelled = self.behaviour.cancel_request(p, id);
+        if cancelled {
+            log::trace!("{:08x}: cancelled request {} to {}", self.id, id, p);
+            if let Some(costs) = self.unsent_costs.get_mut(p) {
+                costs.remove(&id);
+            }
+        }
+        cancelled
+    }
+
+    /// Remember the send budget used up by an outbound request until the
+    /// request is sent, so that it can be reclaimed if the request is
+    /// cancelled before.
+    fn record_unsent(&mut self, p: &PeerId, rid: RequestId, cost: u32) {
+        if !self.behaviour.is_connected(p) {
+            self.unsent_costs.entry(p.clone()).or_default().insert(rid, cost);
+        }
+    }
+
+    /// The amount of send budget the given request uses up.
+    fn request_cost(&self, req: &C::Request) -> u32 {
+        self.request_size.as_ref().map(|f| f(req)).unwrap_or(1)
+    }
+
+    /// The number of receive budget units an inbound request uses up.
+    ///
+    /// The weight in the header is only trusted as a lower bound. With
+    /// byte budgets, a request uses up at least its measured size.
+    fn inbound_cost(&self, request: &Message<C::Request>) -> u32 {
+        let weight = request.header().weight.map_or(1, |w| w.max(1));
+        if self.request_size.is_some() {
+            let len = u32::try_from(request.data_len().unwrap_or(0)).unwrap_or(u32::MAX);
+            match request.header().weight {
+                Some(_) => weight.max(len),
+                None => len
+            }
+        } else {
+            weight
+        }
+    }
+
+    /// Answer an inbound request with a response.
+    ///
+    /// See [`RequestResponse::send_response`] for details.
+    pub fn send_response(&mut self, ch: ResponseChannel<Message<C::Response>>, res: C::Response) {
+        log::trace!("{:08x}: sending response {} to peer {}", self.id, ch.request_id(), &ch.peer);
+        self.inbound_requests.remove(&ch.request_id());
+        if let Some(info) = self.peer_info.get_mut(&ch.peer) {
+            info.stats.requests_served += 1
+        }
+        self.request_finished(&ch.peer);
+        self.behaviour.send_response(ch, Message::response(res))
+    }
+
+    /// Grant more credit to a peer whose inbound request has been answered
+    /// or has failed if its receive budget has been used up.
+    fn request_finished(&mut self, p: &PeerId) {
+        if let Some(info) = self.peer_info.get_mut(p) {
+            // Need to send more credit to the remote peer, unless the grant
+            // for the exhausted budget has been deferred already and will be
+            // sent once the rate limit or the max. pending credits allow.
+            if info.recv_budget == 0 && !info.credit_deferred && !self.draining {
+                if let Some(policy) = self.limit_policy.as_mut() {
+                    let limit = policy(p, &info.stats);
+                    log::trace!("{:08x}: limit policy sets limit of {} to {}", self.id, p, limit);
+                    info.limit.set(limit.into())
+                }
+                self.grant_next_credit(p)
+            }
+        }
+    }
+
+    /// Grant additional credit to a connected peer ahead of time, e.g. in
+    /// anticipation of a burst of requests.
+    ///
+    /// The peer's receive budget is raised by `amount`, regardless of the
+    /// rate limit, but not beyond the peer's receive limit. To grant more,
+    /// raise the limit first, e.g. with [`Throttled::force_receive_limit`].
+    /// If a previous grant is still outstanding, `amount` is sent together
+    /// with any other credit granted in the meantime once the previous
+    /// grant has reached the peer. Returns `false` without granting
+    /// anything if the peer is not connected or its receive budget
+    /// already equals its limit.
+    pub fn grant_credit(&mut self, p: &PeerId, amount: NonZeroU16) -> bool {
+        if self.must_defer_credit(p) {
+            log::debug!("{:08x}: not granting credit to {}: too many pending credits", self.id, p);
+            return false
+        }
+        if let Some(info) = self.peer_info.get_mut(p) {
+            let limit = max(info.limit.max_recv, info.limit.next_max).get();
+            let amount = min(u32::from(amount.get()), limit.saturating_sub(info.recv_budget));
+            if amount == 0 {
+                log::debug!("{:08x}: not granting credit to {}: receive limit reached", self.id, p);
+                return false
+            }
+            let prior = info.recv_budget;
+            info.recv_budget = info.recv_budget.saturating_add(amount);
+            self.send_credit(p, amount, prior);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add a known peer address.
+    ///
+    /// See [`RequestResponse::add_address`] for details.
+    pub fn add_address(&mut self, p: &PeerId, a: Multiaddr) {
+        self.behaviour.add_address(p, a)
+    }
+
+    /// Remove a previously added peer address.
+    ///
+    /// See [`RequestResponse::remove_address`] for details.
+    pub fn remove_address(&mut self, p: &PeerId, a: &Multiaddr) {
+        self.behaviour.remove_address(p, a)
+    }
+
+    /// Get a reference to the wrapped `RequestResponse` behaviour.
+    pub fn inner(&self) -> &RequestResponse<Codec<C>> {
+        &self.behaviour
+    }
+
+    /// Get a mutable reference to the wrapped `RequestResponse` behaviour.
+    ///
+    /// Requests and responses sent through the inner behaviour bypass the
+    /// budget accounting of `Throttled`, so that budgets of both peers
+    /// diverge. Use [`Throttled::send_request`] and
+    /// [`Throttled::send_response`] instead.
+    pub fn inner_mut(&mut self) -> &mut RequestResponse<Codec<C>> {
+        &mut self.behaviour
+    }
+
+    /// Are we connected to the given peer?
+    ///
+    /// See [`RequestResponse::is_connected`] for details.
+    pub fn is_connected(&self, p: &PeerId) -> bool {
+        self.behaviour.is_connected(p)
+    }
+
+    /// Are we waiting for a response to the given request?
+    ///
+    /// See [`RequestResponse::is_pending_outbound`] for details.
+    pub fn is_pending_outbound(&self, p: &RequestId) -> bool {
+        self.behaviour.is_pending_outbound(p)
+    }
+
+    /// Do we owe a response to the given inbound request?
+    ///
+    /// See [`RequestResponse::is_pending_inbound`] for details.
+    pub fn is_pending_inbound(&self, p: &RequestId) -> bool {
+        self.behaviour.is_pending_inbound(p)
+    }
+
+    /// The number of inbound requests of a peer we owe a response.
+    ///
+    /// See [`RequestResponse::pending_inbound_count`] for details.
+    pub fn pending_inbound_count(&self, p: &PeerId) -> usize {
+        self.behaviour.pending_inbound_count(p)
+    }
+
+    /// Activate the next receive limit of a peer and grant it as credit,
+    /// subject to the rate limit.
+    fn grant_next_credit(&mut self, p: &PeerId) {
+        if self.must_defer_credit(p) {
+            log::trace!("{:08x}: deferring credit grant to {} due to too many pending credits", self.id, p);
+            if let Some(info) = self.peer_info.get_mut(p) {
+                info.credit_deferred = true;
+                self.credit_cap_deferred = true
+            }
+            return
+        }
+        let now = self.clock.now();
+        let info =
+            if let Some(info) = self.peer_info.get_mut(p) {
+                info
+            } else {
+                return
+            };
+        let mut crd = info.limit.switch();
+        if let Some(rate) = self.rate_limit {
+            let allowance = info.allowance.get_or_insert(Allowance {
+                remaining: rate.max.get(),
+                start: now
+            });
+            if now >= allowance.start + rate.interval {
+                *allowance = Allowance { remaining: rate.max.get(), start: now }
+            }
+            crd = min(crd, allowance.remaining);
+            allowance.remaining -= crd;
+            if crd == 0 {
+                log::trace!("{:08x}: deferring credit grant to {} due to rate limit", self.id, p);
+                info.credit_deferred = true;
+                let next = allowance.start + rate.interval;
+                if self.rate_timer.is_none() {
+                    self.rate_timer = Some(Delay::new(next.saturating_duration_since(now)))
+                }
+                return
+            }
+        }
+        info.credit_deferred = false;
+        let prior = info.recv_budget;
+        info.recv_budget = info.recv_budget.saturating_add(crd);
+        info.protocol_budgets.clear();
+        self.send_credit(p, crd, prior)
+    }
+
+    /// Would a new credit grant to the given peer exceed the max. number
+    /// of pending credits?
+    fn credit_cap_reached(&self, p: &PeerId) -> bool {
+        if let Some((max, _)) = self.max_pending_credits {
+            !self.credit_messages.contains_key(p) && self.credit_messages.len() >= max.get()
+        } else {
+            false
+        }
+    }
+
+    /// Must a new credit grant to the given peer be deferred?
+    fn must_defer_credit(&self, p: &PeerId) -> bool {
+        matches!(self.max_pending_credits, Some((_, CreditOverflow::Defer))) && self.credit_cap_reached(p)
+    }
+
+    /// Grant credit which has been deferred due to the max. number of
+    /// pending credits once there is room for more.
+    fn grant_credit_below_cap(&mut self) {
+        if !self.credit_cap_deferred {
+            return
+        }
+        if let Some((max, _)) = self.max_pending_credits {
+            if self.credit_messages.len() >= max.get() {
+                return
+            }
+        }
+        self.credit_cap_deferred = false;
+        self.grant_deferred_credit()
+    }
+
+    /// Grant credit to peers whose credit grant has been deferred due
+    /// to the rate limit or the max. number of pending credits.
+    fn grant_deferred_credit(&mut self) {
+        let deferred = self.peer_info.iter()
+            .filter(|(_, i)| i.credit_deferred)
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+        for p in deferred {
+            self.grant_next_credit(&p)
+        }
+    }
+
+    /// Send a credit grant to the given peer, whose receive budget before
+    /// the grant is `prior`.
+    ///
+    /// Only one credit grant per peer is pending at any time. While it is,
+    /// further credit is added to the next grant.
+    fn send_credit(&mut self, p: &PeerId, amount: u32, prior: u32) {
+        enter_span!(span = "send_credit", self.id, peer = %p, credit_id = tracing::field::Empty, amount);
+        if let Some(credit) = self.credit_messages.get_mut(p) {
+            log::trace!("{:08x}: queueing {} as credit after credit {} to {}", self.id, amount, credit.id, p);
+            credit.queued = credit.queued.saturating_add(amount);
+            return
+        }
+        if self.credit_cap_reached(p) {
+            let oldest = self.credit_messages.iter()
+                .min_by_key(|(_, c)| c.id)
+                .map(|(q, _)| q.clone());
+            if let Some(q) = oldest {
+                log::debug!("{:08x}: too many pending credits; dropping credit grant to {}", self.id, q);
+                self.credit_messages.remove(&q);
+            }
+        }
+        let cid = self.next_credit_id();
+        record_field!(span, "credit_id", cid);
+        let rid = self.behaviour.send_request(p, Message::credit(amount, cid));
+        log::trace!("{:08x}: sending {} as credit {} to {}", self.id, amount, cid, p);
+        let now = self.clock.now();
+        let credit = Credit {
+            id: cid,
+            request: Some(rid),
+            amount,
+            prior,
+            queued: 0,
+            attempts: 0,
+            retry_at: None,
+            sent_at: Some(now),
+            last_sent_at: Some(now)
+        };
+        self.credit_messages.insert(p.clone(), credit);
+        if self.credit_timeout.is_some() {
+            self.schedule_credit_timer(now)
+        }
+        self.stats.credits_granted += 1;
+        self.events.push_back(Event::CreditGranted { peer: p.clone(), credit_id: cid, amount })
+    }
+
+    /// Handle an inbound request which exceeds the peer's receive budget.
+    fn exceeded_budget(&mut self, p: &PeerId) {
+        // A flood of requests is only reported once.
+        let report = self.peer_info.get_mut(p)
+            .map(|info| !std::mem::replace(&mut info.over_budget_reported, true))
+            .unwrap_or(true);
+        if report {
+            log::debug!("{:08x}: peer {} exceeds its budget", self.id, p);
+            self.events.push_back(Event::TooManyInboundRequests(p.clone()));
+            self.stats.too_many_inbound_requests += 1
+        }
+        match self.over_budget_action {
+            OverBudgetAction::Ignore => {}
+            OverBudgetAction::Disconnect =>
+                if report {
+                    self.events.push_back(Event::Disconnect(p.clone()))
+                }
+            OverBudgetAction::Ban { duration } => self.ban(p, duration)
+        }
+        if let Some(auto_ban) = self.auto_ban {
+            let now = self.clock.now();
+            let violations = self.violations.entry(p.clone()).or_default();
+            while let Some(t) = violations.front() {
+                if now.duration_since(*t) < auto_ban.window {
+                    break
+                }
+                violations.pop_front();
+            }
+            violations.push_back(now);
+            if violations.len() >= auto_ban.violations.get() as usize {
+                self.violations.remove(p);
+                self.ban(p, auto_ban.duration)
+            }
+        }
+    }
+
+    /// Drop inbound requests of the given peer for the given duration.
+    fn ban(&mut self, p: &PeerId, duration: Duration) {
+        log::debug!("{:08x}: banning {} for {:?}", self.id, p, duration);
+        let until = self.clock.now() + duration;
+        self.banned.insert(p.clone(), until);
+    }
+
+    /// Check if an inbound request is a duplicate of a request received
+    /// from the same peer before, see [`Throttled::set_request_dedup`].
+    fn is_duplicate(&mut self, p: &PeerId, req: &C::Request) -> bool {
+        let now = self.clock.now();
+        match (&self.dedup_key, &mut self.seen_requests) {
+            (Some(key), Some(seen)) => !seen.insert(p, key(req), now),
+            _ => false
+        }
+    }
+
+    /// Account for an inbound message of an unknown or unexpected type.
+    fn unknown_message(&mut self, peer: PeerId, from_request: bool) {
+        self.stats.unknown_messages += 1;
+        if self.report_unknown_messages {
+            self.events.push_back(Event::UnknownMessage { peer, from_request })
+        }
+    }
+
+    /// Check if inbound requests of the given peer are to be dropped,
+    /// removing the peer from the ban list if its ban has expired.
+    fn check_ban(&mut self, p: &PeerId) -> bool {
+        if self.is_banned(p) {
+            return true
+        }
+        self.banned.remove(p);
+        false
+    }
+
+    /// Handle the acknowledgement of a credit grant.
+    ///
+    /// Only an acknowledgement of the latest transmission of the pending
+    /// credit grant clears it. Earlier transmissions have failed or their
+    /// connection has been closed, so any other acknowledgement is stale,
+    /// even if it carries the same credit ID.
+    fn receive_ack(&mut self, p: &PeerId, request_id: RequestId, ident: Option<u64>) {
+        enter_span!(span = "receive_ack", self.id, peer = %p, request_id = %request_id, credit_id = ?ident);
+        if let Some((id, request)) = self.credit_messages.get(p).map(|c| (c.id, c.request)) {
+            if Some(id) == ident && Some(request_id) == request {
+                log::trace!("{:08x}: received ack {} from {}", self.id, id, p);
+                if let Some(credit) = self.credit_messages.remove(p) {
+                    self.stats.credits_acknowledged += 1;
+                    if let Some(sent) = credit.last_sent_at {
+                        let rtt = self.clock.now().saturating_duration_since(sent);
+                        self.events.push_back(Event::CreditRtt { peer: p.clone(), credit_id: id, rtt })
+                    }
+                    self.send_queued_credit(p, credit.queued)
+                }
+            }
+        }
+    }
+
+    /// Send the credit which has been queued while the previous credit
+    /// grant to the given peer was pending.
+    fn send_queued_credit(&mut self, p: &PeerId, queued: u32) {
+        if queued == 0 {
+            return
+        }
+        // The queued credit has been added to the receive budget already.
+        let prior = self.peer_info.get(p).map_or(0, |i| i.recv_budget.saturating_sub(queued));
+        self.send_credit(p, queued, prior)
+    }
+
+    /// Retransmit a credit grant whose request has failed, either
+    /// immediately or after a backoff delay.
+    fn retry_credit(&mut self, p: &PeerId, request_id: RequestId) {
+        let backoff = self.credit_backoff;
+        let now = self.clock.now();
+        let credit = match self.credit_messages.get_mut(p) {
+            Some(credit) if credit.request == Some(request_id) => credit,
+            _ => return
+        };
+        credit.attempts += 1;
+        if matches!(self.max_credit_retries, Some(n) if credit.attempts > n) {
+            log::debug! { "{:08x}: failed to send {} as credit {} to {}; giving up after {} attempts",
+                self.id,
+                credit.amount,
+                credit.id,
+                p,
+                credit.attempts
+            };
+            let credit_id = credit.id;
+            self.credit_messages.remove(p);
+            self.events.push_back(Event::CreditFailed { peer: p.clone(), credit_id });
+            return
+        }
+        if let Some(backoff) = backoff {
+            let delay = backoff.delay(credit.attempts);
+            log::debug! { "{:08x}: failed to send {} as credit {} to {}; retrying in {:?}...",
+                self.id,
+                credit.amount,
+                credit.id,
+                p,
+                delay
+            };
+            credit.retry_at = Some(now + delay);
+            self.schedule_credit_timer(now)
+        } else {
+            log::debug! { "{:08x}: failed to send {} as credit {} to {}; retrying...",
+                self.id,
+                credit.amount,
+                credit.id,
+                p
+            };
+            let msg = Message::credit(credit.amount, credit.id);
+            credit.resent(self.behaviour.send_request(p, msg), now);
+            self.stats.credit_retransmissions += 1
+        }
+    }
+
+    /// Retransmit all credit grants whose backoff delay has elapsed.
+    fn retry_delayed_credit(&mut self) {
+        let now = self.clock.now();
+        for (p, credit) in self.credit_messages.iter_mut() {
+            if matches!(credit.retry_at, Some(t) if t <= now) {
+                log::debug!("{:08x}: resending credit grant {} to {}", self.id, credit.id, p);
+                let msg = Message::credit(credit.amount, credit.id);
+                credit.resent(self.behaviour.send_request(p, msg), now);
+                self.stats.credit_retransmissions += 1
+            }
+        }
+        self.schedule_credit_timer(now)
+    }
+
+    /// Abandon all credit grants which have not been acknowledged
+    /// within the credit timeout.
+    fn expire_credit(&mut self) {
+        let timeout = if let Some(t) = self.credit_timeout { t } else { return };
+        let now = self.clock.now();
+        let expired = self.credit_messages.iter()
+            .filter(|(_, c)| matches!(c.sent_at, Some(t) if t + timeout <= now))
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+        for p in expired {
+            if let Some(credit) = self.credit_messages.remove(&p) {
+                log::debug!("{:08x}: credit grant {} to {} timed out", self.id, credit.id, p);
+                self.events.push_back(Event::CreditTimeout { peer: p, credit_id: credit.id })
+            }
+        }
+    }
+
+    /// Set the credit timer to the earliest pending retransmission or timeout.
+    fn schedule_credit_timer(&mut self, now: Instant) {
+        let timeout = self.credit_timeout;
+        let next = self.credit_messages.values()
+            .flat_map(|c| c.retry_at.into_iter().chain(timeout.and_then(|d| c.sent_at.map(|t| t + d))))
+            .min();
+        self.credit_timer = next.map(|t| Delay::new(t.saturating_duration_since(now)))
+    }
+
+    /// Create a new credit message ID.
+    fn next_credit_id(&mut self) -> u64 {
+        let n = self.credit_id;
+        self.credit_id += 1;
+        self.last_credit_id = Some(n);
+        n
+    }
+}
+
+/// The first credit ID of a new `Throttled` behaviour.
+///
+/// A peer ignores credit grants whose ID is not greater than the ID of
+/// the last grant it received from us, which it remembers across
+/// reconnects and hence also across restarts of our behaviour. The IDs
+/// of a new behaviour start at the number of microseconds since the Unix
+/// epoch, so that they exceed the IDs of a previous instance unless that
+/// one granted more than one credit per microsecond on average.
+fn initial_credit_id() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+/// A Wrapper around [`RequestResponseEvent`].
+#[derive(Debug)]
+pub enum Event<Req, Res, CRes = Res> {
+    /// A regular request-response event.
+    Event(RequestResponseEvent<Req, Res, CRes>),
+    /// We received more inbound requests than allowed.
+    ///
+    /// Emitted once for the first request exceeding the budget. Further
+    /// requests are only reported after the peer has sent a request within
+    /// its budget again.
+    TooManyInboundRequests(PeerId),
+    /// When previously reaching the send limit of a peer,
+    /// this event is eventually emitted when sending is
+    /// allowed to resume.
+    ResumeSending(PeerId),
+    /// We granted more credit to a peer, i.e. allowed it to send
+    /// `amount` more requests (or bytes). Retransmissions of a credit
+    /// grant are not reported.
+    CreditGranted {
+        /// The peer the credit has been granted to.
+        peer: PeerId,
+        /// The ID of the credit grant.
+        credit_id: u64,
+        /// The number of requests (or bytes) granted.
+        amount: u32
+    },
+    /// A credit grant has not been acknowledged within the timeout set
+    /// with [`Throttled::set_credit_timeout`] and is no longer retransmitted.
+    CreditTimeout {
+        /// The peer the credit has been granted to.
+        peer: PeerId,
+        /// The ID of the credit grant.
+        credit_id: u64
+    },
+    /// A credit grant failed to be sent more often than allowed with
+    /// [`Throttled::set_max_credit_retries`], or dialing the peer to send
+    /// it failed, and it is no longer retransmitted.
+    CreditFailed {
+        /// The peer the credit has been granted to.
+        peer: PeerId,
+        /// The ID of the credit grant.
+        credit_id: u64
+    },
+    /// A peer exceeded its receive budget and should be disconnected,
+    /// e.g. with `Swarm::ban_peer_id`, as configured with
+    /// [`OverBudgetAction::Disconnect`].
+    Disconnect(PeerId),
+    /// A credit grant has been acknowledged. The round-trip time is
+    /// measured from the most recent (re)transmission of the grant.
+    CreditRtt {
+        /// The peer the credit has been granted to.
+        peer: PeerId,
+        /// The ID of the credit grant.
+        credit_id: u64,
+        /// The time between sending the credit grant and receiving
+        /// the acknowledgement.
+        rtt: Duration
+    },
+    /// A request has been rejected by the remote peer because it
+    /// exceeded the remote's receive budget.
+    ///
+    /// See [`Throttled::set_reject_over_budget`].
+    RequestThrottled {
+        /// The peer the request has been sent to.
+        peer: PeerId,
+        /// The ID of the request.
+        request_id: RequestId
+    },
+    /// A response to a request has been received without data. The
+    /// request will not complete otherwise.
+    MalformedResponse {
+        /// The peer the request has been sent to.
+        peer: PeerId,
+        /// The ID of the request.
+        request_id: RequestId
+    },
+    /// A request has been queued by [`Throttled::send_request_with_priority`]
+    /// because the send budget is used up. It is not yet in flight.
+    RequestQueued {
+        /// The peer the request is going to be sent to.
+        peer: PeerId,
+        /// The ID of the request, which is also reported by
+        /// [`Event::RequestSent`] once the request is sent.
+        request_id: RequestId
+    },
+    /// A request queued by [`Throttled::send_request_with_priority`]
+    /// has been sent.
+    RequestSent {
+        /// The peer the request has been sent to.
+        peer: PeerId,
+        /// The ID of the request.
+        request_id: RequestId
+    },
+    /// A notification sent with [`Throttled::send_notification`]
+    /// has been received. No response is sent back.
+    Notification {
+        /// The peer which sent the notification.
+        peer: PeerId,
+        /// The ID of the inbound request carrying the notification.
+        request_id: RequestId,
+        /// The notification.
+        notification: Req
+    },
+    /// A message of an unknown or unexpected type has been received,
+    /// if enabled with [`Throttled::set_report_unknown_messages`].
+    UnknownMessage {
+        /// The peer which sent the message.
+        peer: PeerId,
+        /// Has the message been received as a request, as opposed
+        /// to a response?
+        from_request: bool
+    },
+    /// All requests in flight have completed after [`Throttled::begin_drain`].
+    Drained,
+    /// A previously connected peer has reconnected and its budgets
+    /// have been restored.
+    PeerResumed {
+        /// The reconnected peer.
+        peer: PeerId,
+        /// The restored send budget.
+        send_budget: u32,
+        /// The restored receive budget.
+        recv_budget: u32
+    }
+}
+
+impl<C> NetworkBehaviour for Throttled<C>
+where
+    C: RequestResponseCodec + Send + Clone + 'static,
+    C::Protocol: Sync
+{
+    type ProtocolsHandler = RequestResponseHandler<Codec<C>>;
+    type OutEvent = Event<C::Request, C::Response, Message<C::Response>>;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.behaviour.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, p: &PeerId) -> Vec<Multiaddr> {
+        self.behaviour.addresses_of_peer(p)
+    }
+
+    fn inject_connection_established(&mut self, p: &PeerId, id: &ConnectionId, end: &ConnectedPoint) {
+        self.behaviour.inject_connection_established(p, id, end)
+    }
+
+    fn inject_connection_closed(&mut self, peer: &PeerId, id: &ConnectionId, end: &ConnectedPoint) {
+        // Only a credit grant in flight over the closed connection is lost.
+        let lost = self.credit_messages.get(peer)
+            .and_then(|c| c.request)
+            .and_then(|r| self.behaviour.pending_connection(&r)) == Some(*id);
+        self.behaviour.inject_connection_closed(peer, id, end);
+        if lost && self.is_connected(peer) {
+            if let Some(credit) = self.credit_messages.get_mut(peer) {
+                log::debug! { "{:08x}: resending credit grant {} to {} after connection closed",
+                    self.id,
+                    credit.id,
+                    peer
+                };
+                let msg = Message::credit(credit.amount, credit.id);
+                credit.resent(self.behaviour.send_request(peer, msg), self.clock.now());
+                self.stats.credit_retransmissions += 1
+            }
+        }
+    }
+
+    fn inject_connected(&mut self, p: &PeerId) {
+        log::trace!("{:08x}: connected to {}", self.id, p);
+        self.behaviour.inject_connected(p);
+        self.unsent_costs.remove(p);
+        let restored = !self.peer_info.contains_key(p) && self.offline_peer_info.contains(p);
+        // The limit may have been added by `Throttled::send_request` already.
+        let info = self.peer_info_mut(p);
+        if restored {
+            let (send_budget, recv_budget) = (info.send_budget, info.recv_budget);
+            log::trace!("{:08x}: restored budgets of {}", self.id, p);
+            self.events.push_back(Event::PeerResumed { peer: p.clone(), send_budget, recv_budget })
+        }
+        self.send_pending_requests(p)
+    }
+
+    fn inject_disconnected(&mut self, p: &PeerId) {
+        log::trace!("{:08x}: disconnected from {}", self.id, p);
+        if let Some(mut info) = self.peer_info.remove(p) {
+            info.disconnected(self.initial_send_budget(p));
+            self.offline_peer_info.put(p.clone(), info);
+        }
+        self.credit_messages.remove(p);
+        self.inbound_requests.retain(|_, q| q != p);
+        self.behaviour.inject_disconnected(p)
+    }
+
+    fn inject_dial_failure(&mut self, p: &PeerId) {
+        self.behaviour.inject_dial_failure(p);
+        if self.is_connected(p) {
+            return
+        }
+        self.unsent_costs.remove(p);
+        // Credit granted when the peer has been restored by `send_request`
+        // could not be delivered. The peer is put back into the offline
+        // cache, so that the credit is granted again once it connects.
+        if let Some(credit) = self.credit_messages.remove(p) {
+            log::debug!("{:08x}: failed to dial {}; dropping credit grant {}", self.id, p, credit.id);
+            self.events.push_back(Event::CreditFailed { peer: p.clone(), credit_id: credit.id })
+        }
+        if let Some(mut info) = self.peer_info.remove(p) {
+            info.disconnected(self.initial_send_budget(p));
+            self.offline_peer_info.put(p.clone(), info);
+        }
+    }
+
+    fn inject_event(&mut self, p: PeerId, i: ConnectionId, e: RequestResponseHandlerEvent<Codec<C>>) {
+        self.behaviour.inject_event(p, i, e)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>, params: &mut impl PollParameters)
+        -> Poll<NetworkBehaviourAction<RequestResponseHandlerInEvent<Codec<C>>, Self::OutEvent>>
+    {
+        #[cfg(feature = "metrics")]
+        self.update_metrics();
+
+        loop {
+            if let Some(timer) = self.rate_timer.as_mut() {
+                if timer.poll_unpin(cx).is_ready() {
+                    self.rate_timer = None;
+                    self.grant_deferred_credit()
+                }
+            }
+
+            if let Some(timer) = self.credit_timer.as_mut() {
+                if timer.poll_unpin(cx).is_ready() {
+                    self.credit_timer = None;
+                    self.expire_credit();
+                    self.retry_delayed_credit()
+                }
+            }
+
+            self.grant_credit_below_cap();
+
+            if let Some(ev) = self.events.pop_front() {
+                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(ev))
+            } else if self.events.capacity() > self.events_shrink_threshold {
+                self.events.shrink_to_fit()
+            }
+
+            let event = match self.behaviour.poll(cx, params) {
+                Poll::Ready(event) => event,
+                Poll::Pending => {
+                    if self.check_drained() {
+                        continue
+                    }
+                    return Poll::Pending
+                }
+            };
+
+            let event = match event {
+                | NetworkBehaviourAction::GenerateEvent(RequestResponseEvent::Message { peer, message }) => {
+                    let message = match message {
+                        | RequestResponseMessage::Response { request_id, response } =>
+                            match &response.header().typ {
+                                | _ if response.header().version() > VERSION => {
+                                    log::trace! {
+                                        "{:08x}: unsupported header version {} from {}",
+                                        self.id,
+                                        response.header().version(),
+                                        peer
+                                    };
+                                    self.unknown_message(peer, false);
+                                    continue
+                                }
+                                | Some(Type::Ack) => {
+                                    self.receive_ack(&peer, request_id, response.header().ident);
+                                    continue
+                                }
+                                | Some(Type::Throttled) => {
+                                    log::debug!("{:08x}: request {} throttled by {}", self.id, request_id, peer);
+                                    self.events.push_back(Event::RequestThrottled { peer, request_id });
+                                    continue
+                                }
+                                | Some(Type::Response) => {
+                                    enter_span!(span = "receive_response", self.id, peer = %peer, request_id = %request_id);
+                                    log::trace!("{:08x}: received response {} from {}", self.id, request_id, peer);
+                                    if self.collect_sizes {
+                                        if let Some(n) = response.data_len() {
+                                            self.stats.response_sizes.record(n)
+                                        }
+                                    }
+                                    if let Some(rs) = response.into_parts().1 {
+                                        RequestResponseMessage::Response { request_id, response: rs }
+                                    } else {
+                                        log::error! { "{:08x}: missing data for response {} from peer {}",
+                                            self.id,
+                                            request_id,
+                                            peer
+                                        }
+                                        self.events.push_back(Event::MalformedResponse { peer, request_id });
+                                        continue
+                                    }
+                                }
+                                | ty => {
+                                    log::trace! {
+                                        "{:08x}: unknown message type: {:?} from {}; expected response or credit",
+                                        self.id,
+                                        ty,
+                                        peer
+                                    };
+                                    self.unknown_message(peer, false);
+                                    continue
+                                }
+                            }
+                        | RequestResponseMessage::Request { request_id, request, channel } =>
+                            match &request.header().typ {
+                                | _ if request.header().version() > VERSION => {
+                                    log::trace! {
+                                        "{:08x}: unsupported header version {} from {}",
+                                        self.id,
+                                        request.header().version(),
+                                        peer
+                                    };
+                                    self.unknown_message(peer, true);
+                                    continue
+                                }
+                                | Some(Type::Credit) => {
+                                    if self.peer_info.contains_key(&peer) {
+                                        let id = if let Some(n) = request.header().ident {
+                                            n
+                                        } else {
+                                            log::warn! { "{:08x}: missing credit id in message from {}",
+                                                self.id,
+                                                peer
+                                            }
+                                            continue
+                                        };
+                                        let credit = request.header().credit.unwrap_or(0);
+                                        self.receive_credit(&peer, id, credit);
+                                        self.behaviour.send_response(channel, Message::ack(id))
+                                    }
+                                    continue
+                                }
+                                | Some(Type::Request) | Some(Type::Notification) => {
+                                    enter_span!(span = "receive_request", self.id, peer = %peer, request_id = %request_id);
+                                    if request.is_too_large() {
+                                        log::debug!("{:08x}: request {} of {} exceeds the max. message size", self.id, request_id, peer);
+                                        // The oversized request has not been read, but the remote
+                                        // peer has used up its budget all the same. With byte budgets,
+                                        // its size is unknown, but at least the max. message size,
+                                        // so the remaining budget is used up.
+                                        // A weight claimed by the peer can not lower that.
+                                        let byte_budget = self.request_size.is_some();
+                                        if let Some(info) = self.peer_info.get_mut(&peer) {
+                                            let cost = match request.header().weight {
+                                                _ if byte_budget => info.recv_budget,
+                                                Some(weight) => weight.max(1),
+                                                None => 1
+                                            };
+                                            info.recv_budget = info.recv_budget.saturating_sub(cost)
+                                        }
+                                        drop(channel);
+                                        self.request_finished(&peer);
+                                        let error = InboundFailure::MessageTooLarge;
+                                        self.events.push_back(Event::Event(RequestResponseEvent::InboundFailure { peer, request_id, error }));
+                                        continue
+                                    }
+                                    if self.collect_sizes {
+                                        if let Some(n) = request.data_len() {
+                                            self.stats.request_sizes.record(n)
+                                        }
+                                    }
+                                    if self.check_ban(&peer) {
+                                        log::trace!("{:08x}: dropping request {} of banned peer {}", self.id, request_id, peer);
+                                        continue
+                                    }
+                                    if self.draining {
+                                        log::debug!("{:08x}: draining; rejecting request {} of {}", self.id, request_id, peer);
+                                        // No response is sent to a notification.
+                                        if self.reject_over_budget && request.header().typ == Some(Type::Request) {
+                                            self.behaviour.send_response(channel, Message::throttled())
+                                        }
+                                        continue
+                                    }
+                                    // The peer may send requests before we have tracked it.
+                                    if !self.peer_info.contains_key(&peer) {
+                                        self.peer_info_mut(&peer);
+                                    }
+                                    let now = self.clock.now();
+                                    let cost = self.inbound_cost(&request);
+                                    let mut over_protocol_limit = false;
+                                    if let Some(info) = self.peer_info.get_mut(&peer) {
+                                        log::trace! { "{:08x}: received request {} (recv. budget = {})",
+                                            self.id,
+                                            request_id,
+                                            info.recv_budget
+                                        };
+                                        info.last_activity = Some(now);
+                                        if info.recv_budget == 0 {
+                                            info.stats.over_budget += 1;
+                                            self.exceeded_budget(&peer);
+                                            if self.reject_over_budget && request.header().typ == Some(Type::Request) {
+                                                self.behaviour.send_response(channel, Message::throttled())
+                                            }
+                                            continue
+                                        }
+                                        info.recv_budget = info.recv_budget.saturating_sub(cost);
+                                        info.over_budget_reported = false;
+                                        let limits = &self.protocol_limits;
+                                        if let Some((name, limit)) = request.protocol().and_then(|p| limits.get_key_value(p)) {
+                                            let budget = info.protocol_budgets.entry(name.clone()).or_insert(*limit);
+                                            over_protocol_limit = *budget == 0;
+                                            *budget = budget.saturating_sub(cost)
+                                        }
+                                        // We consider a request beyond the budget the peer had
+                                        // before our pending credit grant as proof that the grant
+                                        // has reached the peer. Usually, an ACK has already been
+                                        // received. Requests within the prior budget may have
+                                        // crossed the grant on the wire. Peers whose budget is
+                                        // above the unthrottled threshold skip this bookkeeping.
+                                        if !matches!(self.unthrottled_threshold, Some(n) if info.recv_budget > n) {
+                                            match self.credit_messages.get_mut(&peer) {
+                                                Some(credit) if credit.prior > 0 =>
+                                                    credit.prior = credit.prior.saturating_sub(cost),
+                                                Some(_) => {
+                                                    let queued = self.credit_messages.remove(&peer).map_or(0, |c| c.queued);
+                                                    self.send_queued_credit(&peer, queued)
+                                                }
+                                                None => {}
+                                            }
+                                        }
+                                    }
+                                    if !self.protocol_support(&peer).inbound() {
+                                        log::debug!("{:08x}: inbound requests of {} disabled; rejecting request {}", self.id, peer, request_id);
+                                        // No response is sent to a notification.
+                                        if self.reject_over_budget && request.header().typ == Some(Type::Request) {
+                                            self.behaviour.send_response(channel, Message::throttled())
+                                        }
+                                        self.request_finished(&peer);
+                                        continue
+                                    }
+                                    if over_protocol_limit {
+                                        log::debug!("{:08x}: request {} of {} exceeds the limit of its protocol", self.id, request_id, peer);
+                                        if self.reject_over_budget && request.header().typ == Some(Type::Request) {
+                                            self.behaviour.send_response(channel, Message::throttled())
+                                        }
+                                        self.request_finished(&peer);
+                                        continue
+                                    }
+                                    // A duplicate has used up receive budget at the peer
+                                    // just like any other request, so it is accounted
+                                    // for, but not passed on.
+                                    if matches!(request.data(), Some(rq) if self.is_duplicate(&peer, rq)) {
+                                        log::debug!("{:08x}: dropping duplicate request {} of {}", self.id, request_id, peer);
+                                        self.request_finished(&peer);
+                                        continue
+                                    }
+                                    match request.into_parts() {
+                                        (Header { typ: Some(Type::Notification), .. }, Some(rq)) => {
+                                            // No response is sent to a notification,
+                                            // so it is finished right away.
+                                            drop(channel);
+                                            self.request_finished(&peer);
+                                            self.events.push_back(Event::Notification { peer, request_id, notification: rq });
+                                            continue
+                                        }
+                                        (_, Some(rq)) => {
+                                            self.inbound_requests.insert(request_id, peer.clone());
+                                            RequestResponseMessage::Request { request_id, request: rq, channel }
+                                        }
+                                        (_, None) => {
+                                            log::error! { "{:08x}: missing data for request {} from peer {}",
+                                                self.id,
+                                                request_id,
+                                                peer
+                                            }
+                                            continue
+                                        }
+                                    }
+                                }
+                                | ty => {
+                                    log::trace! {
+                                        "{:08x}: unknown message type: {:?} from {}; expected request or ack",
+                                        self.id,
+                                        ty,
+                                        peer
+                                    };
+                                    self.unknown_message(peer, true);
+                                    continue
+                                }
+                            }
+                    };
+                    let event = RequestResponseEvent::Message { peer, message };
+                    NetworkBehaviourAction::GenerateEvent(Event::Event(event))
+                }
+                | NetworkBehaviourAction::GenerateEvent(RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error
+                }) => {
+                    self.retry_credit(&peer, request_id);
+                    let event = RequestResponseEvent::OutboundFailure { peer, request_id, error };
+                    NetworkBehaviourAction::GenerateEvent(Event::Event(event))
+                }
+                | NetworkBehaviourAction::GenerateEvent(RequestResponseEvent::InboundFailure {
+                    peer,
+                    request_id,
+                    error
+                }) => {
+                    // The remote peer has used up its budget for the failed
+                    // request as well, so it may be waiting for credit.
+                    if self.inbound_requests.remove(&request_id).is_some() {
+                        self.request_finished(&peer)
+                    } else if matches!(error, InboundFailure::ResponseOmission) {
+                        // We dropped the channel of a credit message or of a
+                        // request which was never passed on.
+                        continue
+                    }
+                    let event = RequestResponseEvent::InboundFailure { peer, request_id, error };
+                    NetworkBehaviourAction::GenerateEvent(Event::Event(event))
+                }
+                | NetworkBehaviourAction::DialAddress { address } =>
+                    NetworkBehaviourAction::DialAddress { address },
+                | NetworkBehaviourAction::DialPeer { peer_id, condition } =>
+                    NetworkBehaviourAction::DialPeer { peer_id, condition },
+                | NetworkBehaviourAction::NotifyHandler { peer_id, handler, event } =>
+                    NetworkBehaviourAction::NotifyHandler { peer_id, handler, event },
+                | NetworkBehaviourAction::ReportObservedAddr { address } =>
+                    NetworkBehaviourAction::ReportObservedAddr { address }
+            };
+
+            return Poll::Ready(event)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use crate::RequestId;
+    use futures::{channel::oneshot, prelude::*};
+    use std::{collections::HashMap, io, sync::{Arc, Mutex}};
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    enum TestProtocol {
+        V1,
+        V2
+    }
+
+    impl ProtocolName for TestProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            match self {
+                TestProtocol::V1 => b"/test/1",
+                TestProtocol::V2 => b"/test/2"
+            }
+        }
+    }
+
+    /// A codec which is never used for actual I/O.
+    #[derive(Debug, Clone)]
+    struct TestCodec;
+
+    #[async_trait]
+    impl RequestResponseCodec for TestCodec {
+        type Protocol = TestProtocol;
+        type Request = ();
+        type Response = ();
+
+        async fn read_request<T>(&mut self, _: &TestProtocol, _: &mut T) -> io::Result<()>
+        where
+            T: AsyncRead + Unpin + Send
+        {
+            Ok(())
+        }
+
+        async fn read_response<T>(&mut self, _: &TestProtocol, _: &mut T) -> io::Result<()>
+        where
+            T: AsyncRead + Unpin + Send
+        {
+            Ok(())
+        }
+
+        async fn write_request<T>(&mut self, _: &TestProtocol, _: &mut T, _: ()) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send
+        {
+            Ok(())
+        }
+
+        async fn write_response<T>(&mut self, _: &TestProtocol, _: &mut T, _: ()) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send
+        {
+            Ok(())
+        }
+    }
+
+    fn throttled() -> Throttled<TestCodec> {
+        let protocols = std::iter::once((TestProtocol::V1, ProtocolSupport::Full));
+        Throttled::new(TestCodec, protocols, RequestResponseConfig::default())
+    }
+
+    fn connect_and_disconnect(t: &mut Throttled<TestCodec>, p: &PeerId) {
+        t.inject_connected(p);
+        t.inject_disconnected(p)
+    }
+
+    fn protocol_names(t: &mut Throttled<TestCodec>) -> Vec<Vec<u8>> {
+        use libp2p_core::UpgradeInfo;
+        use libp2p_swarm::ProtocolsHandler;
+
+        t.new_handler()
+            .listen_protocol()
+            .upgrade()
+            .protocol_info()
+            .map(|p| p.protocol_name().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn protocol_prefix() {
+        let protocols = || std::iter::once((TestProtocol::V1, ProtocolSupport::Full));
+        let cfg = RequestResponseConfig::default();
+
+        let mut t1 = throttled();
+        let mut t2 = Throttled::with_prefix(b"/t/2", TestCodec, protocols(), cfg);
+        assert_eq!(protocol_names(&mut t1), vec![b"/t/1/test/1".to_vec()]);
+        assert_eq!(protocol_names(&mut t2), vec![b"/t/2/test/1".to_vec()]);
+    }
+
+    #[test]
+    fn multiple_protocols() {
+        let protocols = vec![
+            (TestProtocol::V1, ProtocolSupport::Full),
+            (TestProtocol::V2, ProtocolSupport::Full)
+        ];
+        let mut t = Throttled::new(TestCodec, protocols, RequestResponseConfig::default());
+        assert_eq!(protocol_names(&mut t), vec![b"/t/1/test/1".to_vec(), b"/t/1/test/2".to_vec()]);
+
+        // Both protocols draw from the same per-peer send budget.
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        assert!(t.send_request(&p, ()).is_ok());
+        assert!(t.send_request(&p, ()).is_err());
+        assert_eq!(t.send_budget(&p), 0)
+    }
+
+    /// Simulate the receipt of inbound requests which use up the receive budget.
+    /// Use up the receive budget of a peer, which implies that the peer
+    /// has received any pending credit grant.
+    fn use_recv_budget(t: &mut Throttled<TestCodec>, p: &PeerId) {
+        t.peer_info.get_mut(p).unwrap().recv_budget = 0;
+        t.credit_messages.remove(p);
+    }
+
+    fn respond(t: &mut Throttled<TestCodec>, p: &PeerId) {
+        let (sender, _receiver) = oneshot::channel();
+        let channel = ResponseChannel { request_id: RequestId(0), peer: p.clone(), sender, deadline: Instant::now() };
+        t.send_response(channel, ())
+    }
+
+    /// The ID and amount of the credit grant in flight to the given peer.
+    fn credit(t: &Throttled<TestCodec>, p: &PeerId) -> Option<(u64, u32)> {
+        t.credit_messages.get(p).map(|c| (c.id, c.amount))
+    }
+
+    struct TestParams(PeerId);
+
+    impl PollParameters for TestParams {
+        type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
+        type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
+        type ExternalAddressesIter = std::iter::Empty<Multiaddr>;
+
+        fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+            std::iter::empty()
+        }
+
+        fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+            std::iter::empty()
+        }
+
+        fn external_addresses(&self) -> Self::ExternalAddressesIter {
+            std::iter::empty()
+        }
+
+        fn local_peer_id(&self) -> &PeerId {
+            &self.0
+        }
+    }
+
+    /// Simulate the receipt of an inbound message from a peer.
+    ///
+    /// Returns the receiver of the response sent back to the peer.
+    fn inject_request(t: &mut Throttled<TestCodec>, p: &PeerId, id: u64, msg: Message<()>)
+        -> oneshot::Receiver<Message<()>>
+    {
+        let (sender, receiver) = oneshot::channel();
+        let event = RequestResponseHandlerEvent::Request { request_id: RequestId(id), request: msg, sender, deadline: Instant::now() };
+        t.inject_event(p.clone(), ConnectionId::new(1), event);
+        receiver
+    }
+
+    /// Poll the behaviour until it is pending and return all events.
+    fn poll_events(t: &mut Throttled<TestCodec>) -> Vec<Event<(), (), Message<()>>> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut params = TestParams(PeerId::random());
+        let mut events = Vec::new();
+        while let Poll::Ready(action) = t.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::GenerateEvent(e) = action {
+                events.push(e)
+            }
+        }
+        events
+    }
+
+    /// The number of inbound requests among the given events.
+    fn inbound_requests(events: &[Event<(), (), Message<()>>]) -> usize {
+        events.iter()
+            .filter(|e| matches!(e, Event::Event(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { .. }, ..
+            })))
+            .count()
+    }
+
+    #[test]
+    fn duplicate_requests_dropped() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+        t.set_request_dedup(Duration::from_secs(10), |_| 7);
+        let p = PeerId::random();
+        t.trust_peer(&p, NonZeroU16::new(3).unwrap());
+        t.inject_connected(&p);
+
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        drop(inject_request(&mut t, &p, 2, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert_eq!(t.recv_budget(&p), 1);
+
+        // The same key is accepted again after the window.
+        *now.lock().unwrap() += Duration::from_secs(10);
+        drop(inject_request(&mut t, &p, 3, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert_eq!(t.recv_budget(&p), 0);
+
+        // Keys are tracked per peer.
+        let q = PeerId::random();
+        t.inject_connected(&q);
+        drop(inject_request(&mut t, &q, 4, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+    }
+
+    #[test]
+    fn duplicate_requests_grant_credit() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(2).unwrap());
+        t.set_request_dedup(Duration::from_secs(10), |_| 7);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert_eq!(granted(&mut t), vec![2]);
+        t.credit_messages.remove(&p);
+
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert!(granted(&mut t).is_empty());
+
+        // The duplicate exhausts the budget, so new credit is granted
+        // without the request reaching the application.
+        drop(inject_request(&mut t, &p, 2, Message::request(())));
+        let events = poll_events(&mut t);
+        assert_eq!(inbound_requests(&events), 0);
+        assert!(events.iter().any(|e| matches!(e, Event::CreditGranted { amount: 2, .. })))
+    }
+
+    /// A request read from the network with the given protocol.
+    fn received_request(proto: TestProtocol) -> Message<()> {
+        let proto = ProtocolWrapper::new(DEFAULT_PREFIX, proto);
+        let mut codec = Codec::new(TestCodec, 8192);
+        let mut io = futures::io::Cursor::new(Vec::new());
+        futures::executor::block_on(codec.write_request(&proto, &mut io, Message::request(()))).unwrap();
+        io.set_position(0);
+        futures::executor::block_on(codec.read_request(&proto, &mut io)).unwrap()
+    }
+
+    #[test]
+    fn receive_limit_for_protocol() {
+        let mut t = throttled();
+        t.set_reject_over_budget(true);
+        t.set_receive_limit(NonZeroU16::new(10).unwrap());
+        t.set_receive_limit_for_protocol(&TestProtocol::V1, NonZeroU16::new(3).unwrap());
+        t.set_receive_limit_for_protocol(&TestProtocol::V2, NonZeroU16::new(1).unwrap());
+        let p = PeerId::random();
+        t.trust_peer(&p, NonZeroU16::new(10).unwrap());
+        t.inject_connected(&p);
+
+        let mut rejected = Vec::new();
+        let protos = vec![TestProtocol::V2, TestProtocol::V1, TestProtocol::V2, TestProtocol::V1];
+        for (i, proto) in protos.into_iter().enumerate() {
+            let mut rx = inject_request(&mut t, &p, i as u64, received_request(proto));
+            poll_events(&mut t);
+            if let Ok(Some(m)) = rx.try_recv() {
+                assert_eq!(m.header().typ, Some(Type::Throttled));
+                rejected.push(i)
+            }
+        }
+        // Only the second request of V2 exceeds the limit of its protocol.
+        assert_eq!(rejected, vec![2]);
+        assert_eq!(t.recv_budget(&p), 6);
+
+        // V1 is limited independently.
+        for i in 4 .. 6 {
+            let mut rx = inject_request(&mut t, &p, i, received_request(TestProtocol::V1));
+            poll_events(&mut t);
+            assert_eq!(matches!(rx.try_recv(), Ok(Some(_))), i == 5)
+        }
+
+        // The protocol budgets are replenished with the peer's budget.
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        let rx = inject_request(&mut t, &p, 6, received_request(TestProtocol::V2));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        drop(rx)
+    }
+
+    #[test]
+    fn rate_limit_defers_credit() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_rate_limit(NonZeroU32::new(3).unwrap(), Duration::from_secs(1));
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        // The credit is capped by the allowance of the current interval.
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        let (id, amount) = credit(&t, &p).unwrap();
+        assert_eq!(amount, 3);
+        assert_eq!(t.recv_budget(&p), 3);
+
+        // The allowance is used up, so the next grant is deferred.
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert!(credit(&t, &p).is_none());
+        assert_eq!(t.recv_budget(&p), 0);
+        assert!(t.rate_timer.is_some());
+
+        *now.lock().unwrap() += Duration::from_millis(500);
+        t.grant_deferred_credit();
+        assert!(credit(&t, &p).is_none());
+
+        // The allowance is refilled in the next interval.
+        *now.lock().unwrap() += Duration::from_millis(500);
+        t.grant_deferred_credit();
+        let (next_id, amount) = credit(&t, &p).unwrap();
+        assert!(next_id > id);
+        assert_eq!(amount, 3);
+        assert_eq!(t.recv_budget(&p), 3);
+    }
+
+    #[test]
+    fn deferred_credit_granted_once() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let calls = Arc::new(Mutex::new(0));
+        let mut t = throttled();
+        t.set_rate_limit(NonZeroU32::new(5).unwrap(), Duration::from_secs(1));
+        t.set_limit_policy({
+            let calls = calls.clone();
+            move |_, _| {
+                *calls.lock().unwrap() += 1;
+                NonZeroU16::new(5).unwrap()
+            }
+        });
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert_eq!(granted(&mut t), vec![5]);
+
+        // The next grant is deferred. Responses to further requests of the
+        // same exhausted budget neither grant credit nor switch the limit.
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        *now.lock().unwrap() += Duration::from_secs(1);
+        respond(&mut t, &p);
+        assert!(granted(&mut t).is_empty());
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        t.grant_deferred_credit();
+        respond(&mut t, &p);
+        assert_eq!(granted(&mut t), vec![5]);
+        assert_eq!(t.recv_budget(&p), 5);
+    }
+
+    #[test]
+    fn stats() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        assert!(t.send_request(&p, ()).is_ok());
+        assert!(t.send_request(&p, ()).is_err());
+        assert!(t.send_request(&p, ()).is_err());
+
+        // Closing one of two connections resends the pending credit grant.
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connection_established(&p, &ConnectionId::new(2), &endpoint);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        let conn = t.behaviour.pending_connection(&t.credit_messages[&p].request.unwrap()).unwrap();
+        t.inject_connection_closed(&p, &conn, &endpoint);
+
+        assert_eq!(t.stats(), ThrottledStats {
+            requests_sent: 1,
+            requests_throttled: 2,
+            credits_granted: 1,
+            credit_retransmissions: 1,
+            too_many_inbound_requests: 0,
+            unknown_messages: 0,
+            .. ThrottledStats::default()
+        })
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn send_request_span() {
+        /// The names and values of the fields of a span.
+        type Fields = Vec<(String, String)>;
+
+        /// The names and fields of all spans created.
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<Vec<(&'static str, Fields)>>>);
+
+        struct Visitor<'a>(&'a mut Fields);
+
+        impl tracing::field::Visit for Visitor<'_> {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+                self.0.push((field.name().to_string(), format!("{:?}", value)))
+            }
+        }
+
+        impl tracing::Subscriber for Spans {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                let mut spans = self.0.lock().unwrap();
+                let mut fields = Vec::new();
+                attrs.record(&mut Visitor(&mut fields));
+                spans.push((attrs.metadata().name(), fields));
+                tracing::span::Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+                let mut spans = self.0.lock().unwrap();
+                values.record(&mut Visitor(&mut spans[id.into_u64() as usize - 1].1))
+            }
+
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+            fn event(&self, _: &tracing::Event<'_>) {}
+
+            fn enter(&self, _: &tracing::span::Id) {}
+
+            fn exit(&self, _: &tracing::span::Id) {}
+        }
+
+        let spans = Spans::default();
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        let rid = tracing::subscriber::with_default(spans.clone(), || t.send_request(&p, ()).unwrap());
+
+        let spans = spans.0.lock().unwrap();
+        let fields = |v: &[(&str, String)]| v.iter().map(|(k, v)| (k.to_string(), v.clone())).collect::<Fields>();
+        assert_eq!(*spans, vec![("send_request", fields(&[
+            ("id", format!("{:08x}", t.id)),
+            ("peer", p.to_string()),
+            ("request_id", rid.to_string())
+        ]))])
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+        let registry = prometheus::Registry::new();
+        t.register_metrics(&registry).unwrap();
+        assert!(t.register_metrics(&registry).is_err());
+
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        assert!(t.send_request(&p, ()).is_ok());
+        assert!(t.send_request(&p, ()).is_err());
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        poll_events(&mut t);
+
+        let values = |name: &str| -> Vec<(Option<String>, f64)> {
+            registry.gather().iter()
+                .filter(|f| f.get_name() == name)
+                .flat_map(|f| f.get_metric().iter())
+                .map(|m| {
+                    let peer = m.get_label().first().map(|l| l.get_value().to_string());
+                    (peer, m.get_counter().get_value() + m.get_gauge().get_value())
+                })
+                .collect()
+        };
+        assert_eq!(values("throttled_requests_sent_total"), vec![(None, 1.0)]);
+        assert_eq!(values("throttled_requests_throttled_total"), vec![(None, 1.0)]);
+        assert_eq!(values("throttled_credits_granted_total"), vec![(None, 1.0)]);
+        assert_eq!(values("throttled_send_budget"), vec![(Some(p.to_base58()), 0.0)]);
+        assert_eq!(values("throttled_recv_budget"), vec![(Some(p.to_base58()), f64::from(t.recv_budget(&p)))]);
+
+        // Metrics are updated at most once per interval.
+        let (id, _) = credit(&t, &p).unwrap();
+        t.receive_ack(&p, t.credit_messages[&p].request.unwrap(), Some(id));
+        t.inject_disconnected(&p);
+        poll_events(&mut t);
+        assert_eq!(values("throttled_credits_acknowledged_total"), vec![(None, 0.0)]);
+        *now.lock().unwrap() += Duration::from_secs(1);
+        poll_events(&mut t);
+        assert_eq!(values("throttled_credits_acknowledged_total"), vec![(None, 1.0)]);
+        assert!(values("throttled_send_budget").is_empty())
+    }
+
+    #[test]
+    fn credit_retransmissions() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connection_established(&p, &ConnectionId::new(2), &endpoint);
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+
+        // Failed credit grants are resent.
+        for _ in 0 .. 3 {
+            let request = t.credit_messages[&p].request.unwrap();
+            t.retry_credit(&p, request);
+        }
+        assert_eq!(t.stats().credit_retransmissions, 3);
+
+        // Failures of earlier transmissions are ignored.
+        t.retry_credit(&p, RequestId(u64::MAX));
+        assert_eq!(t.stats().credit_retransmissions, 3);
+
+        // Closing the connection of the credit grant resends it.
+        let conn = t.behaviour.pending_connection(&t.credit_messages[&p].request.unwrap()).unwrap();
+        t.inject_connection_closed(&p, &conn, &endpoint);
+        assert_eq!(t.stats().credit_retransmissions, 4);
+        assert_eq!(t.stats().credits_granted, 1)
+    }
+
+    #[test]
+    fn multiple_connections() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(4).unwrap());
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        let (c1, c2, c3) = (ConnectionId::new(1), ConnectionId::new(2), ConnectionId::new(3));
+        t.inject_connection_established(&p, &c1, &endpoint);
+        t.inject_connection_established(&p, &c2, &endpoint);
+        t.inject_connected(&p);
+
+        // Requests over any connection use up the same budget.
+        let request = |id| {
+            let (sender, _receiver) = oneshot::channel();
+            RequestResponseHandlerEvent::Request { request_id: RequestId(id), request: Message::request(()), sender, deadline: Instant::now() }
+        };
+        t.inject_event(p.clone(), c2, request(1));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert_eq!(t.recv_budget(&p), 0);
+        respond(&mut t, &p);
+        assert_eq!(t.recv_budget(&p), 4);
+
+        // Closing a connection other than the one the credit grant has been
+        // sent over does not resend it.
+        let (id, request_id) = (t.credit_messages[&p].id, t.credit_messages[&p].request.unwrap());
+        let (used, other) = if t.behaviour.pending_connection(&request_id) == Some(c1) { (c1, c2) } else { (c2, c1) };
+        t.inject_connection_closed(&p, &other, &endpoint);
+        assert_eq!(t.credit_messages[&p].request, Some(request_id));
+        assert_eq!(t.stats().credit_retransmissions, 0);
+
+        // Closing the connection the credit grant has been sent over resends
+        // it over another connection.
+        t.inject_connection_established(&p, &c3, &endpoint);
+        t.inject_connection_closed(&p, &used, &endpoint);
+        let resent = t.credit_messages[&p].request.unwrap();
+        assert_ne!(resent, request_id);
+        assert_eq!(t.credit_messages[&p].id, id);
+        assert_eq!(t.behaviour.pending_connection(&resent), Some(c3));
+        assert_eq!(t.stats().credit_retransmissions, 1);
+
+        t.inject_event(p.clone(), c3, request(2));
+        t.inject_event(p.clone(), c3, request(3));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 2);
+        assert_eq!(t.recv_budget(&p), 2);
+    }
+
+    #[test]
+    fn send_requests_partial_batch() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        t.peer_info.get_mut(&p).unwrap().send_budget = 3;
+
+        let (sent, rest) = t.send_requests(&p, vec![(); 5]).unwrap_err();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(rest.len(), 2);
+        assert_eq!(t.send_budget(&p), 0);
+        assert_eq!(t.stats().requests_sent, 3);
+        assert_eq!(t.stats().requests_throttled, 2);
+
+        t.peer_info.get_mut(&p).unwrap().send_budget = 2;
+        assert_eq!(t.send_requests(&p, rest).unwrap().len(), 2);
+        assert_eq!(t.send_budget(&p), 0);
+    }
+
+    #[test]
+    fn priority_requests_sent_first() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        assert!(t.send_request_with_priority(&p, (), Priority::Low).is_some());
+        assert!(t.send_request_with_priority(&p, (), Priority::Low).is_none());
+        assert!(t.send_request_with_priority(&p, (), Priority::High).is_none());
+        assert_eq!(t.pending_requests[&p].iter().map(|r| r.priority).collect::<Vec<_>>(),
+            vec![Priority::High, Priority::Low]);
+        let queued = t.events.drain(..)
+            .filter_map(|e| match e {
+                Event::RequestQueued { request_id, .. } => Some(request_id),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(queued.len(), 2);
+
+        // Credit for one request sends the high-priority request.
+        t.receive_credit(&p, 1, 1);
+        let sent = |t: &mut Throttled<TestCodec>| t.events.drain(..)
+            .filter_map(|e| match e {
+                Event::RequestSent { request_id, .. } => Some(request_id),
+                Event::ResumeSending(_) => panic!("budget should be used up"),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sent(&mut t), vec![queued[1]]);
+        assert_eq!(t.pending_requests[&p].iter().map(|r| r.priority).collect::<Vec<_>>(), vec![Priority::Low]);
+
+        t.receive_credit(&p, 2, 1);
+        assert_eq!(sent(&mut t), vec![queued[0]]);
+        assert!(!t.pending_requests.contains_key(&p));
+    }
+
+    #[test]
+    fn request_queued_before_sent() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        assert!(t.send_request(&p, ()).is_ok());
+        assert!(t.send_request_with_priority(&p, (), Priority::Low).is_none());
+        let rid = match t.events.pop_front() {
+            Some(Event::RequestQueued { peer, request_id }) if peer == p => request_id,
+            e => panic!("unexpected event: {:?}", e)
+        };
+        assert!(t.events.is_empty());
+
+        t.receive_credit(&p, 1, 1);
+        assert!(matches!(t.events.pop_front(), Some(Event::RequestSent { peer, request_id })
+            if peer == p && request_id == rid));
+        assert!(t.behaviour.pending_requests.contains_key(&p));
+    }
+
+    #[test]
+    fn pause_and_resume_sending() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        t.pause_sending();
+        assert_eq!(t.available_send_budget(&p), 0);
+        assert!(!t.can_send(&p));
+        assert!(t.send_request(&p, ()).is_err());
+        assert_eq!(t.send_requests(&p, vec![(); 2]).unwrap_err().1.len(), 2);
+        assert_eq!(t.send_budget(&p), 1);
+
+        // Credit is still granted while paused.
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert_eq!(granted(&mut t), vec![5]);
+
+        t.resume_sending();
+        assert!(matches!(t.events.pop_front(), Some(Event::ResumeSending(q)) if q == p));
+        assert_eq!(t.available_send_budget(&p), 1);
+        assert!(t.can_send(&p));
+        assert!(t.send_request(&p, ()).is_ok());
+        assert_eq!(t.stats().requests_sent, 1);
+    }
+
+    #[test]
+    fn export_import_state() {
+        let mut t1 = throttled();
+        t1.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let (p1, p2, p3) = (PeerId::random(), PeerId::random(), PeerId::random());
+        t1.override_receive_limit(&p3, NonZeroU16::new(7).unwrap());
+
+        // `p1` is connected and has an unacknowledged credit grant.
+        t1.inject_connected(&p1);
+        use_recv_budget(&mut t1, &p1);
+        respond(&mut t1, &p1);
+        t1.peer_info.get_mut(&p1).unwrap().recv_budget = 3;
+        let (credit_id, _) = credit(&t1, &p1).unwrap();
+
+        // `p2` is offline.
+        connect_and_disconnect(&mut t1, &p2);
+
+        let mut t2 = throttled();
+        t2.import_state(t1.export_state());
+        assert_eq!(t2.send_budget(&p1), 1);
+        assert_eq!(t2.recv_budget(&p1), 3);
+        assert_eq!(t2.send_budget(&p2), t1.send_budget(&p2));
+        assert_eq!(t2.recv_budget(&p2), t1.recv_budget(&p2));
+        assert_eq!(t2.limit_overrides[&p3].next_max.get(), 7);
+        assert!(t2.credit_id >= t1.credit_id);
+
+        // The imported credit is resent on reconnect.
+        t2.inject_connected(&p1);
+        assert_eq!(credit(&t2, &p1), Some((credit_id, 5)));
+        assert!(t2.credit_messages[&p1].request.is_some());
+        assert_eq!(t2.stats().credit_retransmissions, 1);
+    }
+
+    #[test]
+    fn limit_policy() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(2).unwrap());
+        t.set_limit_policy(|_, stats| {
+            if stats.requests_served >= 3 && stats.over_budget == 0 {
+                NonZeroU16::new(10).unwrap()
+            } else {
+                NonZeroU16::new(2).unwrap()
+            }
+        });
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        for expected in &[2, 2, 10, 10] {
+            use_recv_budget(&mut t, &p);
+            respond(&mut t, &p);
+            assert_eq!(granted(&mut t), vec![*expected]);
+        }
+        assert_eq!(t.peer_info[&p].stats.requests_served, 4);
+    }
+
+    #[test]
+    fn reset_peer() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        assert!(t.send_request(&p, ()).is_ok());
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert_eq!((t.send_budget(&p), t.recv_budget(&p)), (0, 5));
+        assert!(credit(&t, &p).is_some());
+
+        t.reset_peer(&p);
+        assert_eq!((t.send_budget(&p), t.recv_budget(&p)), (1, 1));
+        assert!(credit(&t, &p).is_none());
+        assert_eq!(t.peer_info[&p].limit.max_recv.get(), 1);
+        assert_eq!(t.peer_info[&p].limit.next_max.get(), 5);
+    }
+
+    #[test]
+    fn forget_peer() {
+        let mut t = throttled();
+        let (p1, p2) = (PeerId::random(), PeerId::random());
+        t.override_receive_limit(&p1, NonZeroU16::new(5).unwrap());
+        t.override_receive_limit(&p2, NonZeroU16::new(5).unwrap());
+
+        t.inject_connected(&p1);
+        use_recv_budget(&mut t, &p1);
+        respond(&mut t, &p1);
+        assert!(t.send_request(&p1, ()).is_ok());
+        assert!(t.send_request_with_priority(&p1, (), Priority::Low).is_none());
+        connect_and_disconnect(&mut t, &p2);
+
+        for p in &[p1, p2] {
+            t.forget_peer(p);
+            assert!(!t.peer_info.contains_key(p));
+            assert!(!t.offline_peer_info.contains(p));
+            assert!(!t.limit_overrides.contains_key(p));
+            assert!(!t.credit_messages.contains_key(p));
+            assert!(!t.pending_requests.contains_key(p));
+        }
+    }
+
+    #[test]
+    fn peers() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let (p1, p2, p3) = (PeerId::random(), PeerId::random(), PeerId::random());
+        t.inject_connected(&p1);
+        t.inject_connected(&p2);
+        connect_and_disconnect(&mut t, &p3);
+
+        assert!(t.send_request(&p1, ()).is_ok());
+        use_recv_budget(&mut t, &p2);
+        respond(&mut t, &p2);
+
+        let mut peers = t.peers().map(|(p, b)| (p.clone(), b)).collect::<HashMap<_, _>>();
+        let one = NonZeroU32::new(1).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers.remove(&p1), Some(PeerBudget { send_budget: 0, recv_budget: 1, limit: one }));
+        assert_eq!(peers.remove(&p2), Some(PeerBudget {
+            send_budget: 1,
+            recv_budget: 5,
+            limit: NonZeroU32::new(5).unwrap()
+        }));
+    }
+
+    #[test]
+    fn credit_backoff() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_credit_backoff(Duration::from_secs(1), Duration::from_secs(5), 0.0);
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+
+        for secs in &[1, 2, 4, 5, 5] {
+            let request = t.credit_messages[&p].request.unwrap();
+            t.retry_credit(&p, request);
+            let retry_at = t.credit_messages[&p].retry_at.unwrap();
+            assert_eq!(retry_at - *now.lock().unwrap(), Duration::from_secs(*secs));
+            assert!(t.credit_timer.is_some());
+
+            // Nothing is resent before the delay has elapsed.
+            *now.lock().unwrap() += Duration::from_secs(*secs) - Duration::from_millis(1);
+            t.retry_delayed_credit();
+            assert_eq!(t.credit_messages[&p].request, Some(request));
+
+            *now.lock().unwrap() += Duration::from_millis(1);
+            t.retry_delayed_credit();
+            assert_ne!(t.credit_messages[&p].request, Some(request));
+            assert!(t.credit_messages[&p].retry_at.is_none());
+            assert!(t.credit_timer.is_none());
+        }
+        assert_eq!(t.stats().credit_retransmissions, 5);
+
+        // A new credit grant starts without delay.
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert_eq!(t.credit_messages[&p].attempts, 0);
+        assert!(t.credit_messages[&p].retry_at.is_none());
+    }
+
+    /// A clock which only advances when told to.
+    #[derive(Clone)]
+    struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        fn new() -> Self {
+            MockClock(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, d: Duration) {
+            *self.0.lock().unwrap() += d
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new();
+        let mut t = throttled();
+        t.set_credit_timeout(Duration::from_secs(10));
+        t.set_clock(clock.clone());
+
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        t.events.clear();
+        assert!(credit(&t, &p).is_some());
+
+        // No real time passes, but the credit grant expires.
+        clock.advance(Duration::from_secs(10));
+        t.expire_credit();
+        assert!(credit(&t, &p).is_none());
+        assert!(matches!(t.events.pop_front(), Some(Event::CreditTimeout { peer, .. }) if peer == p))
+    }
+
+    #[test]
+    fn credit_timeout() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_credit_timeout(Duration::from_secs(10));
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        t.events.clear();
+        let (id, _) = credit(&t, &p).unwrap();
+        assert!(t.credit_timer.is_some());
+
+        *now.lock().unwrap() += Duration::from_secs(9);
+        t.expire_credit();
+        assert!(credit(&t, &p).is_some());
+        assert!(t.events.is_empty());
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        t.expire_credit();
+        assert!(credit(&t, &p).is_none());
+        match t.events.pop_front() {
+            Some(Event::CreditTimeout { peer, credit_id }) => {
+                assert_eq!(peer, p);
+                assert_eq!(credit_id, id)
+            }
+            e => panic!("unexpected event: {:?}", e)
+        }
+    }
+
+    #[test]
+    fn max_credit_retries() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_max_credit_retries(3);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        t.events.clear();
+        let (id, _) = credit(&t, &p).unwrap();
+
+        for _ in 0 .. 3 {
+            let request = t.credit_messages[&p].request.unwrap();
+            t.retry_credit(&p, request);
+            assert_ne!(t.credit_messages[&p].request, Some(request));
+        }
+        assert_eq!(t.stats().credit_retransmissions, 3);
+        assert!(t.events.is_empty());
+
+        let request = t.credit_messages[&p].request.unwrap();
+        t.retry_credit(&p, request);
+        assert!(credit(&t, &p).is_none());
+        assert_eq!(t.stats().credit_retransmissions, 3);
+        match t.events.pop_front() {
+            Some(Event::CreditFailed { peer, credit_id }) => {
+                assert_eq!(peer, p);
+                assert_eq!(credit_id, id)
+            }
+            e => panic!("unexpected event: {:?}", e)
+        }
+    }
+
+    #[test]
+    fn initial_send_budget() {
+        let mut t = throttled();
+        t.set_initial_send_budget(NonZeroU16::new(2).unwrap());
+        let (p1, p2) = (PeerId::random(), PeerId::random());
+        t.override_initial_send_budget(&p2, NonZeroU16::new(3).unwrap());
+        assert_eq!(t.send_budget(&p1), 2);
+        assert_eq!(t.send_budget(&p2), 3);
+
+        t.inject_connected(&p1);
+        assert!(t.send_request(&p1, ()).is_ok());
+        assert!(t.send_request(&p1, ()).is_ok());
+        assert!(t.send_request(&p1, ()).is_err());
+
+        // The initial budget is restored after a disconnect.
+        t.inject_disconnected(&p1);
+        assert_eq!(t.send_budget(&p1), 2);
+
+        t.inject_connected(&p2);
+        assert_eq!(t.send_requests(&p2, vec![(); 3]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn inner() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        assert!(!t.inner().is_connected(&p));
+
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        assert!(t.is_connected(&p));
+        assert_eq!(t.inner().is_connected(&p), t.is_connected(&p));
+
+        let q = PeerId::random();
+        let addr = "/memory/1".parse::<Multiaddr>().unwrap();
+        t.inner_mut().add_address(&q, addr.clone());
+        assert_eq!(t.addresses_of_peer(&q), vec![addr]);
+    }
+
+    #[test]
+    fn weighted_request() {
+        let mut t = throttled();
+        t.set_initial_send_budget(NonZeroU16::new(5).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        let weight = NonZeroU32::new(3).unwrap();
+        assert!(t.send_request_weighted(&p, (), weight).is_ok());
+        assert_eq!(t.send_budget(&p), 2);
+        assert!(t.send_request_weighted(&p, (), weight).is_err());
+        assert_eq!(t.send_budget(&p), 2);
+        assert_eq!(t.stats().requests_throttled, 1);
+
+        // Unweighted requests still cost one unit.
+        assert!(t.send_request(&p, ()).is_ok());
+        assert_eq!(t.send_budget(&p), 1);
+    }
+
+    #[test]
+    fn inbound_weight_checked() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.trust_peer(&p, NonZeroU16::new(10).unwrap());
+        t.inject_connected(&p);
+
+        // A weight of zero still uses up one unit.
+        drop(inject_request(&mut t, &p, 1, Message::weighted_request((), 0)));
+        drop(inject_request(&mut t, &p, 2, Message::weighted_request((), 3)));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 2);
+        assert_eq!(t.recv_budget(&p), 6);
+
+        // With byte budgets, a request uses up at least its measured size,
+        // whatever weight the peer claims.
+        let mut t = throttled();
+        t.use_byte_budget(NonZeroU32::new(100).unwrap(), |_| 10);
+        t.trust_peer(&p, NonZeroU16::new(100).unwrap());
+        t.inject_connected(&p);
+        drop(inject_request(&mut t, &p, 1, Message::weighted_request((), 1).with_data_len(40)));
+        drop(inject_request(&mut t, &p, 2, Message::weighted_request((), 0).with_data_len(20)));
+        drop(inject_request(&mut t, &p, 3, Message::weighted_request((), 30).with_data_len(5)));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 3);
+        assert_eq!(t.recv_budget(&p), 10);
+
+        // The sender accounts for the size of a weighted request alike.
+        t.set_initial_send_budget(NonZeroU16::new(100).unwrap());
+        let q = PeerId::random();
+        t.inject_connected(&q);
+        assert!(t.send_request_weighted(&q, (), NonZeroU32::new(1).unwrap()).is_ok());
+        assert_eq!(t.send_budget(&q), 90)
+    }
+
+    #[test]
+    fn pending_credits() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        assert_eq!(t.pending_credits().count(), 0);
+
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        let (id, request) = (t.credit_messages[&p].id, t.credit_messages[&p].request);
+        let pending = t.pending_credits().map(|(p, c)| (p.clone(), c)).collect::<Vec<_>>();
+        assert_eq!(pending, vec![(p.clone(), PendingCredit { credit_id: id, amount: 5, request_id: request })]);
+
+        // Acknowledgements of other credit grants are ignored.
+        let request = request.unwrap();
+        t.receive_ack(&p, request, Some(id + 1));
+        assert_eq!(t.pending_credits().count(), 1);
+        assert_eq!(t.stats().credits_acknowledged, 0);
+
+        t.receive_ack(&p, request, Some(id));
+        assert_eq!(t.pending_credits().count(), 0);
+        assert_eq!(t.stats().credits_acknowledged, 1);
+    }
+
+    #[test]
+    fn credit_rtt() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        t.events.clear();
+
+        // The round-trip time is measured from the retransmission.
+        *now.lock().unwrap() += Duration::from_secs(3);
+        let (id, request) = (t.credit_messages[&p].id, t.credit_messages[&p].request.unwrap());
+        t.retry_credit(&p, request);
+        *now.lock().unwrap() += Duration::from_millis(250);
+        let request = t.credit_messages[&p].request.unwrap();
+        t.receive_ack(&p, request, Some(id));
+
+        match t.events.pop_front() {
+            Some(Event::CreditRtt { peer, credit_id, rtt }) => {
+                assert_eq!(peer, p);
+                assert_eq!(credit_id, id);
+                assert_eq!(rtt, Duration::from_millis(250))
+            }
+            e => panic!("unexpected event: {:?}", e)
+        }
+    }
+
+    #[test]
+    fn proactive_credit_grant() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(12).unwrap());
+        let p = PeerId::random();
+        let amount = NonZeroU16::new(5).unwrap();
+        assert!(!t.grant_credit(&p, amount));
+
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        assert!(t.grant_credit(&p, amount));
+        assert_eq!(t.recv_budget(&p), 5);
+        assert_eq!(granted(&mut t), vec![5]);
+
+        // Only one grant can be outstanding.
+        assert!(t.grant_credit(&p, amount));
+        assert_eq!(t.recv_budget(&p), 10);
+        assert!(granted(&mut t).is_empty());
+        let (id, _) = credit(&t, &p).unwrap();
+        t.receive_ack(&p, t.credit_messages[&p].request.unwrap(), Some(id));
+        assert_eq!(granted(&mut t), vec![5]);
+
+        // The receive budget does not grow beyond the receive limit.
+        assert!(t.grant_credit(&p, amount));
+        assert_eq!(t.recv_budget(&p), 12);
+        for _ in 0 .. 3 {
+            assert!(!t.grant_credit(&p, amount));
+            assert!(!t.grant_credit(&p, NonZeroU16::new(u16::MAX).unwrap()));
+        }
+        assert_eq!(t.recv_budget(&p), 12);
+        assert_eq!(credit(&t, &p).map(|c| c.1), Some(5));
+    }
+
+    #[test]
+    fn credit_grants_coalesced() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(10).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        assert!(t.grant_credit(&p, NonZeroU16::new(2).unwrap()));
+        assert_eq!(granted(&mut t), vec![2]);
+        let (id, _) = credit(&t, &p).unwrap();
+
+        // Grants before the first one is acknowledged are combined.
+        assert!(t.grant_credit(&p, NonZeroU16::new(3).unwrap()));
+        assert!(t.grant_credit(&p, NonZeroU16::new(4).unwrap()));
+        assert!(granted(&mut t).is_empty());
+        assert_eq!(credit(&t, &p), Some((id, 2)));
+        assert_eq!(t.recv_budget(&p), 9);
+
+        t.receive_ack(&p, t.credit_messages[&p].request.unwrap(), Some(id));
+        assert_eq!(granted(&mut t), vec![7]);
+        let (next, amount) = credit(&t, &p).unwrap();
+        assert!(next > id);
+        assert_eq!(amount, 7);
+        assert_eq!(t.credit_messages[&p].prior, 2);
+
+        // A request beyond the budget before the combined grant delivers it.
+        t.grant_credit(&p, NonZeroU16::new(1).unwrap());
+        for i in 0 .. 3 {
+            drop(inject_request(&mut t, &p, i, Message::request(())))
+        }
+        let events = poll_events(&mut t);
+        assert!(events.iter().any(|e| matches!(e, Event::CreditGranted { amount: 1, .. })));
+        assert_eq!(t.recv_budget(&p), 7)
+    }
+
+    #[test]
+    fn cancel_sent_request() {
+        use libp2p_swarm::{ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr};
+        use libp2p_core::upgrade::UpgradeError;
+
+        /// The events `t` sends to connection handlers.
+        fn handler_events(t: &mut Throttled<TestCodec>) -> Vec<RequestResponseHandlerInEvent<Codec<TestCodec>>> {
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let mut params = TestParams(PeerId::random());
+            let mut events = Vec::new();
+            while let Poll::Ready(action) = t.poll(&mut cx, &mut params) {
+                if let NetworkBehaviourAction::NotifyHandler { event, .. } = action {
+                    events.push(event)
+                }
+            }
+            events
+        }
+
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connection_established(&p, &ConnectionId::new(1), &ConnectedPoint::Dialer { address: Multiaddr::empty() });
+        t.inject_connected(&p);
+        let id = t.send_request(&p, ()).unwrap();
+
+        let mut handler = t.new_handler();
+        for e in handler_events(&mut t) {
+            handler.inject_event(e)
+        }
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut request = match handler.poll(&mut cx) {
+            Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol }) => protocol.into_upgrade().1,
+            _ => panic!("expected an outbound substream request")
+        };
+        assert_eq!(request.request_id, id);
+        let mut cancelled = request.cancelled.take().unwrap();
+        assert_eq!(cancelled.try_recv(), Ok(None));
+
+        // The handler is told to cancel the request, which aborts the upgrade.
+        assert!(t.cancel_request(&p, id));
+        let events = handler_events(&mut t);
+        assert!(matches!(events[..], [RequestResponseHandlerInEvent::Cancel(c)] if c == id));
+        for e in events {
+            handler.inject_event(e)
+        }
+        assert!(cancelled.try_recv().is_err());
+
+        // The error of the aborted upgrade does not close the connection.
+        let error = io::Error::new(io::ErrorKind::Interrupted, "request cancelled");
+        handler.inject_dial_upgrade_error(id, ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(error)));
+        assert!(handler.poll(&mut cx).is_pending());
+        assert!(!handler.connection_keep_alive().is_yes());
+        assert!(!t.is_pending_outbound(&id))
+    }
+
+    #[test]
+    fn cancel_unsent_request() {
+        let mut t = throttled();
+        t.set_initial_send_budget(NonZeroU16::new(2).unwrap());
+        let p = PeerId::random();
+        let id1 = t.send_request(&p, ()).unwrap();
+        let id2 = t.send_request_weighted(&p, (), NonZeroU32::new(1).unwrap()).unwrap();
+        assert_eq!(t.send_budget(&p), 0);
+
+        assert!(t.cancel_request(&p, id2));
+        assert_eq!(t.send_budget(&p), 1);
+        assert!(!t.cancel_request(&p, id2));
+        assert_eq!(t.send_budget(&p), 1);
+
+        assert!(t.cancel_request(&p, id1));
+        assert_eq!(t.send_budget(&p), 2);
+        assert!(!t.behaviour.is_pending_outbound(&id1));
+
+        // Only the budget actually used up is reclaimed, and only if
+        // the request is cancelled for the peer it was meant for.
+        t.use_byte_budget(NonZeroU32::new(5).unwrap(), |_| 5);
+        let id3 = t.send_request(&p, ()).unwrap();
+        assert_eq!(t.send_budget(&p), 0);
+        let q = PeerId::random();
+        assert!(!t.cancel_request(&q, id3));
+        assert_eq!(t.send_budget(&p), 0);
+        assert!(!t.peer_info.contains_key(&q));
+        assert!(t.cancel_request(&p, id3));
+        assert_eq!(t.send_budget(&p), 2)
+    }
+
+    #[test]
+    fn receive_limit_getters() {
+        let mut t = throttled();
+        assert_eq!(t.receive_limit().get(), 1);
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_receive_limit(NonZeroU16::new(7).unwrap());
+        assert_eq!(t.receive_limit().get(), 7);
+
+        let p = PeerId::random();
+        assert_eq!(t.receive_limit_override(&p), None);
+        t.override_receive_limit(&p, NonZeroU16::new(3).unwrap());
+        assert_eq!(t.receive_limit_override(&p), NonZeroU32::new(3));
+        t.trust_peer(&p, NonZeroU16::new(4).unwrap());
+        assert_eq!(t.receive_limit_override(&p), NonZeroU32::new(4));
+        t.remove_override(&p);
+        assert_eq!(t.receive_limit_override(&p), None);
+        assert_eq!(t.receive_limit().get(), 7);
+    }
+
+    #[test]
+    fn with_config() {
+        let mut config = ThrottledConfig::default();
+        config.set_prefix(b"/custom")
+            .set_receive_limit(NonZeroU16::new(10).unwrap())
+            .set_initial_send_budget(NonZeroU16::new(5).unwrap())
+            .set_offline_cache_capacity(16)
+            .set_max_header_size(256)
+            .set_rate_limit(NonZeroU32::new(20).unwrap(), Duration::from_secs(1))
+            .set_credit_backoff(Duration::from_secs(1), Duration::from_secs(8), 0.5)
+            .set_credit_timeout(Duration::from_secs(30))
+            .set_max_credit_retries(4)
+            .set_reject_over_budget(true)
+            .set_over_budget_action(OverBudgetAction::Disconnect);
+
+        let protos = std::iter::once((TestProtocol::V1, ProtocolSupport::Full));
+        let mut t = Throttled::with_config(TestCodec, protos, config).unwrap();
+        assert_eq!(protocol_names(&mut t), vec![b"/custom/test/1".to_vec()]);
+        assert_eq!(t.receive_limit().get(), 10);
+        assert_eq!(t.send_budget(&PeerId::random()), 5);
+        assert_eq!(t.offline_peer_info.cap(), 16);
+        assert!(matches!(t.rate_limit, Some(RateLimit { interval, .. }) if interval == Duration::from_secs(1)));
+        assert!(matches!(t.credit_backoff, Some(Backoff { jitter, .. }) if jitter == 0.5));
+        assert_eq!(t.credit_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(t.max_credit_retries, Some(4));
+        assert!(t.reject_over_budget);
+        assert_eq!(t.over_budget_action, OverBudgetAction::Disconnect);
+    }
+
+    #[test]
+    fn with_invalid_config() {
+        fn error(config: &ThrottledConfig) -> ConfigError {
+            let protos = std::iter::once((TestProtocol::V1, ProtocolSupport::Full));
+            Throttled::with_config(TestCodec, protos, config.clone()).err().unwrap()
+        }
+
+        let mut config = ThrottledConfig::default();
+        config.set_initial_send_budget(NonZeroU16::new(2).unwrap());
+        assert_eq!(error(&config), ConfigError::InitialSendBudgetExceedsLimit);
+
+        let mut config = ThrottledConfig::default();
+        config.set_rate_limit(NonZeroU32::new(1).unwrap(), Duration::from_secs(0));
+        assert_eq!(error(&config), ConfigError::InvalidRateLimit);
+
+        let mut config = ThrottledConfig::default();
+        config.set_credit_backoff(Duration::from_secs(2), Duration::from_secs(1), 0.0);
+        assert_eq!(error(&config), ConfigError::InvalidCreditBackoff);
+        config.set_credit_backoff(Duration::from_secs(1), Duration::from_secs(2), 1.5);
+        assert_eq!(error(&config), ConfigError::InvalidCreditBackoff);
+
+        config.set_credit_backoff(Duration::from_secs(1), Duration::from_secs(2), 0.0)
+            .set_credit_timeout(Duration::from_secs(1));
+        assert_eq!(error(&config), ConfigError::CreditTimeoutTooShort);
+
+        #[cfg(feature = "serde_json")]
+        {
+            let mut config = ThrottledConfig::default();
+            config.set_header_format(Arc::new(JsonFormat));
+            assert_eq!(error(&config), ConfigError::HeaderEncodingRequiresPrefix);
+            config.set_prefix(b"/t/1/json");
+            let protos = std::iter::once((TestProtocol::V1, ProtocolSupport::Full));
+            assert!(Throttled::with_config(TestCodec, protos, config).is_ok());
+
+            let mut t = throttled();
+            assert_eq!(t.set_header_format(Arc::new(JsonFormat)), Err(ConfigError::HeaderEncodingRequiresPrefix));
+            let protos = std::iter::once((TestProtocol::V1, ProtocolSupport::Full));
+            let mut t = Throttled::with_prefix(b"/t/1/json", TestCodec, protos, RequestResponseConfig::default());
+            assert_eq!(t.set_header_format(Arc::new(JsonFormat)), Ok(()))
+        }
+    }
+
+    #[test]
+    fn peer_resumed_event() {
+        let mut t = throttled();
+        t.set_initial_send_budget(NonZeroU16::new(3).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        assert!(t.events.is_empty());
+
+        let info = t.peer_info.get_mut(&p).unwrap();
+        info.send_budget = 0;
+        info.recv_budget = 4;
+        t.inject_disconnected(&p);
+        t.inject_connected(&p);
+        let resumed = t.events.drain(..)
+            .filter_map(|e| match e {
+                Event::PeerResumed { peer, send_budget, recv_budget } => Some((peer, send_budget, recv_budget)),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(resumed, vec![(p, 3, 4)]);
+    }
+
+    #[test]
+    fn reconnect_grants_credit_once() {
+        for send_first in &[true, false] {
+            let mut t = throttled();
+            let p = PeerId::random();
+            t.inject_connected(&p);
+            t.peer_info.get_mut(&p).unwrap().recv_budget = 4;
+            t.inject_disconnected(&p);
+
+            let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+            if *send_first {
+                assert!(t.send_request(&p, ()).is_ok());
+                t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+                t.inject_connected(&p)
+            } else {
+                t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+                t.inject_connected(&p);
+                assert!(t.send_request(&p, ()).is_ok())
+            }
+            assert_eq!(granted(&mut t), vec![3]);
+            assert_eq!(t.credit_messages.len(), 1);
+        }
+    }
+
+    #[test]
+    fn dial_failure_drops_credit() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        t.peer_info.get_mut(&p).unwrap().recv_budget = 4;
+        t.inject_disconnected(&p);
+
+        // Sending a request restores the peer and dials it.
+        assert!(t.send_request(&p, ()).is_ok());
+        let (id, _) = credit(&t, &p).unwrap();
+        assert_eq!(granted(&mut t), vec![3]);
+
+        t.inject_dial_failure(&p);
+        assert!(credit(&t, &p).is_none());
+        assert!(!t.peer_info.contains_key(&p));
+        assert!(matches!(t.events.pop_front(), Some(Event::CreditFailed { peer, credit_id }) if peer == p && credit_id == id));
+
+        // The failed credit request is not retransmitted.
+        let events = poll_events(&mut t);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| matches!(e, Event::Event(RequestResponseEvent::OutboundFailure { .. }))));
+        assert!(credit(&t, &p).is_none());
+
+        // The credit is granted again once the peer connects.
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        assert_eq!(granted(&mut t), vec![3]);
+        assert_eq!(t.recv_budget(&p), 4);
+    }
+
+    #[test]
+    fn send_budget_saturates() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        t.receive_credit(&p, 1, u32::MAX - 2);
+        assert_eq!(t.send_budget(&p), u32::MAX - 1);
+        t.receive_credit(&p, 2, 10);
+        assert_eq!(t.send_budget(&p), u32::MAX);
+    }
+
+    #[test]
+    fn credit_ids_of_new_instance() {
+        let (p, q) = (PeerId::random(), PeerId::random());
+        let mut remote = throttled();
+        remote.inject_connected(&p);
+
+        let mut old = throttled();
+        old.set_receive_limit(NonZeroU16::new(5).unwrap());
+        old.inject_connected(&q);
+        for _ in 0 .. 3 {
+            use_recv_budget(&mut old, &q);
+            respond(&mut old, &q);
+            let (id, amount) = credit(&old, &q).unwrap();
+            remote.receive_credit(&p, id, amount);
+        }
+        assert_eq!(remote.send_budget(&p), 16);
+
+        // A rebuilt behaviour continues with greater credit IDs, so the
+        // remote peer, which remembers the last ID across reconnects,
+        // accepts its grants.
+        remote.inject_disconnected(&p);
+        remote.inject_connected(&p);
+        std::thread::sleep(Duration::from_millis(1));
+        let mut new = throttled();
+        new.inject_connected(&q);
+        use_recv_budget(&mut new, &q);
+        respond(&mut new, &q);
+        let (id, amount) = credit(&new, &q).unwrap();
+        assert!(Some(id) > old.last_credit_id());
+        remote.receive_credit(&p, id, amount);
+        assert_eq!(remote.send_budget(&p), 2);
+    }
+
+    #[test]
+    fn send_request_budget_lookup() {
+        let mut t = throttled();
+        t.set_initial_send_budget(NonZeroU16::new(2).unwrap());
+        let (p1, p2, p3) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        // A connected peer.
+        t.inject_connected(&p1);
+        assert!(t.send_request(&p1, ()).is_ok());
+        assert_eq!(t.send_budget(&p1), 1);
+
+        // An unknown peer starts with the initial send budget.
+        t.override_initial_send_budget(&p2, NonZeroU16::new(3).unwrap());
+        assert!(t.send_request(&p2, ()).is_ok());
+        assert_eq!(t.send_budget(&p2), 2);
+
+        // A previously connected peer is restored from the offline cache.
+        t.inject_connected(&p3);
+        t.peer_info.get_mut(&p3).unwrap().recv_budget = 3;
+        t.inject_disconnected(&p3);
+        t.events.clear();
+        assert!(t.send_request(&p3, ()).is_ok());
+        assert_eq!(t.send_budget(&p3), 1);
+        assert_eq!(t.recv_budget(&p3), 3);
+        assert_eq!(granted(&mut t), vec![2]);
+    }
+
+    #[test]
+    fn try_send_request() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        assert!(matches!(t.try_send_request(&p, ()), TrySend::NotConnected(())));
+        assert!(!t.peer_info.contains_key(&p));
+
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        t.pause_sending();
+        assert!(matches!(t.try_send_request(&p, ()), TrySend::Paused(())));
+        t.resume_sending();
+
+        let rid = match t.try_send_request(&p, ()) {
+            TrySend::Sent(rid) => rid,
+            other => panic!("unexpected result: {:?}", other)
+        };
+        assert!(t.is_pending_outbound(&rid));
+        assert!(matches!(t.try_send_request(&p, ()), TrySend::NoBudget(())));
+    }
+
+    #[test]
+    fn max_pending_credits_drop_oldest() {
+        let mut t = throttled();
+        t.set_max_pending_credits(NonZeroUsize::new(2).unwrap(), CreditOverflow::DropOldest);
+        let peers = (0 .. 5).map(|_| PeerId::random()).collect::<Vec<_>>();
+        for p in &peers {
+            t.inject_connected(p);
+            use_recv_budget(&mut t, p);
+            respond(&mut t, p);
+            assert!(t.credit_messages.len() <= 2);
+        }
+        assert_eq!(granted(&mut t).len(), 5);
+        assert!(credit(&t, &peers[3]).is_some());
+        assert!(credit(&t, &peers[4]).is_some());
+    }
+
+    #[test]
+    fn max_pending_credits_defer() {
+        let mut t = throttled();
+        t.set_max_pending_credits(NonZeroUsize::new(1).unwrap(), CreditOverflow::Defer);
+        let peers = (0 .. 3).map(|_| PeerId::random()).collect::<Vec<_>>();
+        for p in &peers {
+            t.inject_connected(p);
+            use_recv_budget(&mut t, p);
+            respond(&mut t, p);
+            assert_eq!(t.credit_messages.len(), 1);
+        }
+        assert_eq!(granted(&mut t).len(), 1);
+        assert!(!t.grant_credit(&peers[1], NonZeroU16::new(1).unwrap()));
+
+        // Nothing happens while no credit grant has been acknowledged.
+        t.grant_credit_below_cap();
+        assert!(granted(&mut t).is_empty());
+
+        let (id, _) = credit(&t, &peers[0]).unwrap();
+        t.receive_ack(&peers[0], t.credit_messages[&peers[0]].request.unwrap(), Some(id));
+        t.grant_credit_below_cap();
+        assert_eq!(granted(&mut t).len(), 1);
+        assert_eq!(t.credit_messages.len(), 1);
+        assert_eq!(t.peer_info.values().filter(|i| i.credit_deferred).count(), 1);
+    }
+
+    #[test]
+    fn blocked_and_throttled() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        assert!(!t.is_send_blocked(&p));
+        assert!(!t.is_recv_throttled(&p));
+
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        assert!(!t.is_send_blocked(&p));
+        t.send_request(&p, ()).unwrap();
+        assert!(t.is_send_blocked(&p));
+        t.receive_credit(&p, 1, 2);
+        assert!(!t.is_send_blocked(&p));
+
+        use_recv_budget(&mut t, &p);
+        assert!(t.is_recv_throttled(&p));
+        respond(&mut t, &p);
+        assert!(!t.is_recv_throttled(&p));
+    }
+
+    #[test]
+    fn send_request_when_ready() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+
+        let first = t.send_request_when_ready(&p, ()).now_or_never();
+        assert!(matches!(first, Some(Ok(_))));
+
+        let mut second = Box::pin(t.send_request_when_ready(&p, ()));
+        assert!(second.as_mut().now_or_never().is_none());
+        t.receive_credit(&p, 1, 1);
+        let rid = second.now_or_never().unwrap().unwrap();
+        assert!(t.is_pending_outbound(&rid));
+
+        let third = t.send_request_when_ready(&p, ());
+        t.forget_peer(&p);
+        assert!(matches!(third.now_or_never(), Some(Err(oneshot::Canceled))));
+    }
+
+    #[test]
+    fn preset_peer() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(10).unwrap());
+        let p = PeerId::random();
+        t.preset_peer(&p, 5, NonZeroU16::new(8).unwrap());
+        assert_eq!(t.send_budget(&p), 5);
+        assert_eq!(t.recv_budget(&p), 8);
+
+        // The remote peer is granted credit for the preset receive budget.
+        t.inject_connected(&p);
+        assert_eq!(t.send_budget(&p), 5);
+        assert_eq!(t.recv_budget(&p), 8);
+        assert_eq!(granted(&mut t), vec![7]);
+
+        t.preset_peer(&p, 0, NonZeroU16::new(1).unwrap());
+        assert_eq!(t.send_budget(&p), 0);
+        assert_eq!(t.recv_budget(&p), 1);
+    }
+
+    #[test]
+    fn last_credit_id() {
+        let mut t = throttled();
+        assert_eq!(t.last_credit_id(), None);
+        let base = t.credit_id;
+        let peers = (0 .. 3).map(|_| PeerId::random()).collect::<Vec<_>>();
+        for (i, p) in peers.iter().enumerate() {
+            t.inject_connected(p);
+            use_recv_budget(&mut t, p);
+            respond(&mut t, p);
+            assert_eq!(t.last_credit_id(), Some(base + i as u64));
+            assert_eq!(credit(&t, p).map(|c| c.0), t.last_credit_id());
+        }
+    }
+
+    #[test]
+    fn drain() {
+        let mut t = throttled();
+        t.set_reject_over_budget(true);
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        let rid = t.send_request(&p, ()).unwrap();
+
+        t.begin_drain();
+        assert!(!poll_events(&mut t).iter().any(|e| matches!(e, Event::Drained)));
+
+        // Inbound requests are rejected.
+        let mut rx = inject_request(&mut t, &p, 1, Message::request(()));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 0);
+        let response = rx.try_recv().unwrap().unwrap();
+        assert_eq!(response.header().typ, Some(Type::Throttled));
+
+        // The outstanding request completes.
+        let response = RequestResponseHandlerEvent::Response { request_id: rid, response: Message::response(()) };
+        t.inject_event(p.clone(), ConnectionId::new(1), response);
+        let events = poll_events(&mut t);
+        assert!(matches!(events.last(), Some(Event::Drained)));
+        assert!(matches!(events[0], Event::Event(RequestResponseEvent::Message {
+            message: RequestResponseMessage::Response { .. }, ..
+        })));
+        assert!(poll_events(&mut t).is_empty());
+    }
+
+    #[test]
+    fn unknown_messages() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+
+        // An ack is no valid request.
+        drop(inject_request(&mut t, &p, 1, Message::ack(1)));
+        assert!(poll_events(&mut t).is_empty());
+        assert_eq!(t.stats().unknown_messages, 1);
+
+        t.set_report_unknown_messages(true);
+        drop(inject_request(&mut t, &p, 2, Message::ack(1)));
+        let events = poll_events(&mut t);
+        assert!(matches!(&events[..], [Event::UnknownMessage { peer, from_request: true }] if *peer == p));
+
+        // A credit grant is no valid response.
+        let rid = t.send_request(&p, ()).unwrap();
+        let response = RequestResponseHandlerEvent::Response { request_id: rid, response: Message::credit(1, 1) };
+        t.inject_event(p.clone(), ConnectionId::new(1), response);
+        let events = poll_events(&mut t);
+        assert!(matches!(&events[..], [Event::UnknownMessage { peer, from_request: false }] if *peer == p));
+        assert_eq!(t.stats().unknown_messages, 3);
+
+        // So are messages of an unsupported header version.
+        let budget = t.recv_budget(&p);
+        drop(inject_request(&mut t, &p, 3, Message::request(()).with_version(VERSION + 1)));
+        let events = poll_events(&mut t);
+        assert!(matches!(&events[..], [Event::UnknownMessage { peer, from_request: true }] if *peer == p));
+        assert_eq!(t.recv_budget(&p), budget);
+        assert_eq!(t.stats().unknown_messages, 4)
+    }
+
+    #[test]
+    fn over_budget_request_rejected() {
+        let mut t = throttled();
+        t.set_reject_over_budget(true);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        let mut rx1 = inject_request(&mut t, &p, 1, Message::request(()));
+        let mut rx2 = inject_request(&mut t, &p, 2, Message::request(()));
+        let events = poll_events(&mut t);
+        assert_eq!(inbound_requests(&events), 1);
+        assert!(matches!(events.last(), Some(Event::TooManyInboundRequests(q)) if *q == p));
+
+        // The sender of the over-budget request is answered right away.
+        assert!(matches!(rx1.try_recv(), Ok(None)));
+        let response = rx2.try_recv().unwrap().unwrap();
+        assert_eq!(response.header().typ, Some(Type::Throttled));
+
+        // Dropped requests are not answered at all.
+        t.set_reject_over_budget(false);
+        let mut rx3 = inject_request(&mut t, &p, 3, Message::request(()));
+        poll_events(&mut t);
+        assert!(rx3.try_recv().is_err());
+    }
+
+    #[test]
+    fn over_budget_request_of_old_peer() {
+        use minicbor::{Decode, Encode};
+
+        /// The header of peers which predate `Type::Throttled`.
+        #[derive(Debug, Encode, Decode)]
+        #[cbor(map)]
+        struct OldHeader {
+            #[n(0)] typ: Option<OldType>,
+            #[n(1)] credit: Option<u16>,
+            #[n(2)] ident: Option<u64>
+        }
+
+        #[derive(Debug, PartialEq, Eq, Encode, Decode)]
+        enum OldType {
+            #[n(0)] Request,
+            #[n(1)] Response,
+            #[n(2)] Credit,
+            #[n(3)] Ack
+        }
+
+        fn decode_old(msg: &Message<()>) -> Result<OldHeader, minicbor::decode::Error> {
+            let mut buf = Vec::new();
+            minicbor::encode(msg.header(), &mut buf).unwrap();
+            minicbor::decode(&buf)
+        }
+
+        // Old peers can not decode a rejection.
+        assert!(decode_old(&Message::throttled()).is_err());
+        assert_eq!(decode_old(&Message::response(())).unwrap().typ, Some(OldType::Response));
+
+        // By default, over-budget requests are dropped without a rejection.
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        let mut rx1 = inject_request(&mut t, &p, 1, Message::request(()));
+        let mut rx2 = inject_request(&mut t, &p, 2, Message::request(()));
+        let events = poll_events(&mut t);
+        assert_eq!(inbound_requests(&events), 1);
+        assert!(matches!(rx1.try_recv(), Ok(None)));
+        assert!(rx2.try_recv().is_err())
+    }
+
+    #[test]
+    fn untracked_peer_budget_enforced() {
+        let mut t = throttled();
+        t.set_reject_over_budget(true);
+        let p = PeerId::random();
+        assert!(!t.peer_info.contains_key(&p));
+
+        let mut rx1 = inject_request(&mut t, &p, 1, Message::request(()));
+        let mut rx2 = inject_request(&mut t, &p, 2, Message::request(()));
+        let events = poll_events(&mut t);
+        assert_eq!(inbound_requests(&events), 1);
+        assert!(matches!(events.last(), Some(Event::TooManyInboundRequests(q)) if *q == p));
+        assert!(matches!(rx1.try_recv(), Ok(None)));
+        assert_eq!(rx2.try_recv().unwrap().unwrap().header().typ, Some(Type::Throttled));
+        assert_eq!(t.recv_budget(&p), 0);
+    }
+
+    #[test]
+    fn over_budget_flood_reported_once() {
+        let mut t = throttled();
+        t.set_over_budget_action(OverBudgetAction::Disconnect);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        let flood = |t: &mut Throttled<TestCodec>, ids: std::ops::Range<u64>| {
+            for i in ids {
+                drop(inject_request(t, &p, i, Message::request(())))
+            }
+            let events = poll_events(t);
+            let reported = events.iter().filter(|e| matches!(e, Event::TooManyInboundRequests(_))).count();
+            let disconnects = events.iter().filter(|e| matches!(e, Event::Disconnect(_))).count();
+            (inbound_requests(&events), reported, disconnects)
+        };
+        assert_eq!(flood(&mut t, 0 .. 10), (1, 1, 1));
+        assert_eq!(flood(&mut t, 10 .. 20), (0, 0, 0));
+        assert_eq!(t.stats().too_many_inbound_requests, 1);
+        assert_eq!(t.peer_info[&p].stats.over_budget, 19);
+
+        // Once the peer is back within its budget, the next flood is reported.
+        respond(&mut t, &p);
+        assert_eq!(flood(&mut t, 20 .. 30), (1, 1, 1));
+        assert_eq!(t.stats().too_many_inbound_requests, 2);
+    }
+
+    #[test]
+    fn inbound_failure_grants_credit() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(3).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        // The last request of a budget fails instead of being answered.
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert_eq!(t.recv_budget(&p), 0);
+        t.inject_event(p.clone(), ConnectionId::new(1), RequestResponseHandlerEvent::InboundTimeout(RequestId(1)));
+        poll_events(&mut t);
+        assert_eq!(t.recv_budget(&p), 3);
+        assert_eq!(credit(&t, &p).map(|c| c.1), Some(3));
+
+        // Failures of requests which have not been counted are ignored.
+        let (id, _) = credit(&t, &p).unwrap();
+        t.receive_ack(&p, t.credit_messages[&p].request.unwrap(), Some(id));
+        for i in 2 .. 5 {
+            drop(inject_request(&mut t, &p, i, Message::request(())))
+        }
+        drop(inject_request(&mut t, &p, 5, Message::request(())));
+        poll_events(&mut t);
+        assert_eq!(t.recv_budget(&p), 0);
+        t.inject_event(p.clone(), ConnectionId::new(1), RequestResponseHandlerEvent::InboundTimeout(RequestId(5)));
+        poll_events(&mut t);
+        assert!(credit(&t, &p).is_none());
+        assert_eq!(t.recv_budget(&p), 0);
+    }
+
+    #[test]
+    fn oversized_requests() {
+        let too_large = |events: &[Event<(), (), Message<()>>]| events.iter()
+            .filter(|e| matches!(e, Event::Event(RequestResponseEvent::InboundFailure {
+                error: InboundFailure::MessageTooLarge, ..
+            })))
+            .count();
+
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.trust_peer(&p, NonZeroU16::new(10).unwrap());
+        t.inject_connected(&p);
+
+        // An oversized request uses up a single unit of budget or its weight,
+        // like the sender charged for it.
+        drop(inject_request(&mut t, &p, 1, Message::request(()).into_too_large()));
+        drop(inject_request(&mut t, &p, 2, Message::weighted_request((), 3).into_too_large()));
+        let events = poll_events(&mut t);
+        assert_eq!(too_large(&events), 2);
+        assert_eq!(inbound_requests(&events), 0);
+        assert_eq!(t.recv_budget(&p), 6);
+
+        // With byte budgets, the remaining budget is used up, so that
+        // credit is granted for the next budget right away.
+        let mut t = throttled();
+        t.use_byte_budget(NonZeroU32::new(100).unwrap(), |_| 10);
+        t.trust_peer(&p, NonZeroU16::new(100).unwrap());
+        t.inject_connected(&p);
+        drop(inject_request(&mut t, &p, 1, Message::request(()).with_data_len(10)));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert_eq!(t.recv_budget(&p), 90);
+        assert!(credit(&t, &p).is_none());
+        drop(inject_request(&mut t, &p, 2, Message::request(()).into_too_large()));
+        assert_eq!(too_large(&poll_events(&mut t)), 1);
+        assert_eq!(credit(&t, &p).map(|c| c.1), Some(100));
+        assert_eq!(t.recv_budget(&p), 100)
+    }
+
+    #[test]
+    fn notifications() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+
+        // A notification uses up send budget, but is not awaiting a response.
+        let rid = t.send_notification(&p, ()).unwrap();
+        assert_eq!(t.send_budget(&p), 0);
+        assert!(t.send_notification(&p, ()).is_err());
+        assert!(!t.is_pending_outbound(&rid));
+        t.inject_event(p.clone(), ConnectionId::new(1), RequestResponseHandlerEvent::OutboundTimeout(rid));
+        assert!(poll_events(&mut t).is_empty());
+
+        // A received notification is reported without a response and
+        // credit is granted for the exhausted budget right away.
+        let mut rx = inject_request(&mut t, &p, 1, Message::notification(()));
+        let events = poll_events(&mut t);
+        assert!(events.iter().any(|e| matches!(e, Event::Notification { peer, request_id: RequestId(1), .. } if peer == &p)));
+        assert_eq!(inbound_requests(&events), 0);
+        assert!(rx.try_recv().is_err());
+        assert!(credit(&t, &p).is_some());
+
+        // The dropped response channel is not reported.
+        t.inject_event(p.clone(), ConnectionId::new(1), RequestResponseHandlerEvent::ResponseOmission(RequestId(1)));
+        assert!(poll_events(&mut t).is_empty());
+        assert!(!t.is_pending_inbound(&RequestId(1)));
+    }
+
+    #[test]
+    fn rejected_notifications_unanswered() {
+        let mut t = throttled();
+        t.set_reject_over_budget(true);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        // A notification beyond the receive budget is not answered.
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        let mut rx = inject_request(&mut t, &p, 2, Message::notification(()));
+        let events = poll_events(&mut t);
+        assert!(matches!(events.last(), Some(Event::TooManyInboundRequests(q)) if *q == p));
+        assert!(rx.try_recv().is_err());
+
+        // Neither is a notification received while draining.
+        t.begin_drain();
+        let mut rx = inject_request(&mut t, &p, 3, Message::notification(()));
+        assert!(!poll_events(&mut t).iter().any(|e| matches!(e, Event::Notification { .. })));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn pending_inbound() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        t.peer_info.get_mut(&p).unwrap().recv_budget = 2;
+
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        drop(inject_request(&mut t, &p, 2, Message::request(())));
+        let mut channels = poll_events(&mut t).into_iter()
+            .filter_map(|e| match e {
+                Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                }) => Some(channel),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(channels.len(), 2);
+        assert!(t.is_pending_inbound(&RequestId(1)));
+        assert!(t.is_pending_inbound(&RequestId(2)));
+        assert_eq!(t.pending_inbound_count(&p), 2);
+        assert_eq!(t.pending_inbound_count(&PeerId::random()), 0);
+
+        // The request is pending until it has been answered.
+        t.send_response(channels.remove(0), ());
+        assert!(!t.is_pending_inbound(&RequestId(1)));
+        assert!(t.is_pending_inbound(&RequestId(2)));
+        assert_eq!(t.pending_inbound_count(&p), 1);
+
+        // Requests of a closed connection are no longer pending.
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_closed(&p, &ConnectionId::new(1), &endpoint);
+        assert_eq!(t.pending_inbound_count(&p), 0)
+    }
+
+    #[test]
+    fn response_omission() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        // Omissions of responses to requests which were never passed on
+        // are not reported.
+        let omission = |id| RequestResponseHandlerEvent::ResponseOmission(RequestId(id));
+        t.inject_event(p.clone(), ConnectionId::new(1), omission(7));
+        assert!(poll_events(&mut t).is_empty());
+
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        t.inject_event(p.clone(), ConnectionId::new(1), omission(1));
+        assert!(poll_events(&mut t).iter().any(|e| matches!(e, Event::Event(RequestResponseEvent::InboundFailure {
+            request_id: RequestId(1),
+            error: InboundFailure::ResponseOmission,
+            ..
+        }))));
+    }
+
+    #[test]
+    fn malformed_response() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        let rid = t.send_request(&p, ()).unwrap();
+
+        let response = RequestResponseHandlerEvent::Response { request_id: rid, response: Message::malformed_response() };
+        t.inject_event(p.clone(), ConnectionId::new(1), response);
+        let events = poll_events(&mut t);
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            Event::MalformedResponse { peer, request_id } => {
+                assert_eq!(*peer, p);
+                assert_eq!(*request_id, rid)
+            }
+            e => panic!("unexpected event: {:?}", e)
+        }
+    }
+
+    #[test]
+    fn crossing_requests_do_not_ack_credit() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        t.peer_info.get_mut(&p).unwrap().recv_budget = 2;
+        assert!(t.grant_credit(&p, NonZeroU16::new(3).unwrap()));
+
+        // Requests within the budget before the grant may have been sent
+        // before the grant arrived.
+        for i in 0 .. 2 {
+            drop(inject_request(&mut t, &p, i, Message::request(())))
+        }
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 2);
+        assert!(credit(&t, &p).is_some());
+
+        drop(inject_request(&mut t, &p, 2, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert!(credit(&t, &p).is_none());
+        assert_eq!(t.recv_budget(&p), 2);
+    }
+
+    #[test]
+    fn late_ack_ignored() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let p = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        let (id, old) = (t.credit_messages[&p].id, t.credit_messages[&p].request.unwrap());
+
+        // A new credit grant is issued with the same ID, as may happen after
+        // restoring state, while the ack of the old grant is still in flight.
+        t.credit_messages.remove(&p);
+        t.credit_id = id;
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        let new = t.credit_messages[&p].request.unwrap();
+        assert_eq!(t.credit_messages[&p].id, id);
+        assert_ne!(old, new);
+
+        let ack = |rid| RequestResponseHandlerEvent::Response { request_id: rid, response: Message::ack(id) };
+        t.inject_event(p.clone(), ConnectionId::new(1), ack(old));
+        poll_events(&mut t);
+        assert_eq!(t.credit_messages.get(&p).and_then(|c| c.request), Some(new));
+
+        t.inject_event(p.clone(), ConnectionId::new(1), ack(new));
+        poll_events(&mut t);
+        assert!(!t.credit_messages.contains_key(&p));
+    }
+
+    #[test]
+    fn force_receive_limit() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(10).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+        assert_eq!(t.recv_budget(&p), 10);
+        let (id, _) = credit(&t, &p).unwrap();
+        t.receive_ack(&p, t.credit_messages[&p].request.unwrap(), Some(id));
+
+        for i in 0 .. 2 {
+            drop(inject_request(&mut t, &p, i, Message::request(())))
+        }
+        poll_events(&mut t);
+        assert_eq!(t.recv_budget(&p), 8);
+
+        // A lower limit applies before the current budget is used up.
+        t.force_receive_limit(&p, NonZeroU16::new(3).unwrap());
+        assert_eq!(t.recv_budget(&p), 3);
+        assert_eq!(t.receive_limit_override(&p), NonZeroU32::new(3));
+        for i in 2 .. 6 {
+            drop(inject_request(&mut t, &p, i, Message::request(())))
+        }
+        let events = poll_events(&mut t);
+        assert_eq!(inbound_requests(&events), 3);
+        assert!(matches!(events.last(), Some(Event::TooManyInboundRequests(q)) if *q == p));
+
+        // A higher limit is granted as additional credit.
+        t.force_receive_limit(&p, NonZeroU16::new(5).unwrap());
+        assert_eq!(t.recv_budget(&p), 2);
+        assert_eq!(granted(&mut t), vec![2]);
+    }
+
+    #[test]
+    fn unthrottled_threshold() {
+        let mut t = throttled();
+        t.set_unthrottled_threshold(10);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        assert!(t.grant_credit(&p, NonZeroU16::new(20).unwrap()));
+        let (id, _) = credit(&t, &p).unwrap();
+        let prior = t.credit_messages[&p].prior;
+
+        // Requests leaving the budget above the threshold do not take
+        // part in the credit bookkeeping.
+        for i in 0 .. 9 {
+            drop(inject_request(&mut t, &p, i, Message::request(())))
+        }
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 9);
+        assert_eq!(t.recv_budget(&p), 11);
+        assert_eq!(credit(&t, &p), Some((id, 20)));
+        assert_eq!(t.credit_messages[&p].prior, prior);
+
+        // Crossing the threshold engages the bookkeeping again.
+        drop(inject_request(&mut t, &p, 9, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+        assert_eq!(t.recv_budget(&p), 10);
+        assert!(credit(&t, &p).is_none())
+    }
+
+    #[test]
+    fn last_activity() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_initial_send_budget(NonZeroU16::new(5).unwrap());
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        assert_eq!(t.last_activity(&p), None);
+
+        let start = *now.lock().unwrap();
+        t.send_request(&p, ()).unwrap();
+        assert_eq!(t.last_activity(&p), Some(start));
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        drop(inject_request(&mut t, &p, 1, Message::request(())));
+        poll_events(&mut t);
+        assert_eq!(t.last_activity(&p), Some(start + Duration::from_secs(1)));
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        t.send_request_weighted(&p, (), NonZeroU32::new(2).unwrap()).unwrap();
+        assert_eq!(t.last_activity(&p), Some(start + Duration::from_secs(2)));
+
+        // The timestamp is kept while the peer is disconnected.
+        t.inject_disconnected(&p);
+        assert_eq!(t.last_activity(&p), Some(start + Duration::from_secs(2)))
+    }
+
+    #[test]
+    fn size_histogram() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(10).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        drop(inject_request(&mut t, &p, 0, Message::request(()).with_data_len(1000)));
+        poll_events(&mut t);
+        assert_eq!(t.stats().request_sizes.count(), 0);
+
+        t.set_collect_sizes(true);
+        for (i, n) in [0, 64, 65, 1000, 5_000_000].iter().enumerate() {
+            drop(inject_request(&mut t, &p, i as u64 + 1, Message::request(()).with_data_len(*n)))
+        }
+        poll_events(&mut t);
+        let buckets = t.stats().request_sizes.buckets().collect::<Vec<_>>();
+        assert_eq!(buckets.len(), 10);
+        assert_eq!(buckets[0], (Some(64), 2));
+        assert_eq!(buckets[1], (Some(256), 1));
+        assert_eq!(buckets[2], (Some(1024), 1));
+        assert_eq!(buckets[8], (Some(4 * 1024 * 1024), 0));
+        assert_eq!(buckets[9], (None, 1));
+        assert_eq!(t.stats().request_sizes.count(), 5);
+        assert_eq!(t.stats().response_sizes.count(), 0)
+    }
+
+    #[test]
+    fn events_shrink_threshold() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let burst = |t: &mut Throttled<TestCodec>| {
+            for _ in 0 .. 200 {
+                t.events.push_back(Event::ResumeSending(p.clone()))
+            }
+            assert_eq!(poll_events(t).len(), 200);
+        };
+
+        burst(&mut t);
+        assert!(t.events.capacity() <= crate::EMPTY_QUEUE_SHRINK_THRESHOLD);
+
+        t.set_events_shrink_threshold(1000);
+        burst(&mut t);
+        assert!(t.events.capacity() >= 200)
+    }
+
+    /// A logger which captures all formatted log messages.
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            self.0.lock().unwrap().push(record.args().to_string())
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn log_id() {
+        let logger: &'static CaptureLogger = Box::leak(Box::new(CaptureLogger(Mutex::new(Vec::new()))));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut t = throttled();
+        t.set_log_id(0xdead_beef);
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        assert!(logger.0.lock().unwrap().iter().any(|m| m == "deadbeef: new default limit: 5"))
+    }
+
+    /// All events emitted for a peer which exceeded its budget.
+    fn exceed_budget(t: &mut Throttled<TestCodec>, p: &PeerId) -> Vec<String> {
+        t.events.clear();
+        t.exceeded_budget(p);
+        t.events.drain(..).map(|e| format!("{:?}", e)).collect()
+    }
+
+    #[test]
+    fn over_budget_ignore() {
+        let mut t = throttled();
+        let p = PeerId::random();
+        let events = exceed_budget(&mut t, &p);
+        assert_eq!(events, vec![format!("TooManyInboundRequests({:?})", p)]);
+        assert!(!t.is_banned(&p));
+    }
+
+    #[test]
+    fn over_budget_disconnect() {
+        let mut t = throttled();
+        t.set_over_budget_action(OverBudgetAction::Disconnect);
+        let p = PeerId::random();
+        let events = exceed_budget(&mut t, &p);
+        assert_eq!(events, vec![
+            format!("TooManyInboundRequests({:?})", p),
+            format!("Disconnect({:?})", p)
+        ]);
+        assert!(!t.is_banned(&p));
+    }
+
+    #[test]
+    fn over_budget_ban() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_over_budget_action(OverBudgetAction::Ban { duration: Duration::from_secs(60) });
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+        let p = PeerId::random();
+        let events = exceed_budget(&mut t, &p);
+        assert_eq!(events, vec![format!("TooManyInboundRequests({:?})", p)]);
+        assert!(t.is_banned(&p));
+        assert!(t.check_ban(&p));
+
+        *now.lock().unwrap() += Duration::from_secs(60);
+        assert!(!t.is_banned(&p));
+        assert!(!t.check_ban(&p));
+        assert!(!t.banned.contains_key(&p));
+    }
+
+    #[test]
+    fn auto_ban() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut t = throttled();
+        t.set_auto_ban(NonZeroU32::new(3).unwrap(), Duration::from_secs(10), Duration::from_secs(60));
+        t.set_clock({
+            let now = now.clone();
+            move || *now.lock().unwrap()
+        });
+        let p = PeerId::random();
+
+        // Violations outside of the window do not count.
+        exceed_budget(&mut t, &p);
+        exceed_budget(&mut t, &p);
+        *now.lock().unwrap() += Duration::from_secs(10);
+        exceed_budget(&mut t, &p);
+        assert!(!t.is_banned(&p));
+
+        exceed_budget(&mut t, &p);
+        assert!(!t.is_banned(&p));
+        exceed_budget(&mut t, &p);
+        assert!(t.is_banned(&p));
+        assert!(!t.violations.contains_key(&p));
+
+        *now.lock().unwrap() += Duration::from_secs(59);
+        assert!(t.check_ban(&p));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert!(!t.check_ban(&p));
+
+        // The count starts afresh after the ban.
+        exceed_budget(&mut t, &p);
+        assert!(!t.is_banned(&p))
+    }
+
+    /// The amounts of all `Event::CreditGranted` events emitted so far.
+    fn granted(t: &mut Throttled<TestCodec>) -> Vec<u32> {
+        t.events.drain(..)
+            .filter_map(|e| match e {
+                Event::CreditGranted { amount, .. } => Some(amount),
+                _ => None
+            })
+            .collect()
+    }
+
+    #[test]
+    fn credit_granted_event() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let p = PeerId::random();
+        t.inject_connected(&p);
+
+        respond(&mut t, &p);
+        assert!(granted(&mut t).is_empty());
+
+        for _ in 0 .. 2 {
+            use_recv_budget(&mut t, &p);
+            respond(&mut t, &p);
+            assert_eq!(granted(&mut t), vec![5]);
+        }
+
+        // The remaining budget is granted again after reconnecting.
+        t.peer_info.get_mut(&p).unwrap().recv_budget = 3;
+        t.inject_disconnected(&p);
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        assert_eq!(granted(&mut t), vec![2]);
+    }
+
+    #[test]
+    fn offline_cache_capacity() {
+        let mut t = throttled();
+        t.set_offline_cache_capacity(3);
+
+        let peers = (0 .. 4).map(|_| PeerId::random()).collect::<Vec<_>>();
+        for p in &peers {
+            connect_and_disconnect(&mut t, p)
+        }
+        assert_eq!(t.offline_peer_info.len(), 3);
+        assert!(!t.offline_peer_info.contains(&peers[0]));
+        assert!(peers[1 ..].iter().all(|p| t.offline_peer_info.contains(p)));
+
+        // Shrinking evicts the least recently used entries.
+        t.set_offline_cache_capacity(1);
+        assert_eq!(t.offline_peer_info.len(), 1);
+        assert!(t.offline_peer_info.contains(&peers[3]));
+    }
+
+    #[test]
+    fn offline_cache_disabled() {
+        let mut t = throttled();
+        t.set_offline_cache_capacity(0);
+        let p = PeerId::random();
+        t.inject_connected(&p);
+        t.receive_credit(&p, 1, 10);
+        respond(&mut t, &p);
+        t.send_request(&p, ()).unwrap();
+        assert_eq!(t.peer_info[&p].send_budget_id, Some(1));
+        assert_eq!(t.peer_info[&p].stats.requests_served, 1);
+        assert!(t.last_activity(&p).is_some());
+
+        // Nothing is retained while the peer is disconnected.
+        t.inject_disconnected(&p);
+        assert_eq!(t.offline_cache_len(), 0);
+        assert!(t.last_activity(&p).is_none());
+
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        t.inject_connection_established(&p, &ConnectionId::new(1), &endpoint);
+        t.inject_connected(&p);
+        assert_eq!(t.peer_info[&p].send_budget_id, None);
+        assert_eq!(t.peer_info[&p].stats.requests_served, 0);
+        assert_eq!(t.send_budget(&p), 1);
+        assert_eq!(t.recv_budget(&p), 1);
+        assert!(t.last_activity(&p).is_none())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn debug_snapshot() {
+        let mut t = throttled();
+        t.set_receive_limit(NonZeroU16::new(5).unwrap());
+        let (p, q) = (PeerId::random(), PeerId::random());
+        connect_and_disconnect(&mut t, &q);
+        t.inject_connected(&p);
+        use_recv_budget(&mut t, &p);
+        respond(&mut t, &p);
+
+        let json = serde_json_crate::to_value(t.debug_snapshot()).unwrap();
+        let (p, q) = (p.to_base58(), q.to_base58());
+        assert_eq!(json["connected"][&p]["recv_budget"], 5);
+        assert_eq!(json["connected"][&p]["limit"]["max_recv"], 5);
+        assert_eq!(json["offline"][&q]["send_budget"], 1);
+        assert_eq!(json["default_limit"]["next_max"], 5);
+        assert_eq!(json["credit_messages"][&p]["amount"], 5)
+    }
+
+    #[test]
+    fn clear_offline_cache() {
+        let mut t = throttled();
+        let peers = (0 .. 3).map(|_| PeerId::random()).collect::<Vec<_>>();
+        for p in &peers {
+            connect_and_disconnect(&mut t, p)
+        }
+        let connected = PeerId::random();
+        t.inject_connected(&connected);
+        assert_eq!(t.offline_cache_len(), 3);
+
+        t.clear_offline_cache();
+        assert_eq!(t.offline_cache_len(), 0);
+        assert!(t.peer_info.contains_key(&connected));
+    }
+
+    #[test]
+    fn protocol_support_per_peer() {
+        let mut t = throttled();
+        t.set_reject_over_budget(true);
+        let endpoint = ConnectedPoint::Dialer { address: Multiaddr::empty() };
+        let (p1, p2) = (PeerId::random(), PeerId::random());
+        for p in &[&p1, &p2] {
+            t.inject_connection_established(p, &ConnectionId::new(1), &endpoint);
+            t.inject_connected(p);
+        }
+
+        // A request received before inbound support is disabled completes.
+        let mut rx = inject_request(&mut t, &p1, 1, Message::request(()));
+        let channel = poll_events(&mut t).into_iter()
+            .find_map(|e| match e {
+                Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                }) => Some(channel),
+                _ => None
+            })
+            .unwrap();
+        t.set_protocol_support(&p1, ProtocolSupport::Outbound);
+        assert_eq!(t.protocol_support(&p1), ProtocolSupport::Outbound);
+        t.send_response(channel, ());
+        assert_eq!(rx.try_recv().unwrap().unwrap().header().typ, Some(Type::Response));
+        let _ = poll_events(&mut t);
+
+        // New requests of the peer are rejected, but credit is still granted
+        // once the grant for the first request has been acknowledged.
+        t.credit_messages.remove(&p1);
+        let mut rx = inject_request(&mut t, &p1, 2, Message::request(()));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 0);
+        assert_eq!(rx.try_recv().unwrap().unwrap().header().typ, Some(Type::Throttled));
+        assert!(credit(&t, &p1).is_some());
+        assert!(t.send_request(&p1, ()).is_ok());
+
+        // Requests of other peers are not affected.
+        drop(inject_request(&mut t, &p2, 3, Message::request(())));
+        assert_eq!(inbound_requests(&poll_events(&mut t)), 1);
+
+        // Requests to a peer without outbound support are returned or queued.
+        t.set_protocol_support(&p2, ProtocolSupport::Inbound);
+        assert!(t.send_request(&p2, ()).is_err());
+        assert!(matches!(t.try_send_request(&p2, ()), TrySend::Paused(())));
+        assert_eq!(t.send_request_with_priority(&p2, (), Priority::Low), None);
+        let _ = poll_events(&mut t);
+
+        t.set_protocol_support(&p2, ProtocolSupport::Full);
+        assert_eq!(t.protocol_support(&p2), ProtocolSupport::Full);
+        assert!(poll_events(&mut t).iter().any(|e| matches!(e, Event::RequestSent { peer, .. } if peer == &p2)));
+        assert_eq!(t.send_budget(&p2), 0)
     }
 }
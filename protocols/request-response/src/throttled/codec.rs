@@ -23,42 +23,285 @@ use bytes::{Bytes, BytesMut};
 use futures::prelude::*;
 use libp2p_core::ProtocolName;
 use minicbor::{Encode, Decode};
-use std::io;
+use std::{convert::TryFrom, fmt, io, pin::Pin, sync::Arc, task::{Context, Poll}};
 use super::RequestResponseCodec;
+use crate::codec::{MessageTooLarge, is_message_too_large};
 use unsigned_varint::{aio, io::ReadError};
 
+/// The version of the protocol header this codec reads and writes.
+///
+/// Version 2 introduced compressed messages. Only headers of compressed
+/// messages carry this version, so that peers without support for them
+/// skip these messages instead of misreading them.
+pub const VERSION: u32 = 2;
+
 /// A protocol header.
+///
+/// Fields are encoded as CBOR map entries and decoders skip entries they do
+/// not know, so new optional fields can be added without a version change.
+/// Incompatible changes must increment [`VERSION`].
 #[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
 #[cbor(map)]
 pub struct Header {
-    /// The type of message.
+    /// The type of message, `None` if missing or unknown.
     #[n(0)] pub typ: Option<Type>,
-    /// The number of additional requests the remote is willing to receive.
-    #[n(1)] pub credit: Option<u16>,
+    /// The number of additional requests (or bytes) the remote is willing to receive.
+    #[n(1)] pub credit: Option<u32>,
     /// An identifier used for sending credit grants.
-    #[n(2)] pub ident: Option<u64>
+    #[n(2)] pub ident: Option<u64>,
+    /// The number of budget units a request uses up, if not the default.
+    #[n(3)] pub weight: Option<u32>,
+    /// The header version, if not version 1.
+    #[n(4)] pub version: Option<u32>,
+    /// The compression of the data following the header, if any.
+    #[n(5)] pub compression: Option<Compression>
+}
+
+impl Header {
+    /// The version of this header.
+    pub fn version(&self) -> u32 {
+        self.version.unwrap_or(1)
+    }
 }
 
 /// A protocol message type.
 #[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
 pub enum Type {
+    /// A request carrying data of the inner codec.
     #[n(0)] Request,
+    /// A response carrying data of the inner codec.
     #[n(1)] Response,
+    /// A credit grant, sent as a request.
     #[n(2)] Credit,
-    #[n(3)] Ack
+    /// The acknowledgement of a credit grant, sent as its response.
+    #[n(3)] Ack,
+    /// The response to a request which exceeded the receive budget.
+    #[n(4)] Throttled,
+    /// A request carrying data of the inner codec to which no response
+    /// is sent.
+    #[n(5)] Notification
+}
+
+impl Type {
+    /// The tag of this type in the compact header encoding.
+    fn tag(&self) -> u8 {
+        match self {
+            Type::Request => 1,
+            Type::Response => 2,
+            Type::Credit => 3,
+            Type::Ack => 4,
+            Type::Throttled => 5,
+            Type::Notification => 6
+        }
+    }
+
+    /// The type of a compact header tag, if known.
+    ///
+    /// Like with CBOR headers, unknown types are decoded as `None`.
+    fn from_tag(tag: u8) -> Option<Type> {
+        match tag {
+            1 => Some(Type::Request),
+            2 => Some(Type::Response),
+            3 => Some(Type::Credit),
+            4 => Some(Type::Ack),
+            5 => Some(Type::Throttled),
+            6 => Some(Type::Notification),
+            _ => None
+        }
+    }
+}
+
+/// The compression of the data of a message.
+///
+/// Compressed data is preceded by its length as an unsigned varint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde_crate::Serialize, serde_crate::Deserialize))]
+#[cfg_attr(feature = "serde", serde(crate = "serde_crate"))]
+pub enum Compression {
+    /// Raw DEFLATE (RFC 1951).
+    #[n(0)] Deflate
+}
+
+/// The format of the headers a [`Codec`] reads and writes.
+pub trait HeaderFormat: fmt::Debug + Send + Sync {
+    /// Append the encoding of a header to the given buffer.
+    fn encode(&self, hdr: &Header, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Decode a header.
+    fn decode(&self, buf: &[u8]) -> io::Result<Header>;
+
+    /// Whether headers of this format can not be told apart from the
+    /// default encodings, so that peers using it must negotiate protocols
+    /// with a prefix other than [`DEFAULT_PREFIX`](super::DEFAULT_PREFIX).
+    fn requires_own_prefix(&self) -> bool {
+        true
+    }
+}
+
+/// The built-in encodings of the headers a [`Codec`] writes.
+///
+/// Headers encoded with CBOR or the compact encoding are always read, so
+/// peers may use different encodings as long as both know the compact
+/// encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderEncoding {
+    /// A CBOR map (the default).
+    Cbor,
+    /// A tag byte followed by unsigned varints of the fields present.
+    ///
+    /// The tag byte holds the message type in its lowest three bits and
+    /// one bit for each optional field. Its highest bit is always unset,
+    /// which distinguishes it from the first byte of a CBOR map. There is
+    /// no bit for the compression, so headers of compressed messages are
+    /// encoded with CBOR.
+    Compact
+}
+
+impl HeaderFormat for HeaderEncoding {
+    fn encode(&self, hdr: &Header, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            HeaderEncoding::Compact if hdr.compression.is_none() => encode_compact(hdr, buf),
+            HeaderEncoding::Cbor | HeaderEncoding::Compact => minicbor::encode(hdr, buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        }
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8]) -> io::Result<Header> {
+        match buf.first() {
+            // A CBOR map has major type 5.
+            Some(b) if b >> 5 == 5 =>
+                minicbor::decode(buf).map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            _ => decode_compact(buf)
+        }
+    }
+
+    fn requires_own_prefix(&self) -> bool {
+        false
+    }
+}
+
+/// Headers encoded as JSON objects, e.g. for debugging.
+#[cfg(feature = "serde_json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+#[cfg(feature = "serde_json")]
+impl HeaderFormat for JsonFormat {
+    fn encode(&self, hdr: &Header, buf: &mut Vec<u8>) -> io::Result<()> {
+        serde_json_crate::to_writer(buf, hdr).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn decode(&self, buf: &[u8]) -> io::Result<Header> {
+        serde_json_crate::from_slice(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Headers encoded with bincode.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeFormat;
+
+#[cfg(feature = "bincode")]
+impl HeaderFormat for BincodeFormat {
+    fn encode(&self, hdr: &Header, buf: &mut Vec<u8>) -> io::Result<()> {
+        bincode_crate::serialize_into(buf, hdr).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn decode(&self, buf: &[u8]) -> io::Result<Header> {
+        bincode_crate::deserialize(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+const COMPACT_TYPE_MASK: u8 = 0b0000_0111;
+const COMPACT_CREDIT: u8 = 0b0000_1000;
+const COMPACT_IDENT: u8 = 0b0001_0000;
+const COMPACT_WEIGHT: u8 = 0b0010_0000;
+const COMPACT_VERSION: u8 = 0b0100_0000;
+
+/// Encode a header with the compact encoding.
+fn encode_compact(hdr: &Header, buf: &mut Vec<u8>) {
+    let mut tag = hdr.typ.as_ref().map_or(0, Type::tag);
+    if hdr.credit.is_some() { tag |= COMPACT_CREDIT }
+    if hdr.ident.is_some() { tag |= COMPACT_IDENT }
+    if hdr.weight.is_some() { tag |= COMPACT_WEIGHT }
+    if hdr.version.is_some() { tag |= COMPACT_VERSION }
+    buf.push(tag);
+    let mut b = unsigned_varint::encode::u64_buffer();
+    let fields = [hdr.credit.map(u64::from), hdr.ident, hdr.weight.map(u64::from), hdr.version.map(u64::from)];
+    for n in fields.iter().flatten() {
+        buf.extend_from_slice(unsigned_varint::encode::u64(*n, &mut b))
+    }
+}
+
+/// Decode a header encoded with the compact encoding.
+fn decode_compact(buf: &[u8]) -> io::Result<Header> {
+    fn field(present: bool, buf: &mut &[u8]) -> io::Result<Option<u64>> {
+        if !present {
+            return Ok(None)
+        }
+        let (n, rest) = unsigned_varint::decode::u64(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        *buf = rest;
+        Ok(Some(n))
+    }
+    fn u32_field(present: bool, buf: &mut &[u8]) -> io::Result<Option<u32>> {
+        field(present, buf)?
+            .map(|n| u32::try_from(n).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+            .transpose()
+    }
+    let (tag, mut buf) = buf.split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty header"))?;
+    let header = Header {
+        typ: Type::from_tag(tag & COMPACT_TYPE_MASK),
+        credit: u32_field(tag & COMPACT_CREDIT != 0, &mut buf)?,
+        ident: field(tag & COMPACT_IDENT != 0, &mut buf)?,
+        weight: u32_field(tag & COMPACT_WEIGHT != 0, &mut buf)?,
+        version: u32_field(tag & COMPACT_VERSION != 0, &mut buf)?,
+        compression: None
+    };
+    if !buf.is_empty() || tag & 0b1000_0000 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed header"))
+    }
+    Ok(header)
 }
 
 /// A protocol message consisting of header and data.
+///
+/// [`Throttled`](super::Throttled) creates and consumes these messages
+/// itself. Messages sent or received bypassing it, e.g. through
+/// [`Throttled::inner_mut`](super::Throttled::inner_mut), are not
+/// accounted for, so that the budgets of both peers get out of sync.
+///
+/// ```
+/// use libp2p_request_response::throttled::{Message, Type};
+///
+/// let msg = Message::<()>::credit(5, 1);
+/// assert_eq!(msg.header().typ, Some(Type::Credit));
+/// assert_eq!(msg.header().credit, Some(5));
+/// assert_eq!(msg.header().ident, Some(1));
+/// assert_eq!(msg.into_parts().1, None);
+/// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message<T> {
     header: Header,
-    data: Option<T>
+    data: Option<T>,
+    /// The length of the encoded data, if read from the network.
+    data_len: Option<usize>,
+    /// The name of the protocol a request has been read with.
+    protocol: Option<Bytes>,
+    /// Has the data of a request exceeded the max. message size?
+    too_large: bool
 }
 
 impl<T> Message<T> {
     /// Create a new message of some type.
     fn new(header: Header) -> Self {
-        Message { header, data: None }
+        Message { header, data: None, data_len: None, protocol: None, too_large: false }
     }
 
     /// Create a request message.
@@ -68,6 +311,20 @@ impl<T> Message<T> {
         m
     }
 
+    /// Create a request message which uses up `weight` budget units.
+    pub fn weighted_request(data: T, weight: u32) -> Self {
+        let mut m = Message::request(data);
+        m.header.weight = Some(weight);
+        m
+    }
+
+    /// Create a request message to which no response is sent.
+    pub fn notification(data: T) -> Self {
+        let mut m = Message::new(Header { typ: Some(Type::Notification), .. Header::default() });
+        m.data = Some(data);
+        m
+    }
+
     /// Create a response message.
     pub fn response(data: T) -> Self {
         let mut m = Message::new(Header { typ: Some(Type::Response), .. Header::default() });
@@ -76,13 +333,48 @@ impl<T> Message<T> {
     }
 
     /// Create a credit grant.
-    pub fn credit(credit: u16, ident: u64) -> Self {
-        Message::new(Header { typ: Some(Type::Credit), credit: Some(credit), ident: Some(ident) })
+    pub fn credit(credit: u32, ident: u64) -> Self {
+        Message::new(Header { typ: Some(Type::Credit), credit: Some(credit), ident: Some(ident), .. Header::default() })
     }
 
     /// Create an acknowledge message.
     pub fn ack(ident: u64) -> Self {
-        Message::new(Header { typ: Some(Type::Ack), credit: None, ident: Some(ident) })
+        Message::new(Header { typ: Some(Type::Ack), ident: Some(ident), .. Header::default() })
+    }
+
+    /// Create a response to a request which exceeded the receive budget.
+    pub fn throttled() -> Self {
+        Message::new(Header { typ: Some(Type::Throttled), .. Header::default() })
+    }
+
+    /// Create a response message without data, as a misbehaving
+    /// inner codec or remote might produce.
+    #[cfg(test)]
+    pub fn malformed_response() -> Self {
+        Message::new(Header { typ: Some(Type::Response), .. Header::default() })
+    }
+
+    /// Set the header version, as if read from the network.
+    #[cfg(test)]
+    pub fn with_version(mut self, v: u32) -> Self {
+        self.header.version = Some(v);
+        self
+    }
+
+    /// Mark the data of a request as exceeding the max. message size,
+    /// as if read from the network.
+    #[cfg(test)]
+    pub fn into_too_large(mut self) -> Self {
+        self.data = None;
+        self.too_large = true;
+        self
+    }
+
+    /// Set the length of the encoded data, as if read from the network.
+    #[cfg(test)]
+    pub fn with_data_len(mut self, n: usize) -> Self {
+        self.data_len = Some(n);
+        self
     }
 
     /// Access the message header.
@@ -95,6 +387,24 @@ impl<T> Message<T> {
         self.data.as_ref()
     }
 
+    /// The number of bytes the data of a message read from the network
+    /// has been encoded with, excluding the header.
+    pub fn data_len(&self) -> Option<usize> {
+        self.data_len
+    }
+
+    /// The name of the protocol, without prefix, a request read from the
+    /// network has been received with.
+    pub fn protocol(&self) -> Option<&[u8]> {
+        self.protocol.as_deref()
+    }
+
+    /// Whether the data of a request read from the network has exceeded
+    /// the max. message size, so that it has not been read.
+    pub(super) fn is_too_large(&self) -> bool {
+        self.too_large
+    }
+
     /// Consume this message and return header and data.
     pub fn into_parts(self) -> (Header, Option<T>) {
         (self.header, self.data)
@@ -106,13 +416,25 @@ impl<T> Message<T> {
 /// The type implements `ProtocolName` itself and creates a name for a
 /// request-response protocol based on the protocol name of the wrapped type.
 #[derive(Debug, Clone)]
-pub struct ProtocolWrapper<P>(P, Bytes);
+pub struct ProtocolWrapper<P>(P, Bytes, usize);
 
 impl<P: ProtocolName> ProtocolWrapper<P> {
     pub fn new(prefix: &[u8], p: P) -> Self {
         let mut full = BytesMut::from(prefix);
         full.extend_from_slice(p.protocol_name());
-        ProtocolWrapper(p, full.freeze())
+        ProtocolWrapper(p, full.freeze(), prefix.len())
+    }
+}
+
+impl<P> ProtocolWrapper<P> {
+    /// The name of the wrapped protocol, without prefix.
+    fn inner_name(&self) -> Bytes {
+        self.1.slice(self.2 ..)
+    }
+
+    /// The prefix of the protocol name.
+    pub(super) fn prefix(&self) -> &[u8] {
+        &self.1[.. self.2]
     }
 }
 
@@ -123,27 +445,84 @@ impl<P> ProtocolName for ProtocolWrapper<P> {
 }
 
 /// A `RequestResponseCodec` wrapper that adds headers to the payload data.
+///
+/// Only the header is encoded by this codec. The payload is read and
+/// written by the wrapped codec directly from and to the stream, so it is
+/// never copied to prepend or strip the header.
 #[derive(Debug, Clone)]
 pub struct Codec<C> {
     /// The wrapped codec.
     inner: C,
-    /// Encoding/decoding buffer.
+    /// Encoding/decoding buffer, reused for the headers of all messages
+    /// so that encoding a header does not allocate.
     buffer: Vec<u8>,
     /// Max. header length.
-    max_header_len: u32
+    max_header_len: u32,
+    /// Max. length of the payload data read by the wrapped codec.
+    max_message_len: usize,
+    /// The format of headers read and written.
+    format: Arc<dyn HeaderFormat>,
+    /// The compression of the data written, if any.
+    compression: Option<Compression>
 }
 
 impl<C> Codec<C> {
     /// Create a codec by wrapping an existing one.
     pub fn new(c: C, max_header_len: u32) -> Self {
-        Codec { inner: c, buffer: Vec::new(), max_header_len }
+        Codec {
+            inner: c,
+            buffer: Vec::new(),
+            max_header_len,
+            max_message_len: usize::MAX,
+            format: Arc::new(HeaderEncoding::Cbor),
+            compression: None
+        }
+    }
+
+    /// Set the max. header length.
+    ///
+    /// Headers exceeding this length fail to be read or written with an
+    /// error of kind `InvalidData`.
+    pub fn set_max_header_len(&mut self, n: u32) {
+        self.max_header_len = n
+    }
+
+    /// Set the max. length of the payload data read (default: unlimited).
+    ///
+    /// The wrapped codec fails to read more data than this with a
+    /// [`MessageTooLarge`] error. Codecs which allocate buffers based on a
+    /// length prefix before reading should bound the length themselves.
+    pub fn set_max_message_len(&mut self, n: usize) {
+        self.max_message_len = n
+    }
+
+    /// Set the encoding of the headers written (default: CBOR).
+    pub fn set_header_encoding(&mut self, e: HeaderEncoding) {
+        self.format = Arc::new(e)
+    }
+
+    /// Set the format of the headers read and written.
+    pub fn set_header_format(&mut self, f: Arc<dyn HeaderFormat>) {
+        self.format = f
+    }
+
+    /// Set the compression of the data written (default: none).
+    ///
+    /// The data of a message is only sent compressed if that makes it
+    /// smaller. Compressed data is always read.
+    ///
+    /// Compression requires the data of a message to be buffered in full,
+    /// both when writing and when reading, so inner codecs no longer stream
+    /// their data to and from the substream.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, c: Option<Compression>) {
+        self.compression = c
     }
 
-    /// Read and decode a request header.
-    async fn read_header<T, H>(&mut self, io: &mut T) -> io::Result<H>
+    /// Read and decode a header.
+    async fn read_header<T>(&mut self, io: &mut T) -> io::Result<Header>
     where
-        T: AsyncRead + Unpin + Send,
-        H: for<'a> minicbor::Decode<'a>
+        T: AsyncRead + Unpin + Send
     {
         let header_len = aio::read_u32(&mut *io).await
             .map_err(|e| match e {
@@ -155,17 +534,44 @@ impl<C> Codec<C> {
         }
         self.buffer.resize(u32_to_usize(header_len), 0u8);
         io.read_exact(&mut self.buffer).await?;
-        minicbor::decode(&self.buffer).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.format.decode(&self.buffer)
     }
 
-    /// Encode and write a response header.
-    async fn write_header<T, H>(&mut self, hdr: &H, io: &mut T) -> io::Result<()>
+    /// Read and decode a message header.
+    ///
+    /// Headers of unsupported versions are returned as they are, without
+    /// any further checks, so that the message can be skipped.
+    async fn read_message_header<T>(&mut self, io: &mut T) -> io::Result<Header>
     where
-        T: AsyncWrite + Unpin + Send,
-        H: minicbor::Encode
+        T: AsyncRead + Unpin + Send
+    {
+        let header = self.read_header(io).await?;
+        if header.version() > VERSION {
+            log::debug!("unsupported header version {}", header.version());
+            return Ok(header)
+        }
+        #[cfg(not(feature = "compression"))]
+        {
+            if header.compression.is_some() {
+                log::debug!("compressed messages are not supported");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed messages are not supported"))
+            }
+        }
+        // Every request uses up budget, so a weight of zero is invalid.
+        if header.weight == Some(0) {
+            log::debug!("invalid request weight 0");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid request weight 0"))
+        }
+        Ok(header)
+    }
+
+    /// Encode and write a header.
+    async fn write_header<T>(&mut self, hdr: &Header, io: &mut T) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send
     {
         self.buffer.clear();
-        minicbor::encode(hdr, &mut self.buffer).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.format.encode(hdr, &mut self.buffer)?;
         if self.buffer.len() > u32_to_usize(self.max_header_len) {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "header too large to write"))
         }
@@ -174,6 +580,86 @@ impl<C> Codec<C> {
         io.write_all(header_len).await?;
         io.write_all(&self.buffer).await
     }
+
+    /// Write a message header followed by the data encoded by the
+    /// inner codec, compressed if that makes it smaller.
+    async fn write_compressed<T>(&mut self, mut hdr: Header, c: Compression, data: Vec<u8>, io: &mut T)
+        -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send
+    {
+        let compressed = compress(c, &data)?;
+        if compressed.len() >= data.len() {
+            self.write_header(&hdr, io).await?;
+            return io.write_all(&data).await
+        }
+        hdr.compression = Some(c);
+        hdr.version = Some(VERSION);
+        self.write_header(&hdr, io).await?;
+        let mut b = unsigned_varint::encode::usize_buffer();
+        io.write_all(unsigned_varint::encode::usize(compressed.len(), &mut b)).await?;
+        io.write_all(&compressed).await
+    }
+
+    /// Read and decompress the data of a message.
+    ///
+    /// The buffer grows with the data actually received, so a peer can not
+    /// make us allocate memory by merely announcing a large length.
+    #[cfg(feature = "compression")]
+    async fn read_compressed<T>(&mut self, c: Compression, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        let len = aio::read_usize(&mut *io).await
+            .map_err(|e| match e {
+                ReadError::Io(e) => e,
+                other => io::Error::new(io::ErrorKind::InvalidData, other)
+            })?;
+        // Data is only sent compressed if that makes it smaller.
+        if len > self.max_message_len {
+            return Err(MessageTooLarge { limit: self.max_message_len }.into())
+        }
+        let mut buf = Vec::new();
+        (&mut *io).take(u64::try_from(len).unwrap_or(u64::MAX)).read_to_end(&mut buf).await?;
+        if buf.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        }
+        decompress(c, &buf, self.max_message_len)
+    }
+
+    /// Compressed messages are rejected with the header already.
+    #[cfg(not(feature = "compression"))]
+    async fn read_compressed<T>(&mut self, _: Compression, _: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "compressed messages are not supported"))
+    }
+}
+
+impl<C> Codec<C>
+where
+    C: RequestResponseCodec + Send,
+    C::Protocol: Sync
+{
+    /// Read the data of a request and return it with its length.
+    async fn read_request_data<T>(&mut self, p: &C::Protocol, c: Option<Compression>, io: &mut T)
+        -> io::Result<(C::Request, usize)>
+    where
+        T: AsyncRead + Unpin + Send
+    {
+        if let Some(c) = c {
+            // The length of the uncompressed data is recorded, so
+            // that byte budgets do not depend on the compression.
+            let data = self.read_compressed(c, io).await?;
+            let len = data.len();
+            let mut io = futures::io::Cursor::new(data);
+            return Ok((self.inner.read_request(p, &mut io).await?, len))
+        }
+        let mut io = CountingReader::new(io, self.max_message_len);
+        let data = self.inner.read_request(p, &mut io).await;
+        Ok((io.check(data)?, io.count))
+    }
 }
 
 #[async_trait]
@@ -190,16 +676,30 @@ where
     where
         T: AsyncRead + Unpin + Send
     {
-        let mut msg = Message::new(self.read_header(io).await?);
+        let mut msg = Message::new(self.read_message_header(io).await?);
         match msg.header.typ {
-            Some(Type::Request) => {
-                msg.data = Some(self.inner.read_request(&p.0, io).await?);
+            // The data of an unsupported version can not be read.
+            _ if msg.header.version() > VERSION => Ok(msg),
+            Some(Type::Request) | Some(Type::Notification) => {
+                msg.protocol = Some(p.inner_name());
+                match self.read_request_data(&p.0, msg.header.compression, io).await {
+                    Ok((data, len)) => {
+                        msg.data = Some(data);
+                        msg.data_len = Some(len)
+                    }
+                    // An oversized request is passed on without data, so
+                    // that `Throttled` can account for the budget it used up.
+                    Err(e) if is_message_too_large(&e) => msg.too_large = true,
+                    Err(e) => return Err(e)
+                }
                 Ok(msg)
             }
             Some(Type::Credit) => Ok(msg),
-            Some(Type::Response) | Some(Type::Ack) | None => {
+            // Messages of unexpected or unknown types are passed on
+            // without data, to be accounted for by `Throttled`.
+            Some(Type::Response) | Some(Type::Ack) | Some(Type::Throttled) | None => {
                 log::debug!("unexpected {:?} when expecting request or credit grant", msg.header.typ);
-                Err(io::ErrorKind::InvalidData.into())
+                Ok(msg)
             }
         }
     }
@@ -208,16 +708,32 @@ where
     where
         T: AsyncRead + Unpin + Send
     {
-        let mut msg = Message::new(self.read_header(io).await?);
+        let mut msg = Message::new(self.read_message_header(io).await?);
         match msg.header.typ {
+            // The data of an unsupported version can not be read.
+            _ if msg.header.version() > VERSION => Ok(msg),
             Some(Type::Response) => {
-                msg.data = Some(self.inner.read_response(&p.0, io).await?);
+                if let Some(c) = msg.header.compression {
+                    // The length of the uncompressed data is recorded, so
+                    // that byte budgets do not depend on the compression.
+                    let data = self.read_compressed(c, io).await?;
+                    msg.data_len = Some(data.len());
+                    let mut io = futures::io::Cursor::new(data);
+                    msg.data = Some(self.inner.read_response(&p.0, &mut io).await?);
+                    return Ok(msg)
+                }
+                let mut io = CountingReader::new(io, self.max_message_len);
+                let data = self.inner.read_response(&p.0, &mut io).await;
+                msg.data = Some(io.check(data)?);
+                msg.data_len = Some(io.count);
                 Ok(msg)
             }
-            Some(Type::Ack) => Ok(msg),
-            Some(Type::Request) | Some(Type::Credit) | None => {
+            Some(Type::Ack) | Some(Type::Throttled) => Ok(msg),
+            // Messages of unexpected or unknown types are passed on
+            // without data, to be accounted for by `Throttled`.
+            Some(Type::Request) | Some(Type::Credit) | Some(Type::Notification) | None => {
                 log::debug!("unexpected {:?} when expecting response or ack", msg.header.typ);
-                Err(io::ErrorKind::InvalidData.into())
+                Ok(msg)
             }
         }
     }
@@ -226,26 +742,722 @@ where
     where
         T: AsyncWrite + Unpin + Send
     {
-        self.write_header(&r.header, io).await?;
-        if let Some(data) = r.data {
-            self.inner.write_request(&p.0, io, data).await?
+        match (r.data, self.compression) {
+            (Some(data), Some(c)) => {
+                let mut buf = Vec::new();
+                self.inner.write_request(&p.0, &mut buf, data).await?;
+                self.write_compressed(r.header, c, buf, io).await
+            }
+            (Some(data), None) => {
+                self.write_header(&r.header, io).await?;
+                self.inner.write_request(&p.0, io, data).await
+            }
+            (None, _) => self.write_header(&r.header, io).await
         }
-        Ok(())
     }
 
     async fn write_response<T>(&mut self, p: &Self::Protocol, io: &mut T, r: Self::Response) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send
     {
-        self.write_header(&r.header, io).await?;
-        if let Some(data) = r.data {
-            self.inner.write_response(&p.0, io, data).await?
+        match (r.data, self.compression) {
+            (Some(data), Some(c)) => {
+                let mut buf = Vec::new();
+                self.inner.write_response(&p.0, &mut buf, data).await?;
+                self.write_compressed(r.header, c, buf, io).await
+            }
+            (Some(data), None) => {
+                self.write_header(&r.header, io).await?;
+                self.inner.write_response(&p.0, io, data).await
+            }
+            (None, _) => self.write_header(&r.header, io).await
         }
-        Ok(())
     }
 }
 
+/// An `AsyncRead` wrapper which counts the number of bytes read and
+/// fails reads beyond a limit.
+struct CountingReader<'a, T> {
+    io: &'a mut T,
+    count: usize,
+    limit: usize,
+    exceeded: bool
+}
+
+impl<'a, T> CountingReader<'a, T> {
+    fn new(io: &'a mut T, limit: usize) -> Self {
+        CountingReader { io, count: 0, limit, exceeded: false }
+    }
+
+    /// Check the result of reading through this reader.
+    ///
+    /// The wrapped codec may not pass on our error unchanged, so
+    /// exceeding the limit is reported here regardless.
+    fn check<R>(&self, result: io::Result<R>) -> io::Result<R> {
+        if self.exceeded {
+            return Err(MessageTooLarge { limit: self.limit }.into())
+        }
+        result
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingReader<'_, T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = &mut *self;
+        // Read at most one byte beyond the limit to detect whether it
+        // is exceeded without consuming the data of the stream.
+        let max = this.limit.saturating_sub(this.count).saturating_add(1);
+        let len = buf.len().min(max);
+        let n = futures::ready!(Pin::new(&mut *this.io).poll_read(cx, &mut buf[.. len]))?;
+        this.count += n;
+        if this.count > this.limit {
+            this.exceeded = true;
+            return Poll::Ready(Err(MessageTooLarge { limit: this.limit }.into()))
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Compress the data of a message.
+#[cfg(feature = "compression")]
+fn compress(c: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Write;
+    match c {
+        Compression::Deflate => {
+            let mut e = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            e.write_all(data)?;
+            e.finish()
+        }
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_: Compression, _: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, "compression is not supported"))
+}
+
+/// Decompress the data of a message, reading at most `limit` bytes.
+#[cfg(feature = "compression")]
+fn decompress(c: Compression, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+    let max = u64::try_from(limit).unwrap_or(u64::MAX).saturating_add(1);
+    let mut buf = Vec::new();
+    match c {
+        Compression::Deflate => flate2::read::DeflateDecoder::new(data).take(max).read_to_end(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    };
+    if buf.len() > limit {
+        return Err(MessageTooLarge { limit }.into())
+    }
+    Ok(buf)
+}
+
 #[cfg(any(target_pointer_width = "64", target_pointer_width = "32"))]
 fn u32_to_usize(n: u32) -> usize {
     n as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p_core::upgrade::{read_one, write_one};
+    use super::*;
+
+    const MAX_PAYLOAD: usize = 8 * 1024 * 1024;
+
+    #[derive(Debug, Clone)]
+    struct RawProtocol;
+
+    impl ProtocolName for RawProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            b"/raw/1"
+        }
+    }
+
+    /// An inner codec which writes its payload directly to the stream.
+    #[derive(Debug, Clone)]
+    struct RawCodec;
+
+    #[async_trait]
+    impl RequestResponseCodec for RawCodec {
+        type Protocol = RawProtocol;
+        type Request = Vec<u8>;
+        type Response = Vec<u8>;
+
+        async fn read_request<T>(&mut self, _: &RawProtocol, io: &mut T) -> io::Result<Vec<u8>>
+        where
+            T: AsyncRead + Unpin + Send
+        {
+            read_one(io, MAX_PAYLOAD).await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        async fn read_response<T>(&mut self, _: &RawProtocol, io: &mut T) -> io::Result<Vec<u8>>
+        where
+            T: AsyncRead + Unpin + Send
+        {
+            read_one(io, MAX_PAYLOAD).await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        async fn write_request<T>(&mut self, _: &RawProtocol, io: &mut T, r: Vec<u8>) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send
+        {
+            write_one(io, r).await
+        }
+
+        async fn write_response<T>(&mut self, _: &RawProtocol, io: &mut T, r: Vec<u8>) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send
+        {
+            write_one(io, r).await
+        }
+    }
+
+    fn header() -> Header {
+        Header { typ: Some(Type::Credit), credit: Some(u32::MAX), ident: Some(u64::MAX), .. Header::default() }
+    }
+
+    fn encoded_len(h: &Header) -> u32 {
+        let mut buf = Vec::new();
+        minicbor::encode(h, &mut buf).unwrap();
+        buf.len() as u32
+    }
+
+    #[test]
+    fn header_at_max_len() {
+        let hdr = header();
+        let mut codec = Codec::new((), encoded_len(&hdr));
+
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_header(&hdr, &mut io)).unwrap();
+        io.set_position(0);
+        let decoded: Header = block_on(codec.read_header(&mut io)).unwrap();
+        assert_eq!(decoded, hdr)
+    }
+
+    #[test]
+    fn header_buffer_reused() {
+        let hdr = header();
+        let mut codec = Codec::new((), 8192);
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_header(&hdr, &mut io)).unwrap();
+        let (ptr, cap) = (codec.buffer.as_ptr(), codec.buffer.capacity());
+        for _ in 0 .. 10 {
+            block_on(codec.write_header(&hdr, &mut io)).unwrap();
+            assert_eq!(codec.buffer.as_ptr(), ptr);
+            assert_eq!(codec.buffer.capacity(), cap)
+        }
+    }
+
+    #[test]
+    fn header_exceeding_max_len() {
+        let hdr = header();
+        let mut codec = Codec::new((), encoded_len(&hdr) - 1);
+
+        let mut io = Cursor::new(Vec::new());
+        let e = block_on(codec.write_header(&hdr, &mut io)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(io.get_ref().is_empty());
+
+        codec.set_max_header_len(encoded_len(&hdr));
+        block_on(codec.write_header(&hdr, &mut io)).unwrap();
+        io.set_position(0);
+        codec.set_max_header_len(encoded_len(&hdr) - 1);
+        let e = block_on(codec.read_header(&mut io)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn header_encodings() {
+        let types = [
+            None,
+            Some(Type::Request),
+            Some(Type::Response),
+            Some(Type::Credit),
+            Some(Type::Ack),
+            Some(Type::Throttled),
+            Some(Type::Notification)
+        ];
+        for typ in &types {
+            let headers = [
+                Header { typ: typ.clone(), .. Header::default() },
+                Header {
+                    typ: typ.clone(),
+                    credit: Some(u32::MAX),
+                    ident: Some(u64::MAX),
+                    weight: Some(3),
+                    version: Some(VERSION),
+                    compression: None
+                },
+                // Compressed messages fall back to CBOR headers.
+                Header { typ: typ.clone(), compression: Some(Compression::Deflate), .. Header::default() }
+            ];
+            for hdr in &headers {
+                let mut lens = Vec::new();
+                for encoding in &[HeaderEncoding::Cbor, HeaderEncoding::Compact] {
+                    let mut codec = Codec::new((), 8192);
+                    codec.set_header_encoding(*encoding);
+                    let mut io = Cursor::new(Vec::new());
+                    block_on(codec.write_header(hdr, &mut io)).unwrap();
+                    lens.push(io.get_ref().len());
+                    io.set_position(0);
+                    // The header is decoded regardless of the reader's encoding.
+                    codec.set_header_encoding(HeaderEncoding::Cbor);
+                    assert_eq!(&block_on(codec.read_header(&mut io)).unwrap(), hdr)
+                }
+                assert!(lens[1] <= lens[0], "{:?}: {:?}", hdr, lens)
+            }
+        }
+        // A plain request has a single byte header.
+        let mut codec = Codec::new((), 8192);
+        codec.set_header_encoding(HeaderEncoding::Compact);
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_header(Message::request(()).header(), &mut io)).unwrap();
+        assert_eq!(io.get_ref(), &[1, Type::Request.tag()])
+    }
+
+    #[test]
+    fn malformed_compact_header() {
+        for bytes in &[&[][..], &[1 | COMPACT_CREDIT], &[1, 0], &[1 | COMPACT_CREDIT, 0xff, 0xff, 0xff, 0xff, 0x7f]] {
+            let e = decode_compact(bytes).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData)
+        }
+    }
+
+    #[test]
+    fn unknown_messages() {
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+        let read_request = |codec: &mut Codec<RawCodec>, bytes: &[u8]| {
+            let mut io = Cursor::new(bytes.to_vec());
+            block_on(codec.read_request(&protocol, &mut io)).unwrap()
+        };
+        let read_response = |codec: &mut Codec<RawCodec>, bytes: &[u8]| {
+            let mut io = Cursor::new(bytes.to_vec());
+            block_on(codec.read_response(&protocol, &mut io)).unwrap()
+        };
+
+        // Unknown types, i.e. {0: [9, []]} and compact tag 7, are read as
+        // messages without type.
+        for bytes in &[&[0x05, 0xa1, 0x00, 0x82, 0x09, 0x80][..], &[0x01, 0x07]] {
+            assert_eq!(read_request(&mut codec, bytes).into_parts(), (Header::default(), None));
+            assert_eq!(read_response(&mut codec, bytes).into_parts(), (Header::default(), None))
+        }
+
+        // Messages of unexpected types are read without data.
+        // {0: [3, []], 2: 7}, i.e. an ack
+        let msg = read_request(&mut codec, &[0x07, 0xa2, 0x00, 0x82, 0x03, 0x80, 0x02, 0x07]);
+        assert_eq!(msg.into_parts(), (Message::<()>::ack(7).header, None));
+        // {0: [0, []]}, data "ping"
+        let msg = read_response(&mut codec, &[0x05, 0xa1, 0x00, 0x82, 0x00, 0x80, 0x04, b'p', b'i', b'n', b'g']);
+        assert_eq!(msg.into_parts(), (Message::<()>::request(()).header, None))
+    }
+
+    #[test]
+    fn header_version() {
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+
+        for version in &[None, Some(VERSION), Some(VERSION + 1)] {
+            let hdr = Header { typ: Some(Type::Credit), credit: Some(1), ident: Some(1), version: *version, .. Header::default() };
+            let mut io = Cursor::new(Vec::new());
+            block_on(codec.write_header(&hdr, &mut io)).unwrap();
+            io.set_position(0);
+            let msg = block_on(codec.read_request(&protocol, &mut io)).unwrap();
+            assert_eq!(msg.header(), &hdr)
+        }
+
+        // A message of an unsupported version is read without its data
+        // instead of failing, which would close the connection.
+        for read_request in &[true, false] {
+            let hdr = Header { typ: Some(Type::Request), version: Some(VERSION + 1), .. Header::default() };
+            let mut io = Cursor::new(Vec::new());
+            block_on(codec.write_header(&hdr, &mut io)).unwrap();
+            let header_len = io.get_ref().len();
+            block_on(RawCodec.write_request(&RawProtocol, &mut io, b"ping".to_vec())).unwrap();
+            io.set_position(0);
+            let msg = if *read_request {
+                block_on(codec.read_request(&protocol, &mut io)).unwrap().into_parts()
+            } else {
+                block_on(codec.read_response(&protocol, &mut io)).unwrap().into_parts()
+            };
+            assert_eq!(msg, (hdr, None));
+            assert_eq!(io.position() as usize, header_len)
+        }
+    }
+
+    #[test]
+    fn large_payload() {
+        let payload = (0 .. 4 * 1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+
+        let mut io = Cursor::new(Vec::new());
+        let msg = Message::request(payload.clone());
+        block_on(codec.write_request(&protocol, &mut io, msg)).unwrap();
+        // The payload is written by the inner codec without passing through
+        // the header buffer.
+        assert!(codec.buffer.capacity() < 64);
+
+        io.set_position(0);
+        let msg = block_on(codec.read_request(&protocol, &mut io)).unwrap();
+        assert!(codec.buffer.capacity() < 64);
+        let header_len = 1 + encoded_len(msg.header()) as usize;
+        assert_eq!(msg.data_len(), Some(io.get_ref().len() - header_len));
+        assert_eq!(msg.into_parts().1, Some(payload))
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression() {
+        let payload = br#"{"key": "value", "list": [1, 2, 3]}"#.repeat(100);
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+
+        let mut plain = Cursor::new(Vec::new());
+        block_on(codec.write_response(&protocol, &mut plain, Message::response(payload.clone()))).unwrap();
+
+        codec.set_compression(Some(Compression::Deflate));
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_response(&protocol, &mut io, Message::response(payload.clone()))).unwrap();
+        assert!(io.get_ref().len() < plain.get_ref().len() / 10, "{}", io.get_ref().len());
+
+        // Budgets and stats see the length of the uncompressed data.
+        io.set_position(0);
+        let msg = block_on(codec.read_response(&protocol, &mut io)).unwrap();
+        assert_eq!(msg.header().compression, Some(Compression::Deflate));
+        assert_eq!(msg.header().version(), VERSION);
+        assert_eq!(msg.data_len(), Some(plain.get_ref().len() - 1 - encoded_len(&Message::<()>::response(()).header) as usize));
+        assert_eq!(msg.into_parts().1, Some(payload.clone()));
+
+        // Compressed data is subject to the max. message length.
+        codec.set_max_message_len(payload.len());
+        io.set_position(0);
+        let e = block_on(codec.read_response(&protocol, &mut io)).unwrap_err();
+        assert!(crate::codec::is_message_too_large(&e));
+
+        // Incompressible data is sent as is.
+        let payload = (0 .. 64).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_request(&protocol, &mut io, Message::request(payload.clone()))).unwrap();
+        io.set_position(0);
+        let msg = block_on(codec.read_request(&protocol, &mut io)).unwrap();
+        assert_eq!(msg.header().compression, None);
+        assert_eq!(msg.header().version, None);
+        assert_eq!(msg.into_parts().1, Some(payload))
+    }
+
+    /// A compressed request header announcing `len` bytes of data, of
+    /// which only a few follow.
+    fn compressed_request(codec: &mut Codec<RawCodec>, len: usize) -> (Cursor<Vec<u8>>, usize) {
+        let hdr = Header {
+            typ: Some(Type::Request),
+            version: Some(VERSION),
+            compression: Some(Compression::Deflate),
+            .. Header::default()
+        };
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_header(&hdr, &mut io)).unwrap();
+        let header_len = io.get_ref().len();
+        let mut b = unsigned_varint::encode::usize_buffer();
+        block_on(io.write_all(unsigned_varint::encode::usize(len, &mut b))).unwrap();
+        block_on(io.write_all(&[0; 16])).unwrap();
+        io.set_position(0);
+        (io, header_len)
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_length_not_trusted() {
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+        // Without a max. message length, an announced length which would
+        // exhaust memory if allocated up front fails once the data ends.
+        let (mut io, _) = compressed_request(&mut codec, usize::MAX / 2);
+        let e = block_on(codec.read_request(&protocol, &mut io)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compression_unsupported() {
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+        // The message is rejected before anything beyond the header is read.
+        let (mut io, header_len) = compressed_request(&mut codec, usize::MAX / 2);
+        let e = block_on(codec.read_request(&protocol, &mut io)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(io.position() as usize, header_len)
+    }
+
+    /// An inner codec which writes its responses as a sequence of chunks,
+    /// terminated by an empty chunk, and reads them back one at a time.
+    #[derive(Debug, Clone)]
+    struct ChunkedCodec;
+
+    #[async_trait]
+    impl RequestResponseCodec for ChunkedCodec {
+        type Protocol = RawProtocol;
+        type Request = Vec<u8>;
+        type Response = Vec<Vec<u8>>;
+
+        async fn read_request<T>(&mut self, p: &RawProtocol, io: &mut T) -> io::Result<Vec<u8>>
+        where
+            T: AsyncRead + Unpin + Send
+        {
+            RawCodec.read_request(p, io).await
+        }
+
+        async fn read_response<T>(&mut self, _: &RawProtocol, io: &mut T) -> io::Result<Vec<Vec<u8>>>
+        where
+            T: AsyncRead + Unpin + Send
+        {
+            let mut chunks = Vec::new();
+            loop {
+                let chunk = read_one(io, MAX_PAYLOAD).await
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if chunk.is_empty() {
+                    return Ok(chunks)
+                }
+                chunks.push(chunk)
+            }
+        }
+
+        async fn write_request<T>(&mut self, p: &RawProtocol, io: &mut T, r: Vec<u8>) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send
+        {
+            RawCodec.write_request(p, io, r).await
+        }
+
+        async fn write_response<T>(&mut self, _: &RawProtocol, io: &mut T, r: Vec<Vec<u8>>) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send
+        {
+            for chunk in r.into_iter().chain(std::iter::once(Vec::new())) {
+                write_one(io, chunk).await?;
+                io.flush().await?
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chunked_response() {
+        let chunks = (0 .. 64u8).map(|i| vec![i; 64 * 1024]).collect::<Vec<_>>();
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(ChunkedCodec, 8192);
+
+        let mut io = Cursor::new(Vec::new());
+        let msg = Message::response(chunks.clone());
+        block_on(codec.write_response(&protocol, &mut io, msg)).unwrap();
+        assert!(codec.buffer.capacity() < 64);
+
+        // All chunks follow a single response header.
+        io.set_position(0);
+        let msg = block_on(codec.read_response(&protocol, &mut io)).unwrap();
+        assert_eq!(msg.header().typ, Some(Type::Response));
+        assert_eq!(msg.header().weight, None);
+        assert_eq!(msg.data_len(), Some(io.get_ref().len() - 1 - encoded_len(msg.header()) as usize));
+        assert_eq!(msg.into_parts().1, Some(chunks))
+    }
+
+    #[test]
+    fn zero_weight_rejected() {
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+        for encoding in &[HeaderEncoding::Cbor, HeaderEncoding::Compact] {
+            codec.set_header_format(Arc::new(*encoding));
+            let mut io = Cursor::new(Vec::new());
+            block_on(codec.write_request(&protocol, &mut io, Message::weighted_request(vec![7], 0))).unwrap();
+            io.set_position(0);
+            let e = block_on(codec.read_request(&protocol, &mut io)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", encoding)
+        }
+    }
+
+    #[test]
+    fn message_too_large() {
+        let protocol = ProtocolWrapper::new(b"/t/1", RawProtocol);
+        let mut codec = Codec::new(RawCodec, 8192);
+
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_request(&protocol, &mut io, Message::request(vec![7; 1024]))).unwrap();
+        let header_len = 1 + encoded_len(&Message::<()>::request(()).header) as usize;
+        let data_len = io.get_ref().len() - header_len;
+
+        codec.set_max_message_len(data_len);
+        io.set_position(0);
+        let msg = block_on(codec.read_request(&protocol, &mut io)).unwrap();
+        assert_eq!(msg.data_len(), Some(data_len));
+
+        // An oversized request is read without data and no more
+        // than one byte beyond the limit is read.
+        codec.set_max_message_len(data_len - 1);
+        io.set_position(0);
+        let msg = block_on(codec.read_request(&protocol, &mut io)).unwrap();
+        assert!(msg.is_too_large());
+        assert_eq!(msg.data_len(), None);
+        assert_eq!(msg.into_parts(), (Message::<()>::request(()).header, None));
+        assert_eq!(io.position() as usize, header_len + data_len);
+
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_response(&protocol, &mut io, Message::response(vec![7; 1024]))).unwrap();
+        io.set_position(0);
+        let e = block_on(codec.read_response(&protocol, &mut io)).unwrap_err();
+        let e = e.into_inner().unwrap().downcast::<MessageTooLarge>().unwrap();
+        assert_eq!(*e, MessageTooLarge { limit: data_len - 1 })
+    }
+
+    #[cfg(all(feature = "serde_json", feature = "bincode"))]
+    #[test]
+    fn header_formats() {
+        let types = vec![
+            None,
+            Some(Type::Request),
+            Some(Type::Response),
+            Some(Type::Credit),
+            Some(Type::Ack),
+            Some(Type::Throttled),
+            Some(Type::Notification)
+        ];
+        let formats: [Arc<dyn HeaderFormat>; 3] =
+            [Arc::new(HeaderEncoding::Cbor), Arc::new(JsonFormat), Arc::new(BincodeFormat)];
+        for typ in types {
+            let hdr = Header { typ, weight: Some(2), .. header() };
+            for format in &formats {
+                let mut codec = Codec::new((), 8192);
+                codec.set_header_format(format.clone());
+                let mut io = Cursor::new(Vec::new());
+                block_on(codec.write_header(&hdr, &mut io)).unwrap();
+                io.set_position(0);
+                assert_eq!(block_on(codec.read_header(&mut io)).unwrap(), hdr, "{:?}", format)
+            }
+        }
+        assert!(!HeaderEncoding::Compact.requires_own_prefix());
+        assert!(JsonFormat.requires_own_prefix());
+        assert!(BincodeFormat.requires_own_prefix());
+
+        // JSON headers are only read by codecs expecting them.
+        let mut codec = Codec::new((), 8192);
+        codec.set_header_format(Arc::new(JsonFormat));
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_header(&header(), &mut io)).unwrap();
+        io.set_position(0);
+        codec.set_header_format(Arc::new(BincodeFormat));
+        assert!(block_on(codec.read_header(&mut io)).is_err())
+    }
+
+    /// Fixed encodings of the `/t/1` wire format.
+    ///
+    /// Each message is framed as the unsigned varint length of the header,
+    /// the header and the data written by the inner codec, which here is
+    /// an unsigned varint length followed by the bytes.
+    mod vectors {
+        use super::*;
+
+        const PROTOCOL: &[u8] = b"/t/1";
+
+        fn request(encoding: HeaderEncoding, msg: Message<Vec<u8>>, bytes: &[u8]) {
+            let protocol = ProtocolWrapper::new(PROTOCOL, RawProtocol);
+            let mut codec = Codec::new(RawCodec, 8192);
+            codec.set_header_encoding(encoding);
+            let mut io = Cursor::new(Vec::new());
+            block_on(codec.write_request(&protocol, &mut io, msg.clone())).unwrap();
+            assert_eq!(io.get_ref().as_slice(), bytes);
+            io.set_position(0);
+            let decoded = block_on(codec.read_request(&protocol, &mut io)).unwrap();
+            assert_eq!(decoded.into_parts(), msg.into_parts())
+        }
+
+        fn response(encoding: HeaderEncoding, msg: Message<Vec<u8>>, bytes: &[u8]) {
+            let protocol = ProtocolWrapper::new(PROTOCOL, RawProtocol);
+            let mut codec = Codec::new(RawCodec, 8192);
+            codec.set_header_encoding(encoding);
+            let mut io = Cursor::new(Vec::new());
+            block_on(codec.write_response(&protocol, &mut io, msg.clone())).unwrap();
+            assert_eq!(io.get_ref().as_slice(), bytes);
+            io.set_position(0);
+            let decoded = block_on(codec.read_response(&protocol, &mut io)).unwrap();
+            assert_eq!(decoded.into_parts(), msg.into_parts())
+        }
+
+        #[test]
+        fn protocol_name() {
+            assert_eq!(ProtocolWrapper::new(PROTOCOL, RawProtocol).protocol_name(), b"/t/1/raw/1")
+        }
+
+        #[test]
+        fn cbor() {
+            use HeaderEncoding::Cbor;
+            // {0: [0, []]}, data "ping"
+            request(Cbor, Message::request(b"ping".to_vec()),
+                &[0x05, 0xa1, 0x00, 0x82, 0x00, 0x80, 0x04, b'p', b'i', b'n', b'g']);
+            // {0: [0, []], 3: 2}, empty data
+            request(Cbor, Message::weighted_request(Vec::new(), 2),
+                &[0x07, 0xa2, 0x00, 0x82, 0x00, 0x80, 0x03, 0x02, 0x00]);
+            // {0: [2, []], 1: 5, 2: 7}
+            request(Cbor, Message::credit(5, 7),
+                &[0x09, 0xa3, 0x00, 0x82, 0x02, 0x80, 0x01, 0x05, 0x02, 0x07]);
+            // {0: [2, []], 1: 4294967295, 2: 1099511627776}
+            request(Cbor, Message::credit(u32::MAX, 1 << 40),
+                &[0x15, 0xa3, 0x00, 0x82, 0x02, 0x80,
+                  0x01, 0x1a, 0xff, 0xff, 0xff, 0xff,
+                  0x02, 0x1b, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            // {0: [1, []]}, data "pong"
+            response(Cbor, Message::response(b"pong".to_vec()),
+                &[0x05, 0xa1, 0x00, 0x82, 0x01, 0x80, 0x04, b'p', b'o', b'n', b'g']);
+            // {0: [3, []], 2: 7}
+            response(Cbor, Message::ack(7),
+                &[0x07, 0xa2, 0x00, 0x82, 0x03, 0x80, 0x02, 0x07]);
+            // {0: [4, []]}
+            response(Cbor, Message::throttled(),
+                &[0x05, 0xa1, 0x00, 0x82, 0x04, 0x80]);
+        }
+
+        #[test]
+        fn compact() {
+            use HeaderEncoding::Compact;
+            request(Compact, Message::request(b"ping".to_vec()),
+                &[0x01, 0x01, 0x04, b'p', b'i', b'n', b'g']);
+            request(Compact, Message::weighted_request(Vec::new(), 2),
+                &[0x02, 0x21, 0x02, 0x00]);
+            request(Compact, Message::credit(5, 7),
+                &[0x03, 0x1b, 0x05, 0x07]);
+            request(Compact, Message::credit(u32::MAX, 1 << 40),
+                &[0x0c, 0x1b, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x80, 0x80, 0x80, 0x80, 0x80, 0x20]);
+            response(Compact, Message::response(b"pong".to_vec()),
+                &[0x01, 0x02, 0x04, b'p', b'o', b'n', b'g']);
+            response(Compact, Message::ack(7),
+                &[0x02, 0x14, 0x07]);
+            response(Compact, Message::throttled(),
+                &[0x01, 0x05]);
+        }
+
+        #[test]
+        fn optional_fields() {
+            let decode = |bytes: &[u8]| {
+                let mut codec = Codec::new((), 8192);
+                block_on(codec.read_header(&mut Cursor::new(bytes))).unwrap()
+            };
+            // {0: [2, []], 2: 7}
+            assert_eq!(decode(&[0x07, 0xa2, 0x00, 0x82, 0x02, 0x80, 0x02, 0x07]),
+                Header { typ: Some(Type::Credit), ident: Some(7), .. Header::default() });
+            // {0: [3, []]}
+            assert_eq!(decode(&[0x05, 0xa1, 0x00, 0x82, 0x03, 0x80]),
+                Header { typ: Some(Type::Ack), .. Header::default() });
+            // {}
+            assert_eq!(decode(&[0x01, 0xa0]), Header::default());
+            // {0: [0, []], 4: 1}, i.e. an explicit version 1
+            assert_eq!(decode(&[0x07, 0xa2, 0x00, 0x82, 0x00, 0x80, 0x04, 0x01]),
+                Header { typ: Some(Type::Request), version: Some(1), .. Header::default() });
+            // Compact: credit without ident, ack without ident
+            assert_eq!(decode(&[0x02, 0x0b, 0x05]),
+                Header { typ: Some(Type::Credit), credit: Some(5), .. Header::default() });
+            assert_eq!(decode(&[0x01, 0x04]),
+                Header { typ: Some(Type::Ack), .. Header::default() })
+        }
+    }
+}
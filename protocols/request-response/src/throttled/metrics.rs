@@ -0,0 +1,118 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Prometheus metrics of a [`Throttled`](super::Throttled) behaviour.
+
+use libp2p_core::PeerId;
+use prometheus::{IntCounter, IntGaugeVec, Opts, Registry};
+use std::{cmp::max, convert::TryFrom, time::Duration};
+use super::ThrottledStats;
+use wasm_timer::Instant;
+
+/// The min. time between two updates of the metrics.
+pub(super) const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The counters of [`ThrottledStats`] and the budgets of all
+/// connected peers.
+///
+/// The metrics share their values with the clones registered, so
+/// updating them here updates the registered ones.
+#[derive(Clone, Debug)]
+pub(super) struct Metrics {
+    requests_sent: IntCounter,
+    requests_throttled: IntCounter,
+    credits_granted: IntCounter,
+    credits_acknowledged: IntCounter,
+    credit_retransmissions: IntCounter,
+    too_many_inbound_requests: IntCounter,
+    unknown_messages: IntCounter,
+    send_budget: IntGaugeVec,
+    recv_budget: IntGaugeVec,
+    /// When the metrics have last been updated.
+    pub(super) updated_at: Option<Instant>
+}
+
+impl Metrics {
+    pub(super) fn new() -> Self {
+        fn counter(name: &str, help: &str) -> IntCounter {
+            IntCounter::new(name, help).expect("valid counter name and help")
+        }
+        fn gauge(name: &str, help: &str) -> IntGaugeVec {
+            IntGaugeVec::new(Opts::new(name, help), &["peer"]).expect("valid gauge name, help and labels")
+        }
+        Metrics {
+            requests_sent: counter("throttled_requests_sent_total", "Number of requests sent."),
+            requests_throttled: counter("throttled_requests_throttled_total",
+                "Number of requests which were not sent due to the send budget."),
+            credits_granted: counter("throttled_credits_granted_total", "Number of credit grants sent."),
+            credits_acknowledged: counter("throttled_credits_acknowledged_total",
+                "Number of credit grants acknowledged by peers."),
+            credit_retransmissions: counter("throttled_credit_retransmissions_total",
+                "Number of retransmitted credit grants."),
+            too_many_inbound_requests: counter("throttled_too_many_inbound_requests_total",
+                "Number of times a peer exceeded its receive budget."),
+            unknown_messages: counter("throttled_unknown_messages_total",
+                "Number of inbound messages of an unknown or unexpected type."),
+            send_budget: gauge("throttled_send_budget", "Remaining send budget of a peer."),
+            recv_budget: gauge("throttled_recv_budget", "Remaining receive budget of a peer."),
+            updated_at: None
+        }
+    }
+
+    /// Register the metrics with the given registry.
+    pub(super) fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.requests_sent.clone()))?;
+        registry.register(Box::new(self.requests_throttled.clone()))?;
+        registry.register(Box::new(self.credits_granted.clone()))?;
+        registry.register(Box::new(self.credits_acknowledged.clone()))?;
+        registry.register(Box::new(self.credit_retransmissions.clone()))?;
+        registry.register(Box::new(self.too_many_inbound_requests.clone()))?;
+        registry.register(Box::new(self.unknown_messages.clone()))?;
+        registry.register(Box::new(self.send_budget.clone()))?;
+        registry.register(Box::new(self.recv_budget.clone()))
+    }
+
+    /// Update the metrics with the given counters and the send and
+    /// receive budgets of all connected peers.
+    pub(super) fn update<'a, I>(&mut self, now: Instant, stats: &ThrottledStats, budgets: I)
+    where
+        I: IntoIterator<Item = (&'a PeerId, u32, u32)>
+    {
+        fn set(c: &IntCounter, n: u64) {
+            let n = i64::try_from(n).unwrap_or(i64::MAX);
+            c.inc_by(max(n - c.get(), 0))
+        }
+        set(&self.requests_sent, stats.requests_sent);
+        set(&self.requests_throttled, stats.requests_throttled);
+        set(&self.credits_granted, stats.credits_granted);
+        set(&self.credits_acknowledged, stats.credits_acknowledged);
+        set(&self.credit_retransmissions, stats.credit_retransmissions);
+        set(&self.too_many_inbound_requests, stats.too_many_inbound_requests);
+        set(&self.unknown_messages, stats.unknown_messages);
+        self.send_budget.reset();
+        self.recv_budget.reset();
+        for (p, send, recv) in budgets {
+            let peer = p.to_base58();
+            self.send_budget.with_label_values(&[&peer]).set(i64::from(send));
+            self.recv_budget.with_label_values(&[&peer]).set(i64::from(recv))
+        }
+        self.updated_at = Some(now)
+    }
+}
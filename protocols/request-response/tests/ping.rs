@@ -22,7 +22,6 @@
 
 use async_trait::async_trait;
 use libp2p_core::{
-    Multiaddr,
     PeerId,
     identity,
     muxing::StreamMuxerBox,
@@ -31,12 +30,12 @@ use libp2p_core::{
 };
 use libp2p_noise::{NoiseConfig, X25519Spec, Keypair};
 use libp2p_request_response::*;
-use libp2p_swarm::Swarm;
+use libp2p_swarm::{Swarm, SwarmEvent};
 use libp2p_tcp::TcpConfig;
-use futures::{prelude::*, channel::mpsc};
+use futures::{prelude::*, channel::{mpsc, oneshot}};
 use rand::{self, Rng};
-use std::{io, iter};
-use std::{collections::HashSet, num::NonZeroU16};
+use std::{io, iter, time::{Duration, Instant}};
+use std::{collections::HashSet, num::{NonZeroU16, NonZeroU32}};
 
 /// Exercises a simple ping protocol.
 #[test]
@@ -44,31 +43,12 @@ fn ping_protocol() {
     let ping = Ping("ping".to_string().into_bytes());
     let pong = Pong("pong".to_string().into_bytes());
 
-    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
-    let cfg = RequestResponseConfig::default();
-
-    let (peer1_id, trans) = mk_transport();
-    let ping_proto1 = RequestResponse::new(PingCodec(), protocols.clone(), cfg.clone());
-    let mut swarm1 = Swarm::new(trans, ping_proto1, peer1_id.clone());
-
-    let (peer2_id, trans) = mk_transport();
-    let ping_proto2 = RequestResponse::new(PingCodec(), protocols, cfg);
-    let mut swarm2 = Swarm::new(trans, ping_proto2, peer2_id.clone());
-
-    let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
-
-    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
-    Swarm::listen_on(&mut swarm1, addr).unwrap();
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = pair(RequestResponseConfig::default());
 
     let expected_ping = ping.clone();
     let expected_pong = pong.clone();
 
     let peer1 = async move {
-        while let Some(_) = swarm1.next().now_or_never() {}
-
-        let l = Swarm::listeners(&swarm1).next().unwrap();
-        tx.send(l.clone()).await.unwrap();
-
         loop {
             match swarm1.next().await {
                 RequestResponseEvent::Message {
@@ -88,8 +68,6 @@ fn ping_protocol() {
 
     let peer2 = async move {
         let mut count = 0;
-        let addr = rx.next().await.unwrap();
-        swarm2.add_address(&peer1_id, addr.clone());
         let mut req_id = swarm2.send_request(&peer1_id, ping.clone());
 
         loop {
@@ -122,21 +100,7 @@ fn ping_protocol_throttled() {
     let ping = Ping("ping".to_string().into_bytes());
     let pong = Pong("pong".to_string().into_bytes());
 
-    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
-    let cfg = RequestResponseConfig::default();
-
-    let (peer1_id, trans) = mk_transport();
-    let ping_proto1 = RequestResponse::throttled(PingCodec(), protocols.clone(), cfg.clone());
-    let mut swarm1 = Swarm::new(trans, ping_proto1, peer1_id.clone());
-
-    let (peer2_id, trans) = mk_transport();
-    let ping_proto2 = RequestResponse::throttled(PingCodec(), protocols, cfg);
-    let mut swarm2 = Swarm::new(trans, ping_proto2, peer2_id.clone());
-
-    let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
-
-    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
-    Swarm::listen_on(&mut swarm1, addr).unwrap();
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
 
     let expected_ping = ping.clone();
     let expected_pong = pong.clone();
@@ -147,10 +111,6 @@ fn ping_protocol_throttled() {
     swarm2.set_receive_limit(NonZeroU16::new(limit2).unwrap());
 
     let peer1 = async move {
-        while let Some(_) = swarm1.next().now_or_never() {}
-
-        let l = Swarm::listeners(&swarm1).next().unwrap();
-        tx.send(l.clone()).await.unwrap();
         for i in 1 .. {
             match swarm1.next().await {
                 throttled::Event::Event(RequestResponseEvent::Message {
@@ -161,6 +121,10 @@ fn ping_protocol_throttled() {
                     assert_eq!(&peer, &peer2_id);
                     swarm1.send_response(channel, pong.clone());
                 },
+                throttled::Event::CreditGranted { peer, .. } | throttled::Event::CreditRtt { peer, .. } => {
+                    assert_eq!(&peer, &peer2_id);
+                    continue
+                }
                 e => panic!("Peer1: Unexpected event: {:?}", e)
             }
             if i % 31 == 0 {
@@ -174,9 +138,6 @@ fn ping_protocol_throttled() {
 
     let peer2 = async move {
         let mut count = 0;
-        let addr = rx.next().await.unwrap();
-        swarm2.add_address(&peer1_id, addr.clone());
-
         let mut blocked = false;
         let mut req_ids = HashSet::new();
 
@@ -213,6 +174,979 @@ fn ping_protocol_throttled() {
     let () = async_std::task::block_on(peer2);
 }
 
+#[test]
+fn throttled_send_budget() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, _, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    let limit = 3;
+    swarm1.set_receive_limit(NonZeroU16::new(limit).unwrap());
+
+    let peer1 = async move {
+        loop {
+            match swarm1.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                }) => swarm1.send_response(channel, pong.clone()),
+                throttled::Event::CreditGranted { .. } | throttled::Event::CreditRtt { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        // Unknown peers have an initial budget of 1.
+        assert_eq!(swarm2.send_budget(&peer1_id), 1);
+        assert_eq!(swarm2.available_send_budget(&peer1_id), 1);
+        assert!(swarm2.can_send(&peer1_id));
+        swarm2.send_request(&peer1_id, ping.clone()).unwrap();
+        assert_eq!(swarm2.send_budget(&peer1_id), 0);
+        assert_eq!(swarm2.available_send_budget(&peer1_id), 0);
+        assert!(!swarm2.can_send(&peer1_id));
+        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_err());
+
+        let mut responses = 0;
+        let mut resumed = false;
+        while responses < 1 || !resumed {
+            match swarm2.next().await {
+                throttled::Event::ResumeSending(peer) => {
+                    assert_eq!(peer, peer1_id);
+                    resumed = true
+                }
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => responses += 1,
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+
+        // The credit grant restores the budget to the receive limit of peer1.
+        assert_eq!(swarm2.send_budget(&peer1_id), u32::from(limit));
+        assert_eq!(swarm2.available_send_budget(&peer1_id), u32::from(limit));
+        assert!(swarm2.can_send(&peer1_id));
+        for n in (0 .. u32::from(limit)).rev() {
+            swarm2.send_request(&peer1_id, ping.clone()).unwrap();
+            assert_eq!(swarm2.send_budget(&peer1_id), n);
+        }
+        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_err());
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+#[test]
+fn throttled_recv_budget() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    let limit = 3;
+    swarm1.set_receive_limit(NonZeroU16::new(limit).unwrap());
+
+    let peer1 = async move {
+        // The initial request is always allowed; the configured limit
+        // becomes effective once it has been answered. Responding after
+        // the budget is used up grants new credit to peer2.
+        let mut channels = Vec::new();
+        for budget in iter::once(0).chain((0 .. u32::from(limit)).rev()) {
+            let channel = loop {
+                match swarm1.next().await {
+                    throttled::Event::Event(RequestResponseEvent::Message {
+                        peer,
+                        message: RequestResponseMessage::Request { channel, .. }
+                    }) => {
+                        assert_eq!(peer, peer2_id);
+                        break channel
+                    },
+                    throttled::Event::CreditGranted { .. } | throttled::Event::CreditRtt { .. } => {}
+                    e => panic!("Peer1: Unexpected event: {:?}", e)
+                }
+            };
+            assert_eq!(swarm1.recv_budget(&peer2_id), budget);
+            channels.push(channel);
+            if budget == 0 {
+                for channel in channels.drain(..) {
+                    swarm1.send_response(channel, pong.clone());
+                    assert_eq!(swarm1.recv_budget(&peer2_id), u32::from(limit));
+                }
+            }
+        }
+    };
+
+    let peer2 = async move {
+        loop {
+            while swarm2.send_request(&peer1_id, ping.clone()).is_ok() {}
+            match swarm2.next().await {
+                throttled::Event::ResumeSending(_) => {}
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer2));
+    let () = async_std::task::block_on(peer1);
+}
+
+#[test]
+fn throttled_trusted_peer() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    let limit = 3;
+    swarm1.trust_peer(&peer2_id, NonZeroU16::new(limit).unwrap());
+    swarm2.override_initial_send_budget(&peer1_id, NonZeroU16::new(limit).unwrap());
+
+    let peer1 = async move {
+        // All requests are accepted without an intermediate credit grant.
+        let mut channels = Vec::new();
+        for budget in (0 .. u32::from(limit)).rev() {
+            match swarm1.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Request { channel, .. }
+                }) => {
+                    assert_eq!(peer, peer2_id);
+                    assert_eq!(swarm1.recv_budget(&peer2_id), budget);
+                    channels.push(channel)
+                },
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+        for channel in channels {
+            swarm1.send_response(channel, pong.clone())
+        }
+        loop {
+            match swarm1.next().await {
+                throttled::Event::CreditGranted { peer, amount, .. } => {
+                    assert_eq!(peer, peer2_id);
+                    assert_eq!(amount, u32::from(limit));
+                    break
+                }
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        for _ in 0 .. limit {
+            assert!(swarm2.send_request(&peer1_id, ping.clone()).is_ok())
+        }
+        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_err());
+        loop {
+            match swarm2.next().await {
+                throttled::Event::ResumeSending(_) => {}
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer2));
+    let () = async_std::task::block_on(peer1);
+}
+
+#[test]
+fn throttled_proactive_credit() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    let extra = 2;
+
+    let peer1 = async move {
+        // The first request uses up the initial budget, after which
+        // more credit is granted without responding.
+        let mut channels = Vec::new();
+        while channels.len() < 1 + usize::from(extra) {
+            match swarm1.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Request { channel, .. }
+                }) => {
+                    assert_eq!(peer, peer2_id);
+                    channels.push(channel);
+                    if channels.len() == 1 {
+                        assert_eq!(swarm1.recv_budget(&peer2_id), 0);
+                        assert!(swarm1.grant_credit(&peer2_id, NonZeroU16::new(extra).unwrap()))
+                    }
+                },
+                throttled::Event::CreditGranted { amount, .. } => assert_eq!(amount, u32::from(extra)),
+                throttled::Event::CreditRtt { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+        assert_eq!(swarm1.recv_budget(&peer2_id), 0);
+        for channel in channels {
+            swarm1.send_response(channel, pong.clone())
+        }
+    };
+
+    let peer2 = async move {
+        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_ok());
+        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_err());
+        loop {
+            match swarm2.next().await {
+                throttled::Event::ResumeSending(peer) => {
+                    assert_eq!(peer, peer1_id);
+                    assert_eq!(swarm2.send_budget(&peer1_id), u32::from(extra));
+                    for _ in 0 .. extra {
+                        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_ok())
+                    }
+                    assert!(swarm2.send_request(&peer1_id, ping.clone()).is_err())
+                }
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer2));
+    let () = async_std::task::block_on(peer1);
+}
+
+#[test]
+fn throttled_both_directions() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+    swarm1.set_receive_limit(NonZeroU16::new(3).unwrap());
+    swarm2.set_receive_limit(NonZeroU16::new(2).unwrap());
+
+    let (done_tx, mut done_rx) = oneshot::channel::<()>();
+
+    let num_pings = 100;
+
+    // Both peers send requests as fast as their budgets allow and answer
+    // all requests of the other, so that they keep exhausting each other's
+    // budget at the same time.
+    let peer1 = {
+        let (ping, pong) = (ping.clone(), pong.clone());
+        async move {
+            let mut count = 0;
+            let mut connected = false;
+            let mut done_tx = Some(done_tx);
+            loop {
+                if connected {
+                    while swarm1.send_request(&peer2_id, ping.clone()).is_ok() {}
+                }
+                match swarm1.next().await {
+                    throttled::Event::Event(RequestResponseEvent::Message {
+                        message: RequestResponseMessage::Request { channel, .. }, ..
+                    }) => {
+                        connected = true;
+                        swarm1.send_response(channel, pong.clone())
+                    }
+                    throttled::Event::Event(RequestResponseEvent::Message {
+                        message: RequestResponseMessage::Response { .. }, ..
+                    }) => {
+                        count += 1;
+                        if count == num_pings {
+                            done_tx.take().unwrap().send(()).unwrap()
+                        }
+                    }
+                    throttled::Event::ResumeSending(_)
+                    | throttled::Event::CreditGranted { .. }
+                    | throttled::Event::CreditRtt { .. } => {}
+                    e => panic!("Peer1: Unexpected event: {:?}", e)
+                }
+            }
+        }
+    };
+
+    let peer2 = async move {
+        let mut count = 0;
+        let mut peer1_done = false;
+        while count < num_pings || !peer1_done {
+            while swarm2.send_request(&peer1_id, ping.clone()).is_ok() {}
+            match swarm2.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                }) => swarm2.send_response(channel, pong.clone()),
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => count += 1,
+                throttled::Event::ResumeSending(_)
+                | throttled::Event::CreditGranted { .. }
+                | throttled::Event::CreditRtt { .. } => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+            if let Ok(Some(())) = done_rx.try_recv() {
+                peer1_done = true
+            }
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+#[test]
+fn throttled_reject_over_budget() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    // Peer2 assumes a larger budget than peer1 grants.
+    swarm1.set_reject_over_budget(true);
+    swarm2.override_initial_send_budget(&peer1_id, NonZeroU16::new(2).unwrap());
+
+    let peer1 = async move {
+        // The first request uses up the budget of 1 and is answered after
+        // the second one has been rejected.
+        let mut channel = None;
+        loop {
+            match swarm1.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Request { channel: ch, .. }
+                }) => {
+                    assert_eq!(peer, peer2_id);
+                    assert!(channel.replace(ch).is_none())
+                }
+                throttled::Event::TooManyInboundRequests(peer) => {
+                    assert_eq!(peer, peer2_id);
+                    swarm1.send_response(channel.take().unwrap(), pong.clone())
+                }
+                throttled::Event::CreditGranted { .. } | throttled::Event::CreditRtt { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        let sent = (0 .. 2)
+            .map(|_| swarm2.send_request(&peer1_id, ping.clone()).unwrap())
+            .collect::<HashSet<_>>();
+
+        let mut responded = None;
+        let mut throttled = None;
+        while responded.is_none() || throttled.is_none() {
+            match swarm2.next().await {
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Response { request_id, .. }
+                }) => {
+                    assert_eq!(peer, peer1_id);
+                    assert!(responded.replace(request_id).is_none())
+                }
+                throttled::Event::RequestThrottled { peer, request_id } => {
+                    assert_eq!(peer, peer1_id);
+                    assert!(throttled.replace(request_id).is_none())
+                }
+                throttled::Event::ResumeSending(_) => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+        let received = vec![responded.unwrap(), throttled.unwrap()].into_iter().collect::<HashSet<_>>();
+        assert_eq!(received, sent);
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+#[test]
+fn throttled_byte_budget() {
+    let small = Ping(vec![1; 10]);
+    let large = Ping(vec![2; 60]);
+    let pong = Pong("pong".to_string().into_bytes());
+
+    // `write_one` prefixes the data with its varint encoded length,
+    // which is a single byte for the messages used in this test.
+    fn ping_size(Ping(data): &Ping) -> u32 {
+        data.len() as u32 + 1
+    }
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    let limit = NonZeroU32::new(100).unwrap();
+    swarm1.use_byte_budget(limit, ping_size);
+    swarm2.use_byte_budget(limit, ping_size);
+
+    let peer1 = async move {
+        // The initial request is always allowed. Afterwards, requests are
+        // accepted as long as the budget in bytes is not used up. Requests
+        // may arrive in any order.
+        let mut channels = Vec::new();
+        let mut budget: u32 = 1;
+        for _ in 0 .. 4 {
+            let (request, channel) = loop {
+                match swarm1.next().await {
+                    throttled::Event::Event(RequestResponseEvent::Message {
+                        peer,
+                        message: RequestResponseMessage::Request { request, channel, .. }
+                    }) => {
+                        assert_eq!(peer, peer2_id);
+                        break (request, channel)
+                    },
+                    throttled::Event::CreditGranted { .. } | throttled::Event::CreditRtt { .. } => {}
+                    e => panic!("Peer1: Unexpected event: {:?}", e)
+                }
+            };
+            budget = budget.saturating_sub(ping_size(&request));
+            assert_eq!(swarm1.recv_budget(&peer2_id), budget);
+            channels.push(channel);
+            if budget == 0 {
+                budget = limit.get();
+                for channel in channels.drain(..) {
+                    swarm1.send_response(channel, pong.clone());
+                    assert_eq!(swarm1.recv_budget(&peer2_id), limit.get());
+                }
+            }
+        }
+    };
+
+    let peer2 = async move {
+        swarm2.send_request(&peer1_id, large.clone()).unwrap();
+        assert_eq!(swarm2.send_budget(&peer1_id), 0);
+
+        loop {
+            match swarm2.next().await {
+                throttled::Event::ResumeSending(peer) => {
+                    assert_eq!(peer, peer1_id);
+                    break
+                }
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+
+        // The credit is granted in bytes.
+        assert_eq!(swarm2.send_budget(&peer1_id), limit.get());
+        swarm2.send_request(&peer1_id, small.clone()).unwrap();
+        assert_eq!(swarm2.send_budget(&peer1_id), 100 - 11);
+        swarm2.send_request(&peer1_id, large.clone()).unwrap();
+        assert_eq!(swarm2.send_budget(&peer1_id), 100 - 11 - 61);
+        swarm2.send_request(&peer1_id, large.clone()).unwrap();
+        assert_eq!(swarm2.send_budget(&peer1_id), 0);
+        assert!(swarm2.send_request(&peer1_id, small.clone()).is_err());
+
+        loop {
+            match swarm2.next().await {
+                throttled::Event::ResumeSending(_) => {}
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { .. }, ..
+                }) => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer2));
+    let () = async_std::task::block_on(peer1);
+}
+
+/// A request with a short timeout fails before a request with a long
+/// timeout sent earlier to the same peer.
+#[test]
+fn request_timeout_override() {
+    let ping = Ping("ping".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, _, mut swarm2) = pair(RequestResponseConfig::default());
+
+    let peer1 = async move {
+        // Requests are received but never answered.
+        let mut channels = Vec::new();
+        loop {
+            match swarm1.next().await {
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                } => channels.push(channel),
+                RequestResponseEvent::InboundFailure { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        let long = swarm2.send_request_with_timeout(&peer1_id, ping.clone(), Duration::from_secs(60));
+        let short = swarm2.send_request_with_timeout(&peer1_id, ping.clone(), Duration::from_millis(500));
+        assert_ne!(long, short);
+
+        match swarm2.next().await {
+            RequestResponseEvent::OutboundFailure { peer, request_id, error: OutboundFailure::Timeout } => {
+                assert_eq!(&peer, &peer1_id);
+                assert_eq!(request_id, short)
+            }
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+/// Holding a response channel past its deadline results in a timeout,
+/// dropping it results in a response omission.
+#[test]
+fn response_channel_deadline() {
+    let ping = Ping("ping".to_string().into_bytes());
+
+    let mut cfg = RequestResponseConfig::default();
+    cfg.set_request_timeout(Duration::from_secs(1));
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = pair(cfg);
+
+    let peer1 = async move {
+        // The channel of the first request is held past its deadline.
+        let held = match swarm1.next().await {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { channel, .. }, ..
+            } => {
+                assert!(channel.deadline() <= Instant::now() + Duration::from_secs(1));
+                channel
+            }
+            e => panic!("Peer1: Unexpected event: {:?}", e)
+        };
+        match swarm1.next().await {
+            RequestResponseEvent::InboundFailure { peer, error: InboundFailure::Timeout, .. } => {
+                assert_eq!(&peer, &peer2_id);
+                assert!(!held.is_open())
+            }
+            e => panic!("Peer1: Unexpected event: {:?}", e)
+        }
+
+        // The channel of the second request is dropped.
+        let request_id = match swarm1.next().await {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { request_id, channel, .. }, ..
+            } => {
+                drop(channel);
+                request_id
+            }
+            e => panic!("Peer1: Unexpected event: {:?}", e)
+        };
+        match swarm1.next().await {
+            RequestResponseEvent::InboundFailure { peer, request_id: id, error: InboundFailure::ResponseOmission } => {
+                assert_eq!(&peer, &peer2_id);
+                assert_eq!(id, request_id)
+            }
+            e => panic!("Peer1: Unexpected event: {:?}", e)
+        }
+    };
+
+    let peer2 = async move {
+        let req_id = swarm2.send_request(&peer1_id, ping.clone());
+
+        loop {
+            match swarm2.next().await {
+                RequestResponseEvent::OutboundFailure { request_id, .. } if request_id == req_id => {
+                    swarm2.send_request(&peer1_id, ping.clone());
+                }
+                RequestResponseEvent::OutboundFailure { .. } => {}
+                e => panic!("Peer2: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer2));
+    let () = async_std::task::block_on(peer1);
+}
+
+/// No event is emitted for a cancelled request, even after its timeout.
+#[test]
+fn cancel_request() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let cancel = Ping("cancel".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = pair(RequestResponseConfig::default());
+
+    let expected_cancel = cancel.clone();
+    let (mut received_tx, mut received_rx) = mpsc::channel::<()>(1);
+
+    let peer1 = async move {
+        // The cancelled request is never answered, others are answered
+        // after the cancelled request timed out.
+        let mut held = Vec::new();
+        loop {
+            match swarm1.next().await {
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { request, channel, .. }, ..
+                } => {
+                    if request == expected_cancel {
+                        held.push(channel);
+                        received_tx.send(()).await.unwrap()
+                    } else {
+                        async_std::task::sleep(Duration::from_millis(200)).await;
+                        swarm1.send_response(channel, pong.clone())
+                    }
+                }
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        // A request to an unconnected peer is discarded.
+        let unsent = swarm2.send_request(&peer1_id, ping.clone());
+        assert!(!swarm2.cancel_request(&peer2_id, unsent));
+        assert!(swarm2.cancel_request(&peer1_id, unsent));
+        assert!(!swarm2.is_pending_outbound(&unsent));
+
+        let first = swarm2.send_request(&peer1_id, ping.clone());
+        match swarm2.next().await {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. }, ..
+            } => assert_eq!(request_id, first),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+
+        // The request is cancelled once it reached peer1, so that its
+        // substream is dropped without closing the connection.
+        let cancelled = swarm2.send_request_with_timeout(&peer1_id, cancel, Duration::from_millis(100));
+        match future::select(Box::pin(swarm2.next()), received_rx.next()).await {
+            future::Either::Right(_) => {}
+            future::Either::Left((e, _)) => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+        assert!(!swarm2.cancel_request(&peer2_id, cancelled));
+        assert!(swarm2.cancel_request(&peer1_id, cancelled));
+        assert!(!swarm2.cancel_request(&peer1_id, cancelled));
+        let last = swarm2.send_request(&peer1_id, ping.clone());
+        match swarm2.next().await {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. }, ..
+            } => assert_eq!(request_id, last),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+/// Requests cancelled while queued in the connection handler or while their
+/// substream is being negotiated do not lead to any later event.
+#[test]
+fn cancel_request_mid_upgrade() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let cancel = Ping("cancel".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, _, mut swarm2) = pair(RequestResponseConfig::default());
+
+    let expected_cancel = cancel.clone();
+
+    let peer1 = async move {
+        // Cancelled requests which still reach peer1 are answered after
+        // their timeout, so that neither a response nor a timeout must
+        // be reported to peer2.
+        loop {
+            match swarm1.next().await {
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { request, channel, .. }, ..
+                } => {
+                    if request == expected_cancel {
+                        async_std::task::sleep(Duration::from_millis(200)).await
+                    }
+                    swarm1.send_response(channel, pong.clone())
+                }
+                RequestResponseEvent::InboundFailure { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        let first = swarm2.send_request(&peer1_id, ping.clone());
+        match swarm2.next().await {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. }, ..
+            } => assert_eq!(request_id, first),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+
+        // Cancelled before the handler received the request.
+        let queued = swarm2.send_request_with_timeout(&peer1_id, cancel.clone(), Duration::from_millis(100));
+        assert!(swarm2.cancel_request(&peer1_id, queued));
+
+        // Cancelled after the handler requested the substream, while
+        // the protocol is negotiated or the request is being sent.
+        let upgrading = swarm2.send_request_with_timeout(&peer1_id, cancel, Duration::from_millis(100));
+        let wait = async_std::task::sleep(Duration::from_millis(5));
+        match future::select(Box::pin(swarm2.next()), Box::pin(wait)).await {
+            future::Either::Right(_) => {}
+            future::Either::Left((e, _)) => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+        assert!(swarm2.cancel_request(&peer1_id, upgrading));
+        assert!(!swarm2.is_pending_outbound(&upgrading));
+
+        // Neither a response nor a timeout is reported for either request.
+        let wait = async_std::task::sleep(Duration::from_millis(400));
+        match future::select(Box::pin(swarm2.next()), Box::pin(wait)).await {
+            future::Either::Right(_) => {}
+            future::Either::Left((e, _)) => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+
+        let last = swarm2.send_request(&peer1_id, ping.clone());
+        match swarm2.next().await {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. }, ..
+            } => assert_eq!(request_id, last),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+/// Notifications are delivered without a response and use up send budget.
+#[test]
+fn throttled_notification() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+    let note = Ping("note".to_string().into_bytes());
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+
+    let expected_note = note.clone();
+
+    let peer1 = async move {
+        loop {
+            match swarm1.next().await {
+                throttled::Event::Notification { peer, notification, .. } => {
+                    assert_eq!(&peer, &peer2_id);
+                    assert_eq!(&notification, &expected_note)
+                }
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. }, ..
+                }) => swarm1.send_response(channel, pong.clone()),
+                throttled::Event::CreditGranted { .. } | throttled::Event::CreditRtt { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        swarm2.send_notification(&peer1_id, note).unwrap();
+        assert_eq!(swarm2.send_budget(&peer1_id), 0);
+        assert!(swarm2.send_request(&peer1_id, ping.clone()).is_err());
+
+        // Credit is granted once peer1 received the notification.
+        match swarm2.next().await {
+            throttled::Event::ResumeSending(peer) => assert_eq!(peer, peer1_id),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+
+        // Only the response to the request is reported.
+        let rid = swarm2.send_request(&peer1_id, ping.clone()).unwrap();
+        match swarm2.next().await {
+            throttled::Event::Event(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. }, ..
+            }) => assert_eq!(request_id, rid),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+/// An oversized request is rejected without closing the connection.
+#[test]
+fn max_message_size() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+    let large = Ping(vec![0; 1024]);
+
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = throttled_pair(RequestResponseConfig::default());
+    swarm1.set_max_message_size(64);
+
+    let expected_ping = ping.clone();
+
+    let peer1 = async move {
+        loop {
+            match swarm1.next().await {
+                throttled::Event::Event(RequestResponseEvent::InboundFailure {
+                    peer, error: InboundFailure::MessageTooLarge, ..
+                }) => assert_eq!(&peer, &peer2_id),
+                throttled::Event::Event(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { request, channel, .. }, ..
+                }) => {
+                    assert_eq!(&request, &expected_ping);
+                    swarm1.send_response(channel, pong.clone())
+                }
+                throttled::Event::CreditGranted { .. } | throttled::Event::CreditRtt { .. } => {}
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        // The notification is rejected by peer1, which still grants
+        // credit for it.
+        swarm2.send_notification(&peer1_id, large).unwrap();
+        match swarm2.next().await {
+            throttled::Event::ResumeSending(peer) => assert_eq!(peer, peer1_id),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+
+        let rid = swarm2.send_request(&peer1_id, ping.clone()).unwrap();
+        match swarm2.next().await {
+            throttled::Event::Event(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. }, ..
+            }) => assert_eq!(request_id, rid),
+            e => panic!("Peer2: Unexpected event: {:?}", e)
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+/// An idle connection is closed after the keep-alive timeout and
+/// re-established by the next request.
+#[test]
+fn connection_keep_alive() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let pong = Pong("pong".to_string().into_bytes());
+
+    let mut cfg = RequestResponseConfig::default();
+    cfg.set_connection_keep_alive(Duration::from_millis(500));
+    let (peer1_id, mut swarm1, peer2_id, mut swarm2) = pair(cfg);
+
+    let peer1 = async move {
+        loop {
+            match swarm1.next().await {
+                RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Request { channel, .. }
+                } => {
+                    assert_eq!(&peer, &peer2_id);
+                    swarm1.send_response(channel, pong.clone())
+                }
+                e => panic!("Peer1: Unexpected event: {:?}", e)
+            }
+        }
+    };
+
+    let peer2 = async move {
+        for _ in 0 .. 2 {
+            let rid = swarm2.send_request(&peer1_id, ping.clone());
+            let idle = loop {
+                match swarm2.next_event().await {
+                    SwarmEvent::Behaviour(RequestResponseEvent::Message {
+                        message: RequestResponseMessage::Response { request_id, .. }, ..
+                    }) => {
+                        assert_eq!(request_id, rid);
+                        break Instant::now()
+                    }
+                    SwarmEvent::Behaviour(e) => panic!("Peer2: Unexpected event: {:?}", e),
+                    _ => {}
+                }
+            };
+            // The connection closes well before the request timeout.
+            loop {
+                if let SwarmEvent::ConnectionClosed { peer_id, .. } = swarm2.next_event().await {
+                    assert_eq!(peer_id, peer1_id);
+                    break
+                }
+            }
+            let elapsed = idle.elapsed();
+            assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+            assert!(!swarm2.is_connected(&peer1_id))
+        }
+    };
+
+    async_std::task::spawn(Box::pin(peer1));
+    let () = async_std::task::block_on(peer2);
+}
+
+/// The IDs returned by `send_request` are those of the resulting events.
+#[test]
+fn request_ids() {
+    let ping = Ping("ping".to_string().into_bytes());
+    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
+    let cfg = RequestResponseConfig::default();
+
+    let (peer_id, trans) = mk_transport();
+    let ping_proto = RequestResponse::new(PingCodec(), protocols, cfg);
+    let mut swarm = Swarm::new(trans, ping_proto, peer_id);
+
+    // Neither peer has a known address, so dialing fails.
+    let rid1 = swarm.send_request(&PeerId::random(), ping.clone());
+    let rid2 = swarm.send_request(&PeerId::random(), ping);
+    assert_eq!(rid2.as_u64(), rid1.as_u64() + 1);
+    assert_eq!(RequestId::new(rid1.as_u64()), rid1);
+    assert_eq!(rid1.to_string(), rid1.as_u64().to_string());
+
+    let mut expected: HashSet<_> = vec![rid1, rid2].into_iter().collect();
+    async_std::task::block_on(async move {
+        while !expected.is_empty() {
+            match swarm.next().await {
+                RequestResponseEvent::OutboundFailure { request_id, error: OutboundFailure::DialFailure, .. } =>
+                    assert!(expected.remove(&request_id), "{}", request_id),
+                e => panic!("Unexpected event: {:?}", e)
+            }
+        }
+    })
+}
+
+/// Two swarms of which the second knows the listen address of the first.
+fn pair(cfg: RequestResponseConfig)
+    -> (PeerId, Swarm<RequestResponse<PingCodec>>, PeerId, Swarm<RequestResponse<PingCodec>>)
+{
+    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
+
+    let (peer1_id, trans) = mk_transport();
+    let ping_proto1 = RequestResponse::new(PingCodec(), protocols.clone(), cfg.clone());
+    let mut swarm1 = Swarm::new(trans, ping_proto1, peer1_id.clone());
+
+    let (peer2_id, trans) = mk_transport();
+    let ping_proto2 = RequestResponse::new(PingCodec(), protocols, cfg);
+    let mut swarm2 = Swarm::new(trans, ping_proto2, peer2_id.clone());
+
+    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+    Swarm::listen_on(&mut swarm1, addr).unwrap();
+    while let Some(_) = swarm1.next().now_or_never() {}
+    let addr = Swarm::listeners(&swarm1).next().unwrap().clone();
+    swarm2.add_address(&peer1_id, addr);
+
+    (peer1_id, swarm1, peer2_id, swarm2)
+}
+
+/// Like [`pair`], with throttled swarms.
+fn throttled_pair(cfg: RequestResponseConfig)
+    -> (PeerId, Swarm<throttled::Throttled<PingCodec>>, PeerId, Swarm<throttled::Throttled<PingCodec>>)
+{
+    let protocols = iter::once((PingProtocol(), ProtocolSupport::Full));
+
+    let (peer1_id, trans) = mk_transport();
+    let ping_proto1 = RequestResponse::throttled(PingCodec(), protocols.clone(), cfg.clone());
+    let mut swarm1 = Swarm::new(trans, ping_proto1, peer1_id.clone());
+
+    let (peer2_id, trans) = mk_transport();
+    let ping_proto2 = RequestResponse::throttled(PingCodec(), protocols, cfg);
+    let mut swarm2 = Swarm::new(trans, ping_proto2, peer2_id.clone());
+
+    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+    Swarm::listen_on(&mut swarm1, addr).unwrap();
+    while let Some(_) = swarm1.next().now_or_never() {}
+    let addr = Swarm::listeners(&swarm1).next().unwrap().clone();
+    swarm2.add_address(&peer1_id, addr);
+
+    (peer1_id, swarm1, peer2_id, swarm2)
+}
+
 fn mk_transport() -> (PeerId, transport::Boxed<(PeerId, StreamMuxerBox)>) {
     let id_keys = identity::Keypair::generate_ed25519();
     let peer_id = id_keys.public().into_peer_id();